@@ -40,6 +40,11 @@ impl AgentRegistry {
         self.write_agents().insert(key, agent);
     }
 
+    /// Remove an agent, returning it if it was registered.
+    pub fn remove(&self, r: &AgentRef) -> Option<Arc<Agent>> {
+        self.write_agents().remove(r)
+    }
+
     /// Look up an agent by its [`AgentRef`].
     pub fn get(&self, r: &AgentRef) -> Option<Arc<Agent>> {
         self.read_agents().get(r).cloned()
@@ -0,0 +1,598 @@
+//! Wire-format adapters for chat completion backends.
+//!
+//! An [`ApiProvider`] decides how a request body is laid out, how the response
+//! body is read back into a [`ChatCompletionResponse`], and which header carries
+//! the API key. Transport, key rotation and retries stay in `Swarm`.
+
+use crate::constants::{ANTHROPIC_DEFAULT_API_URL, OPENAI_DEFAULT_API_URL};
+use crate::error::{SwarmError, SwarmResult};
+use crate::types::{
+    Agent, AgentFunction, ChatCompletionResponse, FunctionCallPolicy, LogprobsConfig, Message,
+    MessageRole,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+
+/// Value sent in the `anthropic-version` header.
+pub const ANTHROPIC_API_VERSION: &str = "2023-06-01";
+/// `max_tokens` is mandatory on the Anthropic messages API.
+pub const ANTHROPIC_DEFAULT_MAX_TOKENS: u32 = 4096;
+
+pub trait ApiProvider: Send + Sync {
+    /// Endpoint used when the builder is not given another API URL.
+    fn default_api_url(&self) -> &'static str {
+        OPENAI_DEFAULT_API_URL
+    }
+
+    fn build_request_body(
+        &self,
+        agent: &Agent,
+        messages: &[Message],
+        functions: &[AgentFunction],
+        model: &str,
+    ) -> Value;
+
+    fn parse_response(&self, body: &str) -> SwarmResult<ChatCompletionResponse>;
+
+    /// Header name and value that authenticate a request with `api_key`.
+    fn auth_header(&self, api_key: &str) -> (&'static str, String);
+
+    /// Additional headers the backend requires on every request.
+    fn extra_headers(&self) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    /// Attach the end-user identifier used for provider-side abuse tracking.
+    fn apply_user_id(&self, body: &mut Value, user_id: &str) {
+        body["user"] = json!(user_id);
+    }
+
+    /// Attach sequences that end generation.
+    fn apply_stop_sequences(&self, body: &mut Value, stop: &[String]) {
+        body["stop"] = json!(stop);
+    }
+
+    /// Attach the sampling seed for approximately deterministic completions.
+    fn apply_seed(&self, body: &mut Value, seed: i64) {
+        body["seed"] = json!(seed);
+    }
+
+    /// Request token log probabilities for the completion.
+    fn apply_logprobs(&self, body: &mut Value, config: &LogprobsConfig) {
+        body["logprobs"] = json!(true);
+        if let Some(top_logprobs) = config.top_logprobs {
+            body["top_logprobs"] = json!(top_logprobs);
+        }
+    }
+
+    /// The text of the partial assistant message that ends a request.
+    fn prefill<'a>(&self, prefill: &'a str) -> &'a str {
+        prefill
+    }
+}
+
+fn function_schema(function: &AgentFunction) -> Value {
+    json!({
+        "name": function.name(),
+        "description": function.description(),
+        "parameters": function.parameters_schema(),
+    })
+}
+
+fn parse_json(body: &str) -> SwarmResult<Value> {
+    serde_json::from_str(body).map_err(|e| {
+        SwarmError::DeserializationError(format!("Failed to parse provider response: {}", e))
+    })
+}
+
+fn into_chat_completion(value: Value) -> SwarmResult<ChatCompletionResponse> {
+    serde_json::from_value(value).map_err(|e| SwarmError::DeserializationError(e.to_string()))
+}
+
+/// Rewrite a body's `functions`/`function_call` as `tools`/`tool_choice`.
+///
+/// Bodies without those keys (such as Anthropic's) are left unchanged.
+pub(crate) fn legacy_functions_to_tools(body: &mut Value) {
+    let Some(object) = body.as_object_mut() else {
+        return;
+    };
+    if let Some(Value::Array(functions)) = object.remove("functions") {
+        let tools = functions
+            .into_iter()
+            .map(|function| json!({"type": "function", "function": function}))
+            .collect();
+        object.insert("tools".to_string(), Value::Array(tools));
+    }
+    if let Some(function_call) = object.remove("function_call") {
+        let tool_choice = match function_call {
+            Value::String(name) if name != "auto" && name != "none" => {
+                json!({"type": "function", "function": {"name": name}})
+            }
+            other => other,
+        };
+        object.insert("tool_choice".to_string(), tool_choice);
+    }
+}
+
+/// Map `tool_calls` → `function_call` when a choice carries exactly one tool call.
+///
+/// Single calls take the legacy function-call path; multiple calls are left as
+/// `tool_calls` so they reach the parallel/serial dispatch branch.
+pub(crate) fn promote_single_tool_calls(response: &mut Value) -> SwarmResult<()> {
+    let Some(choices) = response["choices"].as_array_mut() else {
+        return Ok(());
+    };
+    for choice in choices.iter_mut() {
+        let tc_count = choice["message"]["tool_calls"]
+            .as_array()
+            .map(|a| a.len())
+            .unwrap_or(0);
+        if tc_count != 1 {
+            continue;
+        }
+        let first = choice["message"]["tool_calls"][0].clone();
+        let name = first["function"]["name"].clone();
+        let args = first["function"]["arguments"].clone();
+        let args_str = if args.is_string() {
+            args
+        } else {
+            Value::String(
+                serde_json::to_string(&args)
+                    .map_err(|e| SwarmError::DeserializationError(e.to_string()))?,
+            )
+        };
+        choice["message"]["function_call"] = json!({"name": name, "arguments": args_str});
+        choice["message"]
+            .as_object_mut()
+            .map(|m| m.remove("tool_calls"));
+    }
+    Ok(())
+}
+
+/// OpenAI chat completions format (the default wire format).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct OpenAiApiProvider;
+
+impl OpenAiApiProvider {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl ApiProvider for OpenAiApiProvider {
+    fn build_request_body(
+        &self,
+        agent: &Agent,
+        messages: &[Message],
+        functions: &[AgentFunction],
+        model: &str,
+    ) -> Value {
+        let mut body = json!({
+            "model": model,
+            "messages": messages,
+        });
+        if !functions.is_empty() {
+            body["functions"] = Value::Array(functions.iter().map(function_schema).collect());
+        }
+        if let Some(function_call) = agent.function_call().to_wire_value() {
+            body["function_call"] = json!(function_call);
+        }
+        if agent.tool_call_execution().is_parallel() {
+            body["parallel_tool_calls"] = json!(true);
+        }
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> SwarmResult<ChatCompletionResponse> {
+        let mut value = parse_json(body)?;
+        promote_single_tool_calls(&mut value)?;
+        into_chat_completion(value)
+    }
+
+    fn auth_header(&self, api_key: &str) -> (&'static str, String) {
+        ("Authorization", format!("Bearer {}", api_key))
+    }
+}
+
+/// Anthropic messages API format.
+///
+/// System messages are lifted into the top-level `system` field, function calls
+/// become `tool_use` blocks and function results become `tool_result` blocks.
+#[derive(Clone, Debug)]
+pub struct AnthropicApiProvider {
+    max_tokens: u32,
+}
+
+impl AnthropicApiProvider {
+    pub fn new() -> Self {
+        Self {
+            max_tokens: ANTHROPIC_DEFAULT_MAX_TOKENS,
+        }
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> SwarmResult<Self> {
+        if max_tokens == 0 {
+            return Err(SwarmError::ValidationError(
+                "Anthropic max_tokens must be greater than 0".to_string(),
+            ));
+        }
+        self.max_tokens = max_tokens;
+        Ok(self)
+    }
+
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens
+    }
+
+    /// Appends `block` to the conversation, merging it into the previous turn when
+    /// the role repeats; the messages API requires strictly alternating roles.
+    fn push_block(turns: &mut Vec<Value>, role: &str, block: Value) {
+        if let Some(last) = turns.last_mut() {
+            if last["role"] == role {
+                if let Some(content) = last["content"].as_array_mut() {
+                    content.push(block);
+                    return;
+                }
+            }
+        }
+        turns.push(json!({"role": role, "content": [block]}));
+    }
+
+    fn tool_input(arguments: &str) -> Value {
+        serde_json::from_str(arguments).unwrap_or_else(|_| json!({}))
+    }
+}
+
+impl Default for AnthropicApiProvider {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ApiProvider for AnthropicApiProvider {
+    fn default_api_url(&self) -> &'static str {
+        ANTHROPIC_DEFAULT_API_URL
+    }
+
+    fn build_request_body(
+        &self,
+        agent: &Agent,
+        messages: &[Message],
+        functions: &[AgentFunction],
+        model: &str,
+    ) -> Value {
+        let mut system = Vec::new();
+        let mut turns: Vec<Value> = Vec::new();
+        // Legacy function calls carry no id; synthesize one per call and hand it to
+        // the matching function result by name.
+        let mut pending_ids: HashMap<String, String> = HashMap::new();
+
+        for (position, message) in messages.iter().enumerate() {
+            match message.role() {
+                MessageRole::System => {
+                    if let Some(content) = message.content() {
+                        system.push(content.to_string());
+                    }
+                }
+                MessageRole::User => {
+                    if let Some(content) = message.content() {
+                        Self::push_block(
+                            &mut turns,
+                            "user",
+                            json!({"type": "text", "text": content}),
+                        );
+                    }
+                }
+                MessageRole::Assistant => {
+                    if let Some(content) = message.content() {
+                        Self::push_block(
+                            &mut turns,
+                            "assistant",
+                            json!({"type": "text", "text": content}),
+                        );
+                    }
+                    if let Some(call) = message.function_call() {
+                        let id = format!("toolu_{}", position);
+                        pending_ids.insert(call.name().to_string(), id.clone());
+                        Self::push_block(
+                            &mut turns,
+                            "assistant",
+                            json!({
+                                "type": "tool_use",
+                                "id": id,
+                                "name": call.name(),
+                                "input": Self::tool_input(call.arguments()),
+                            }),
+                        );
+                    }
+                    for call in message.tool_calls().unwrap_or_default() {
+                        Self::push_block(
+                            &mut turns,
+                            "assistant",
+                            json!({
+                                "type": "tool_use",
+                                "id": call.id(),
+                                "name": call.function().name(),
+                                "input": Self::tool_input(call.function().arguments()),
+                            }),
+                        );
+                    }
+                }
+                MessageRole::Function | MessageRole::Tool => {
+                    let tool_use_id = match message.tool_call_id() {
+                        Some(id) => id.to_string(),
+                        None => message
+                            .name()
+                            .and_then(|name| pending_ids.remove(name))
+                            .unwrap_or_else(|| format!("toolu_{}", position)),
+                    };
+                    Self::push_block(
+                        &mut turns,
+                        "user",
+                        json!({
+                            "type": "tool_result",
+                            "tool_use_id": tool_use_id,
+                            "content": message.content().unwrap_or_default(),
+                        }),
+                    );
+                }
+            }
+        }
+
+        let mut body = json!({
+            "model": model,
+            "max_tokens": self.max_tokens,
+            "messages": turns,
+        });
+        if !system.is_empty() {
+            body["system"] = json!(system.join("\n\n"));
+        }
+        if !functions.is_empty() {
+            let tools = functions
+                .iter()
+                .map(|function| {
+                    json!({
+                        "name": function.name(),
+                        "description": function.description(),
+                        "input_schema": function.parameters_schema(),
+                    })
+                })
+                .collect();
+            body["tools"] = Value::Array(tools);
+            match agent.function_call() {
+                FunctionCallPolicy::Disabled => {}
+                FunctionCallPolicy::Auto => body["tool_choice"] = json!({"type": "auto"}),
+                FunctionCallPolicy::None => body["tool_choice"] = json!({"type": "none"}),
+                FunctionCallPolicy::Named(name) => {
+                    body["tool_choice"] = json!({"type": "tool", "name": name})
+                }
+            }
+        }
+        body
+    }
+
+    fn parse_response(&self, body: &str) -> SwarmResult<ChatCompletionResponse> {
+        let value = parse_json(body)?;
+        if value["type"] == "error" {
+            return Err(SwarmError::ApiError(
+                value["error"]["message"]
+                    .as_str()
+                    .unwrap_or(body)
+                    .to_string(),
+            ));
+        }
+        let blocks = value["content"].as_array().ok_or_else(|| {
+            SwarmError::DeserializationError(
+                "Anthropic response is missing the content array".to_string(),
+            )
+        })?;
+
+        let text = blocks
+            .iter()
+            .filter(|block| block["type"] == "text")
+            .filter_map(|block| block["text"].as_str())
+            .collect::<String>();
+        let tool_uses = blocks
+            .iter()
+            .filter(|block| block["type"] == "tool_use")
+            .map(|block| {
+                let arguments = serde_json::to_string(&block["input"])
+                    .map_err(|e| SwarmError::DeserializationError(e.to_string()))?;
+                Ok(json!({
+                    "id": block["id"],
+                    "type": "function",
+                    "function": {"name": block["name"], "arguments": arguments},
+                }))
+            })
+            .collect::<SwarmResult<Vec<Value>>>()?;
+
+        // An assistant message carries either text or tool calls; any preamble text
+        // emitted alongside tool_use blocks is dropped.
+        let mut message = Map::new();
+        message.insert("role".to_string(), json!("assistant"));
+        let finish_reason = match tool_uses.len() {
+            0 => {
+                message.insert("content".to_string(), json!(text));
+                match value["stop_reason"].as_str() {
+                    Some("max_tokens") => "length",
+                    _ => "stop",
+                }
+            }
+            1 => {
+                message.insert(
+                    "function_call".to_string(),
+                    tool_uses[0]["function"].clone(),
+                );
+                "function_call"
+            }
+            _ => {
+                message.insert("tool_calls".to_string(), Value::Array(tool_uses));
+                "tool_calls"
+            }
+        };
+
+        let input_tokens = value["usage"]["input_tokens"].as_u64().unwrap_or(0);
+        let output_tokens = value["usage"]["output_tokens"].as_u64().unwrap_or(0);
+        into_chat_completion(json!({
+            "id": value["id"].as_str().unwrap_or_default(),
+            "object": "chat.completion",
+            "created": 0,
+            "choices": [{
+                "index": 0,
+                "message": Value::Object(message),
+                "finish_reason": finish_reason,
+            }],
+            "usage": {
+                "prompt_tokens": input_tokens,
+                "completion_tokens": output_tokens,
+                "total_tokens": input_tokens + output_tokens,
+            },
+        }))
+    }
+
+    fn auth_header(&self, api_key: &str) -> (&'static str, String) {
+        ("x-api-key", api_key.to_string())
+    }
+
+    fn extra_headers(&self) -> Vec<(&'static str, String)> {
+        vec![("anthropic-version", ANTHROPIC_API_VERSION.to_string())]
+    }
+
+    fn apply_user_id(&self, body: &mut Value, user_id: &str) {
+        body["metadata"] = json!({ "user_id": user_id });
+    }
+
+    fn apply_stop_sequences(&self, body: &mut Value, stop: &[String]) {
+        body["stop_sequences"] = json!(stop);
+    }
+
+    /// The messages API has no seed parameter and rejects unknown fields.
+    fn apply_seed(&self, _body: &mut Value, seed: i64) {
+        tracing::warn!(
+            seed,
+            "Anthropic does not support a sampling seed; ignoring it"
+        );
+    }
+
+    /// The messages API does not return token log probabilities.
+    fn apply_logprobs(&self, _body: &mut Value, _config: &LogprobsConfig) {
+        tracing::warn!("Anthropic does not support logprobs; ignoring them");
+    }
+
+    /// The messages API rejects a final assistant message ending in whitespace.
+    fn prefill<'a>(&self, prefill: &'a str) -> &'a str {
+        prefill.trim_end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Instructions;
+
+    fn agent() -> Agent {
+        Agent::new(
+            "claude",
+            "claude-3-5-sonnet",
+            Instructions::Text("Be brief.".into()),
+        )
+        .expect("agent")
+    }
+
+    #[test]
+    fn test_anthropic_body_lifts_system_and_maps_function_turns() {
+        let messages = vec![
+            Message::system("Be brief.").expect("system"),
+            Message::user("Weather?").expect("user"),
+            Message::assistant_named("claude", "Checking.").expect("assistant"),
+            Message::function("get_weather", "sunny").expect("function"),
+        ];
+        let body = AnthropicApiProvider::new().build_request_body(
+            &agent(),
+            &messages,
+            &[],
+            "claude-3-5-sonnet",
+        );
+
+        assert_eq!(body["system"], "Be brief.");
+        assert_eq!(body["max_tokens"], ANTHROPIC_DEFAULT_MAX_TOKENS);
+        let turns = body["messages"].as_array().expect("turns");
+        assert_eq!(turns.len(), 3);
+        assert_eq!(turns[0]["role"], "user");
+        assert_eq!(turns[1]["role"], "assistant");
+        assert_eq!(turns[2]["content"][0]["type"], "tool_result");
+        assert_eq!(turns[2]["content"][0]["content"], "sunny");
+    }
+
+    #[test]
+    fn test_anthropic_response_maps_text_and_tool_use() {
+        let provider = AnthropicApiProvider::new();
+        let text = provider
+            .parse_response(
+                r#"{"id":"msg_1","type":"message","role":"assistant","content":[{"type":"text","text":"Hi"}],"stop_reason":"end_turn","usage":{"input_tokens":3,"output_tokens":1}}"#,
+            )
+            .expect("text response");
+        assert_eq!(text.choices()[0].message.content(), Some("Hi"));
+
+        let tool = provider
+            .parse_response(
+                r#"{"id":"msg_2","type":"message","role":"assistant","content":[{"type":"tool_use","id":"toolu_1","name":"lookup","input":{"q":"x"}}],"stop_reason":"tool_use","usage":{"input_tokens":3,"output_tokens":1}}"#,
+            )
+            .expect("tool response");
+        let call = tool.choices()[0]
+            .message
+            .function_call()
+            .expect("function call");
+        assert_eq!(call.name(), "lookup");
+        assert_eq!(call.arguments(), r#"{"q":"x"}"#);
+    }
+
+    #[test]
+    fn test_seed_is_only_sent_to_openai() {
+        let mut openai = json!({});
+        OpenAiApiProvider::new().apply_seed(&mut openai, 7);
+        assert_eq!(openai["seed"], 7);
+
+        let mut anthropic = json!({});
+        AnthropicApiProvider::new().apply_seed(&mut anthropic, 7);
+        assert!(anthropic.get("seed").is_none());
+    }
+
+    #[test]
+    fn test_anthropic_prefill_drops_trailing_whitespace() {
+        assert_eq!(
+            AnthropicApiProvider::new().prefill("Think step by step:\n"),
+            "Think step by step:"
+        );
+        assert_eq!(OpenAiApiProvider::new().prefill("Think:\n"), "Think:\n");
+    }
+
+    #[test]
+    fn test_logprobs_are_only_sent_to_openai() {
+        let config = LogprobsConfig {
+            enabled: true,
+            top_logprobs: Some(3),
+        };
+        let mut openai = json!({});
+        OpenAiApiProvider::new().apply_logprobs(&mut openai, &config);
+        assert_eq!(openai["logprobs"], true);
+        assert_eq!(openai["top_logprobs"], 3);
+
+        let mut anthropic = json!({});
+        AnthropicApiProvider::new().apply_logprobs(&mut anthropic, &config);
+        assert!(anthropic.get("logprobs").is_none());
+        assert!(anthropic.get("top_logprobs").is_none());
+    }
+
+    #[test]
+    fn test_auth_headers() {
+        assert_eq!(
+            OpenAiApiProvider::new().auth_header("sk-a"),
+            ("Authorization", "Bearer sk-a".to_string())
+        );
+        assert_eq!(
+            AnthropicApiProvider::new().auth_header("sk-a"),
+            ("x-api-key", "sk-a".to_string())
+        );
+    }
+}
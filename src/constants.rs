@@ -1,11 +1,12 @@
 pub const CTX_VARS_NAME: &str = "context_variables";
 pub const OPENAI_DEFAULT_API_URL: &str = "https://api.openai.com/v1/chat/completions";
+pub const ANTHROPIC_DEFAULT_API_URL: &str = "https://api.anthropic.com/v1/messages";
 pub const ROLE_ASSISTANT: &str = "assistant";
 pub const ROLE_FUNCTION: &str = "function";
 pub const ROLE_SYSTEM: &str = "system";
 pub const DEFAULT_REQUEST_TIMEOUT: u64 = 30; // 30 seconds timeout
 pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10; // 10 seconds for connection timeout
-pub const VALID_API_URL_PREFIXES: [&str; 8] = [
+pub const VALID_API_URL_PREFIXES: [&str; 9] = [
     "https://api.openai.com",
     "https://api.azure.com/openai",
     "https://openrouter.ai/api/",
@@ -14,13 +15,96 @@ pub const VALID_API_URL_PREFIXES: [&str; 8] = [
     "https://api.deepseek.com",
     "https://api.deepseek.com/chat/",
     "https://api.deepseek.com/chat/completions",
+    "https://api.anthropic.com",
 ];
 pub const DEFAULT_API_VERSION: &str = "v1";
 pub const DEFAULT_MAX_LOOP_ITERATIONS: u32 = 10;
 pub const DEFAULT_ITERATION_DELAY_MS: u64 = 100;
+/// Context snapshots a run keeps before dropping the oldest.
+pub const DEFAULT_MAX_CONTEXT_SNAPSHOTS: usize = 10;
 pub const DEFAULT_BREAK_CONDITIONS: [&str; 1] = ["end_loop"];
+/// Model name patterns allowed to receive image content parts.
+pub const DEFAULT_VISION_CAPABLE_MODEL_PATTERNS: [&str; 9] = [
+    "vision",
+    "gpt-4o",
+    "gpt-4-turbo",
+    "gpt-4.1",
+    "gpt-5",
+    "o1",
+    "o3",
+    "o4",
+    "claude",
+];
 pub const MIN_REQUEST_TIMEOUT: u64 = 5;
 pub const MAX_REQUEST_TIMEOUT: u64 = 300;
+/// Most stop sequences OpenAI accepts in one request.
+pub const MAX_STOP_SEQUENCES: usize = 4;
+/// Most alternatives per token OpenAI returns for `top_logprobs`.
+pub const MAX_TOP_LOGPROBS: u32 = 20;
+/// Tokens held back from the context window when `adaptive_max_tokens` caps a request.
+pub const ADAPTIVE_MAX_TOKENS_BUFFER: u32 = 50;
+/// Assistant messages compared against a new answer when `semantic_dedup_threshold` is set.
+pub const SEMANTIC_DEDUP_LOOKBACK: usize = 3;
+/// Re-asks per turn before a near-duplicate answer is accepted.
+pub const SEMANTIC_DEDUP_MAX_RETRIES: usize = 2;
+pub const SEMANTIC_DEDUP_RETRY_PROMPT: &str =
+    "Your answer was too similar to a previous response. \
+Please provide a substantially different response.";
+pub const HISTORY_SUMMARY_PROMPT: &str = "You condense conversation history. Summarize the \
+transcript you are given, keeping facts, decisions, open questions and any values later turns \
+may depend on. Fold in the previous summary when one is provided. Reply with the summary only.";
+/// Context variables whose key starts with this are removed after every step.
+pub const DEFAULT_EPHEMERAL_KEY_PREFIX: &str = "__temp_";
+/// Characters of a function result available to `post_function_call_prompt`.
+pub const POST_FUNCTION_CALL_RESULT_PREVIEW_CHARS: usize = 100;
+/// Sent to `tool_summarizer_agent` ahead of an oversized function result.
+pub const TOOL_RESULT_SUMMARY_PROMPT: &str = "Summarize this function result for another \
+assistant, keeping names, numbers, identifiers and anything needed to answer the user. Reply with \
+the summary only.";
+/// Sent to an `evaluate` step's evaluator ahead of the output it scores.
+pub const STEP_EVALUATION_PROMPT: &str = "Evaluate the assistant output below. Reply with JSON \
+only, in the form {\"score\": <number from 0 to 1>, \"feedback\": \"<how to improve it>\"}.";
+/// Sent to `plan_and_run`'s planner ahead of the task and the agents it may use.
+pub const PLAN_STEPS_PROMPT: &str = "Plan a workflow for the task below. Reply with only an XML <steps> block: one <step number=\"N\" action=\"run_once\" or \"loop\" agent=\"NAME\"> per step, numbered from 1, each holding a <prompt> for that step. Use only the listed agents.";
+/// Re-runs an `evaluate` step allows when its step sets no `max_retry`.
+pub const DEFAULT_EVALUATE_MAX_RETRY: usize = 1;
+/// Sent to `context_summarizer_agent` for each context value it compresses.
+pub const CONTEXT_VALUE_SUMMARY_PROMPT: &str = "Summarize this in 2 sentences: {{value}}";
+/// Sent by a `summarize_history` step that has no prompt of its own.
+pub const SUMMARIZE_HISTORY_PROMPT: &str = "Summarize the conversation above in bullet points.";
+/// Re-asks per turn for a reply in the agent's `response_language`.
+pub const LANGUAGE_ENFORCEMENT_MAX_RETRIES: usize = 1;
+/// Sent after a reply that is not in the agent's `response_language`.
+pub const LANGUAGE_RETRY_PROMPT: &str = "Your last reply was not in {{language}}. Answer again \
+using only {{language}}, whatever language the conversation is in.";
+/// Corrected function calls requested per turn when `function_arg_retry_prompt` is set.
+pub const DEFAULT_FUNCTION_ARG_MAX_RETRIES: u32 = 1;
+/// Sent after a function call that failed and was handed to the dead-letter queue.
+pub const DEAD_LETTER_RETRY_PROMPT: &str = "function failed, please try another approach";
+/// Sent after a step reply shorter than the step's `min_response_length`.
+pub const SHORT_RESPONSE_RETRY_PROMPT: &str = "Your response was too brief. Please elaborate.";
+/// Re-asks a step allows for a too-short reply when it sets no `short_response_max_retries`.
+pub const DEFAULT_SHORT_RESPONSE_MAX_RETRIES: usize = 1;
+/// Prefix of context keys the library reserves, such as `__dynamic_steps` and
+/// `__agent_*` metadata.
+pub const RESERVED_CONTEXT_KEY_PREFIX: &str = "__";
+/// Context key whose `<steps>` XML is appended to the running workflow.
+pub const DYNAMIC_STEPS_KEY: &str = "__dynamic_steps";
+/// Times a run may append steps from `__dynamic_steps` unless configured otherwise.
+pub const DEFAULT_MAX_DYNAMIC_STEP_INJECTIONS: usize = 3;
+/// Deprecated OpenAI model names and their replacements, preloaded into `model_aliases`.
+/// A replacement never has a smaller context window than the model it stands in for.
+pub const DEFAULT_MODEL_ALIASES: &[(&str, &str)] = &[
+    ("gpt-3.5-turbo-0301", "gpt-3.5-turbo"),
+    ("gpt-3.5-turbo-0613", "gpt-3.5-turbo"),
+    ("gpt-3.5-turbo-16k", "gpt-3.5-turbo"),
+    ("gpt-4-0314", "gpt-4"),
+    ("gpt-4-32k", "gpt-4-turbo"),
+    ("gpt-4-1106-preview", "gpt-4-turbo"),
+    ("gpt-4-0125-preview", "gpt-4-turbo"),
+    ("gpt-4-turbo-preview", "gpt-4-turbo"),
+    ("gpt-4-vision-preview", "gpt-4-turbo"),
+];
 
 #[derive(Clone, Debug)]
 pub struct OpenAICredentials {
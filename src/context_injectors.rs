@@ -0,0 +1,110 @@
+//! Built-in sources of context variables for `SwarmBuilder::with_context_injector`.
+//!
+//! Each injector is called at the start of every run; the results are merged in
+//! registration order, so later injectors win on key collisions.
+
+use crate::error::{SwarmError, SwarmResult};
+use crate::types::{ContextInjector, ContextVariables};
+use serde_json::Value;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// Environment variables whose names start with the prefix, keyed by the rest of
+/// the name (`APP_REGION` becomes `REGION` for the prefix `APP_`).
+#[derive(Clone, Debug)]
+pub struct EnvContextInjector(String);
+
+impl EnvContextInjector {
+    /// The prefix cannot be empty, which would copy the whole environment,
+    /// secrets included, into the context variables.
+    pub fn new(prefix: impl Into<String>) -> SwarmResult<Self> {
+        let prefix = prefix.into();
+        if prefix.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "EnvContextInjector prefix cannot be empty".to_string(),
+            ));
+        }
+        Ok(Self(prefix))
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.0
+    }
+
+    pub fn inject(&self) -> SwarmResult<ContextVariables> {
+        Ok(std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)))
+            .filter_map(|(key, value)| {
+                let key = key.strip_prefix(self.0.as_str())?;
+                (!key.is_empty()).then(|| (key.to_string(), value))
+            })
+            .collect())
+    }
+}
+
+/// Top-level fields of a JSON object file. String values are used as they are;
+/// other values are stored as JSON text.
+#[derive(Clone, Debug)]
+pub struct FileContextInjector(pub PathBuf);
+
+impl FileContextInjector {
+    pub fn inject(&self) -> SwarmResult<ContextVariables> {
+        let content = std::fs::read_to_string(&self.0).map_err(|e| {
+            SwarmError::ConfigError(format!(
+                "Failed to read context file '{}': {}",
+                self.0.display(),
+                e
+            ))
+        })?;
+        let Value::Object(fields) = serde_json::from_str::<Value>(&content)? else {
+            return Err(SwarmError::DeserializationError(format!(
+                "Context file '{}' must hold a JSON object",
+                self.0.display()
+            )));
+        };
+        Ok(fields
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(text) => (key, text),
+                other => (key, other.to_string()),
+            })
+            .collect())
+    }
+}
+
+/// A fixed set of context variables.
+#[derive(Clone, Debug)]
+pub struct StaticContextInjector(pub ContextVariables);
+
+impl StaticContextInjector {
+    pub fn inject(&self) -> SwarmResult<ContextVariables> {
+        Ok(self.0.clone())
+    }
+}
+
+impl From<EnvContextInjector> for Arc<ContextInjector> {
+    fn from(injector: EnvContextInjector) -> Self {
+        Arc::new(move || injector.inject())
+    }
+}
+
+impl From<FileContextInjector> for Arc<ContextInjector> {
+    fn from(injector: FileContextInjector) -> Self {
+        Arc::new(move || injector.inject())
+    }
+}
+
+impl From<StaticContextInjector> for Arc<ContextInjector> {
+    fn from(injector: StaticContextInjector) -> Self {
+        Arc::new(move || injector.inject())
+    }
+}
+
+/// Calls every injector in order and merges the results; later injectors win.
+pub(crate) fn inject_context(injectors: &[Arc<ContextInjector>]) -> SwarmResult<ContextVariables> {
+    let mut merged = ContextVariables::new();
+    for injector in injectors {
+        merged.extend(injector()?);
+    }
+    Ok(merged)
+}
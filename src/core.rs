@@ -8,9 +8,22 @@
 
 use crate::agent_comm::{AgentMessage, ChannelRegistry, InProcessChannel};
 use crate::agent_registry::AgentRegistry;
+use crate::api_provider::{
+    legacy_functions_to_tools, promote_single_tool_calls, ApiProvider, OpenAiApiProvider,
+};
 use crate::checkpoint::{CheckpointData, CheckpointEnvelope};
 use crate::circuit_breaker::{CircuitBreaker, CircuitStateSnapshot};
-use crate::constants::{CTX_VARS_NAME, MAX_REQUEST_TIMEOUT, MIN_REQUEST_TIMEOUT};
+use crate::constants::{
+    ADAPTIVE_MAX_TOKENS_BUFFER, CONTEXT_VALUE_SUMMARY_PROMPT, CTX_VARS_NAME,
+    DEAD_LETTER_RETRY_PROMPT, DEFAULT_EVALUATE_MAX_RETRY, DEFAULT_SHORT_RESPONSE_MAX_RETRIES,
+    DYNAMIC_STEPS_KEY, HISTORY_SUMMARY_PROMPT, LANGUAGE_ENFORCEMENT_MAX_RETRIES,
+    LANGUAGE_RETRY_PROMPT, MAX_REQUEST_TIMEOUT, MIN_REQUEST_TIMEOUT, OPENAI_DEFAULT_API_URL,
+    PLAN_STEPS_PROMPT, POST_FUNCTION_CALL_RESULT_PREVIEW_CHARS, RESERVED_CONTEXT_KEY_PREFIX,
+    SEMANTIC_DEDUP_LOOKBACK, SEMANTIC_DEDUP_MAX_RETRIES, SEMANTIC_DEDUP_RETRY_PROMPT,
+    SHORT_RESPONSE_RETRY_PROMPT, STEP_EVALUATION_PROMPT, SUMMARIZE_HISTORY_PROMPT,
+    TOOL_RESULT_SUMMARY_PROMPT,
+};
+use crate::context_injectors::inject_context;
 use crate::distribution::{
     AgentAddress, DistributedMessage, DistributedTransport, HttpDistributedTransport,
 };
@@ -19,10 +32,12 @@ use crate::escalation::{
     EscalationAction, EscalationConfig, EscalationDetector, EscalationTrigger,
 };
 use crate::event::{AgentEvent, EventSubscriber, TraceId};
+use crate::execution_trace::{TraceEntry, TraceEvent};
 use crate::guardrails::{
     check_injection_with_policy, classify_and_redact, ContentPolicy, DataClassification,
     DefaultContentPolicy, InjectionOutcome, InjectionPolicy, PolicyResult, RedactionPolicy,
 };
+use crate::key_pool::{ApiKeyPool, DEFAULT_API_KEY_COOLDOWN};
 use crate::observability::{
     record_budget_exhausted, record_circuit_breaker_state, record_guardrail_triggered,
     record_iteration, record_llm_latency, record_token_usage, record_tool_call,
@@ -31,30 +46,53 @@ use crate::persistence::{
     CheckpointStore, EventStore, MemoryStore, PersistenceBackend, SessionStore,
 };
 use crate::phase::TokenUsage;
+use crate::profile::{ProfileSpan, ProfileSpanKind, Profiler, SpanTimer};
 use crate::provider::{CompletionRequest, LlmProvider, OpenAiProvider};
+use crate::response_cache::{response_cache_key, ResponseCache};
+use crate::steps_parser::{QuickXmlStepsParser, StepsParser};
 use crate::team::{
     AgentTeam, ConsensusStrategy, TeamAssignment, TeamDecision, TeamFormationPolicy, TeamRole,
     TeamVote, VoteTally,
 };
-use crate::tool::InvocationArgs;
+use crate::tool::{InvocationArgs, ToolSchema};
 use crate::types::{
-    Agent, AgentFunction, AgentRef, ApiKey, ApiUrl, ChatCompletionResponse, Choice,
-    ContextVariables, FinishReason, FunctionCall, FunctionCallPolicy, Instructions, Message,
-    MessageRole, ModelId, OpenAIErrorResponse, Response, ResultType, RuntimeLimits, Step, Steps,
-    SwarmConfig, ToolCall, ToolCallExecution,
+    validate_stop_sequences, Agent, AgentFunction, AgentRef, ApiKey, ApiUrl, BranchMergeStrategy,
+    BranchPoint, ChatCompletionResponse, Choice, CircularHandoffAction, CompressionStrategy,
+    ContentOverflowAction, ContextInjector, ContextOverflow, ContextOverflowStrategy,
+    ContextSnapshotInterval, ContextVariables, DeadLetterEntry, ErrorRecoveryStrategy,
+    FinishReason, FunctionCall, FunctionCallFormat, FunctionCallPolicy, HandoffRecord,
+    HistoryWindowStrategy, Instructions, JitterStrategy, Message, MessageRole,
+    MessageSerializationAdapter, ModelId, ModelParameters, OpenAIErrorResponse,
+    PromptCompressionConfig, PromptCompressionStats, Response, ResultType, RuntimeLimits,
+    SafetyPlacement, Step, StepRecoveryAction, Steps, SwarmConfig, SystemMessageFormat, ToolCall,
+    ToolCallExecution, TurnMetadata, XmlEncoding, FUNCTION_CALL_OPTIONS,
+};
+use crate::util::{
+    debug_print, estimate_prompt_tokens, extract_namespaced_xml_steps, extract_xml_steps,
+    fill_template, function_to_json, jaccard_similarity, json_get_path, message_name, repair_json,
+    resolve_xml_includes_with_encoding, safe_truncate, strip_xml_namespace, truncate_middle,
+    truncate_to_size, unresolved_placeholders, validate_steps,
 };
-use crate::util::{debug_print, extract_xml_steps, function_to_json, parse_steps_from_xml};
 use crate::validation::{
-    validate_api_request, verify_structured_response, BudgetEnforcer, BudgetExhausted,
+    validate_api_request_with_config, verify_structured_response, BudgetEnforcer, BudgetExhausted,
 };
 use chrono::Utc;
 use futures::StreamExt;
-use reqwest::Client;
+use regex::Regex;
+use reqwest::{tls, Certificate, Client, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::{HashMap, HashSet};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
+use std::fmt;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
 
 #[derive(Clone, Debug)]
 struct CircuitBreakerSettings {
@@ -83,20 +121,373 @@ impl Default for CircuitBreakerSettings {
     }
 }
 
+/// Called with the step's position, the step and its error when
+/// [`ErrorRecoveryStrategy::SkipStep`] skips a failed step. The run waits for the
+/// returned future before moving on.
+pub type StepFailureCallback =
+    dyn Fn(usize, &Step, &SwarmError) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// Per-run execution options.
+///
+/// [`Swarm::run`] builds these from its positional arguments; use
+/// [`Swarm::run_with_options`] to reach the less common settings.
 #[derive(Clone)]
-struct RunOptions {
+pub struct RunOptions {
     model_override: Option<String>,
     stream: bool,
     debug: bool,
     max_turns: usize,
+    max_turns_per_step: Option<usize>,
+    priming_messages: Vec<Message>,
+    user_id: Option<String>,
+    error_recovery_strategy: ErrorRecoveryStrategy,
+    profiling_enabled: bool,
+    steps_base_dir: Option<PathBuf>,
+    on_step_failure: Option<Arc<StepFailureCallback>>,
+    execution_trace: bool,
+    cot_prefix: Option<String>,
+    extract_response_fields: Vec<(String, String)>,
+    auto_adjust_max_turns: bool,
+    /// HTTP requests sent for the run; shared by clones and reset by each run.
+    api_calls: Arc<AtomicUsize>,
+}
+
+impl fmt::Debug for RunOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunOptions")
+            .field("model_override", &self.model_override)
+            .field("stream", &self.stream)
+            .field("debug", &self.debug)
+            .field("max_turns", &self.max_turns)
+            .field("max_turns_per_step", &self.max_turns_per_step)
+            .field("priming_messages", &self.priming_messages)
+            .field("user_id", &self.user_id)
+            .field("error_recovery_strategy", &self.error_recovery_strategy)
+            .field("profiling_enabled", &self.profiling_enabled)
+            .field("steps_base_dir", &self.steps_base_dir)
+            // The callback is a closure; only report whether one is set.
+            .field("on_step_failure", &self.on_step_failure.is_some())
+            .field("execution_trace", &self.execution_trace)
+            .field("cot_prefix", &self.cot_prefix)
+            .field("extract_response_fields", &self.extract_response_fields)
+            .field("auto_adjust_max_turns", &self.auto_adjust_max_turns)
+            .finish()
+    }
+}
+
+impl RunOptions {
+    pub fn new(max_turns: usize) -> Self {
+        Self {
+            model_override: None,
+            stream: false,
+            debug: false,
+            max_turns,
+            max_turns_per_step: None,
+            priming_messages: Vec::new(),
+            user_id: None,
+            error_recovery_strategy: ErrorRecoveryStrategy::FailFast,
+            profiling_enabled: false,
+            steps_base_dir: None,
+            on_step_failure: None,
+            execution_trace: false,
+            cot_prefix: None,
+            extract_response_fields: Vec::new(),
+            auto_adjust_max_turns: false,
+            api_calls: Arc::default(),
+        }
+    }
+
+    /// Options for a one-off request made on behalf of this run, counted with
+    /// the run's own requests.
+    fn side_request(&self) -> Self {
+        Self {
+            api_calls: Arc::clone(&self.api_calls),
+            ..Self::new(1).with_debug(self.debug)
+        }
+    }
+
+    fn count_api_call(&self) {
+        self.api_calls.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn with_model_override(mut self, model: impl Into<String>) -> Self {
+        self.model_override = Some(model.into());
+        self
+    }
+
+    pub fn with_stream(mut self, stream: bool) -> Self {
+        self.stream = stream;
+        self
+    }
+
+    pub fn with_debug(mut self, debug: bool) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    /// Default turn limit for `loop` steps that do not set `max_turns_per_step`.
+    pub fn with_max_turns_per_step(mut self, max_turns: usize) -> Self {
+        self.max_turns_per_step = Some(max_turns);
+        self
+    }
+
+    /// Messages sent between the system prompt and the history on every request
+    /// (few-shot examples, retrieved documents). They are never stored in history.
+    pub fn with_priming_messages(mut self, messages: Vec<Message>) -> Self {
+        self.priming_messages = messages;
+        self
+    }
+
+    pub fn model_override(&self) -> Option<&str> {
+        self.model_override.as_deref()
+    }
+
+    pub fn stream(&self) -> bool {
+        self.stream
+    }
+
+    pub fn debug(&self) -> bool {
+        self.debug
+    }
+
+    pub fn max_turns(&self) -> usize {
+        self.max_turns
+    }
+
+    /// End-user identifier sent as the request `user` field. Takes precedence over
+    /// [`SwarmBuilder::with_user_id_provider`].
+    pub fn with_user_id(mut self, user_id: impl Into<String>) -> Self {
+        self.user_id = Some(user_id.into());
+        self
+    }
+
+    pub fn user_id(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    /// What happens when a step fails; defaults to [`ErrorRecoveryStrategy::FailFast`].
+    pub fn with_error_recovery_strategy(mut self, strategy: ErrorRecoveryStrategy) -> Self {
+        self.error_recovery_strategy = strategy;
+        self
+    }
+
+    pub fn error_recovery_strategy(&self) -> &ErrorRecoveryStrategy {
+        &self.error_recovery_strategy
+    }
+
+    /// Time steps, turns and chat completions into [`Response::profile`].
+    pub fn with_profiling(mut self, enabled: bool) -> Self {
+        self.profiling_enabled = enabled;
+        self
+    }
+
+    pub fn profiling_enabled(&self) -> bool {
+        self.profiling_enabled
+    }
+
+    /// Directory that `<include file="…"/>` paths in `<steps>` are relative to;
+    /// defaults to the working directory.
+    pub fn with_steps_base_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.steps_base_dir = Some(dir.into());
+        self
+    }
+
+    pub fn steps_base_dir(&self) -> Option<&Path> {
+        self.steps_base_dir.as_deref()
+    }
+
+    /// Notified in the background whenever a step is skipped under
+    /// [`ErrorRecoveryStrategy::SkipStep`]; the run does not wait for it.
+    pub fn with_on_step_failure(
+        mut self,
+        callback: impl Fn(usize, &Step, &SwarmError) -> Pin<Box<dyn Future<Output = ()> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.on_step_failure = Some(Arc::new(callback));
+        self
+    }
+
+    /// Record steps, messages, function calls and context updates into
+    /// [`Response::trace`](crate::types::Response::trace).
+    pub fn with_execution_trace(mut self, enabled: bool) -> Self {
+        self.execution_trace = enabled;
+        self
+    }
+
+    pub fn execution_trace(&self) -> bool {
+        self.execution_trace
+    }
+
+    /// Prefill every request with a partial assistant message such as
+    /// `"Let me think step by step:\n"` so the model reasons before answering.
+    /// A reply that repeats the prefix is stored without it.
+    pub fn with_cot_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.cot_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn cot_prefix(&self) -> Option<&str> {
+        self.cot_prefix.as_deref()
+    }
+
+    /// Copy fields of every chat completion response into context variables, as
+    /// `(JSON Pointer, context key)` pairs such as `("/usage/total_tokens", "__total_tokens")`.
+    /// Missing or `null` fields leave the key untouched. Extracted values obey the
+    /// same size and count limits as function-set context variables.
+    pub fn with_extract_response_fields(mut self, fields: Vec<(String, String)>) -> Self {
+        self.extract_response_fields = fields;
+        self
+    }
+
+    pub fn extract_response_fields(&self) -> &[(String, String)] {
+        &self.extract_response_fields
+    }
+
+    /// Raise `max_turns` to the number of workflow steps, with a warning, instead
+    /// of rejecting a workflow that has more steps than turns.
+    pub fn with_auto_adjust_max_turns(mut self, enabled: bool) -> Self {
+        self.auto_adjust_max_turns = enabled;
+        self
+    }
+
+    pub fn auto_adjust_max_turns(&self) -> bool {
+        self.auto_adjust_max_turns
+    }
+
+    pub fn max_turns_per_step(&self) -> Option<usize> {
+        self.max_turns_per_step
+    }
+
+    pub fn priming_messages(&self) -> &[Message] {
+        &self.priming_messages
+    }
 }
 
-struct RunState {
-    agent: Agent,
-    history: Vec<Message>,
-    context_variables: ContextVariables,
+pub(crate) struct RunState {
+    pub(crate) agent: Agent,
+    pub(crate) history: Vec<Message>,
+    pub(crate) context_variables: ContextVariables,
     iterations: u32,
-    total_tokens: u32,
+    pub(crate) total_tokens: u32,
+    /// Step prompts dispatched so far; counted against `max_turns`.
+    step_turns: usize,
+    /// `RunOptions::max_turns`, raised for long workflows by `auto_adjust_max_turns`.
+    max_turns: usize,
+    /// Agents reached through `ResultType::Agent` handoffs, starting with the initial agent.
+    handoff_chain: Vec<String>,
+    /// Rolling summary for `HistoryWindowStrategy::SlidingWithOverlap`.
+    history_summary: Option<HistorySummary>,
+    /// Request window of the running step when it is over its context limit.
+    step_window: Option<StepWindow>,
+    pub(crate) handoff_history: Vec<HandoffRecord>,
+    /// Model set by the last `switch_model` step; survives later agent changes.
+    switched_model: Option<String>,
+    /// Present when `RunOptions::profiling_enabled` is set.
+    profiler: Option<Profiler>,
+    turn_metadata: Vec<TurnMetadata>,
+    /// Tokens used by the requests of the turn in progress.
+    turn_prompt_tokens: u32,
+    turn_completion_tokens: u32,
+    /// `logprobs` of the turn's latest completion.
+    turn_logprobs: Option<Value>,
+    /// Prompt compression summed over the turn's requests.
+    turn_prompt_compression: Option<PromptCompressionStats>,
+    /// Present when `RunOptions::execution_trace` is set.
+    trace: Option<Vec<TraceEntry>>,
+    context_snapshots: VecDeque<(String, ContextVariables)>,
+    branches: HashMap<String, BranchPoint>,
+    system_fingerprint: Option<String>,
+}
+
+impl RunState {
+    /// Opens a profiling span when the run has profiling enabled.
+    fn start_span(&self, kind: ProfileSpanKind) -> Option<SpanTimer> {
+        self.profiler.as_ref().map(|_| SpanTimer::start(kind))
+    }
+
+    fn end_span(&mut self, timer: Option<SpanTimer>) {
+        self.record_spans(timer.map(SpanTimer::finish));
+    }
+
+    /// Adds one request's compression to the turn's totals.
+    fn record_prompt_compression(&mut self, stats: Option<PromptCompressionStats>) {
+        let Some(stats) = stats else {
+            return;
+        };
+        let total = self
+            .turn_prompt_compression
+            .get_or_insert(PromptCompressionStats {
+                original_tokens: 0,
+                compressed_tokens: 0,
+            });
+        total.original_tokens = total.original_tokens.saturating_add(stats.original_tokens);
+        total.compressed_tokens = total
+            .compressed_tokens
+            .saturating_add(stats.compressed_tokens);
+    }
+
+    /// Copies the context variables under `label`, dropping the oldest snapshot
+    /// beyond `max_snapshots`.
+    fn snapshot_context(&mut self, label: String, max_snapshots: usize) {
+        if self.context_snapshots.len() >= max_snapshots {
+            self.context_snapshots.pop_front();
+        }
+        self.context_snapshots
+            .push_back((label, self.context_variables.clone()));
+    }
+
+    /// Appends to the execution trace; `event` is only built when tracing is on.
+    fn trace(&mut self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(TraceEntry::now(event()));
+        }
+    }
+
+    /// Traces a function's result and the context variables it set, passing each
+    /// recorded value through `sanitize`.
+    fn trace_function_response(
+        &mut self,
+        name: &str,
+        response: &Response,
+        sanitize: impl Fn(&str) -> String,
+    ) {
+        self.trace(|| {
+            let result = response
+                .messages
+                .first()
+                .and_then(Message::content)
+                .unwrap_or_default();
+            TraceEvent::FunctionReturned(name.to_string(), sanitize(result))
+        });
+        let mut updates = response.context_variables.iter().collect::<Vec<_>>();
+        updates.sort();
+        for (key, value) in updates {
+            self.trace(|| TraceEvent::ContextUpdated(key.clone(), sanitize(value)));
+        }
+    }
+
+    fn record_spans(&mut self, spans: impl IntoIterator<Item = ProfileSpan>) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            for span in spans {
+                profiler.record(span);
+            }
+        }
+    }
+}
+
+struct HistorySummary {
+    /// Number of leading history messages folded into `text`.
+    summarized: usize,
+    text: String,
+}
+
+struct StepWindow {
+    /// First history message sent while the step runs.
+    start: usize,
+    /// Summary of the messages the step cut from its window, if summarized.
+    summary: Option<String>,
 }
 
 struct ExecutionContext<'a> {
@@ -111,6 +502,32 @@ struct ToolCallOutcome {
     response: SwarmResult<Response>,
 }
 
+/// Reply expected from an `evaluate` step's evaluator agent.
+#[derive(Deserialize)]
+struct StepEvaluation {
+    score: f32,
+    #[serde(default)]
+    feedback: String,
+}
+
+/// `global` followed by the entries of `agent` not already in it.
+fn merge_stop_sequences(global: &[String], agent: &[String]) -> Vec<String> {
+    let mut merged = global.to_vec();
+    for sequence in agent {
+        if !merged.contains(sequence) {
+            merged.push(sequence.clone());
+        }
+    }
+    merged
+}
+
+/// Adds `logprobs`/`top_logprobs` to a raw request body when `agent` asks for them.
+fn apply_logprobs(api_provider: &dyn ApiProvider, request_body: &mut Value, agent: &Agent) {
+    if let Some(config) = agent.logprobs_config().filter(|config| config.enabled) {
+        api_provider.apply_logprobs(request_body, config);
+    }
+}
+
 fn max_classification(
     current: Option<DataClassification>,
     candidate: Option<DataClassification>,
@@ -126,12 +543,21 @@ fn max_classification(
 /// Main struct for managing AI agent interactions and chat completions.
 pub struct Swarm {
     client: Client,
-    api_key: ApiKey,
+    key_pool: ApiKeyPool,
     agent_registry: HashMap<String, Agent>,
     agent_directory: AgentRegistry,
     channel_registry: Arc<ChannelRegistry>,
     config: SwarmConfig,
-    provider: Arc<dyn LlmProvider>,
+    /// Endpoint every chat completion request goes to: the configured API URL, or
+    /// `OPENAI_API_URL` for OpenAI-format requests when that variable is set.
+    request_url: String,
+    /// One provider per pooled API key, indexed like `key_pool`.
+    providers: Vec<Arc<dyn LlmProvider>>,
+    /// Wire format for non-streaming requests; `None` keeps the built-in OpenAI provider.
+    api_provider: Option<Arc<dyn ApiProvider>>,
+    response_cache: Option<Arc<dyn ResponseCache>>,
+    response_cache_ttl: Option<Duration>,
+    steps_parser: Arc<dyn StepsParser>,
     distributed_transport: Arc<dyn DistributedTransport>,
     subscribers: Vec<Arc<dyn EventSubscriber>>,
     session_store: Option<Arc<dyn SessionStore>>,
@@ -149,12 +575,21 @@ pub struct Swarm {
     tool_breaker_settings: CircuitBreakerSettings,
     tool_breakers: Arc<Mutex<HashMap<String, CircuitBreaker>>>,
     team_assignment_load: Arc<Mutex<HashMap<AgentRef, u64>>>,
+    dead_letter_tx: Option<mpsc::Sender<DeadLetterEntry>>,
+    /// When each registered agent was last registered or looked up by name.
+    agent_last_access: Arc<Mutex<HashMap<String, Instant>>>,
 }
 
 /// Builder pattern implementation for creating Swarm instances.
 pub struct SwarmBuilder {
     client: Option<Client>,
     api_key: Option<ApiKey>,
+    api_keys: Vec<ApiKey>,
+    api_key_cooldown: Duration,
+    api_provider: Option<Arc<dyn ApiProvider>>,
+    response_cache: Option<Arc<dyn ResponseCache>>,
+    response_cache_ttl: Option<Duration>,
+    steps_parser: Arc<dyn StepsParser>,
     agents: HashMap<String, Agent>,
     distributed_transport: Option<Arc<dyn DistributedTransport>>,
     config: SwarmConfig,
@@ -171,6 +606,10 @@ pub struct SwarmBuilder {
     escalation_config: EscalationConfig,
     provider_breaker_settings: CircuitBreakerSettings,
     tool_breaker_settings: CircuitBreakerSettings,
+    ca_certs: Vec<Certificate>,
+    danger_accept_invalid_certs: bool,
+    tls_min_version: Option<tls::Version>,
+    dead_letter_tx: Option<mpsc::Sender<DeadLetterEntry>>,
 }
 
 impl SwarmBuilder {
@@ -179,6 +618,12 @@ impl SwarmBuilder {
         SwarmBuilder {
             client: None,
             api_key: None,
+            api_keys: Vec::new(),
+            api_key_cooldown: DEFAULT_API_KEY_COOLDOWN,
+            api_provider: None,
+            response_cache: None,
+            response_cache_ttl: None,
+            steps_parser: Arc::new(QuickXmlStepsParser),
             agents: HashMap::new(),
             distributed_transport: None,
             config,
@@ -195,6 +640,10 @@ impl SwarmBuilder {
             escalation_config: EscalationConfig::default(),
             provider_breaker_settings: CircuitBreakerSettings::default(),
             tool_breaker_settings: CircuitBreakerSettings::default(),
+            ca_certs: Vec::new(),
+            danger_accept_invalid_certs: false,
+            tls_min_version: None,
+            dead_letter_tx: None,
         }
     }
 
@@ -243,6 +692,12 @@ impl SwarmBuilder {
         self
     }
 
+    /// Randomizes retry delays; see [`JitterStrategy`].
+    pub fn with_retry_jitter(mut self, jitter: JitterStrategy) -> Self {
+        self.config.set_retry_jitter(jitter);
+        self
+    }
+
     pub fn with_max_loop_iterations(mut self, iterations: u32) -> Self {
         if let Err(err) = self.config.set_max_loop_iterations(iterations) {
             self.record_error(err);
@@ -255,2215 +710,5093 @@ impl SwarmBuilder {
         self
     }
 
-    pub fn with_valid_model_prefixes(mut self, prefixes: Vec<String>) -> Self {
-        if let Err(err) = self.config.set_valid_model_prefixes(prefixes) {
+    /// How agent instructions are written into the system message, such as
+    /// wrapped in `<instructions>` tags for Anthropic models.
+    pub fn with_system_message_format(mut self, format: SystemMessageFormat) -> Self {
+        self.config.set_system_message_format(format);
+        self
+    }
+
+    /// How messages are written into request bodies, for non-OpenAI backends.
+    pub fn with_message_serialization(mut self, adapter: MessageSerializationAdapter) -> Self {
+        self.config.set_message_serialization(adapter);
+        self
+    }
+
+    /// Model name patterns allowed to receive image content parts.
+    ///
+    /// A pattern matches whole `-`, `.`, `/`, `:` or `_` separated parts of the
+    /// model name, e.g. `o1` matches `o1-mini` but not `gpt-4o1`.
+    pub fn with_vision_capable_model_patterns(mut self, patterns: Vec<String>) -> Self {
+        if let Err(err) = self.config.set_vision_capable_model_patterns(patterns) {
             self.record_error(err);
         }
         self
     }
 
-    pub fn with_valid_api_url_prefixes(mut self, prefixes: Vec<String>) -> Self {
-        if let Err(err) = self.config.set_valid_api_url_prefixes(prefixes) {
+    /// Stop sequences sent with every request, merged ahead of each agent's own.
+    pub fn with_global_stop_sequences(mut self, sequences: Vec<String>) -> Self {
+        if let Err(err) = self.config.set_global_stop_sequences(sequences) {
             self.record_error(err);
         }
         self
     }
 
-    pub fn with_checkpoint_store(mut self, store: Arc<dyn CheckpointStore>) -> Self {
-        self.checkpoint_store = Some(store);
+    /// Function format for agents that do not call [`Agent::with_function_format`].
+    pub fn with_default_function_format(mut self, format: FunctionCallFormat) -> Self {
+        self.config.set_default_function_format(format);
         self
     }
 
-    pub fn with_session_store(mut self, store: Arc<dyn SessionStore>) -> Self {
-        self.session_store = Some(store);
+    /// Attribute the assistant replies and tool results each turn appends to the
+    /// running agent, so [`Response::messages_by_agent`] can split the history.
+    /// Replies get the agent's `name`, cut to 64 characters with anything outside
+    /// `[a-zA-Z0-9_-]` replaced by `_` as the API requires; tool results, which
+    /// cannot carry a name, record it in [`Message::author`]. Function results keep
+    /// the function's name, which the API requires. Enabled by default.
+    pub fn with_inject_agent_name_as_message_name(mut self, enabled: bool) -> Self {
+        self.config.set_inject_agent_name_as_message_name(enabled);
         self
     }
 
-    pub fn with_event_store(mut self, store: Arc<dyn EventStore>) -> Self {
-        self.event_store = Some(store);
+    pub fn with_deduplicate_system_messages(mut self, enabled: bool) -> Self {
+        self.config.set_deduplicate_system_messages(enabled);
         self
     }
 
-    pub fn with_memory_store(mut self, store: Arc<dyn MemoryStore>) -> Self {
-        self.memory_store = Some(store);
+    /// Rewrite the most recent system message holding the previous agent's
+    /// instructions with the new agent's when a step switches agents.
+    pub fn with_update_system_message_on_switch(mut self, enabled: bool) -> Self {
+        self.config.set_update_system_message_on_switch(enabled);
         self
     }
 
-    pub fn with_persistence_backend<T>(mut self, backend: T) -> Self
-    where
-        T: PersistenceBackend + 'static,
-    {
-        let backend = Arc::new(backend);
-        self.session_store = Some(backend.clone());
-        self.event_store = Some(backend.clone());
-        self.checkpoint_store = Some(backend.clone());
-        self.memory_store = Some(backend);
+    /// Repair malformed JSON in function call arguments instead of rejecting the call.
+    pub fn with_repair_function_arguments(mut self, enabled: bool) -> Self {
+        self.config.set_repair_function_arguments(enabled);
         self
     }
 
-    pub fn with_client(mut self, client: Client) -> Self {
-        self.client = Some(client);
+    /// Largest value, in bytes, a function result may store in a context variable.
+    pub fn with_context_variable_max_size(mut self, max_size: usize) -> Self {
+        if let Err(err) = self.config.set_context_variable_max_size(Some(max_size)) {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_distributed_transport(mut self, transport: Arc<dyn DistributedTransport>) -> Self {
-        self.distributed_transport = Some(transport);
+    /// Most context variables a run may hold.
+    pub fn with_context_variable_max_count(mut self, max_count: usize) -> Self {
+        if let Err(err) = self.config.set_context_variable_max_count(Some(max_count)) {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_api_key(mut self, api_key: String) -> Self {
-        match ApiKey::new(api_key) {
-            Ok(api_key) => self.api_key = Some(api_key),
-            Err(err) => self.record_error(err),
+    /// Record context variable snapshots during runs for
+    /// [`Swarm::restore_context_snapshot`].
+    pub fn with_context_snapshot_interval(mut self, interval: ContextSnapshotInterval) -> Self {
+        self.config.set_context_snapshot_interval(interval);
+        self
+    }
+
+    pub fn with_max_context_snapshots(mut self, max_snapshots: usize) -> Self {
+        if let Err(err) = self.config.set_max_context_snapshots(max_snapshots) {
+            self.record_error(err);
         }
         self
     }
 
-    pub fn with_agent(mut self, agent: Agent) -> Self {
-        self.agents.insert(agent.name.clone(), agent);
+    pub fn with_context_overflow(mut self, overflow: ContextOverflow) -> Self {
+        self.config.set_context_overflow(overflow);
         self
     }
 
-    pub fn with_agents(mut self, agents: &HashMap<String, Agent>) -> Self {
-        for agent in agents.values() {
-            self = self.with_agent(agent.clone());
+    /// Cap the estimated prompt tokens each workflow step starts with.
+    pub fn with_max_context_tokens_per_step(mut self, max_tokens: u32) -> Self {
+        if let Err(err) = self
+            .config
+            .set_max_context_tokens_per_step(Some(max_tokens))
+        {
+            self.record_error(err);
         }
         self
     }
 
-    pub fn with_content_policy(mut self, policy: Arc<dyn ContentPolicy>) -> Self {
-        self.content_policy = policy;
+    /// Limit the content of `role` messages to `max_length` bytes, handling longer
+    /// ones with `action` before each turn.
+    pub fn with_max_content_length(
+        mut self,
+        role: MessageRole,
+        max_length: usize,
+        action: ContentOverflowAction,
+    ) -> Self {
+        let mut limits = self.config.max_content_length_per_role().clone();
+        limits.insert(role.as_str().to_string(), max_length);
+        let mut actions = self.config.content_overflow_per_role().clone();
+        actions.insert(role.as_str().to_string(), action);
+        if let Err(err) = self
+            .config
+            .set_max_content_length_per_role(limits)
+            .and_then(|()| self.config.set_content_overflow_per_role(actions))
+        {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_injection_policy(mut self, policy: InjectionPolicy) -> Self {
-        self.injection_policy = policy;
+    /// Send requests for model `from` to model `to`; on top of the built-in aliases for
+    /// deprecated OpenAI models.
+    pub fn with_model_alias(mut self, from: &str, to: &str) -> Self {
+        let mut aliases = self.config.model_aliases().clone();
+        aliases.insert(from.to_string(), to.to_string());
+        if let Err(err) = self.config.set_model_aliases(aliases) {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_redaction_policy(mut self, policy: RedactionPolicy) -> Self {
-        self.redaction_policy = policy;
+    /// Common words of `language` (matched case-insensitively) that a reply from an
+    /// agent with `enforce_language` must contain at least one of.
+    pub fn with_language_word_list(mut self, language: &str, words: Vec<String>) -> Self {
+        let mut lists = self.config.language_word_lists().clone();
+        lists.insert(language.to_string(), words);
+        if let Err(err) = self.config.set_language_word_lists(lists) {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_redaction_threshold(mut self, threshold: DataClassification) -> Self {
-        self.redaction_threshold = threshold;
+    /// Ask the model for a corrected call when function arguments fail validation,
+    /// sending `prompt` followed by the validation error, instead of failing the run.
+    pub fn with_function_arg_retry_prompt(mut self, prompt: impl Into<String>) -> Self {
+        if let Err(err) = self
+            .config
+            .set_function_arg_retry_prompt(Some(prompt.into()))
+        {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_escalation_config(mut self, config: EscalationConfig) -> Self {
-        self.escalation_config = config;
+    /// Corrected calls requested per turn with `function_arg_retry_prompt`; defaults to 1.
+    pub fn with_function_arg_max_retries(mut self, retries: u32) -> Self {
+        self.config.set_function_arg_max_retries(retries);
         self
     }
 
-    pub fn with_provider_circuit_breaker(
-        mut self,
-        failure_threshold: u32,
-        reset_secs: u64,
-    ) -> Self {
-        self.provider_breaker_settings = CircuitBreakerSettings {
-            failure_threshold,
-            reset_secs,
-        };
+    /// Add `prompt` as a user message after every function result. `{{function_name}}`
+    /// and `{{function_result}}` (its first 100 characters) are filled in.
+    pub fn with_post_function_call_prompt(mut self, prompt: impl Into<String>) -> Self {
+        if let Err(err) = self
+            .config
+            .set_post_function_call_prompt(Some(prompt.into()))
+        {
+            self.record_error(err);
+        }
         self
     }
 
-    pub fn with_tool_circuit_breaker(mut self, failure_threshold: u32, reset_secs: u64) -> Self {
-        self.tool_breaker_settings = CircuitBreakerSettings {
-            failure_threshold,
-            reset_secs,
-        };
+    /// Encoding of `<include>`d step files.
+    pub fn with_xml_encoding(mut self, encoding: XmlEncoding) -> Self {
+        self.config.set_xml_encoding(encoding);
         self
     }
 
-    pub fn build(self) -> SwarmResult<Swarm> {
-        if let Some(err) = self.build_error {
-            return Err(err);
+    /// Accept step tags in the `namespace` prefix, e.g. `<my:steps>` for `"my"`.
+    pub fn with_xml_namespace(mut self, namespace: impl Into<String>) -> Self {
+        if let Err(err) = self.config.set_xml_namespace(Some(namespace.into())) {
+            self.record_error(err);
         }
+        self
+    }
 
-        self.config.validate()?;
-
-        for agent in self.agents.values() {
-            agent.validate(&self.config)?;
+    /// Prefix marking context variables that are removed after every step.
+    pub fn with_ephemeral_key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        if let Err(err) = self.config.set_ephemeral_key_prefix(prefix.into()) {
+            self.record_error(err);
         }
+        self
+    }
 
-        self.provider_breaker_settings
-            .validate("provider circuit breaker")?;
-        self.tool_breaker_settings
-            .validate("tool circuit breaker")?;
+    /// How many times a run may append the `<steps>` found under the
+    /// `__dynamic_steps` context key; a further injection fails the run.
+    pub fn with_max_dynamic_step_injections(mut self, limit: usize) -> Self {
+        self.config.set_max_dynamic_step_injections(limit);
+        self
+    }
 
-        let api_key = match self.api_key {
-            Some(key) => key,
-            None => match env::var("OPENAI_API_KEY") {
-                Ok(key) => ApiKey::new(key)?,
-                Err(_) => {
-                    return Err(SwarmError::ValidationError(
-                        "API key must be set either in environment or passed to builder"
-                            .to_string(),
-                    ))
-                }
-            },
-        };
+    /// Compress completion requests whose estimated prompt size is over
+    /// `compression.max_tokens`.
+    pub fn with_prompt_compression(mut self, compression: PromptCompressionConfig) -> Self {
+        if let Err(err) = self.config.set_prompt_compression(Some(compression)) {
+            self.record_error(err);
+        }
+        self
+    }
 
-        let client = self.client.unwrap_or_else(|| {
-            Client::builder()
-                .timeout(Duration::from_secs(self.config.request_timeout()))
-                .connect_timeout(Duration::from_secs(self.config.connect_timeout()))
-                .build()
-                .unwrap_or_else(|e| {
-                    tracing::warn!(
-                        "Failed to build configured HTTP client ({}), falling back to default — \
-                         request/connect timeouts will not be applied",
-                        e
-                    );
-                    Client::new()
-                })
-        });
-
-        let provider: Arc<dyn LlmProvider> = Arc::new(OpenAiProvider::new(
-            client.clone(),
-            api_key.as_str(),
-            self.config.api_url(),
-        ));
-        let distributed_transport = self
-            .distributed_transport
-            .unwrap_or_else(|| Arc::new(HttpDistributedTransport::new(client.clone())));
-        let agent_directory = AgentRegistry::new();
-        for agent in self.agents.values() {
-            agent_directory.register(Arc::new(agent.clone()));
+    /// Most agents the swarm may hold; `Swarm::register_agent` fails beyond it.
+    pub fn with_max_registered_agents(mut self, limit: usize) -> Self {
+        if let Err(err) = self.config.set_max_registered_agents(Some(limit)) {
+            self.record_error(err);
         }
-        let channel_registry = ChannelRegistry::new();
-
-        Ok(Swarm {
-            client,
-            api_key,
-            agent_registry: self.agents,
-            agent_directory,
-            channel_registry,
-            config: self.config,
-            provider,
-            distributed_transport,
-            subscribers: self.subscribers,
-            session_store: self.session_store,
-            event_store: self.event_store,
-            checkpoint_store: self.checkpoint_store,
-            memory_store: self.memory_store,
-            content_policy: self.content_policy,
-            injection_policy: self.injection_policy,
-            redaction_policy: self.redaction_policy,
-            redaction_threshold: self.redaction_threshold,
-            escalation_config: self.escalation_config,
-            provider_breaker: CircuitBreaker::new(
-                "provider",
-                self.provider_breaker_settings.failure_threshold,
-                self.provider_breaker_settings.reset_secs,
-            ),
-            tool_breaker_settings: self.tool_breaker_settings,
-            tool_breakers: Arc::new(Mutex::new(HashMap::new())),
-            team_assignment_load: Arc::new(Mutex::new(HashMap::new())),
-        })
+        self
     }
 
-    fn record_error(&mut self, err: SwarmError) {
-        if self.build_error.is_none() {
-            self.build_error = Some(err);
+    /// Rename context keys (`old_key` to `new_key`) whenever a step switches to
+    /// `agent_name`.
+    pub fn with_context_rename_on_switch(
+        mut self,
+        agent_name: &str,
+        renames: HashMap<String, String>,
+    ) -> Self {
+        let mut all = self.config.context_rename_on_switch().clone();
+        all.insert(agent_name.to_string(), renames);
+        if let Err(err) = self.config.set_context_rename_on_switch(all) {
+            self.record_error(err);
         }
+        self
     }
-}
 
-impl Default for SwarmBuilder {
-    fn default() -> Self {
-        Self::new()
+    /// Add a source of context variables, called at the start of every run after
+    /// those added before it. Later injectors win on shared keys, and context
+    /// variables passed to the run win over all of them.
+    pub fn with_context_injector(mut self, injector: Arc<ContextInjector>) -> Self {
+        self.config.add_context_injector(injector);
+        self
     }
-}
 
-impl Swarm {
-    pub fn builder() -> SwarmBuilder {
-        SwarmBuilder::new()
+    /// How `merge` steps combine a branch's context variables with the current ones.
+    pub fn with_branch_merge_strategy(mut self, strategy: BranchMergeStrategy) -> Self {
+        self.config.set_branch_merge_strategy(strategy);
+        self
     }
 
-    fn next_trace_id() -> TraceId {
-        TraceId::from(uuid::Uuid::new_v4().to_string())
+    pub fn with_context_overflow_strategy(mut self, strategy: ContextOverflowStrategy) -> Self {
+        self.config.set_context_overflow_strategy(strategy);
+        self
     }
 
-    // For backward compatibility.
-    pub fn new(
-        client: Option<Client>,
-        api_key: Option<String>,
-        agents: HashMap<String, Agent>,
-    ) -> SwarmResult<Self> {
-        let mut builder = SwarmBuilder::new();
-
-        if let Some(client) = client {
-            builder = builder.with_client(client);
-        }
-        if let Some(api_key) = api_key {
-            builder = builder.with_api_key(api_key);
+    /// Baseline instructions added to every request's system prompt, regardless of agent.
+    pub fn with_safety_instructions(mut self, instructions: impl Into<String>) -> Self {
+        if let Err(err) = self
+            .config
+            .set_safety_instructions(Some(instructions.into()))
+        {
+            self.record_error(err);
         }
-        builder = builder.with_agents(&agents);
-
-        builder.build()
+        self
     }
 
-    pub fn client(&self) -> &Client {
-        &self.client
+    pub fn with_safety_placement(mut self, placement: SafetyPlacement) -> Self {
+        self.config.set_safety_placement(placement);
+        self
     }
 
-    pub fn api_key(&self) -> &ApiKey {
-        &self.api_key
+    /// Estimated token count above which a function's return value is compressed
+    /// before it enters the history.
+    pub fn with_tool_result_max_tokens(mut self, max_tokens: u32) -> Self {
+        if let Err(err) = self.config.set_tool_result_max_tokens(Some(max_tokens)) {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn agents(&self) -> &HashMap<String, Agent> {
-        &self.agent_registry
+    /// Registered agent that summarizes results over `tool_result_max_tokens`.
+    /// Without one, oversized results are truncated.
+    pub fn with_tool_summarizer_agent(mut self, agent_name: impl Into<String>) -> Self {
+        if let Err(err) = self
+            .config
+            .set_tool_summarizer_agent(Some(agent_name.into()))
+        {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn agent_directory(&self) -> &AgentRegistry {
-        &self.agent_directory
+    /// Summarize large context values with `context_summarizer_agent` once keys and
+    /// values together exceed `bytes`.
+    pub fn with_context_size_limit(mut self, bytes: usize) -> Self {
+        if let Err(err) = self.config.set_context_size_limit(Some(bytes)) {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn channel_registry(&self) -> &Arc<ChannelRegistry> {
-        &self.channel_registry
+    /// Only context values larger than `bytes` are summarized.
+    pub fn with_context_value_max_bytes(mut self, bytes: usize) -> Self {
+        if let Err(err) = self.config.set_context_value_max_bytes(Some(bytes)) {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn config(&self) -> &SwarmConfig {
-        &self.config
+    /// Registered agent that summarizes context values once `context_size_limit` is
+    /// exceeded. A summarized value is stored under its key plus `_summarized`.
+    pub fn with_context_summarizer_agent(mut self, agent_name: impl Into<String>) -> Self {
+        if let Err(err) = self
+            .config
+            .set_context_summarizer_agent(Some(agent_name.into()))
+        {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn provider(&self) -> &Arc<dyn LlmProvider> {
-        &self.provider
+    /// Re-ask the model when its answer's word-set Jaccard similarity to a recent
+    /// assistant message exceeds `threshold` (0.0–1.0).
+    pub fn with_semantic_dedup_threshold(mut self, threshold: f32) -> Self {
+        if let Err(err) = self.config.set_semantic_dedup_threshold(Some(threshold)) {
+            self.record_error(err);
+        }
+        self
     }
 
-    pub fn find_agents_by_capability(&self, capability: &str) -> Vec<AgentRef> {
-        self.agent_directory.find_by_capability(capability)
+    /// Score each run's first user message; the score picks a model from
+    /// [`Self::with_complexity_model_map`].
+    pub fn with_task_complexity_scorer(
+        mut self,
+        scorer: impl Fn(&str) -> f32 + Send + Sync + 'static,
+    ) -> Self {
+        self.config.set_task_complexity_scorer(Arc::new(scorer));
+        self
     }
 
-    pub async fn open_agent_channel(
-        &self,
-        agent: impl Into<AgentRef>,
-    ) -> SwarmResult<InProcessChannel> {
-        let agent = agent.into();
-        if self.agent_directory.get(&agent).is_none() {
-            return Err(SwarmError::AgentNotFoundError(agent.to_string()));
+    /// `(threshold, model)` pairs: a run uses the model with the highest threshold
+    /// not above its complexity score. Scores below every threshold keep the agent's model.
+    pub fn with_complexity_model_map(mut self, map: Vec<(f32, String)>) -> Self {
+        if let Err(err) = self.config.set_complexity_model_map(map) {
+            self.record_error(err);
         }
-        InProcessChannel::new(agent, self.channel_registry.clone()).await
+        self
     }
 
-    async fn emit_message_sent(
-        &self,
-        trace_id: &TraceId,
-        from: AgentAddress,
-        to: AgentAddress,
-        message_id: crate::agent_comm::MessageId,
-    ) {
-        self.emit(AgentEvent::MessageSent {
-            trace_id: trace_id.clone(),
-            from,
-            to,
-            message_id,
-            timestamp: Utc::now(),
-        })
-        .await;
+    /// Derive the request `user` field from context variables when a run does not set
+    /// [`RunOptions::with_user_id`].
+    pub fn with_user_id_provider(
+        mut self,
+        provider: impl Fn(&ContextVariables) -> Option<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.config.set_user_id_provider(Arc::new(provider));
+        self
     }
 
-    async fn emit_message_received(
-        &self,
-        trace_id: &TraceId,
-        by: AgentAddress,
-        message_id: crate::agent_comm::MessageId,
-    ) {
-        self.emit(AgentEvent::MessageReceived {
-            trace_id: trace_id.clone(),
-            by,
-            message_id,
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_history_window(mut self, strategy: HistoryWindowStrategy) -> Self {
+        if let Err(err) = self.config.set_history_window(strategy) {
+            self.record_error(err);
+        }
+        self
     }
 
-    async fn emit_reply_timeout(
-        &self,
-        trace_id: &TraceId,
-        from: AgentAddress,
-        to: AgentAddress,
-        correlation_id: crate::agent_comm::MessageId,
-        timeout: Duration,
-    ) {
-        self.emit(AgentEvent::ReplyTimeout {
-            trace_id: trace_id.clone(),
-            from,
-            to,
-            correlation_id,
-            timeout_ms: timeout.as_millis() as u64,
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_adaptive_max_tokens(mut self, enabled: bool) -> Self {
+        self.config.set_adaptive_max_tokens(enabled);
+        self
     }
 
-    fn validate_local_agent(&self, agent: &AgentRef) -> SwarmResult<()> {
-        if self.agent_directory.get(agent).is_some() {
-            Ok(())
-        } else {
-            Err(SwarmError::AgentNotFoundError(agent.to_string()))
+    /// Registers the context window for models starting with `prefix`.
+    pub fn with_model_context_window(mut self, prefix: impl Into<String>, tokens: u32) -> Self {
+        match self
+            .config
+            .model_context_windows()
+            .clone()
+            .with_window(prefix, tokens)
+        {
+            Ok(windows) => self.config.set_model_context_windows(windows),
+            Err(err) => self.record_error(err),
         }
+        self
     }
 
-    fn local_message_from_distributed(message: &DistributedMessage) -> SwarmResult<AgentMessage> {
-        let (AgentAddress::Local { agent: from }, AgentAddress::Local { agent: to }) =
-            (&message.from, &message.to)
-        else {
-            return Err(SwarmError::ValidationError(
-                "Local message routing requires local source and destination".to_string(),
-            ));
-        };
+    pub fn with_handoff_cycle_detection(mut self, enabled: bool) -> Self {
+        self.config.set_handoff_cycle_detection(enabled);
+        self
+    }
 
-        Ok(AgentMessage {
-            id: message.id.clone(),
-            from: from.clone(),
-            to: to.clone(),
-            payload: message.payload.clone(),
-            timestamp: message.timestamp,
-            correlation_id: message.correlation_id.clone(),
-            is_reply: message.is_reply,
-        })
+    pub fn with_on_circular_handoff(mut self, action: CircularHandoffAction) -> Self {
+        self.config.set_on_circular_handoff(action);
+        self
     }
 
-    pub async fn send_agent_message(
-        &self,
-        trace_id: Option<TraceId>,
-        from: AgentAddress,
-        to: AgentAddress,
-        payload: Value,
-    ) -> SwarmResult<crate::agent_comm::MessageId> {
-        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
-        let message = DistributedMessage::new(from.clone(), to.clone(), payload)
-            .with_trace_id(trace_id.clone());
+    pub fn with_valid_model_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        if let Err(err) = self.config.set_valid_model_prefixes(prefixes) {
+            self.record_error(err);
+        }
+        self
+    }
 
-        match (&from, &to) {
-            (
-                AgentAddress::Local { agent: local_from },
-                AgentAddress::Local { agent: local_to },
-            ) => {
-                self.validate_local_agent(local_from)?;
-                self.validate_local_agent(local_to)?;
-                self.channel_registry
-                    .send(Self::local_message_from_distributed(&message)?)
-                    .await?;
-            }
-            _ => {
-                self.distributed_transport.send(message.clone()).await?;
-            }
+    pub fn with_valid_api_url_prefixes(mut self, prefixes: Vec<String>) -> Self {
+        if let Err(err) = self.config.set_valid_api_url_prefixes(prefixes) {
+            self.record_error(err);
         }
+        self
+    }
 
-        self.emit_message_sent(&trace_id, from, to, message.id.clone())
-            .await;
-        Ok(message.id)
+    pub fn with_checkpoint_store(mut self, store: Arc<dyn CheckpointStore>) -> Self {
+        self.checkpoint_store = Some(store);
+        self
     }
 
-    pub async fn request_agent_message(
-        &self,
-        trace_id: Option<TraceId>,
-        from: AgentAddress,
-        to: AgentAddress,
-        payload: Value,
-        timeout: Duration,
-    ) -> SwarmResult<DistributedMessage> {
-        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
-        let message = DistributedMessage::new(from.clone(), to.clone(), payload)
-            .with_trace_id(trace_id.clone());
-
-        let result = match (&from, &to) {
-            (
-                AgentAddress::Local { agent: local_from },
-                AgentAddress::Local { agent: local_to },
-            ) => {
-                self.validate_local_agent(local_from)?;
-                self.validate_local_agent(local_to)?;
-                self.channel_registry
-                    .request(Self::local_message_from_distributed(&message)?, timeout)
-                    .await
-                    .map(|reply| DistributedMessage {
-                        id: reply.id,
-                        from: AgentAddress::local(reply.from),
-                        to: AgentAddress::local(reply.to),
-                        payload: reply.payload,
-                        timestamp: reply.timestamp,
-                        correlation_id: reply.correlation_id,
-                        trace_id: Some(trace_id.clone()),
-                        is_reply: reply.is_reply,
-                    })
-            }
-            _ => {
-                self.distributed_transport
-                    .request(message.clone(), timeout)
-                    .await
-            }
-        };
+    pub fn with_session_store(mut self, store: Arc<dyn SessionStore>) -> Self {
+        self.session_store = Some(store);
+        self
+    }
 
-        match result {
-            Ok(reply) => {
-                self.emit_message_sent(&trace_id, from, to, message.id.clone())
-                    .await;
-                self.emit_message_received(&trace_id, reply.to.clone(), reply.id.clone())
-                    .await;
-                Ok(reply)
-            }
-            Err(err) => {
-                if matches!(err, SwarmError::TimeoutError(_)) {
-                    self.emit_message_sent(&trace_id, from.clone(), to.clone(), message.id.clone())
-                        .await;
-                    self.emit_reply_timeout(&trace_id, from, to, message.id.clone(), timeout)
-                        .await;
-                }
-                Err(err)
-            }
-        }
+    pub fn with_event_store(mut self, store: Arc<dyn EventStore>) -> Self {
+        self.event_store = Some(store);
+        self
     }
 
-    pub async fn multicast_agent_message(
-        &self,
-        trace_id: Option<TraceId>,
-        from: AgentAddress,
-        recipients: Vec<AgentAddress>,
-        payload: Value,
-    ) -> SwarmResult<Vec<crate::agent_comm::MessageId>> {
-        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
-        let mut ids = Vec::new();
-        for recipient in recipients {
-            let message_id = self
-                .send_agent_message(
-                    Some(trace_id.clone()),
-                    from.clone(),
-                    recipient,
-                    payload.clone(),
-                )
-                .await?;
-            ids.push(message_id);
-        }
-        Ok(ids)
+    /// Send failed function calls to `sender` and let the conversation continue:
+    /// the model gets the error and is asked to try another approach instead of the
+    /// run failing. Entries are dropped with a warning when the channel is full or closed.
+    pub fn with_dead_letter_queue(mut self, sender: mpsc::Sender<DeadLetterEntry>) -> Self {
+        self.dead_letter_tx = Some(sender);
+        self
     }
 
-    pub async fn broadcast_agent_message(
-        &self,
-        trace_id: Option<TraceId>,
-        from: AgentAddress,
-        payload: Value,
-        include_sender: bool,
-    ) -> SwarmResult<Vec<crate::agent_comm::MessageId>> {
-        let sender_ref = from.agent_ref().clone();
-        let recipients = self
-            .agent_directory
-            .all_refs()
-            .into_iter()
-            .filter(|agent| include_sender || *agent != sender_ref)
-            .map(AgentAddress::local)
-            .collect::<Vec<_>>();
-        self.multicast_agent_message(trace_id, from, recipients, payload)
-            .await
+    pub fn with_memory_store(mut self, store: Arc<dyn MemoryStore>) -> Self {
+        self.memory_store = Some(store);
+        self
     }
 
-    fn agent_matches_role(agent: &Agent, role: &TeamRole) -> bool {
-        role.required_capabilities()
-            .iter()
-            .all(|capability| agent.has_capability(capability))
+    pub fn with_persistence_backend<T>(mut self, backend: T) -> Self
+    where
+        T: PersistenceBackend + 'static,
+    {
+        let backend = Arc::new(backend);
+        self.session_store = Some(backend.clone());
+        self.event_store = Some(backend.clone());
+        self.checkpoint_store = Some(backend.clone());
+        self.memory_store = Some(backend);
+        self
     }
 
-    fn optional_capability_score(agent: &Agent, role: &TeamRole) -> usize {
-        role.optional_capabilities()
-            .iter()
-            .filter(|capability| agent.has_capability(capability))
-            .count()
+    pub fn with_client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
     }
 
-    async fn form_team_internal(
-        &self,
-        existing: Option<&AgentTeam>,
-        roles: &[TeamRole],
-        policy: TeamFormationPolicy,
-    ) -> SwarmResult<AgentTeam> {
-        if roles.is_empty() {
-            return Err(SwarmError::ValidationError(
-                "At least one team role is required".to_string(),
+    /// Trusts the certificates in a PEM bundle in addition to the system roots.
+    ///
+    /// Cannot be combined with [`SwarmBuilder::with_client`].
+    pub fn with_ca_cert(mut self, pem: Vec<u8>) -> SwarmResult<Self> {
+        let certs = Certificate::from_pem_bundle(&pem)
+            .map_err(|e| SwarmError::ConfigError(format!("Invalid CA certificate: {}", e)))?;
+        if certs.is_empty() {
+            return Err(SwarmError::ConfigError(
+                "CA certificate bundle contains no certificates".to_string(),
             ));
         }
+        self.ca_certs.extend(certs);
+        Ok(self)
+    }
 
-        let load_snapshot = self
-            .team_assignment_load
-            .lock()
-            .map_err(|_| SwarmError::Other("team_assignment_load lock poisoned".into()))?
-            .clone();
-        let mut projected_load = load_snapshot;
-        let mut used = HashSet::new();
-        let mut assignments = Vec::with_capacity(roles.len());
-        let mut all_refs = self.agent_directory.all_refs();
-        all_refs.sort_by(|a, b| a.as_str().cmp(b.as_str()));
-
-        for role in roles {
-            if policy.prefer_existing_assignments {
-                if let Some(existing_team) = existing {
-                    if let Some(existing_agent) = existing_team.agent_for_role(role.name()) {
-                        if let Some(agent) = self.agent_directory.get(existing_agent) {
-                            if Self::agent_matches_role(&agent, role)
-                                && (policy.allow_agent_reuse || used.insert(existing_agent.clone()))
-                            {
-                                *projected_load.entry(existing_agent.clone()).or_default() += 1;
-                                assignments.push(TeamAssignment::new(
-                                    role.clone(),
-                                    existing_agent.clone(),
-                                ));
-                                continue;
-                            }
-                        }
-                    }
-                }
-            }
+    /// Disables certificate verification. Only for testing against self-signed endpoints.
+    ///
+    /// Cannot be combined with [`SwarmBuilder::with_client`].
+    pub fn with_danger_accept_invalid_certs(mut self, accept: bool) -> Self {
+        if accept {
+            tracing::warn!("TLS certificate verification is disabled for this swarm");
+        }
+        self.danger_accept_invalid_certs = accept;
+        self
+    }
 
-            let mut candidates = all_refs
-                .iter()
-                .filter(|agent_ref| policy.allow_agent_reuse || !used.contains(*agent_ref))
-                .filter_map(|agent_ref| {
-                    self.agent_directory
-                        .get(agent_ref)
-                        .map(|agent| (agent_ref.clone(), agent))
-                })
-                .filter(|(_, agent)| Self::agent_matches_role(agent, role))
-                .collect::<Vec<_>>();
+    /// Lowest TLS version the HTTP client will negotiate.
+    ///
+    /// Cannot be combined with [`SwarmBuilder::with_client`].
+    pub fn with_tls_min_version(mut self, version: tls::Version) -> Self {
+        self.tls_min_version = Some(version);
+        self
+    }
 
-            candidates.sort_by(|(left_ref, left_agent), (right_ref, right_agent)| {
-                let left_optional = Self::optional_capability_score(left_agent, role);
-                let right_optional = Self::optional_capability_score(right_agent, role);
-                right_optional
-                    .cmp(&left_optional)
-                    .then_with(|| {
-                        projected_load
-                            .get(left_ref)
-                            .copied()
-                            .unwrap_or(0)
-                            .cmp(&projected_load.get(right_ref).copied().unwrap_or(0))
-                    })
-                    .then_with(|| left_ref.as_str().cmp(right_ref.as_str()))
-            });
+    fn has_tls_settings(&self) -> bool {
+        !self.ca_certs.is_empty()
+            || self.danger_accept_invalid_certs
+            || self.tls_min_version.is_some()
+    }
 
-            let Some((selected_ref, _)) = candidates.into_iter().next() else {
-                return Err(SwarmError::AgentNotFoundError(format!(
-                    "No registered agent satisfies role '{}' with capabilities {:?}",
-                    role.name(),
-                    role.required_capabilities()
-                )));
-            };
+    pub fn with_distributed_transport(mut self, transport: Arc<dyn DistributedTransport>) -> Self {
+        self.distributed_transport = Some(transport);
+        self
+    }
 
-            if !policy.allow_agent_reuse {
-                used.insert(selected_ref.clone());
-            }
-            *projected_load.entry(selected_ref.clone()).or_default() += 1;
-            assignments.push(TeamAssignment::new(role.clone(), selected_ref));
+    pub fn with_api_key(mut self, api_key: String) -> Self {
+        match ApiKey::new(api_key) {
+            Ok(api_key) => self.api_key = Some(api_key),
+            Err(err) => self.record_error(err),
         }
+        self
+    }
 
-        let team = AgentTeam::new(assignments)?;
-        {
-            let mut load = self
-                .team_assignment_load
-                .lock()
-                .map_err(|_| SwarmError::Other("team_assignment_load lock poisoned".into()))?;
-            *load = projected_load;
+    /// Rotate requests round-robin across several API keys.
+    ///
+    /// Keys that report a rate limit are skipped for the configured cooldown
+    /// (see [`SwarmBuilder::with_api_key_cooldown`]). Takes precedence over
+    /// [`SwarmBuilder::with_api_key`].
+    pub fn with_api_keys(mut self, keys: Vec<String>) -> SwarmResult<Self> {
+        if keys.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "with_api_keys requires at least one key".to_string(),
+            ));
         }
-        self.emit(AgentEvent::TeamFormed {
-            trace_id: Self::next_trace_id(),
-            team: team.clone(),
-            timestamp: Utc::now(),
-        })
-        .await;
-        Ok(team)
+        self.api_keys = keys
+            .into_iter()
+            .map(ApiKey::new)
+            .collect::<SwarmResult<Vec<_>>>()?;
+        Ok(self)
     }
 
-    pub async fn form_team(&self, roles: &[TeamRole]) -> SwarmResult<AgentTeam> {
-        self.form_team_internal(None, roles, TeamFormationPolicy::default())
-            .await
+    pub fn with_api_key_cooldown(mut self, cooldown: Duration) -> Self {
+        self.api_key_cooldown = cooldown;
+        self
     }
 
-    pub async fn form_team_with_policy(
-        &self,
-        roles: &[TeamRole],
-        policy: TeamFormationPolicy,
-    ) -> SwarmResult<AgentTeam> {
-        self.form_team_internal(None, roles, policy).await
+    /// Select the wire format used for chat completion requests, e.g.
+    /// [`AnthropicApiProvider`](crate::api_provider::AnthropicApiProvider).
+    ///
+    /// Requests go to the provider's default endpoint unless an API URL other
+    /// than the OpenAI default has been set.
+    pub fn with_provider(mut self, provider: impl ApiProvider + 'static) -> Self {
+        if self.config.api_url() == OPENAI_DEFAULT_API_URL {
+            if let Err(err) = self.config.set_api_url(provider.default_api_url()) {
+                self.record_error(err);
+            }
+        }
+        self.api_provider = Some(Arc::new(provider));
+        self
     }
 
-    pub async fn reconfigure_team(
-        &self,
-        existing: &AgentTeam,
-        roles: &[TeamRole],
-        policy: TeamFormationPolicy,
-    ) -> SwarmResult<AgentTeam> {
-        self.form_team_internal(Some(existing), roles, policy).await
+    /// Serve repeated non-streaming requests (same model, messages and temperature) from `cache`.
+    pub fn with_response_cache(mut self, cache: Arc<dyn ResponseCache>) -> Self {
+        self.response_cache = Some(cache);
+        self
     }
 
-    pub async fn reach_consensus(
-        &self,
-        votes: &[TeamVote],
-        strategy: ConsensusStrategy,
-    ) -> SwarmResult<TeamDecision> {
-        if votes.is_empty() {
-            return Err(SwarmError::ValidationError(
-                "At least one team vote is required".to_string(),
-            ));
-        }
-
-        let mut tallies = HashMap::<String, u32>::new();
-        for vote in votes {
-            self.validate_local_agent(vote.agent())?;
-            *tallies.entry(vote.option().to_string()).or_default() += vote.weight();
-        }
-
-        let total_votes = tallies.values().copied().sum::<u32>();
-        let mut tallies = tallies
-            .into_iter()
-            .map(|(option, weight)| VoteTally::new(option, weight))
-            .collect::<Vec<_>>();
-        tallies.sort_by(|left, right| {
-            right
-                .weight()
-                .cmp(&left.weight())
-                .then_with(|| left.option().cmp(right.option()))
-        });
-
-        let selected = tallies.first().ok_or_else(|| {
-            SwarmError::Other("Consensus tally was unexpectedly empty".to_string())
-        })?;
-        let unanimous = tallies.len() == 1;
-
-        match strategy {
-            ConsensusStrategy::Majority if selected.weight() * 2 <= total_votes => {
-                return Err(SwarmError::Other(
-                    "Majority consensus was not reached".to_string(),
-                ));
-            }
-            ConsensusStrategy::Unanimous if !unanimous => {
-                return Err(SwarmError::Other(
-                    "Unanimous consensus was not reached".to_string(),
-                ));
-            }
-            _ => {}
-        }
-
-        let decision = TeamDecision::new(
-            strategy,
-            selected.option().to_string(),
-            total_votes,
-            tallies,
-            unanimous,
-        );
-        self.emit(AgentEvent::ConsensusReached {
-            trace_id: Self::next_trace_id(),
-            decision: decision.clone(),
-            timestamp: Utc::now(),
-        })
-        .await;
-        Ok(decision)
+    /// Expire cached responses after `ttl`; entries live until evicted when unset.
+    pub fn with_response_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.response_cache_ttl = Some(ttl);
+        self
     }
 
-    /// Emit an event to all registered subscribers.
-    async fn emit(&self, event: AgentEvent) {
-        if let Some(store) = &self.event_store {
-            if let Err(err) = store.append_event(event.trace_id(), &event).await {
-                tracing::warn!(
-                    trace_id = event.trace_id(),
-                    "event persistence failed (non-fatal): {}",
-                    err
-                );
-            }
-        }
-        for sub in &self.subscribers {
-            sub.on_event(&event).await;
-        }
+    /// Parse the `<steps>` block in agent instructions with `parser` instead of quick-xml.
+    pub fn with_steps_parser(mut self, parser: impl StepsParser + 'static) -> Self {
+        self.steps_parser = Arc::new(parser);
+        self
     }
 
-    fn sanitize_text(&self, text: &str) -> (DataClassification, String) {
-        classify_and_redact(
-            text,
-            &self.redaction_policy,
-            self.redaction_threshold.clone(),
-        )
+    pub fn with_agent(mut self, agent: Agent) -> Self {
+        self.agents.insert(agent.name.clone(), agent);
+        self
     }
 
-    fn sanitize_json_value(&self, value: &Value) -> (Option<DataClassification>, Value) {
-        match value {
-            Value::String(text) => {
-                let (classification, redacted) = self.sanitize_text(text);
-                let classification = match classification {
-                    DataClassification::Public => None,
-                    other => Some(other),
-                };
-                (classification, Value::String(redacted))
-            }
-            Value::Array(items) => {
-                let mut highest = None;
-                let mut sanitized = Vec::with_capacity(items.len());
-                for item in items {
-                    let (classification, value) = self.sanitize_json_value(item);
-                    highest = max_classification(highest, classification);
-                    sanitized.push(value);
-                }
-                (highest, Value::Array(sanitized))
-            }
-            Value::Object(map) => {
-                let mut highest = None;
-                let mut sanitized = serde_json::Map::with_capacity(map.len());
-                for (key, item) in map {
-                    let (classification, value) = self.sanitize_json_value(item);
-                    highest = max_classification(highest, classification);
-                    sanitized.insert(key.clone(), value);
-                }
-                (highest, Value::Object(sanitized))
-            }
-            _ => (None, value.clone()),
+    pub fn with_agents(mut self, agents: &HashMap<String, Agent>) -> Self {
+        for agent in agents.values() {
+            self = self.with_agent(agent.clone());
         }
+        self
     }
 
-    fn get_tool_breaker(&self, tool_name: &str) -> SwarmResult<CircuitBreaker> {
-        let mut guard = self
-            .tool_breakers
-            .lock()
-            .map_err(|_| SwarmError::Other("tool_breakers lock poisoned".into()))?;
-        Ok(guard
-            .entry(tool_name.to_string())
-            .or_insert_with(|| {
-                CircuitBreaker::new(
-                    format!("tool:{}", tool_name),
-                    self.tool_breaker_settings.failure_threshold,
-                    self.tool_breaker_settings.reset_secs,
-                )
-            })
-            .clone())
+    pub fn with_content_policy(mut self, policy: Arc<dyn ContentPolicy>) -> Self {
+        self.content_policy = policy;
+        self
     }
 
-    async fn emit_guardrail_event(
-        &self,
-        trace_id: &TraceId,
-        guardrail_type: &str,
-        action: &str,
-        details: &str,
-        classification: Option<DataClassification>,
-    ) {
-        let (_, details) = self.sanitize_text(details);
-        record_guardrail_triggered(guardrail_type);
-        self.emit(AgentEvent::GuardrailTriggered {
-            trace_id: trace_id.clone(),
-            guardrail_type: guardrail_type.to_string(),
-            action: action.to_string(),
-            details,
-            classification,
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_injection_policy(mut self, policy: InjectionPolicy) -> Self {
+        self.injection_policy = policy;
+        self
     }
 
-    async fn emit_budget_event(&self, trace_id: &TraceId, exhausted: &BudgetExhausted) {
-        let limit_type = match exhausted {
-            BudgetExhausted::TokenBudget { .. } => "token_budget",
-            BudgetExhausted::TokensPerRequest { .. } => "tokens_per_request",
-            BudgetExhausted::WallTime { .. } => "wall_time",
-            BudgetExhausted::ToolCallQuota { .. } => "tool_call_quota",
-            BudgetExhausted::MaxDepth { .. } => "max_depth",
-        };
-        record_budget_exhausted(limit_type);
-        self.emit(AgentEvent::BudgetExceeded {
-            trace_id: trace_id.clone(),
-            limit_type: limit_type.to_string(),
-            details: exhausted.to_string(),
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_redaction_policy(mut self, policy: RedactionPolicy) -> Self {
+        self.redaction_policy = policy;
+        self
     }
 
-    async fn emit_breaker_event(
-        &self,
-        trace_id: &TraceId,
-        breaker: &CircuitBreaker,
-        state: CircuitStateSnapshot,
-        reason: Option<String>,
-    ) {
-        record_circuit_breaker_state(breaker.name(), &state.to_string());
-        self.emit(AgentEvent::CircuitBreakerStateChanged {
-            trace_id: trace_id.clone(),
-            breaker_name: breaker.name().to_string(),
-            state,
-            reason,
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_redaction_threshold(mut self, threshold: DataClassification) -> Self {
+        self.redaction_threshold = threshold;
+        self
     }
 
-    async fn emit_escalation_event(
-        &self,
-        trace_id: &TraceId,
-        trigger: EscalationTrigger,
-        action: EscalationAction,
-    ) {
-        self.emit(AgentEvent::EscalationTriggered {
-            trace_id: trace_id.clone(),
-            trigger,
-            action,
-            timestamp: Utc::now(),
-        })
-        .await;
+    pub fn with_escalation_config(mut self, config: EscalationConfig) -> Self {
+        self.escalation_config = config;
+        self
     }
 
-    async fn create_session_if_configured(&self, trace_id: &TraceId, agent_name: &str) {
-        let Some(store) = &self.session_store else {
-            return;
+    pub fn with_provider_circuit_breaker(
+        mut self,
+        failure_threshold: u32,
+        reset_secs: u64,
+    ) -> Self {
+        self.provider_breaker_settings = CircuitBreakerSettings {
+            failure_threshold,
+            reset_secs,
         };
-        if let Err(err) = store
-            .create_session(trace_id.as_str(), agent_name, trace_id.as_str())
-            .await
-        {
-            tracing::warn!(
-                session_id = trace_id.as_str(),
-                "session creation failed (non-fatal): {}",
-                err
-            );
-        }
+        self
     }
 
-    async fn store_messages_if_configured(&self, trace_id: &TraceId, messages: &[Message]) {
-        let Some(store) = &self.session_store else {
-            return;
+    pub fn with_tool_circuit_breaker(mut self, failure_threshold: u32, reset_secs: u64) -> Self {
+        self.tool_breaker_settings = CircuitBreakerSettings {
+            failure_threshold,
+            reset_secs,
         };
-        if let Err(err) = store.store_messages(trace_id.as_str(), messages).await {
-            tracing::warn!(
-                session_id = trace_id.as_str(),
-                "message persistence failed (non-fatal): {}",
-                err
-            );
-        }
+        self
     }
 
-    async fn complete_session_if_configured(&self, trace_id: &TraceId, outcome: &str) {
-        let Some(store) = &self.session_store else {
-            return;
-        };
-        if let Err(err) = store.complete_session(trace_id.as_str(), outcome).await {
-            tracing::warn!(
-                session_id = trace_id.as_str(),
-                "session completion failed (non-fatal): {}",
-                err
-            );
+    pub fn build(self) -> SwarmResult<Swarm> {
+        if let Some(err) = self.build_error {
+            return Err(err);
         }
-    }
 
-    async fn persist_memory_hook(&self, trace_id: &TraceId, key: &str, value: &str, source: &str) {
-        let Some(store) = &self.memory_store else {
-            return;
-        };
-        let (classification, sanitized) = self.sanitize_text(value);
-        if let Err(err) = store
-            .persist_memory(trace_id.as_str(), key, &sanitized)
-            .await
-        {
-            tracing::warn!(
-                session_id = trace_id.as_str(),
-                memory_key = key,
-                "memory persistence failed (non-fatal): {}",
-                err
-            );
-            return;
+        self.config.validate()?;
+        if self.client.is_some() && self.has_tls_settings() {
+            return Err(SwarmError::ConfigError(
+                "TLS settings cannot be combined with a custom HTTP client; \
+                 configure TLS on that client instead"
+                    .to_string(),
+            ));
         }
-        self.emit(AgentEvent::MemoryPersisted {
-            trace_id: trace_id.clone(),
-            key: key.to_string(),
-            source: source.to_string(),
-            classification,
-            timestamp: Utc::now(),
-        })
-        .await;
-    }
 
-    async fn enforce_content_policy(
-        &self,
-        trace_id: &TraceId,
-        text: &str,
-        context: &str,
-    ) -> SwarmResult<()> {
-        match self.content_policy.check_text(text, context).await {
-            PolicyResult::Allow => Ok(()),
-            PolicyResult::Warn(message) => {
-                self.emit_guardrail_event(
-                    trace_id,
-                    "content_policy",
-                    "warn",
-                    &message,
-                    Some(DataClassification::Restricted),
-                )
-                .await;
-                Ok(())
+        for agent in self.agents.values() {
+            agent.validate(&self.config)?;
+        }
+        if self
+            .config
+            .max_registered_agents()
+            .is_some_and(|limit| self.agents.len() > limit)
+        {
+            return Err(SwarmError::ConfigError(
+                "agent registry is full".to_string(),
+            ));
+        }
+        if let Some(name) = self.config.tool_summarizer_agent() {
+            if !self.agents.contains_key(name) {
+                return Err(SwarmError::ValidationError(format!(
+                    "tool_summarizer_agent '{}' is not a registered agent",
+                    name
+                )));
             }
-            PolicyResult::Block(message) => {
-                self.emit_guardrail_event(
-                    trace_id,
-                    "content_policy",
-                    "block",
-                    &message,
-                    Some(DataClassification::Restricted),
-                )
-                .await;
-                Err(SwarmError::Other(message))
+        }
+        if let Some(name) = self.config.context_summarizer_agent() {
+            if !self.agents.contains_key(name) {
+                return Err(SwarmError::ValidationError(format!(
+                    "context_summarizer_agent '{}' is not a registered agent",
+                    name
+                )));
+            }
+        }
+        for name in self.config.context_rename_on_switch().keys() {
+            if !self.agents.contains_key(name) {
+                return Err(SwarmError::ValidationError(format!(
+                    "context_rename_on_switch names '{}', which is not a registered agent",
+                    name
+                )));
             }
         }
-    }
 
-    async fn apply_injection_policy(
-        &self,
-        trace_id: &TraceId,
-        messages: &mut [Message],
-    ) -> SwarmResult<()> {
-        for message in messages.iter_mut() {
-            let Some(content) = message.content().map(str::to_string) else {
-                continue;
-            };
-            match check_injection_with_policy(&content, &self.injection_policy) {
-                InjectionOutcome::Clean => {}
-                InjectionOutcome::Warned { patterns } => {
-                    self.emit_guardrail_event(
-                        trace_id,
-                        "prompt_injection",
-                        "warn",
-                        &format!("patterns={:?}", patterns),
-                        Some(DataClassification::Restricted),
-                    )
-                    .await;
+        self.provider_breaker_settings
+            .validate("provider circuit breaker")?;
+        self.tool_breaker_settings
+            .validate("tool circuit breaker")?;
+
+        let has_tls_settings = self.has_tls_settings();
+        let api_keys = if !self.api_keys.is_empty() {
+            self.api_keys
+        } else {
+            match self.api_key {
+                Some(key) => vec![key],
+                None => match env::var("OPENAI_API_KEY") {
+                    Ok(key) => vec![ApiKey::new(key)?],
+                    Err(_) => {
+                        return Err(SwarmError::ValidationError(
+                            "API key must be set either in environment or passed to builder"
+                                .to_string(),
+                        ))
+                    }
+                },
+            }
+        };
+        let key_pool = ApiKeyPool::new(api_keys, self.api_key_cooldown)?;
+
+        // `OPENAI_API_URL` only redirects the OpenAI wire format; other providers keep
+        // their configured endpoint.
+        let openai_format = self
+            .api_provider
+            .as_ref()
+            .is_none_or(|provider| provider.default_api_url() == OPENAI_DEFAULT_API_URL);
+        let request_url = match env::var("OPENAI_API_URL") {
+            Ok(url) if openai_format => ApiUrl::new(url, self.config.valid_api_url_prefixes())?
+                .as_str()
+                .to_string(),
+            _ => self.config.api_url().to_string(),
+        };
+
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                let mut client_builder = Client::builder()
+                    .timeout(Duration::from_secs(self.config.request_timeout()))
+                    .connect_timeout(Duration::from_secs(self.config.connect_timeout()))
+                    .danger_accept_invalid_certs(self.danger_accept_invalid_certs);
+                for cert in self.ca_certs {
+                    client_builder = client_builder.add_root_certificate(cert);
                 }
-                InjectionOutcome::Sanitized {
-                    patterns,
-                    sanitized,
-                } => {
-                    self.emit_guardrail_event(
-                        trace_id,
-                        "prompt_injection",
-                        "sanitize",
-                        &format!("patterns={:?}", patterns),
-                        Some(DataClassification::Restricted),
-                    )
-                    .await;
-                    *message = Message::from_parts_unchecked(
-                        message.role(),
-                        Some(sanitized),
-                        message.name().map(str::to_string),
-                        message.function_call().cloned(),
-                    );
+                if let Some(version) = self.tls_min_version {
+                    client_builder = client_builder.min_tls_version(version);
                 }
-                InjectionOutcome::Rejected { patterns } => {
-                    self.emit_guardrail_event(
-                        trace_id,
-                        "prompt_injection",
-                        "reject",
-                        &format!("patterns={:?}", patterns),
-                        Some(DataClassification::Restricted),
-                    )
-                    .await;
-                    return Err(SwarmError::ValidationError(format!(
-                        "Prompt injection rejected by policy: {:?}",
-                        patterns
-                    )));
+                match client_builder.build() {
+                    Ok(client) => client,
+                    // Falling back would silently drop the requested trust settings.
+                    Err(e) if has_tls_settings => {
+                        return Err(SwarmError::ConfigError(format!(
+                            "Failed to build HTTP client with TLS settings: {}",
+                            e
+                        )))
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to build configured HTTP client ({}), falling back to default — \
+                             request/connect timeouts will not be applied",
+                            e
+                        );
+                        Client::new()
+                    }
                 }
             }
+        };
+
+        let providers = (0..key_pool.len())
+            .map(|index| {
+                Arc::new(
+                    OpenAiProvider::new(
+                        client.clone(),
+                        key_pool.key(index).as_str(),
+                        request_url.as_str(),
+                    )
+                    .with_message_serialization(self.config.message_serialization().clone()),
+                ) as Arc<dyn LlmProvider>
+            })
+            .collect();
+        let distributed_transport = self
+            .distributed_transport
+            .unwrap_or_else(|| Arc::new(HttpDistributedTransport::new(client.clone())));
+        let agent_directory = AgentRegistry::new();
+        for agent in self.agents.values() {
+            agent_directory.register(Arc::new(agent.clone()));
         }
-        Ok(())
+        let now = Instant::now();
+        let agent_last_access = self.agents.keys().map(|name| (name.clone(), now)).collect();
+        let channel_registry = ChannelRegistry::new();
+
+        Ok(Swarm {
+            client,
+            key_pool,
+            agent_registry: self.agents,
+            agent_directory,
+            channel_registry,
+            config: self.config,
+            request_url,
+            providers,
+            api_provider: self.api_provider,
+            response_cache: self.response_cache,
+            response_cache_ttl: self.response_cache_ttl,
+            steps_parser: self.steps_parser,
+            distributed_transport,
+            subscribers: self.subscribers,
+            session_store: self.session_store,
+            event_store: self.event_store,
+            checkpoint_store: self.checkpoint_store,
+            memory_store: self.memory_store,
+            content_policy: self.content_policy,
+            injection_policy: self.injection_policy,
+            redaction_policy: self.redaction_policy,
+            redaction_threshold: self.redaction_threshold,
+            escalation_config: self.escalation_config,
+            provider_breaker: CircuitBreaker::new(
+                "provider",
+                self.provider_breaker_settings.failure_threshold,
+                self.provider_breaker_settings.reset_secs,
+            ),
+            tool_breaker_settings: self.tool_breaker_settings,
+            tool_breakers: Arc::new(Mutex::new(HashMap::new())),
+            team_assignment_load: Arc::new(Mutex::new(HashMap::new())),
+            dead_letter_tx: self.dead_letter_tx,
+            agent_last_access: Arc::new(Mutex::new(agent_last_access)),
+        })
     }
 
-    async fn check_budget(&self, trace_id: &TraceId, budget: &BudgetEnforcer) -> SwarmResult<()> {
-        if let Err(exhausted) = budget.check() {
-            self.emit_budget_event(trace_id, &exhausted).await;
-            return Err(exhausted.into());
+    fn record_error(&mut self, err: SwarmError) {
+        if self.build_error.is_none() {
+            self.build_error = Some(err);
         }
-        Ok(())
     }
+}
 
-    /// Makes an asynchronous chat completion request.
-    pub async fn get_chat_completion(
-        &self,
-        agent: &Agent,
-        history: &[Message],
-        context_variables: &ContextVariables,
-        model_override: Option<String>,
-        stream: bool,
-        debug: bool,
-    ) -> SwarmResult<ChatCompletionResponse> {
-        // Defense-in-depth: preflight (validate_api_request) is the authoritative check.
-        if history.is_empty() {
-            return Err(SwarmError::ValidationError(
-                "Message history cannot be empty".to_string(),
-            ));
-        }
+impl Default for SwarmBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        let instructions = match &agent.instructions {
-            Instructions::Text(text) => text.clone(),
-            Instructions::Function(func) => func(context_variables.clone()),
-        };
+impl Swarm {
+    pub fn builder() -> SwarmBuilder {
+        SwarmBuilder::new()
+    }
 
-        let mut messages = vec![Message::system(instructions)?];
+    fn next_trace_id() -> TraceId {
+        TraceId::from(uuid::Uuid::new_v4().to_string())
+    }
 
-        messages.extend_from_slice(history);
+    // For backward compatibility.
+    pub fn new(
+        client: Option<Client>,
+        api_key: Option<String>,
+        agents: HashMap<String, Agent>,
+    ) -> SwarmResult<Self> {
+        let mut builder = SwarmBuilder::new();
 
-        debug_print(
-            debug,
-            &format!("Getting chat completion with messages: {:?}", messages),
-        );
+        if let Some(client) = client {
+            builder = builder.with_client(client);
+        }
+        if let Some(api_key) = api_key {
+            builder = builder.with_api_key(api_key);
+        }
+        builder = builder.with_agents(&agents);
 
-        let model = model_override.unwrap_or_else(|| agent.model.clone());
+        builder.build()
+    }
 
-        if stream {
-            // Streaming path: keep legacy HTTP implementation with functions support.
-            let functions: Vec<Value> = agent
-                .functions
-                .iter()
-                .map(function_to_json)
-                .collect::<SwarmResult<Vec<Value>>>()?;
-
-            let mut request_body = json!({
-                "model": model,
-                "messages": messages,
-            });
-
-            if !functions.is_empty() {
-                request_body["functions"] = Value::Array(functions);
-            }
-
-            if let Some(function_call) = agent.function_call().to_wire_value() {
-                request_body["function_call"] = json!(function_call);
-            }
-
-            request_body["stream"] = json!(true);
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
 
-            if agent.tool_call_execution().is_parallel() {
-                request_body["parallel_tool_calls"] = json!(true);
-            }
+    /// The primary API key (the first key when several are configured).
+    pub fn api_key(&self) -> &ApiKey {
+        self.key_pool.primary()
+    }
 
-            let url = env::var("OPENAI_API_URL")
-                .map(|url| {
-                    ApiUrl::new(url, self.config.valid_api_url_prefixes())
-                        .map(|url| url.as_str().to_string())
-                })
-                .unwrap_or_else(|_| Ok(self.config.api_url().to_string()))?;
+    pub fn api_key_pool(&self) -> &ApiKeyPool {
+        &self.key_pool
+    }
 
-            let response = self
-                .client
-                .post(url)
-                .bearer_auth(self.api_key.as_str())
-                .json(&request_body)
-                .send()
-                .await
-                .map_err(|e| SwarmError::NetworkError(e.to_string()))?;
+    pub fn agents(&self) -> &HashMap<String, Agent> {
+        &self.agent_registry
+    }
 
-            if !response.status().is_success() {
-                let error_text = response.text().await.map_err(|e| {
-                    SwarmError::NetworkError(format!("Failed to read error response: {}", e))
-                })?;
-                debug_print(debug, &format!("API Error Response: {}", error_text));
-                let api_error: serde_json::Result<OpenAIErrorResponse> =
-                    serde_json::from_str(&error_text);
-                return match api_error {
-                    Ok(err_resp) => Err(SwarmError::ApiError(err_resp.error.message)),
-                    Err(_) => Err(SwarmError::ApiError(error_text)),
-                };
-            }
+    pub fn agent_directory(&self) -> &AgentRegistry {
+        &self.agent_directory
+    }
 
-            let mut stream = response.bytes_stream();
+    pub fn channel_registry(&self) -> &Arc<ChannelRegistry> {
+        &self.channel_registry
+    }
 
-            // Line buffer: TCP chunks can split SSE `data:` lines across boundaries.
-            let mut line_buf = String::new();
-            // Delta accumulators for the single choice we're building.
-            let mut content_buf = String::new();
-            let mut fc_name = String::new();
-            let mut fc_args = String::new();
-            let mut finish_reason: Option<FinishReason> = None;
-            // Accumulator for multi-tool-call streaming deltas (OpenAI tool_calls API).
-            let mut tc_acc_msg =
-                Message::from_parts_unchecked(MessageRole::Assistant, None, None, None);
+    pub fn config(&self) -> &SwarmConfig {
+        &self.config
+    }
 
-            'sse: while let Some(chunk_result) = stream.next().await {
-                let data = chunk_result.map_err(|e| {
-                    SwarmError::StreamError(format!("Error reading streaming response: {}", e))
-                })?;
-                line_buf.push_str(&String::from_utf8_lossy(&data));
+    /// The provider bound to the primary API key.
+    pub fn provider(&self) -> &Arc<dyn LlmProvider> {
+        &self.providers[0]
+    }
 
-                // Process every complete line (terminated by \n).
-                while let Some(newline_pos) = line_buf.find('\n') {
-                    let line = line_buf[..newline_pos].trim_end_matches('\r').to_string();
-                    line_buf.drain(..=newline_pos);
+    /// The wire format selected with [`SwarmBuilder::with_provider`], if any.
+    pub fn api_provider(&self) -> Option<&Arc<dyn ApiProvider>> {
+        self.api_provider.as_ref()
+    }
 
-                    if let Some(json_str) = line.strip_prefix("data: ") {
-                        let json_str = json_str.trim();
-                        if json_str == "[DONE]" {
-                            break 'sse;
-                        }
-                        let chunk: Value = serde_json::from_str(json_str).map_err(|e| {
-                            SwarmError::DeserializationError(format!(
-                                "Failed to parse SSE chunk: {}",
-                                e
-                            ))
-                        })?;
-                        if let Some(choices) = chunk["choices"].as_array() {
-                            for choice in choices {
-                                let delta = &choice["delta"];
-                                if let Some(text) = delta["content"].as_str() {
-                                    content_buf.push_str(text);
-                                }
-                                if let Some(fc) = delta.get("function_call") {
-                                    if let Some(name) = fc["name"].as_str() {
-                                        fc_name.push_str(name);
-                                    }
-                                    if let Some(args) = fc["arguments"].as_str() {
-                                        fc_args.push_str(args);
-                                    }
-                                }
-                                if let Some(tc_arr) =
-                                    delta.get("tool_calls").and_then(|v| v.as_array())
-                                {
-                                    for tc_delta in tc_arr {
-                                        let index =
-                                            tc_delta["index"].as_u64().unwrap_or(0) as usize;
-                                        tc_acc_msg.merge_tool_call_delta(index, tc_delta);
-                                    }
-                                }
-                                if let Some(fr) = choice["finish_reason"].as_str() {
-                                    finish_reason = Some(match fr {
-                                        "stop" => FinishReason::Stop,
-                                        "length" => FinishReason::Length,
-                                        "content_filter" => FinishReason::ContentFilter,
-                                        "tool_calls" => FinishReason::ToolCalls,
-                                        "function_call" => FinishReason::FunctionCall,
-                                        other => FinishReason::Unknown(other.to_string()),
-                                    });
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+    pub fn response_cache(&self) -> Option<&Arc<dyn ResponseCache>> {
+        self.response_cache.as_ref()
+    }
 
-            // Assemble the fully merged message from accumulated deltas.
-            tc_acc_msg.finalize_tool_calls();
-            let merged_message = if let Some(tool_calls) = tc_acc_msg.tool_calls() {
-                // Multi-tool-call streaming response.
-                Message::assistant_tool_calls(tool_calls.to_vec()).map_err(|e| {
-                    SwarmError::DeserializationError(format!(
-                        "Failed to build tool_calls message: {}",
-                        e
-                    ))
-                })?
-            } else {
-                let merged_fc = if !fc_name.is_empty() || !fc_args.is_empty() {
-                    Some(FunctionCall::from_parts_unchecked(fc_name, fc_args))
-                } else {
-                    None
-                };
-                let merged_content = if !content_buf.is_empty() {
-                    Some(content_buf)
-                } else {
-                    None
-                };
-                Message::from_parts_unchecked(
-                    MessageRole::Assistant,
-                    merged_content,
-                    None,
-                    merged_fc,
-                )
-            };
-            let mut full_response = ChatCompletionResponse::accumulator();
-            full_response.extend_choices(vec![Choice {
-                index: 0,
-                message: merged_message,
-                finish_reason,
-            }]);
-            Ok(full_response)
-        } else {
-            // Non-streaming path: delegate to provider, then map response via JSON round-trip.
-            let functions: Vec<Value> = agent
-                .functions
-                .iter()
-                .map(function_to_json)
-                .collect::<SwarmResult<Vec<Value>>>()?;
-            let function_call_policy = agent.function_call().to_wire_value().map(|v| json!(v));
+    pub fn find_agents_by_capability(&self, capability: &str) -> Vec<AgentRef> {
+        self.agent_directory.find_by_capability(capability)
+    }
 
-            let mut request = CompletionRequest::new(model, messages);
-            if !functions.is_empty() {
-                request = request.with_functions(functions, function_call_policy);
-            }
-            if agent.tool_call_execution().is_parallel() {
-                request = request.with_parallel_tool_calls(true);
-            }
+    pub async fn open_agent_channel(
+        &self,
+        agent: impl Into<AgentRef>,
+    ) -> SwarmResult<InProcessChannel> {
+        let agent = agent.into();
+        if self.agent_directory.get(&agent).is_none() {
+            return Err(SwarmError::AgentNotFoundError(agent.to_string()));
+        }
+        InProcessChannel::new(agent, self.channel_registry.clone()).await
+    }
 
-            let provider_response = self.provider.complete(request).await?;
-            debug_print(
-                debug,
-                &format!("Provider Response: {:?}", provider_response),
-            );
+    async fn emit_message_sent(
+        &self,
+        trace_id: &TraceId,
+        from: AgentAddress,
+        to: AgentAddress,
+        message_id: crate::agent_comm::MessageId,
+    ) {
+        self.emit(AgentEvent::MessageSent {
+            trace_id: trace_id.clone(),
+            from,
+            to,
+            message_id,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-            let mut json_val = serde_json::to_value(&provider_response).map_err(|e| {
-                SwarmError::DeserializationError(format!(
-                    "Failed to serialize provider response: {}",
-                    e
-                ))
-            })?;
+    async fn emit_message_received(
+        &self,
+        trace_id: &TraceId,
+        by: AgentAddress,
+        message_id: crate::agent_comm::MessageId,
+    ) {
+        self.emit(AgentEvent::MessageReceived {
+            trace_id: trace_id.clone(),
+            by,
+            message_id,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-            // Map tool_calls → function_call when there is exactly one tool call (backward-compat).
-            // For multiple tool calls, leave the array intact so MessageDto deserializes it into
-            // Message::tool_calls, which is dispatched by the parallel/serial execution branch.
-            if let Some(choices) = json_val["choices"].as_array_mut() {
-                for choice in choices.iter_mut() {
-                    let tc_count = choice["message"]["tool_calls"]
-                        .as_array()
-                        .map(|a| a.len())
-                        .unwrap_or(0);
-                    if tc_count == 1 {
-                        // Single-call: promote to function_call and remove the array (legacy path).
-                        let first = choice["message"]["tool_calls"][0].clone();
-                        let name = first["function"]["name"].clone();
-                        let args = first["function"]["arguments"].clone();
-                        let args_str = if args.is_string() {
-                            args.clone()
-                        } else {
-                            Value::String(
-                                serde_json::to_string(&args)
-                                    .map_err(|e| SwarmError::DeserializationError(e.to_string()))?,
-                            )
-                        };
-                        choice["message"]["function_call"] =
-                            json!({"name": name, "arguments": args_str});
-                        choice["message"]
-                            .as_object_mut()
-                            .map(|m| m.remove("tool_calls"));
-                    }
-                    // tc_count > 1: leave tool_calls in place for the multi-call dispatch path.
-                    // tc_count == 0: no-op.
-                }
-            }
+    async fn emit_reply_timeout(
+        &self,
+        trace_id: &TraceId,
+        from: AgentAddress,
+        to: AgentAddress,
+        correlation_id: crate::agent_comm::MessageId,
+        timeout: Duration,
+    ) {
+        self.emit(AgentEvent::ReplyTimeout {
+            trace_id: trace_id.clone(),
+            from,
+            to,
+            correlation_id,
+            timeout_ms: timeout.as_millis() as u64,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-            serde_json::from_value(json_val)
-                .map_err(|e| SwarmError::DeserializationError(e.to_string()))
+    fn validate_local_agent(&self, agent: &AgentRef) -> SwarmResult<()> {
+        if self.agent_directory.get(agent).is_some() {
+            Ok(())
+        } else {
+            Err(SwarmError::AgentNotFoundError(agent.to_string()))
         }
     }
 
-    /// Asynchronously handles a function call from an agent.
-    pub async fn handle_function_call(
-        &self,
-        function_call: &FunctionCall,
-        functions: &[AgentFunction],
-        context_variables: ContextVariables,
-        debug: bool,
-    ) -> SwarmResult<Response> {
-        if function_call.name().trim().is_empty() {
-            return Err(SwarmError::ValidationError(
-                "Function call name cannot be empty.".to_string(),
+    fn local_message_from_distributed(message: &DistributedMessage) -> SwarmResult<AgentMessage> {
+        let (AgentAddress::Local { agent: from }, AgentAddress::Local { agent: to }) =
+            (&message.from, &message.to)
+        else {
+            return Err(SwarmError::ValidationError(
+                "Local message routing requires local source and destination".to_string(),
             ));
-        }
+        };
 
-        let mut function_map = HashMap::new();
-        for func in functions {
-            function_map.insert(func.name().to_string(), func.clone());
+        Ok(AgentMessage {
+            id: message.id.clone(),
+            from: from.clone(),
+            to: to.clone(),
+            payload: message.payload.clone(),
+            timestamp: message.timestamp,
+            correlation_id: message.correlation_id.clone(),
+            is_reply: message.is_reply,
+        })
+    }
+
+    pub async fn send_agent_message(
+        &self,
+        trace_id: Option<TraceId>,
+        from: AgentAddress,
+        to: AgentAddress,
+        payload: Value,
+    ) -> SwarmResult<crate::agent_comm::MessageId> {
+        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
+        let message = DistributedMessage::new(from.clone(), to.clone(), payload)
+            .with_trace_id(trace_id.clone());
+
+        match (&from, &to) {
+            (
+                AgentAddress::Local { agent: local_from },
+                AgentAddress::Local { agent: local_to },
+            ) => {
+                self.validate_local_agent(local_from)?;
+                self.validate_local_agent(local_to)?;
+                self.channel_registry
+                    .send(Self::local_message_from_distributed(&message)?)
+                    .await?;
+            }
+            _ => {
+                self.distributed_transport.send(message.clone()).await?;
+            }
         }
 
-        let mut response = Response {
-            messages: Vec::new(),
-            agent: None,
-            context_variables: HashMap::new(),
-            termination_reason: None,
-            tokens_used: 0,
-        };
+        self.emit_message_sent(&trace_id, from, to, message.id.clone())
+            .await;
+        Ok(message.id)
+    }
 
-        if let Some(func) = function_map.get(function_call.name()) {
-            let invocation_args = InvocationArgs::from_json_str(function_call.arguments())
-                .map_err(|error| SwarmError::ValidationError(error.to_string()))?;
-            invocation_args
-                .validate_against_schema(func.parameters_schema())
-                .map_err(|error| SwarmError::ValidationError(error.to_string()))?;
-            let args: ContextVariables = invocation_args
-                .to_context_variables()
-                .map_err(|error| SwarmError::ValidationError(error.to_string()))?;
-            debug_print(
-                debug,
-                &format!(
-                    "Processing function call: {} with arguments {:?}",
-                    function_call.name(),
-                    args
-                ),
-            );
+    pub async fn request_agent_message(
+        &self,
+        trace_id: Option<TraceId>,
+        from: AgentAddress,
+        to: AgentAddress,
+        payload: Value,
+        timeout: Duration,
+    ) -> SwarmResult<DistributedMessage> {
+        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
+        let message = DistributedMessage::new(from.clone(), to.clone(), payload)
+            .with_trace_id(trace_id.clone());
 
-            let mut args = args.clone();
-            if func.accepts_context_variables() {
-                let serialized_context = serde_json::to_string(&context_variables)?;
-                args.insert(CTX_VARS_NAME.to_string(), serialized_context);
+        let result = match (&from, &to) {
+            (
+                AgentAddress::Local { agent: local_from },
+                AgentAddress::Local { agent: local_to },
+            ) => {
+                self.validate_local_agent(local_from)?;
+                self.validate_local_agent(local_to)?;
+                self.channel_registry
+                    .request(Self::local_message_from_distributed(&message)?, timeout)
+                    .await
+                    .map(|reply| DistributedMessage {
+                        id: reply.id,
+                        from: AgentAddress::local(reply.from),
+                        to: AgentAddress::local(reply.to),
+                        payload: reply.payload,
+                        timestamp: reply.timestamp,
+                        correlation_id: reply.correlation_id,
+                        trace_id: Some(trace_id.clone()),
+                        is_reply: reply.is_reply,
+                    })
             }
+            _ => {
+                self.distributed_transport
+                    .request(message.clone(), timeout)
+                    .await
+            }
+        };
 
-            // Await the asynchronous call.
-            let raw_result = (func.function)(args).await?;
-            let result = self.handle_function_result(raw_result, debug)?;
-            match result {
-                ResultType::Value(value) => response
-                    .messages
-                    .push(Message::function(function_call.name(), value)?),
-                ResultType::Agent(agent) => {
-                    response.agent = Some(agent);
-                }
-                ResultType::ContextVariables(context) => {
-                    response.context_variables.extend(context);
-                }
-                ResultType::Termination(reason) => {
-                    response.termination_reason = Some(reason);
+        match result {
+            Ok(reply) => {
+                self.emit_message_sent(&trace_id, from, to, message.id.clone())
+                    .await;
+                self.emit_message_received(&trace_id, reply.to.clone(), reply.id.clone())
+                    .await;
+                Ok(reply)
+            }
+            Err(err) => {
+                if matches!(err, SwarmError::TimeoutError(_)) {
+                    self.emit_message_sent(&trace_id, from.clone(), to.clone(), message.id.clone())
+                        .await;
+                    self.emit_reply_timeout(&trace_id, from, to, message.id.clone(), timeout)
+                        .await;
                 }
+                Err(err)
             }
-        } else {
-            debug_print(
-                debug,
-                &format!("Function {} not found.", function_call.name()),
-            );
-            response.messages.push(Message::assistant_named(
-                function_call.name(),
-                format!("Error: Function {} not found.", function_call.name()),
-            )?);
         }
-        Ok(response)
     }
 
-    /// Executes multiple tool calls serially, threading context from each call to the next.
-    async fn handle_tool_calls_serial(
+    pub async fn multicast_agent_message(
         &self,
-        tool_calls: &[ToolCall],
-        functions: &[AgentFunction],
-        context_variables: &ContextVariables,
-        debug: bool,
-    ) -> Vec<ToolCallOutcome> {
-        let mut output = Vec::with_capacity(tool_calls.len());
-        let mut running_ctx = context_variables.clone();
-        for tc in tool_calls {
-            let response = self
-                .handle_function_call(tc.function(), functions, running_ctx.clone(), debug)
-                .await;
-            match response {
-                Ok(response) => {
-                    running_ctx.extend(response.context_variables.clone());
-                    output.push(ToolCallOutcome {
-                        tool_call: tc.clone(),
-                        response: Ok(response),
-                    });
-                }
-                Err(err) => {
-                    output.push(ToolCallOutcome {
-                        tool_call: tc.clone(),
-                        response: Err(err),
-                    });
-                    break;
-                }
-            }
+        trace_id: Option<TraceId>,
+        from: AgentAddress,
+        recipients: Vec<AgentAddress>,
+        payload: Value,
+    ) -> SwarmResult<Vec<crate::agent_comm::MessageId>> {
+        let trace_id = trace_id.unwrap_or_else(Self::next_trace_id);
+        let mut ids = Vec::new();
+        for recipient in recipients {
+            let message_id = self
+                .send_agent_message(
+                    Some(trace_id.clone()),
+                    from.clone(),
+                    recipient,
+                    payload.clone(),
+                )
+                .await?;
+            ids.push(message_id);
         }
-        output
+        Ok(ids)
     }
 
-    /// Executes multiple tool calls in parallel. Each call receives a clone of the current
-    /// context; results are merged in input order (last-writer-wins for conflicting keys).
-    async fn handle_tool_calls_parallel(
+    pub async fn broadcast_agent_message(
         &self,
-        tool_calls: &[ToolCall],
-        functions: &[AgentFunction],
-        context_variables: &ContextVariables,
-        debug: bool,
-    ) -> Vec<ToolCallOutcome> {
-        let futs: Vec<_> = tool_calls
-            .iter()
-            .map(|tc| {
-                let ctx = context_variables.clone();
-                let fc = tc.function().clone();
-                let fns = functions.to_vec();
-                async move { self.handle_function_call(&fc, &fns, ctx, debug).await }
-            })
-            .collect();
-        let results = futures::future::join_all(futs).await;
-        tool_calls
-            .iter()
-            .zip(results)
-            .map(|(tc, response)| ToolCallOutcome {
-                tool_call: tc.clone(),
-                response,
-            })
-            .collect()
+        trace_id: Option<TraceId>,
+        from: AgentAddress,
+        payload: Value,
+        include_sender: bool,
+    ) -> SwarmResult<Vec<crate::agent_comm::MessageId>> {
+        let sender_ref = from.agent_ref().clone();
+        let recipients = self
+            .agent_directory
+            .all_refs()
+            .into_iter()
+            .filter(|agent| include_sender || *agent != sender_ref)
+            .map(AgentAddress::local)
+            .collect::<Vec<_>>();
+        self.multicast_agent_message(trace_id, from, recipients, payload)
+            .await
     }
 
-    /// Handles the result of a function call.
-    pub fn handle_function_result(
-        &self,
-        result: ResultType,
-        debug: bool,
-    ) -> SwarmResult<ResultType> {
-        debug_print(debug, &format!("Handling function result: {:?}", result));
-        Ok(result)
+    fn agent_matches_role(agent: &Agent, role: &TeamRole) -> bool {
+        role.required_capabilities()
+            .iter()
+            .all(|capability| agent.has_capability(capability))
     }
 
-    async fn persist_iteration_state(&self, trace_id: &TraceId, state: &RunState) {
-        self.store_messages_if_configured(trace_id, &state.history)
-            .await;
-        self.save_checkpoint_if_configured(
-            trace_id.as_str(),
-            state.agent.name(),
-            &state.history,
-            &state.context_variables,
-            state.iterations,
-            state.total_tokens,
-        )
-        .await;
+    fn optional_capability_score(agent: &Agent, role: &TeamRole) -> usize {
+        role.optional_capabilities()
+            .iter()
+            .filter(|capability| agent.has_capability(capability))
+            .count()
     }
 
-    async fn apply_escalation_trigger(
+    async fn form_team_internal(
         &self,
-        state: &mut RunState,
-        exec: &mut ExecutionContext<'_>,
-        trigger: EscalationTrigger,
-    ) -> SwarmResult<Option<crate::phase::TerminationReason>> {
-        let action = exec.escalation.config().action.clone();
-        self.emit_escalation_event(exec.trace_id, trigger.clone(), action.clone())
-            .await;
-        match action {
-            EscalationAction::Stop => Ok(Some(crate::phase::TerminationReason::DoomLoopDetected)),
-            EscalationAction::InjectWarning => {
-                let warning = EscalationDetector::warning_message(&trigger);
-                state.history.push(Message::assistant(warning.clone())?);
-                self.persist_memory_hook(
-                    exec.trace_id,
-                    &format!("warning:{}", state.iterations),
-                    &warning,
-                    "escalation_warning",
-                )
-                .await;
-                Ok(None)
+        existing: Option<&AgentTeam>,
+        roles: &[TeamRole],
+        policy: TeamFormationPolicy,
+    ) -> SwarmResult<AgentTeam> {
+        if roles.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "At least one team role is required".to_string(),
+            ));
+        }
+
+        let load_snapshot = self
+            .team_assignment_load
+            .lock()
+            .map_err(|_| SwarmError::Other("team_assignment_load lock poisoned".into()))?
+            .clone();
+        let mut projected_load = load_snapshot;
+        let mut used = HashSet::new();
+        let mut assignments = Vec::with_capacity(roles.len());
+        let mut all_refs = self.agent_directory.all_refs();
+        all_refs.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+
+        for role in roles {
+            if policy.prefer_existing_assignments {
+                if let Some(existing_team) = existing {
+                    if let Some(existing_agent) = existing_team.agent_for_role(role.name()) {
+                        if let Some(agent) = self.agent_directory.get(existing_agent) {
+                            if Self::agent_matches_role(&agent, role)
+                                && (policy.allow_agent_reuse || used.insert(existing_agent.clone()))
+                            {
+                                *projected_load.entry(existing_agent.clone()).or_default() += 1;
+                                assignments.push(TeamAssignment::new(
+                                    role.clone(),
+                                    existing_agent.clone(),
+                                ));
+                                continue;
+                            }
+                        }
+                    }
+                }
             }
-            EscalationAction::HumanReviewEvent => Ok(None),
+
+            let mut candidates = all_refs
+                .iter()
+                .filter(|agent_ref| policy.allow_agent_reuse || !used.contains(*agent_ref))
+                .filter_map(|agent_ref| {
+                    self.agent_directory
+                        .get(agent_ref)
+                        .map(|agent| (agent_ref.clone(), agent))
+                })
+                .filter(|(_, agent)| Self::agent_matches_role(agent, role))
+                .collect::<Vec<_>>();
+
+            candidates.sort_by(|(left_ref, left_agent), (right_ref, right_agent)| {
+                let left_optional = Self::optional_capability_score(left_agent, role);
+                let right_optional = Self::optional_capability_score(right_agent, role);
+                right_optional
+                    .cmp(&left_optional)
+                    .then_with(|| {
+                        projected_load
+                            .get(left_ref)
+                            .copied()
+                            .unwrap_or(0)
+                            .cmp(&projected_load.get(right_ref).copied().unwrap_or(0))
+                    })
+                    .then_with(|| left_ref.as_str().cmp(right_ref.as_str()))
+            });
+
+            let Some((selected_ref, _)) = candidates.into_iter().next() else {
+                return Err(SwarmError::AgentNotFoundError(format!(
+                    "No registered agent satisfies role '{}' with capabilities {:?}",
+                    role.name(),
+                    role.required_capabilities()
+                )));
+            };
+
+            if !policy.allow_agent_reuse {
+                used.insert(selected_ref.clone());
+            }
+            *projected_load.entry(selected_ref.clone()).or_default() += 1;
+            assignments.push(TeamAssignment::new(role.clone(), selected_ref));
+        }
+
+        let team = AgentTeam::new(assignments)?;
+        {
+            let mut load = self
+                .team_assignment_load
+                .lock()
+                .map_err(|_| SwarmError::Other("team_assignment_load lock poisoned".into()))?;
+            *load = projected_load;
         }
+        self.emit(AgentEvent::TeamFormed {
+            trace_id: Self::next_trace_id(),
+            team: team.clone(),
+            timestamp: Utc::now(),
+        })
+        .await;
+        Ok(team)
     }
 
-    /// Executes a single round of conversation with the agent.
-    async fn single_execution(
+    pub async fn form_team(&self, roles: &[TeamRole]) -> SwarmResult<AgentTeam> {
+        self.form_team_internal(None, roles, TeamFormationPolicy::default())
+            .await
+    }
+
+    pub async fn form_team_with_policy(
         &self,
-        state: &mut RunState,
-        exec: &mut ExecutionContext<'_>,
-    ) -> SwarmResult<Response> {
-        self.check_budget(exec.trace_id, exec.budget).await?;
-        exec.budget.increment_iterations();
-        state.iterations = exec.budget.iterations;
-        record_iteration(state.agent.name());
+        roles: &[TeamRole],
+        policy: TeamFormationPolicy,
+    ) -> SwarmResult<AgentTeam> {
+        self.form_team_internal(None, roles, policy).await
+    }
 
-        let model = exec
-            .options
-            .model_override
-            .as_deref()
-            .unwrap_or(state.agent.model())
-            .to_string();
-        // Heuristic: ~4 bytes per token. Actual counts come from the API response.
-        let prompt_tokens: u32 = state
-            .history
-            .iter()
-            .map(|m| m.content().map(|c| (c.len() / 4) as u32).unwrap_or(4))
-            .sum();
-        if let Some(limit) = self.config.runtime_limits().max_tokens_per_request {
-            if prompt_tokens > limit {
-                let exhausted = BudgetExhausted::TokensPerRequest {
-                    used: prompt_tokens,
-                    limit,
-                };
-                self.emit_budget_event(exec.trace_id, &exhausted).await;
-                return Err(exhausted.into());
+    pub async fn reconfigure_team(
+        &self,
+        existing: &AgentTeam,
+        roles: &[TeamRole],
+        policy: TeamFormationPolicy,
+    ) -> SwarmResult<AgentTeam> {
+        self.form_team_internal(Some(existing), roles, policy).await
+    }
+
+    pub async fn reach_consensus(
+        &self,
+        votes: &[TeamVote],
+        strategy: ConsensusStrategy,
+    ) -> SwarmResult<TeamDecision> {
+        if votes.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "At least one team vote is required".to_string(),
+            ));
+        }
+
+        let mut tallies = HashMap::<String, u32>::new();
+        for vote in votes {
+            self.validate_local_agent(vote.agent())?;
+            *tallies.entry(vote.option().to_string()).or_default() += vote.weight();
+        }
+
+        let total_votes = tallies.values().copied().sum::<u32>();
+        let mut tallies = tallies
+            .into_iter()
+            .map(|(option, weight)| VoteTally::new(option, weight))
+            .collect::<Vec<_>>();
+        tallies.sort_by(|left, right| {
+            right
+                .weight()
+                .cmp(&left.weight())
+                .then_with(|| left.option().cmp(right.option()))
+        });
+
+        let selected = tallies.first().ok_or_else(|| {
+            SwarmError::Other("Consensus tally was unexpectedly empty".to_string())
+        })?;
+        let unanimous = tallies.len() == 1;
+
+        match strategy {
+            ConsensusStrategy::Majority if selected.weight() * 2 <= total_votes => {
+                return Err(SwarmError::Other(
+                    "Majority consensus was not reached".to_string(),
+                ));
+            }
+            ConsensusStrategy::Unanimous if !unanimous => {
+                return Err(SwarmError::Other(
+                    "Unanimous consensus was not reached".to_string(),
+                ));
             }
+            _ => {}
         }
 
-        self.emit(AgentEvent::LlmRequest {
-            trace_id: exec.trace_id.clone(),
-            model: model.clone(),
-            prompt_tokens: prompt_tokens as usize,
+        let decision = TeamDecision::new(
+            strategy,
+            selected.option().to_string(),
+            total_votes,
+            tallies,
+            unanimous,
+        );
+        self.emit(AgentEvent::ConsensusReached {
+            trace_id: Self::next_trace_id(),
+            decision: decision.clone(),
             timestamp: Utc::now(),
         })
         .await;
+        Ok(decision)
+    }
 
-        let start = Instant::now();
-        let strategy = self.config.api_settings().retry_strategy().clone();
-        let completion = {
-            let mut delay = strategy.initial_delay();
-            let mut last_err: Option<SwarmError> = None;
-            let mut result = None;
+    /// Emit an event to all registered subscribers.
+    async fn emit(&self, event: AgentEvent) {
+        if let Some(store) = &self.event_store {
+            if let Err(err) = store.append_event(event.trace_id(), &event).await {
+                tracing::warn!(
+                    trace_id = event.trace_id(),
+                    "event persistence failed (non-fatal): {}",
+                    err
+                );
+            }
+        }
+        for sub in &self.subscribers {
+            sub.on_event(&event).await;
+        }
+    }
 
-            for attempt in 0..=strategy.max_retries() {
-                let provider_before = self.provider_breaker.state_snapshot();
-                let provider_open = self.provider_breaker.is_open();
-                let provider_after = self.provider_breaker.state_snapshot();
-                if provider_after != provider_before {
-                    self.emit_breaker_event(
-                        exec.trace_id,
-                        &self.provider_breaker,
-                        provider_after.clone(),
-                        None,
-                    )
-                    .await;
-                }
-                if provider_open {
-                    return Err(SwarmError::Other(format!(
-                        "Provider circuit breaker '{}' is open",
-                        self.provider_breaker.name()
-                    )));
-                }
+    fn sanitize_text(&self, text: &str) -> (DataClassification, String) {
+        classify_and_redact(
+            text,
+            &self.redaction_policy,
+            self.redaction_threshold.clone(),
+        )
+    }
 
-                match self
-                    .get_chat_completion(
-                        &state.agent,
-                        &state.history,
-                        &state.context_variables,
-                        exec.options.model_override.clone(),
-                        exec.options.stream,
-                        exec.options.debug,
-                    )
-                    .await
-                {
-                    Ok(completion) => {
-                        let provider_before = self.provider_breaker.state_snapshot();
-                        self.provider_breaker.record_success();
-                        let provider_after = self.provider_breaker.state_snapshot();
-                        if provider_after != provider_before {
-                            self.emit_breaker_event(
-                                exec.trace_id,
-                                &self.provider_breaker,
-                                provider_after,
-                                None,
-                            )
-                            .await;
-                        }
-                        result = Some(completion);
-                        break;
-                    }
-                    Err(err) if attempt < strategy.max_retries() && err.is_retriable() => {
-                        let provider_before = self.provider_breaker.state_snapshot();
-                        let reason = err.to_string();
-                        let provider_after = self.provider_breaker.record_failure();
-                        if provider_after != provider_before {
-                            self.emit_breaker_event(
-                                exec.trace_id,
-                                &self.provider_breaker,
-                                provider_after,
-                                Some(reason.clone()),
-                            )
-                            .await;
-                        }
-                        tracing::warn!(
-                            "Retryable LLM error on attempt {}/{}, retrying in {}ms: {}",
-                            attempt + 1,
-                            strategy.max_retries(),
-                            delay.as_millis(),
-                            err
-                        );
-                        tokio::time::sleep(delay).await;
-                        let next_ms =
-                            (delay.as_millis() as f64 * strategy.backoff_factor() as f64) as u64;
-                        delay = Duration::from_millis(
-                            next_ms.min(strategy.max_delay().as_millis() as u64),
-                        );
-                        last_err = Some(err);
-                    }
-                    Err(err) => {
-                        let provider_before = self.provider_breaker.state_snapshot();
-                        let reason = err.to_string();
-                        let provider_after = self.provider_breaker.record_failure();
-                        if provider_after != provider_before {
-                            self.emit_breaker_event(
-                                exec.trace_id,
-                                &self.provider_breaker,
-                                provider_after,
-                                Some(reason),
-                            )
-                            .await;
-                        }
-                        last_err = Some(err);
-                        break;
-                    }
+    fn sanitize_json_value(&self, value: &Value) -> (Option<DataClassification>, Value) {
+        match value {
+            Value::String(text) => {
+                let (classification, redacted) = self.sanitize_text(text);
+                let classification = match classification {
+                    DataClassification::Public => None,
+                    other => Some(other),
+                };
+                (classification, Value::String(redacted))
+            }
+            Value::Array(items) => {
+                let mut highest = None;
+                let mut sanitized = Vec::with_capacity(items.len());
+                for item in items {
+                    let (classification, value) = self.sanitize_json_value(item);
+                    highest = max_classification(highest, classification);
+                    sanitized.push(value);
                 }
+                (highest, Value::Array(sanitized))
             }
-
-            result.ok_or_else(|| {
-                last_err
-                    .unwrap_or_else(|| SwarmError::Other("Retry attempts exhausted".to_string()))
-            })?
-        };
-        let latency_ms = start.elapsed().as_millis() as u64;
-
-        if completion.choices().is_empty() {
-            return Err(SwarmError::ApiError(
-                "No choices returned from the model".to_string(),
-            ));
+            Value::Object(map) => {
+                let mut highest = None;
+                let mut sanitized = serde_json::Map::with_capacity(map.len());
+                for (key, item) in map {
+                    let (classification, value) = self.sanitize_json_value(item);
+                    highest = max_classification(highest, classification);
+                    sanitized.insert(key.clone(), value);
+                }
+                (highest, Value::Object(sanitized))
+            }
+            _ => (None, value.clone()),
         }
+    }
 
-        let completion_tokens = completion
-            .usage()
-            .map(|usage| usage.completion_tokens)
-            .unwrap_or_else(|| {
-                completion
-                    .choices()
-                    .first()
-                    .and_then(|choice| choice.message.content().map(|text| (text.len() / 4) as u32))
-                    .unwrap_or(0)
-            });
-        let tokens_used = completion
-            .usage()
-            .map(|usage| usage.total_tokens)
-            .unwrap_or(prompt_tokens.saturating_add(completion_tokens));
+    fn get_tool_breaker(&self, tool_name: &str) -> SwarmResult<CircuitBreaker> {
+        let mut guard = self
+            .tool_breakers
+            .lock()
+            .map_err(|_| SwarmError::Other("tool_breakers lock poisoned".into()))?;
+        Ok(guard
+            .entry(tool_name.to_string())
+            .or_insert_with(|| {
+                CircuitBreaker::new(
+                    format!("tool:{}", tool_name),
+                    self.tool_breaker_settings.failure_threshold,
+                    self.tool_breaker_settings.reset_secs,
+                )
+            })
+            .clone())
+    }
 
-        exec.budget.add_tokens(tokens_used);
-        state.total_tokens = exec.budget.total_tokens;
-        self.check_budget(exec.trace_id, exec.budget).await?;
-        record_llm_latency(latency_ms as f64, &model);
-        record_token_usage(tokens_used as u64, &model);
+    async fn emit_guardrail_event(
+        &self,
+        trace_id: &TraceId,
+        guardrail_type: &str,
+        action: &str,
+        details: &str,
+        classification: Option<DataClassification>,
+    ) {
+        let (_, details) = self.sanitize_text(details);
+        record_guardrail_triggered(guardrail_type);
+        self.emit(AgentEvent::GuardrailTriggered {
+            trace_id: trace_id.clone(),
+            guardrail_type: guardrail_type.to_string(),
+            action: action.to_string(),
+            details,
+            classification,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-        self.emit(AgentEvent::LlmResponse {
-            trace_id: exec.trace_id.clone(),
-            model,
-            completion_tokens: completion_tokens as usize,
-            latency_ms,
+    async fn emit_budget_event(&self, trace_id: &TraceId, exhausted: &BudgetExhausted) {
+        let limit_type = match exhausted {
+            BudgetExhausted::TokenBudget { .. } => "token_budget",
+            BudgetExhausted::TokensPerRequest { .. } => "tokens_per_request",
+            BudgetExhausted::WallTime { .. } => "wall_time",
+            BudgetExhausted::ToolCallQuota { .. } => "tool_call_quota",
+            BudgetExhausted::MaxDepth { .. } => "max_depth",
+        };
+        record_budget_exhausted(limit_type);
+        self.emit(AgentEvent::BudgetExceeded {
+            trace_id: trace_id.clone(),
+            limit_type: limit_type.to_string(),
+            details: exhausted.to_string(),
             timestamp: Utc::now(),
         })
         .await;
+    }
 
-        let message = completion.choices()[0].message.clone();
-        if let Some(content) = message.content() {
-            self.enforce_content_policy(exec.trace_id, content, "llm_response")
-                .await?;
-        }
-        if !state.agent.expected_response_fields().is_empty() {
-            let content = message.content().ok_or_else(|| {
-                SwarmError::ValidationError(
-                    "Expected a structured JSON response but assistant content was empty"
-                        .to_string(),
-                )
-            })?;
-            let structured: Value = serde_json::from_str(content).map_err(|error| {
-                SwarmError::ValidationError(format!("Expected structured JSON response: {}", error))
-            })?;
-            let expected_fields = state
-                .agent
-                .expected_response_fields()
-                .iter()
-                .map(String::as_str)
-                .collect::<Vec<_>>();
-            verify_structured_response(&structured, &expected_fields)?;
-        }
+    async fn emit_breaker_event(
+        &self,
+        trace_id: &TraceId,
+        breaker: &CircuitBreaker,
+        state: CircuitStateSnapshot,
+        reason: Option<String>,
+    ) {
+        record_circuit_breaker_state(breaker.name(), &state.to_string());
+        self.emit(AgentEvent::CircuitBreakerStateChanged {
+            trace_id: trace_id.clone(),
+            breaker_name: breaker.name().to_string(),
+            state,
+            reason,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-        state.history.push(message.clone());
-        if let Some(content) = message.content() {
-            self.persist_memory_hook(
-                exec.trace_id,
-                &format!("assistant:{}:response", state.iterations),
-                content,
-                "assistant_response",
-            )
-            .await;
-        }
+    async fn emit_escalation_event(
+        &self,
+        trace_id: &TraceId,
+        trigger: EscalationTrigger,
+        action: EscalationAction,
+    ) {
+        self.emit(AgentEvent::EscalationTriggered {
+            trace_id: trace_id.clone(),
+            trigger,
+            action,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
 
-        let mut termination_reason = None;
-        if let Some(function_call) = message.function_call() {
-            let known_tools = state
-                .agent
-                .functions()
-                .iter()
-                .map(|function| function.name())
-                .collect::<Vec<_>>();
-            let breaker = self.get_tool_breaker(function_call.name())?;
+    async fn create_session_if_configured(&self, trace_id: &TraceId, agent_name: &str) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+        if let Err(err) = store
+            .create_session(trace_id.as_str(), agent_name, trace_id.as_str())
+            .await
+        {
+            tracing::warn!(
+                session_id = trace_id.as_str(),
+                "session creation failed (non-fatal): {}",
+                err
+            );
+        }
+    }
 
-            let tool_before = breaker.state_snapshot();
-            let tool_open = breaker.is_open();
-            let tool_after = breaker.state_snapshot();
-            if tool_after != tool_before {
-                self.emit_breaker_event(exec.trace_id, &breaker, tool_after.clone(), None)
+    async fn store_messages_if_configured(&self, trace_id: &TraceId, messages: &[Message]) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+        if let Err(err) = store.store_messages(trace_id.as_str(), messages).await {
+            tracing::warn!(
+                session_id = trace_id.as_str(),
+                "message persistence failed (non-fatal): {}",
+                err
+            );
+        }
+    }
+
+    async fn complete_session_if_configured(&self, trace_id: &TraceId, outcome: &str) {
+        let Some(store) = &self.session_store else {
+            return;
+        };
+        if let Err(err) = store.complete_session(trace_id.as_str(), outcome).await {
+            tracing::warn!(
+                session_id = trace_id.as_str(),
+                "session completion failed (non-fatal): {}",
+                err
+            );
+        }
+    }
+
+    async fn persist_memory_hook(&self, trace_id: &TraceId, key: &str, value: &str, source: &str) {
+        let Some(store) = &self.memory_store else {
+            return;
+        };
+        let (classification, sanitized) = self.sanitize_text(value);
+        if let Err(err) = store
+            .persist_memory(trace_id.as_str(), key, &sanitized)
+            .await
+        {
+            tracing::warn!(
+                session_id = trace_id.as_str(),
+                memory_key = key,
+                "memory persistence failed (non-fatal): {}",
+                err
+            );
+            return;
+        }
+        self.emit(AgentEvent::MemoryPersisted {
+            trace_id: trace_id.clone(),
+            key: key.to_string(),
+            source: source.to_string(),
+            classification,
+            timestamp: Utc::now(),
+        })
+        .await;
+    }
+
+    async fn enforce_content_policy(
+        &self,
+        trace_id: &TraceId,
+        text: &str,
+        context: &str,
+    ) -> SwarmResult<()> {
+        match self.content_policy.check_text(text, context).await {
+            PolicyResult::Allow => Ok(()),
+            PolicyResult::Warn(message) => {
+                self.emit_guardrail_event(
+                    trace_id,
+                    "content_policy",
+                    "warn",
+                    &message,
+                    Some(DataClassification::Restricted),
+                )
+                .await;
+                Ok(())
+            }
+            PolicyResult::Block(message) => {
+                self.emit_guardrail_event(
+                    trace_id,
+                    "content_policy",
+                    "block",
+                    &message,
+                    Some(DataClassification::Restricted),
+                )
+                .await;
+                Err(SwarmError::Other(message))
+            }
+        }
+    }
+
+    async fn apply_injection_policy(
+        &self,
+        trace_id: &TraceId,
+        messages: &mut [Message],
+    ) -> SwarmResult<()> {
+        for message in messages.iter_mut() {
+            let Some(content) = message.content().map(str::to_string) else {
+                continue;
+            };
+            match check_injection_with_policy(&content, &self.injection_policy) {
+                InjectionOutcome::Clean => {}
+                InjectionOutcome::Warned { patterns } => {
+                    self.emit_guardrail_event(
+                        trace_id,
+                        "prompt_injection",
+                        "warn",
+                        &format!("patterns={:?}", patterns),
+                        Some(DataClassification::Restricted),
+                    )
+                    .await;
+                }
+                InjectionOutcome::Sanitized {
+                    patterns,
+                    sanitized,
+                } => {
+                    self.emit_guardrail_event(
+                        trace_id,
+                        "prompt_injection",
+                        "sanitize",
+                        &format!("patterns={:?}", patterns),
+                        Some(DataClassification::Restricted),
+                    )
+                    .await;
+                    *message = Message::from_parts_unchecked(
+                        message.role(),
+                        Some(sanitized),
+                        message.name().map(str::to_string),
+                        message.function_call().cloned(),
+                    );
+                }
+                InjectionOutcome::Rejected { patterns } => {
+                    self.emit_guardrail_event(
+                        trace_id,
+                        "prompt_injection",
+                        "reject",
+                        &format!("patterns={:?}", patterns),
+                        Some(DataClassification::Restricted),
+                    )
                     .await;
+                    return Err(SwarmError::ValidationError(format!(
+                        "Prompt injection rejected by policy: {:?}",
+                        patterns
+                    )));
+                }
             }
-            if tool_open {
-                return Err(SwarmError::Other(format!(
-                    "Tool circuit breaker '{}' is open",
-                    breaker.name()
-                )));
+        }
+        Ok(())
+    }
+
+    async fn check_budget(&self, trace_id: &TraceId, budget: &BudgetEnforcer) -> SwarmResult<()> {
+        if let Err(exhausted) = budget.check() {
+            self.emit_budget_event(trace_id, &exhausted).await;
+            return Err(exhausted.into());
+        }
+        Ok(())
+    }
+
+    /// Runs `call` with the next available pooled API key, rotating past keys
+    /// that report a rate limit until one succeeds or every key is cooling down.
+    async fn with_rotating_api_key<T, F, Fut>(&self, mut call: F) -> SwarmResult<T>
+    where
+        F: FnMut(usize) -> Fut,
+        Fut: Future<Output = SwarmResult<T>>,
+    {
+        let mut last_err = None;
+        for _ in 0..self.key_pool.len() {
+            let index = match self.key_pool.next_index() {
+                Ok(index) => index,
+                Err(err) => return Err(last_err.unwrap_or(err)),
+            };
+            match call(index).await {
+                Err(SwarmError::RateLimitError(message)) => {
+                    self.key_pool.mark_rate_limited(index);
+                    last_err = Some(SwarmError::RateLimitError(message));
+                }
+                other => return other,
             }
+        }
+        Err(last_err.unwrap_or_else(|| SwarmError::Other("API key pool is empty".to_string())))
+    }
 
-            let arguments = serde_json::from_str(function_call.arguments()).unwrap_or(Value::Null);
-            let (_, sanitized_arguments) = self.sanitize_json_value(&arguments);
-            self.emit(AgentEvent::ToolCall {
-                trace_id: exec.trace_id.clone(),
-                tool_name: function_call.name().to_string(),
-                arguments: sanitized_arguments,
-                timestamp: Utc::now(),
+    /// Makes an asynchronous chat completion request.
+    pub async fn get_chat_completion(
+        &self,
+        agent: &Agent,
+        history: &[Message],
+        context_variables: &ContextVariables,
+        model_override: Option<String>,
+        stream: bool,
+        debug: bool,
+    ) -> SwarmResult<ChatCompletionResponse> {
+        let mut options = RunOptions::new(1).with_stream(stream).with_debug(debug);
+        options.model_override = model_override;
+        self.request_chat_completion(agent, history, context_variables, &options)
+            .await
+    }
+
+    /// Builds and sends one chat completion request for `agent`, applying the
+    /// request-level settings carried by the run's options.
+    async fn request_chat_completion(
+        &self,
+        agent: &Agent,
+        history: &[Message],
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+    ) -> SwarmResult<ChatCompletionResponse> {
+        self.request_chat_completion_with_stats(agent, history, context_variables, options)
+            .await
+            .map(|(completion, _)| completion)
+    }
+
+    /// [`Self::request_chat_completion`], also reporting how prompt compression
+    /// shrank the request.
+    async fn request_chat_completion_with_stats(
+        &self,
+        agent: &Agent,
+        history: &[Message],
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+    ) -> SwarmResult<(ChatCompletionResponse, Option<PromptCompressionStats>)> {
+        // Defense-in-depth: preflight (validate_api_request) is the authoritative check.
+        if history.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Message history cannot be empty".to_string(),
+            ));
+        }
+
+        let instructions = self.system_instructions(agent, context_variables);
+
+        // A reused history may already open with these exact instructions; prepending
+        // them again would hand the model duplicate system context.
+        let already_primed = self.config.deduplicate_system_messages()
+            && history.first().is_some_and(|first| {
+                first.role() == MessageRole::System
+                    && first.content() == Some(instructions.as_str())
+            });
+
+        let mut messages = Vec::with_capacity(history.len() + options.priming_messages.len() + 1);
+        if !already_primed {
+            messages.push(Message::system(instructions)?);
+        }
+        messages.extend_from_slice(&options.priming_messages);
+        messages.extend_from_slice(history);
+        self.inject_safety_instructions(&mut messages)?;
+        let stats = self.compress_prompt(&mut messages, options).await?;
+        let completion = self
+            .send_chat_completion(agent, messages, context_variables, options)
+            .await?;
+        Ok((completion, stats))
+    }
+
+    /// Applies `SwarmConfig::prompt_compression` when the estimated prompt size
+    /// is over its limit.
+    async fn compress_prompt(
+        &self,
+        messages: &mut Vec<Message>,
+        options: &RunOptions,
+    ) -> SwarmResult<Option<PromptCompressionStats>> {
+        let Some(compression) = self.config.prompt_compression() else {
+            return Ok(None);
+        };
+        let original_tokens = estimate_prompt_tokens(messages.iter());
+        if original_tokens <= compression.max_tokens {
+            return Ok(None);
+        }
+        match &compression.strategy {
+            CompressionStrategy::TruncateMiddle => {
+                truncate_middle(messages, compression.max_tokens, |_| true)
+            }
+            CompressionStrategy::Selective(roles) => {
+                truncate_middle(messages, compression.max_tokens, |message| {
+                    roles.contains(&message.role())
+                })
+            }
+            #[cfg(feature = "llmlingua")]
+            CompressionStrategy::LLMLingua(endpoint) => {
+                self.llmlingua_compress(endpoint, messages, compression.max_tokens, options)
+                    .await?
+            }
+            #[cfg(not(feature = "llmlingua"))]
+            CompressionStrategy::LLMLingua(_) => {
+                return Err(SwarmError::ConfigError(
+                    "LLMLingua prompt compression requires the `llmlingua` feature".to_string(),
+                ))
+            }
+        }
+        let compressed_tokens = estimate_prompt_tokens(messages.iter());
+        debug_print(
+            options.debug,
+            &format!(
+                "Compressed prompt from about {} to {} tokens",
+                original_tokens, compressed_tokens
+            ),
+        );
+        Ok(Some(PromptCompressionStats {
+            original_tokens,
+            compressed_tokens,
+        }))
+    }
+
+    /// Replaces the text of the user and assistant messages `truncate_middle`
+    /// could drop with their compressed form from an LLMLingua-style endpoint.
+    #[cfg(feature = "llmlingua")]
+    async fn llmlingua_compress(
+        &self,
+        endpoint: &str,
+        messages: &mut [Message],
+        max_tokens: u32,
+        options: &RunOptions,
+    ) -> SwarmResult<()> {
+        let first = messages
+            .iter()
+            .take_while(|m| m.role() == MessageRole::System)
+            .count()
+            + 1;
+        let targets: Vec<usize> = (first..messages.len().saturating_sub(1))
+            .filter(|&i| {
+                matches!(
+                    messages[i].role(),
+                    MessageRole::User | MessageRole::Assistant
+                ) && messages[i].content().is_some_and(|c| !c.is_empty())
             })
-            .await;
+            .collect();
+        if targets.is_empty() {
+            return Ok(());
+        }
+        let prompts: Vec<&str> = targets
+            .iter()
+            .filter_map(|&i| messages[i].content())
+            .collect();
+        options.count_api_call();
+        let reply: Value = self
+            .client
+            .post(endpoint)
+            .json(&json!({"prompts": prompts, "target_token": max_tokens}))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        let compressed = reply["compressed_prompts"]
+            .as_array()
+            .filter(|compressed| compressed.len() == targets.len())
+            .ok_or_else(|| {
+                SwarmError::ApiError(
+                    "LLMLingua reply must hold one compressed prompt per message".to_string(),
+                )
+            })?;
+        for (&i, text) in targets.iter().zip(compressed) {
+            if let Some(text) = text.as_str() {
+                messages[i].set_content(text);
+            }
+        }
+        Ok(())
+    }
+
+    /// The partial assistant message ending each request: the agent's assistant
+    /// prefix followed by the run's chain-of-thought prefix, as the provider
+    /// accepts it.
+    fn assistant_prefill(&self, agent: &Agent, options: &RunOptions) -> Option<String> {
+        let prefill = match (agent.assistant_prefix(), options.cot_prefix()) {
+            (Some(prefix), Some(cot_prefix)) => format!("{}{}", prefix, cot_prefix),
+            (prefix, cot_prefix) => prefix.or(cot_prefix)?.to_string(),
+        };
+        let prefill = self.provider_prefill(&prefill);
+        (!prefill.is_empty()).then(|| prefill.to_string())
+    }
+
+    /// `prefill` adjusted by the configured provider, if any.
+    fn provider_prefill<'a>(&self, prefill: &'a str) -> &'a str {
+        match &self.api_provider {
+            Some(provider) => provider.prefill(prefill),
+            None => prefill,
+        }
+    }
+
+    /// Sends the prepared `messages` for `agent`, after adding any assistant prefill.
+    async fn send_chat_completion(
+        &self,
+        agent: &Agent,
+        mut messages: Vec<Message>,
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+    ) -> SwarmResult<ChatCompletionResponse> {
+        let debug = options.debug;
+        if let Some(prefill) = self.assistant_prefill(agent, options) {
+            messages.push(Message::assistant(prefill)?);
+        }
+
+        debug_print(
+            debug,
+            &format!("Getting chat completion with messages: {:?}", messages),
+        );
+
+        let model = options
+            .model_override
+            .clone()
+            .unwrap_or_else(|| agent.model.clone());
+        let model = self.config.resolve_model_alias(&model).to_string();
+        let user_id = options.user_id.clone().or_else(|| {
+            self.config
+                .user_id_provider()
+                .and_then(|provider| provider(context_variables))
+        });
+        let model_parameters = self.effective_model_parameters(agent, &model, &messages)?;
+        let stop = merge_stop_sequences(
+            self.config.global_stop_sequences(),
+            agent.stop_sequences().unwrap_or_default(),
+        );
+
+        if options.stream && self.api_provider.is_some() {
+            return Err(SwarmError::ConfigError(
+                "Streaming is not supported with a custom ApiProvider".to_string(),
+            ));
+        }
+
+        if options.stream {
+            // Streaming path: keep legacy HTTP implementation with functions support.
+            let functions: Vec<Value> = agent
+                .functions
+                .iter()
+                .map(function_to_json)
+                .collect::<SwarmResult<Vec<Value>>>()?;
+
+            let mut request_body = json!({
+                "model": model,
+                "messages": self.config.message_serialization().serialize_messages(&messages)?,
+            });
+
+            match self.function_format(agent) {
+                FunctionCallFormat::Legacy => {
+                    if !functions.is_empty() {
+                        request_body["functions"] = Value::Array(functions);
+                    }
+                    if let Some(function_call) = agent.function_call().to_wire_value() {
+                        request_body["function_call"] = json!(function_call);
+                    }
+                }
+                FunctionCallFormat::Tools => {
+                    if !functions.is_empty() {
+                        request_body["tools"] = functions
+                            .into_iter()
+                            .map(|function| json!({"type": "function", "function": function}))
+                            .collect();
+                    }
+                    if let Some(tool_choice) = agent.function_call().to_tool_choice() {
+                        request_body["tool_choice"] = tool_choice;
+                    }
+                }
+            }
+
+            request_body["stream"] = json!(true);
+
+            if let Some(temperature) = model_parameters.temperature {
+                request_body["temperature"] = json!(temperature);
+            }
+            if let Some(max_tokens) = model_parameters.max_tokens {
+                request_body["max_tokens"] = json!(max_tokens);
+            }
+            if let Some(seed) = model_parameters.seed {
+                request_body["seed"] = json!(seed);
+            }
+            if !stop.is_empty() {
+                request_body["stop"] = json!(stop);
+            }
+
+            if let Some(user_id) = &user_id {
+                request_body["user"] = json!(user_id);
+            }
+            apply_logprobs(&OpenAiApiProvider, &mut request_body, agent);
+
+            if agent.tool_call_execution().is_parallel() {
+                request_body["parallel_tool_calls"] = json!(true);
+            }
+
+            let response = self
+                .with_rotating_api_key(|index| {
+                    let request = self
+                        .client
+                        .post(self.request_url.as_str())
+                        .bearer_auth(self.key_pool.key(index).as_str())
+                        .json(&request_body);
+                    options.count_api_call();
+                    async move {
+                        let response = request
+                            .send()
+                            .await
+                            .map_err(|e| SwarmError::NetworkError(e.to_string()))?;
+                        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                            let error_text = response.text().await.unwrap_or_default();
+                            return Err(SwarmError::RateLimitError(error_text));
+                        }
+                        Ok(response)
+                    }
+                })
+                .await?;
+
+            if !response.status().is_success() {
+                let error_text = response.text().await.map_err(|e| {
+                    SwarmError::NetworkError(format!("Failed to read error response: {}", e))
+                })?;
+                debug_print(debug, &format!("API Error Response: {}", error_text));
+                let api_error: serde_json::Result<OpenAIErrorResponse> =
+                    serde_json::from_str(&error_text);
+                return match api_error {
+                    Ok(err_resp) => Err(SwarmError::ApiError(err_resp.error.message)),
+                    Err(_) => Err(SwarmError::ApiError(error_text)),
+                };
+            }
+
+            let mut stream = response.bytes_stream();
+
+            // Line buffer: TCP chunks can split SSE `data:` lines across boundaries.
+            let mut line_buf = String::new();
+            // Delta accumulators for the single choice we're building.
+            let mut content_buf = String::new();
+            let mut fc_name = String::new();
+            let mut fc_args = String::new();
+            let mut finish_reason: Option<FinishReason> = None;
+            let mut logprobs_content: Vec<Value> = Vec::new();
+            let mut system_fingerprint = None;
+            // Accumulator for multi-tool-call streaming deltas (OpenAI tool_calls API).
+            let mut tc_acc_msg =
+                Message::from_parts_unchecked(MessageRole::Assistant, None, None, None);
+
+            'sse: while let Some(chunk_result) = stream.next().await {
+                let data = chunk_result.map_err(|e| {
+                    SwarmError::StreamError(format!("Error reading streaming response: {}", e))
+                })?;
+                line_buf.push_str(&String::from_utf8_lossy(&data));
+
+                // Process every complete line (terminated by \n).
+                while let Some(newline_pos) = line_buf.find('\n') {
+                    let line = line_buf[..newline_pos].trim_end_matches('\r').to_string();
+                    line_buf.drain(..=newline_pos);
+
+                    if let Some(json_str) = line.strip_prefix("data: ") {
+                        let json_str = json_str.trim();
+                        if json_str == "[DONE]" {
+                            break 'sse;
+                        }
+                        let chunk: Value = serde_json::from_str(json_str).map_err(|e| {
+                            SwarmError::DeserializationError(format!(
+                                "Failed to parse SSE chunk: {}",
+                                e
+                            ))
+                        })?;
+                        if let Some(fingerprint) = chunk["system_fingerprint"].as_str() {
+                            system_fingerprint = Some(fingerprint.to_string());
+                        }
+                        if let Some(choices) = chunk["choices"].as_array() {
+                            for choice in choices {
+                                let delta = &choice["delta"];
+                                if let Some(text) = delta["content"].as_str() {
+                                    content_buf.push_str(text);
+                                }
+                                if let Some(fc) = delta.get("function_call") {
+                                    if let Some(name) = fc["name"].as_str() {
+                                        fc_name.push_str(name);
+                                    }
+                                    if let Some(args) = fc["arguments"].as_str() {
+                                        fc_args.push_str(args);
+                                    }
+                                }
+                                if let Some(tc_arr) =
+                                    delta.get("tool_calls").and_then(|v| v.as_array())
+                                {
+                                    for tc_delta in tc_arr {
+                                        let index =
+                                            tc_delta["index"].as_u64().unwrap_or(0) as usize;
+                                        tc_acc_msg.merge_tool_call_delta(index, tc_delta);
+                                    }
+                                }
+                                if let Some(content) = choice["logprobs"]["content"].as_array() {
+                                    logprobs_content.extend(content.iter().cloned());
+                                }
+                                if let Some(fr) = choice["finish_reason"].as_str() {
+                                    finish_reason = Some(match fr {
+                                        "stop" => FinishReason::Stop,
+                                        "length" => FinishReason::Length,
+                                        "content_filter" => FinishReason::ContentFilter,
+                                        "tool_calls" => FinishReason::ToolCalls,
+                                        "function_call" => FinishReason::FunctionCall,
+                                        other => FinishReason::Unknown(other.to_string()),
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+
+            // Assemble the fully merged message from accumulated deltas.
+            tc_acc_msg.finalize_tool_calls();
+            let merged_message = if let Some(tool_calls) = tc_acc_msg.tool_calls() {
+                // Multi-tool-call streaming response.
+                Message::assistant_tool_calls(tool_calls.to_vec()).map_err(|e| {
+                    SwarmError::DeserializationError(format!(
+                        "Failed to build tool_calls message: {}",
+                        e
+                    ))
+                })?
+            } else {
+                let merged_fc = if !fc_name.is_empty() || !fc_args.is_empty() {
+                    Some(FunctionCall::from_parts_unchecked(fc_name, fc_args))
+                } else {
+                    None
+                };
+                let merged_content = if !content_buf.is_empty() {
+                    Some(content_buf)
+                } else {
+                    None
+                };
+                Message::from_parts_unchecked(
+                    MessageRole::Assistant,
+                    merged_content,
+                    None,
+                    merged_fc,
+                )
+            };
+            let mut full_response = ChatCompletionResponse::accumulator();
+            full_response.extend_choices(vec![Choice {
+                index: 0,
+                message: merged_message,
+                finish_reason,
+                logprobs: (!logprobs_content.is_empty())
+                    .then(|| json!({ "content": logprobs_content })),
+            }]);
+            full_response.set_system_fingerprint(system_fingerprint);
+            Ok(full_response)
+        } else {
+            match &self.api_provider {
+                Some(api_provider) => {
+                    self.request_with_api_provider(
+                        api_provider.as_ref(),
+                        agent,
+                        &messages,
+                        &model,
+                        &model_parameters,
+                        &stop,
+                        user_id.as_deref(),
+                        options,
+                    )
+                    .await
+                }
+                None => {
+                    self.request_with_llm_provider(
+                        agent,
+                        messages,
+                        model,
+                        &model_parameters,
+                        stop,
+                        user_id,
+                        options,
+                    )
+                    .await
+                }
+            }
+        }
+    }
+
+    /// Response-cache key for a fully built request, or `None` without a cache.
+    fn response_cache_key(&self, request: &impl Serialize) -> Option<String> {
+        self.response_cache
+            .as_ref()
+            .map(|_| response_cache_key(&self.request_url, request))
+    }
+
+    fn cached_response(&self, key: Option<&str>, debug: bool) -> Option<ChatCompletionResponse> {
+        let cached = self.response_cache.as_ref()?.get(key?)?;
+        debug_print(debug, "Serving chat completion from response cache");
+        Some(cached)
+    }
+
+    fn cache_response(&self, key: Option<&str>, response: &ChatCompletionResponse) {
+        if let (Some(cache), Some(key)) = (&self.response_cache, key) {
+            cache.set(key, response.clone(), self.response_cache_ttl);
+        }
+    }
+
+    /// The agent's function format, falling back to `default_function_format`.
+    fn function_format(&self, agent: &Agent) -> FunctionCallFormat {
+        agent
+            .function_format()
+            .unwrap_or_else(|| self.config.default_function_format())
+    }
+
+    /// Adds `safety_instructions` to the outgoing messages. This runs after the
+    /// agent's system prompt is built, so agent instructions cannot drop it.
+    fn inject_safety_instructions(&self, messages: &mut Vec<Message>) -> SwarmResult<()> {
+        let Some(safety) = self.config.safety_instructions() else {
+            return Ok(());
+        };
+        match self.config.safety_placement() {
+            SafetyPlacement::AppendToSystem => {
+                for message in messages.iter_mut() {
+                    if message.role() == MessageRole::System {
+                        let content = message.content().unwrap_or_default();
+                        let combined = Message::system(format!("{}\n\n{}", content, safety))?;
+                        *message = combined;
+                    }
+                }
+                tracing::debug!("Appended safety instructions to system messages");
+            }
+            SafetyPlacement::SeparateSystemMessage => {
+                let position = messages
+                    .iter()
+                    .take_while(|message| message.role() == MessageRole::System)
+                    .count();
+                messages.insert(position, Message::system(safety)?);
+                tracing::debug!(position, "Inserted safety instructions system message");
+            }
+        }
+        Ok(())
+    }
+
+    /// The agent's sampling parameters, with `max_tokens` capped to the room the
+    /// model's context window leaves after `messages` when `adaptive_max_tokens` is on.
+    fn effective_model_parameters(
+        &self,
+        agent: &Agent,
+        model: &str,
+        messages: &[Message],
+    ) -> SwarmResult<ModelParameters> {
+        let mut parameters = *agent.model_parameters();
+        let Some(max_tokens) = parameters.max_tokens else {
+            return Ok(parameters);
+        };
+        if !self.config.adaptive_max_tokens() {
+            return Ok(parameters);
+        }
+        let Some(window) = self.config.model_context_windows().context_window(model) else {
+            return Ok(parameters);
+        };
+        let available = window
+            .saturating_sub(estimate_prompt_tokens(messages))
+            .saturating_sub(ADAPTIVE_MAX_TOKENS_BUFFER);
+        if available == 0 {
+            return Err(SwarmError::ValidationError(format!(
+                "Prompt leaves no room for a completion in the {}-token context window of '{}'",
+                window, model
+            )));
+        }
+        parameters.max_tokens = Some(max_tokens.min(available));
+        Ok(parameters)
+    }
+
+    /// Non-streaming path: delegate to the pooled provider, then map the response via JSON round-trip.
+    #[allow(clippy::too_many_arguments)]
+    async fn request_with_llm_provider(
+        &self,
+        agent: &Agent,
+        messages: Vec<Message>,
+        model: String,
+        model_parameters: &ModelParameters,
+        stop: Vec<String>,
+        user_id: Option<String>,
+        options: &RunOptions,
+    ) -> SwarmResult<ChatCompletionResponse> {
+        let debug = options.debug;
+        let mut request = CompletionRequest::new(model, messages);
+        match self.function_format(agent) {
+            FunctionCallFormat::Legacy => {
+                let functions: Vec<Value> = agent
+                    .functions
+                    .iter()
+                    .map(function_to_json)
+                    .collect::<SwarmResult<Vec<Value>>>()?;
+                let function_call_policy = agent.function_call().to_wire_value().map(|v| json!(v));
+                if !functions.is_empty() {
+                    request = request.with_functions(functions, function_call_policy);
+                }
+            }
+            FunctionCallFormat::Tools => {
+                if !agent.functions.is_empty() {
+                    let tools = agent
+                        .functions
+                        .iter()
+                        .map(|function| ToolSchema {
+                            name: function.name().to_string(),
+                            description: function.description().to_string(),
+                            parameters: function.parameters_schema().clone(),
+                        })
+                        .collect();
+                    request = request.with_tools(tools);
+                    if let Some(tool_choice) = agent.function_call().to_tool_choice() {
+                        request = request.with_tool_choice(tool_choice);
+                    }
+                }
+            }
+        }
+        if agent.tool_call_execution().is_parallel() {
+            request = request.with_parallel_tool_calls(true);
+        }
+        if let Some(temperature) = model_parameters.temperature {
+            request = request.with_temperature(temperature);
+        }
+        if let Some(max_tokens) = model_parameters.max_tokens {
+            request = request.with_max_tokens(max_tokens);
+        }
+        if let Some(seed) = model_parameters.seed {
+            request = request.with_seed(seed);
+        }
+        if !stop.is_empty() {
+            request = request.with_stop(stop);
+        }
+        if let Some(user_id) = user_id {
+            request = request.with_user(user_id);
+        }
+        if let Some(config) = agent.logprobs_config().filter(|config| config.enabled) {
+            request = request.with_logprobs(config.top_logprobs);
+        }
+        let cache_key = self.response_cache_key(&request);
+        if let Some(cached) = self.cached_response(cache_key.as_deref(), debug) {
+            return Ok(cached);
+        }
+
+        let provider_response = self
+            .with_rotating_api_key(|index| {
+                options.count_api_call();
+                self.providers[index].complete(request.clone())
+            })
+            .await?;
+        debug_print(
+            debug,
+            &format!("Provider Response: {:?}", provider_response),
+        );
+
+        let mut json_val = serde_json::to_value(&provider_response).map_err(|e| {
+            SwarmError::DeserializationError(format!(
+                "Failed to serialize provider response: {}",
+                e
+            ))
+        })?;
+
+        promote_single_tool_calls(&mut json_val)?;
+
+        let response: ChatCompletionResponse = serde_json::from_value(json_val)
+            .map_err(|e| SwarmError::DeserializationError(e.to_string()))?;
+        self.cache_response(cache_key.as_deref(), &response);
+        Ok(response)
+    }
+
+    /// Sends one non-streaming request laid out by `api_provider`, rotating pooled keys on 429s.
+    #[allow(clippy::too_many_arguments)]
+    async fn request_with_api_provider(
+        &self,
+        api_provider: &dyn ApiProvider,
+        agent: &Agent,
+        messages: &[Message],
+        model: &str,
+        model_parameters: &ModelParameters,
+        stop: &[String],
+        user_id: Option<&str>,
+        options: &RunOptions,
+    ) -> SwarmResult<ChatCompletionResponse> {
+        let debug = options.debug;
+        let mut request_body =
+            api_provider.build_request_body(agent, messages, &agent.functions, model);
+        if self.function_format(agent) == FunctionCallFormat::Tools {
+            legacy_functions_to_tools(&mut request_body);
+        }
+        if let Some(temperature) = model_parameters.temperature {
+            request_body["temperature"] = json!(temperature);
+        }
+        if let Some(max_tokens) = model_parameters.max_tokens {
+            request_body["max_tokens"] = json!(max_tokens);
+        }
+        if let Some(seed) = model_parameters.seed {
+            api_provider.apply_seed(&mut request_body, seed);
+        }
+        if !stop.is_empty() {
+            api_provider.apply_stop_sequences(&mut request_body, stop);
+        }
+        if let Some(user_id) = user_id {
+            api_provider.apply_user_id(&mut request_body, user_id);
+        }
+        apply_logprobs(api_provider, &mut request_body, agent);
+        let cache_key = self.response_cache_key(&request_body);
+        if let Some(cached) = self.cached_response(cache_key.as_deref(), debug) {
+            return Ok(cached);
+        }
+        let body = self
+            .with_rotating_api_key(|index| {
+                let (auth_name, auth_value) =
+                    api_provider.auth_header(self.key_pool.key(index).as_str());
+                let mut request = self
+                    .client
+                    .post(self.request_url.as_str())
+                    .header(auth_name, auth_value)
+                    .json(&request_body);
+                for (name, value) in api_provider.extra_headers() {
+                    request = request.header(name, value);
+                }
+                options.count_api_call();
+                async move {
+                    let response = request
+                        .send()
+                        .await
+                        .map_err(|e| SwarmError::NetworkError(e.to_string()))?;
+                    let status = response.status();
+                    let text = response.text().await.map_err(|e| {
+                        SwarmError::NetworkError(format!("Failed to read response: {}", e))
+                    })?;
+                    if status == StatusCode::TOO_MANY_REQUESTS {
+                        return Err(SwarmError::RateLimitError(text));
+                    }
+                    if !status.is_success() {
+                        return Err(SwarmError::ApiError(text));
+                    }
+                    Ok(text)
+                }
+            })
+            .await?;
+        debug_print(debug, &format!("Provider Response: {}", body));
+        let response = api_provider.parse_response(&body)?;
+        self.cache_response(cache_key.as_deref(), &response);
+        Ok(response)
+    }
+
+    /// Asynchronously handles a function call from an agent.
+    pub async fn handle_function_call(
+        &self,
+        function_call: &FunctionCall,
+        functions: &[AgentFunction],
+        context_variables: ContextVariables,
+        debug: bool,
+    ) -> SwarmResult<Response> {
+        self.call_function(
+            function_call,
+            functions,
+            context_variables,
+            &RunOptions::new(1).with_debug(debug),
+        )
+        .await
+    }
+
+    /// [`Self::handle_function_call`] within a run, counting any requests it makes
+    /// against `options`.
+    async fn call_function(
+        &self,
+        function_call: &FunctionCall,
+        functions: &[AgentFunction],
+        context_variables: ContextVariables,
+        options: &RunOptions,
+    ) -> SwarmResult<Response> {
+        let debug = options.debug;
+        if function_call.name().trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Function call name cannot be empty.".to_string(),
+            ));
+        }
+
+        let mut function_map = HashMap::new();
+        for func in functions {
+            function_map.insert(func.name().to_string(), func.clone());
+        }
+
+        let mut response = Response::default();
+
+        if let Some(func) = function_map.get(function_call.name()) {
+            let args = self.function_arguments(func, function_call)?;
+            debug_print(
+                debug,
+                &format!(
+                    "Processing function call: {} with arguments {:?}",
+                    function_call.name(),
+                    args
+                ),
+            );
+
+            let mut args = args.clone();
+            if func.accepts_context_variables() {
+                let serialized_context = serde_json::to_string(&context_variables)?;
+                args.insert(CTX_VARS_NAME.to_string(), serialized_context);
+            }
+
+            // Await the asynchronous call.
+            let raw_result = (func.function)(args).await?;
+            let result = self.handle_function_result(raw_result, debug)?;
+            match result {
+                ResultType::Value(value) => {
+                    let (value, tokens) = self
+                        .compress_tool_result(function_call.name(), value, options)
+                        .await;
+                    response.tokens_used = tokens;
+                    let prompt = self.post_function_call_prompt(function_call.name(), &value);
+                    response
+                        .messages
+                        .push(Message::function(function_call.name(), value)?);
+                    if let Some(prompt) = prompt {
+                        response.messages.push(Message::user(prompt)?);
+                    }
+                }
+                ResultType::Agent(agent) => {
+                    response.agent = Some(agent);
+                }
+                ResultType::ContextVariables(mut context) => {
+                    if func.ephemeral_context() {
+                        let prefix = self.config.ephemeral_key_prefix();
+                        context = context
+                            .into_iter()
+                            .map(|(key, value)| {
+                                if key.starts_with(prefix) {
+                                    (key, value)
+                                } else {
+                                    (format!("{}{}", prefix, key), value)
+                                }
+                            })
+                            .collect();
+                    }
+                    let context = self.limit_context_variables(&context_variables, context)?;
+                    response.context_variables.extend(context);
+                }
+                ResultType::Termination(reason) => {
+                    response.termination_reason = Some(reason);
+                }
+            }
+        } else {
+            debug_print(
+                debug,
+                &format!("Function {} not found.", function_call.name()),
+            );
+            response.messages.push(Message::assistant_named(
+                function_call.name(),
+                format!("Error: Function {} not found.", function_call.name()),
+            )?);
+        }
+        Ok(response)
+    }
+
+    /// `post_function_call_prompt` filled in for one function result.
+    fn post_function_call_prompt(&self, function_name: &str, result: &str) -> Option<String> {
+        let template = self.config.post_function_call_prompt()?;
+        let preview: String = result
+            .chars()
+            .take(POST_FUNCTION_CALL_RESULT_PREVIEW_CHARS)
+            .collect();
+        Some(fill_template(
+            template,
+            &[
+                ("function_name", function_name),
+                ("function_result", &preview),
+            ],
+        ))
+    }
+
+    /// Shrinks a function result whose estimated size exceeds `tool_result_max_tokens`,
+    /// using `tool_summarizer_agent` when configured and truncation otherwise.
+    ///
+    /// A failed or still-oversized summary falls back to truncation, so a bad
+    /// summarizer never fails the function call. Also returns the tokens the
+    /// summarizer used.
+    async fn compress_tool_result(
+        &self,
+        function_name: &str,
+        value: String,
+        options: &RunOptions,
+    ) -> (String, u32) {
+        let debug = options.debug;
+        let Some(max_tokens) = self.config.tool_result_max_tokens() else {
+            return (value, 0);
+        };
+        // Same ~4 bytes per token estimate as the request budget.
+        let max_bytes = max_tokens as usize * 4;
+        if value.len() <= max_bytes {
+            return (value, 0);
+        }
+
+        let mut tokens = 0;
+        let value = match self.config.tool_summarizer_agent() {
+            Some(name) => {
+                let (summary, summary_tokens) = self
+                    .summarize_tool_result(name, function_name, &value, max_tokens, options)
+                    .await;
+                tokens = summary_tokens;
+                match summary {
+                    Ok(summary) => {
+                        debug_print(
+                            debug,
+                            &format!(
+                                "Summarized {} result from {} to {} bytes",
+                                function_name,
+                                value.len(),
+                                summary.len()
+                            ),
+                        );
+                        summary
+                    }
+                    Err(err) => {
+                        tracing::warn!(
+                            function = %function_name,
+                            error = %err,
+                            "Tool result summarization failed; truncating instead"
+                        );
+                        value
+                    }
+                }
+            }
+            None => value,
+        };
+        if value.len() <= max_bytes {
+            return (value, tokens);
+        }
+        tracing::warn!(
+            function = %function_name,
+            size = value.len(),
+            max_bytes,
+            "Truncating oversized function result"
+        );
+        (truncate_to_size(&value, max_bytes), tokens)
+    }
+
+    /// Returns the summary, or the error, with the tokens the request used.
+    async fn summarize_tool_result(
+        &self,
+        agent_name: &str,
+        function_name: &str,
+        value: &str,
+        max_tokens: u32,
+        options: &RunOptions,
+    ) -> (SwarmResult<String>, u32) {
+        let summarizer = match self.get_agent_by_name(agent_name) {
+            Ok(summarizer) => summarizer,
+            Err(err) => return (Err(err), 0),
+        };
+        let prompt = format!(
+            "{} Use at most {} tokens.\n\nResult of `{}`:\n{}",
+            TOOL_RESULT_SUMMARY_PROMPT, max_tokens, function_name, value
+        );
+        let prompt = match Message::user(prompt) {
+            Ok(prompt) => prompt,
+            Err(err) => return (Err(err), 0),
+        };
+        self.side_completion(
+            &summarizer,
+            &[prompt],
+            &ContextVariables::new(),
+            options,
+            "Tool result summary",
+        )
+        .await
+    }
+
+    /// Once the context's keys and values exceed `context_size_limit` bytes, replaces
+    /// values over `context_value_max_bytes`, largest first, with a summary from
+    /// `context_summarizer_agent` stored under `{key}_summarized`. Stops as soon as the
+    /// context fits; a failed summary leaves its value as it is. Reserved (`__`) and
+    /// ephemeral keys are never summarized.
+    async fn summarize_context_variables(
+        &self,
+        context: &mut ContextVariables,
+        total_tokens: &mut u32,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<()> {
+        let (Some(limit), Some(agent_name)) = (
+            self.config.context_size_limit(),
+            self.config.context_summarizer_agent(),
+        ) else {
+            return Ok(());
+        };
+        let size = |context: &ContextVariables| {
+            context
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>()
+        };
+        if size(context) <= limit {
+            return Ok(());
+        }
+        let min_bytes = self.config.context_value_max_bytes().unwrap_or(0);
+        let ephemeral_prefix = self.config.ephemeral_key_prefix();
+        let mut candidates = context
+            .iter()
+            .filter(|(key, value)| {
+                !key.starts_with(RESERVED_CONTEXT_KEY_PREFIX)
+                    && !key.starts_with(ephemeral_prefix)
+                    && value.len() > min_bytes
+            })
+            .map(|(key, value)| (key.clone(), value.len()))
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        for (key, original_size) in candidates {
+            if size(context) <= limit {
+                break;
+            }
+            let value = &context[&key];
+            let (summary, tokens) = self
+                .summarize_context_value(agent_name, value, exec.options)
+                .await;
+            self.account_tokens(tokens, total_tokens, exec).await?;
+            match summary {
+                Ok(summary) => {
+                    tracing::info!(
+                        key = %key,
+                        original_size,
+                        new_size = summary.len(),
+                        "Summarized context variable"
+                    );
+                    context.remove(&key);
+                    context.insert(format!("{}_summarized", key), summary);
+                }
+                Err(err) => {
+                    tracing::warn!(
+                        key = %key,
+                        error = %err,
+                        "Context variable summarization failed; keeping the value"
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the summary, or the error, with the tokens the request used.
+    async fn summarize_context_value(
+        &self,
+        agent_name: &str,
+        value: &str,
+        options: &RunOptions,
+    ) -> (SwarmResult<String>, u32) {
+        let summarizer = match self.get_agent_by_name(agent_name) {
+            Ok(summarizer) => summarizer,
+            Err(err) => return (Err(err), 0),
+        };
+        let prompt = fill_template(CONTEXT_VALUE_SUMMARY_PROMPT, &[("value", value)]);
+        let prompt = match Message::user(prompt) {
+            Ok(prompt) => prompt,
+            Err(err) => return (Err(err), 0),
+        };
+        self.side_completion(
+            &summarizer,
+            &[prompt],
+            &ContextVariables::new(),
+            options,
+            "Context value summary",
+        )
+        .await
+    }
+
+    /// Sends a one-off request (a summary or an evaluation) for `agent` and returns its
+    /// first non-empty reply. The tokens the request used come back even when the
+    /// reply is empty, so callers can always account for them.
+    async fn side_completion(
+        &self,
+        agent: &Agent,
+        messages: &[Message],
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+        what: &str,
+    ) -> (SwarmResult<String>, u32) {
+        let completion = match self
+            .request_chat_completion(agent, messages, context_variables, &options.side_request())
+            .await
+        {
+            Ok(completion) => completion,
+            Err(err) => return (Err(err), 0),
+        };
+        let tokens = completion.usage().map_or(0, |usage| usage.total_tokens);
+        let reply = completion
+            .choices()
+            .first()
+            .and_then(|choice| choice.message.content())
+            .filter(|text| !text.trim().is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| SwarmError::Other(format!("{} request returned no content", what)));
+        (reply, tokens)
+    }
+
+    /// [`Self::side_completion`] inside a run, counting its usage against the run's
+    /// token budget and `total_tokens`.
+    async fn request_side_completion(
+        &self,
+        agent: &Agent,
+        messages: &[Message],
+        context_variables: &ContextVariables,
+        what: &str,
+        total_tokens: &mut u32,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<String> {
+        let (reply, tokens) = self
+            .side_completion(agent, messages, context_variables, exec.options, what)
+            .await;
+        self.account_tokens(tokens, total_tokens, exec).await?;
+        reply
+    }
+
+    /// Adds tokens spent outside the conversation's own requests to the run's budget.
+    async fn account_tokens(
+        &self,
+        tokens: u32,
+        total_tokens: &mut u32,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<()> {
+        if tokens == 0 {
+            return Ok(());
+        }
+        exec.budget.add_tokens(tokens);
+        *total_tokens = exec.budget.total_tokens;
+        self.check_budget(exec.trace_id, exec.budget).await
+    }
+
+    /// Applies `max_content_length_per_role` to the text of every message in `history`,
+    /// per `content_overflow_per_role`.
+    fn limit_content_lengths(&self, history: &mut [Message]) -> SwarmResult<()> {
+        let limits = self.config.max_content_length_per_role();
+        if limits.is_empty() {
+            return Ok(());
+        }
+        for message in history.iter_mut() {
+            let role = message.role().as_str();
+            let Some(&max_length) = limits.get(role) else {
+                continue;
+            };
+            let length = message.content().map_or(0, str::len);
+            if length <= max_length {
+                continue;
+            }
+            let action = self
+                .config
+                .content_overflow_per_role()
+                .get(role)
+                .copied()
+                .unwrap_or(ContentOverflowAction::Error);
+            match action {
+                ContentOverflowAction::Error => {
+                    return Err(SwarmError::ValidationError(format!(
+                        "{} message content is {} bytes, over the limit of {}",
+                        role, length, max_length
+                    )));
+                }
+                ContentOverflowAction::Truncate(truncate_to) => {
+                    tracing::warn!(
+                        role,
+                        size = length,
+                        max_length,
+                        "Truncating oversized message content"
+                    );
+                    message.truncate_content(truncate_to.min(max_length));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies `context_variable_max_size` and `context_variable_max_count` to the
+    /// variables a function wants to add to `existing`, per `context_overflow`.
+    fn limit_context_variables(
+        &self,
+        existing: &ContextVariables,
+        updates: ContextVariables,
+    ) -> SwarmResult<ContextVariables> {
+        let overflow = self.config.context_overflow();
+        let mut updates = updates.into_iter().collect::<Vec<_>>();
+        // Sort so the count limit keeps the same variables on every run.
+        updates.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut count = existing.len();
+        let mut accepted = ContextVariables::new();
+        for (key, mut value) in updates {
+            if let Some(max_size) = self.config.context_variable_max_size() {
+                if value.len() > max_size {
+                    if overflow == ContextOverflow::Error {
+                        return Err(SwarmError::ContextError(format!(
+                            "context variable '{}' value exceeds max size",
+                            key
+                        )));
+                    }
+                    tracing::warn!(
+                        key = %key,
+                        size = value.len(),
+                        max_size,
+                        "Truncating oversized context variable"
+                    );
+                    value = truncate_to_size(&value, max_size);
+                }
+            }
+            if !existing.contains_key(&key) {
+                if let Some(max_count) = self.config.context_variable_max_count() {
+                    if count >= max_count {
+                        if overflow == ContextOverflow::Error {
+                            return Err(SwarmError::ContextError(format!(
+                                "context variable '{}' exceeds the limit of {} variables",
+                                key, max_count
+                            )));
+                        }
+                        tracing::warn!(
+                            key = %key,
+                            max_count,
+                            "Dropping context variable over the count limit"
+                        );
+                        continue;
+                    }
+                }
+                count += 1;
+            }
+            accepted.insert(key, value);
+        }
+        Ok(accepted)
+    }
+
+    /// Executes multiple tool calls serially, threading context from each call to the next.
+    async fn handle_tool_calls_serial(
+        &self,
+        tool_calls: &[ToolCall],
+        functions: &[AgentFunction],
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+    ) -> Vec<ToolCallOutcome> {
+        let mut output = Vec::with_capacity(tool_calls.len());
+        let mut running_ctx = context_variables.clone();
+        for tc in tool_calls {
+            let response = self
+                .call_function(tc.function(), functions, running_ctx.clone(), options)
+                .await;
+            match response {
+                Ok(response) => {
+                    running_ctx.extend(response.context_variables.clone());
+                    output.push(ToolCallOutcome {
+                        tool_call: tc.clone(),
+                        response: Ok(response),
+                    });
+                }
+                Err(err) => {
+                    output.push(ToolCallOutcome {
+                        tool_call: tc.clone(),
+                        response: Err(err),
+                    });
+                    break;
+                }
+            }
+        }
+        output
+    }
+
+    /// Executes multiple tool calls in parallel. Each call receives a clone of the current
+    /// context; results are merged in input order (last-writer-wins for conflicting keys).
+    async fn handle_tool_calls_parallel(
+        &self,
+        tool_calls: &[ToolCall],
+        functions: &[AgentFunction],
+        context_variables: &ContextVariables,
+        options: &RunOptions,
+    ) -> Vec<ToolCallOutcome> {
+        let futs: Vec<_> = tool_calls
+            .iter()
+            .map(|tc| {
+                let ctx = context_variables.clone();
+                let fc = tc.function().clone();
+                let fns = functions.to_vec();
+                async move { self.call_function(&fc, &fns, ctx, options).await }
+            })
+            .collect();
+        let results = futures::future::join_all(futs).await;
+        tool_calls
+            .iter()
+            .zip(results)
+            .map(|(tc, response)| ToolCallOutcome {
+                tool_call: tc.clone(),
+                response,
+            })
+            .collect()
+    }
+
+    /// Handles the result of a function call.
+    pub fn handle_function_result(
+        &self,
+        result: ResultType,
+        debug: bool,
+    ) -> SwarmResult<ResultType> {
+        debug_print(debug, &format!("Handling function result: {:?}", result));
+        Ok(result)
+    }
+
+    /// Hands a failed function call to the dead-letter queue. Returns `false` when
+    /// no queue is configured, in which case the caller fails as before.
+    fn dead_letter(&self, agent_name: &str, function_call: &FunctionCall, error: &str) -> bool {
+        let Some(sender) = &self.dead_letter_tx else {
+            return false;
+        };
+        let arguments = InvocationArgs::from_json_str(function_call.arguments())
+            .and_then(|args| args.to_context_variables())
+            .unwrap_or_default();
+        let entry = DeadLetterEntry {
+            agent_name: agent_name.to_string(),
+            function_name: function_call.name().to_string(),
+            arguments,
+            error: error.to_string(),
+            timestamp: SystemTime::now(),
+        };
+        if let Err(err) = sender.try_send(entry) {
+            tracing::warn!(
+                function = function_call.name(),
+                error = %err,
+                "Dropped dead-letter entry"
+            );
+        }
+        true
+    }
+
+    async fn persist_iteration_state(&self, trace_id: &TraceId, state: &RunState) {
+        self.store_messages_if_configured(trace_id, &state.history)
+            .await;
+        self.save_checkpoint_if_configured(
+            trace_id.as_str(),
+            state.agent.name(),
+            &state.history,
+            &state.context_variables,
+            state.iterations,
+            state.total_tokens,
+        )
+        .await;
+    }
+
+    async fn apply_escalation_trigger(
+        &self,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+        trigger: EscalationTrigger,
+    ) -> SwarmResult<Option<crate::phase::TerminationReason>> {
+        let action = exec.escalation.config().action.clone();
+        self.emit_escalation_event(exec.trace_id, trigger.clone(), action.clone())
+            .await;
+        match action {
+            EscalationAction::Stop => Ok(Some(crate::phase::TerminationReason::DoomLoopDetected)),
+            EscalationAction::InjectWarning => {
+                let warning = EscalationDetector::warning_message(&trigger);
+                state.history.push(Message::assistant(warning.clone())?);
+                self.persist_memory_hook(
+                    exec.trace_id,
+                    &format!("warning:{}", state.iterations),
+                    &warning,
+                    "escalation_warning",
+                )
+                .await;
+                Ok(None)
+            }
+            EscalationAction::HumanReviewEvent => Ok(None),
+        }
+    }
+
+    /// Requests one completion for the current history and validates the
+    /// assistant message it returns, along with the tokens the request used.
+    /// The message is not added to history.
+    async fn request_assistant_message(
+        &self,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<(Message, u32)> {
+        self.check_budget(exec.trace_id, exec.budget).await?;
+        exec.budget.increment_iterations();
+        state.iterations = exec.budget.iterations;
+        record_iteration(state.agent.name());
+
+        let model = exec
+            .options
+            .model_override
+            .as_deref()
+            .unwrap_or(state.agent.model())
+            .to_string();
+        self.refresh_history_summary(state, exec).await?;
+        let request_history = self.request_history(state)?;
+
+        let prompt_tokens = estimate_prompt_tokens(
+            exec.options
+                .priming_messages
+                .iter()
+                .chain(request_history.iter()),
+        );
+        if let Some(limit) = self.config.runtime_limits().max_tokens_per_request {
+            if prompt_tokens > limit {
+                let exhausted = BudgetExhausted::TokensPerRequest {
+                    used: prompt_tokens,
+                    limit,
+                };
+                self.emit_budget_event(exec.trace_id, &exhausted).await;
+                return Err(exhausted.into());
+            }
+        }
+
+        self.emit(AgentEvent::LlmRequest {
+            trace_id: exec.trace_id.clone(),
+            model: model.clone(),
+            prompt_tokens: prompt_tokens as usize,
+            timestamp: Utc::now(),
+        })
+        .await;
+
+        let start = Instant::now();
+        let strategy = self.config.api_settings().retry_strategy().clone();
+        let completion = {
+            let mut last_err: Option<SwarmError> = None;
+            let mut result = None;
+            let mut completion_spans = Vec::new();
+            let mut prompt_compression = None;
+
+            for attempt in 0..=strategy.max_retries() {
+                let provider_before = self.provider_breaker.state_snapshot();
+                let provider_open = self.provider_breaker.is_open();
+                let provider_after = self.provider_breaker.state_snapshot();
+                if provider_after != provider_before {
+                    self.emit_breaker_event(
+                        exec.trace_id,
+                        &self.provider_breaker,
+                        provider_after.clone(),
+                        None,
+                    )
+                    .await;
+                }
+                if provider_open {
+                    return Err(SwarmError::Other(format!(
+                        "Provider circuit breaker '{}' is open",
+                        self.provider_breaker.name()
+                    )));
+                }
+
+                let timer = state.start_span(ProfileSpanKind::ChatCompletion {
+                    model: model.clone(),
+                });
+                let attempt_result = self
+                    .request_chat_completion_with_stats(
+                        &state.agent,
+                        &request_history,
+                        &state.context_variables,
+                        exec.options,
+                    )
+                    .await
+                    .map(|(completion, stats)| {
+                        prompt_compression = stats;
+                        completion
+                    });
+                completion_spans.extend(timer.map(SpanTimer::finish));
+                match attempt_result {
+                    Ok(completion) => {
+                        let provider_before = self.provider_breaker.state_snapshot();
+                        self.provider_breaker.record_success();
+                        let provider_after = self.provider_breaker.state_snapshot();
+                        if provider_after != provider_before {
+                            self.emit_breaker_event(
+                                exec.trace_id,
+                                &self.provider_breaker,
+                                provider_after,
+                                None,
+                            )
+                            .await;
+                        }
+                        result = Some(completion);
+                        break;
+                    }
+                    Err(err) if attempt < strategy.max_retries() && err.is_retriable() => {
+                        let provider_before = self.provider_breaker.state_snapshot();
+                        let reason = err.to_string();
+                        let provider_after = self.provider_breaker.record_failure();
+                        if provider_after != provider_before {
+                            self.emit_breaker_event(
+                                exec.trace_id,
+                                &self.provider_breaker,
+                                provider_after,
+                                Some(reason.clone()),
+                            )
+                            .await;
+                        }
+                        let delay = strategy.compute_delay(attempt);
+                        tracing::warn!(
+                            "Retryable LLM error on attempt {}/{}, retrying in {}ms: {}",
+                            attempt + 1,
+                            strategy.max_retries(),
+                            delay.as_millis(),
+                            err
+                        );
+                        tokio::time::sleep(delay).await;
+                        last_err = Some(err);
+                    }
+                    Err(err) => {
+                        let provider_before = self.provider_breaker.state_snapshot();
+                        let reason = err.to_string();
+                        let provider_after = self.provider_breaker.record_failure();
+                        if provider_after != provider_before {
+                            self.emit_breaker_event(
+                                exec.trace_id,
+                                &self.provider_breaker,
+                                provider_after,
+                                Some(reason),
+                            )
+                            .await;
+                        }
+                        last_err = Some(err);
+                        break;
+                    }
+                }
+            }
+            state.record_spans(completion_spans);
+            state.record_prompt_compression(prompt_compression);
+
+            result.ok_or_else(|| {
+                last_err
+                    .unwrap_or_else(|| SwarmError::Other("Retry attempts exhausted".to_string()))
+            })?
+        };
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        if completion.choices().is_empty() {
+            return Err(SwarmError::ApiError(
+                "No choices returned from the model".to_string(),
+            ));
+        }
+
+        let completion_tokens = completion
+            .usage()
+            .map(|usage| usage.completion_tokens)
+            .unwrap_or_else(|| {
+                completion
+                    .choices()
+                    .first()
+                    .and_then(|choice| choice.message.content().map(|text| (text.len() / 4) as u32))
+                    .unwrap_or(0)
+            });
+        let tokens_used = completion
+            .usage()
+            .map(|usage| usage.total_tokens)
+            .unwrap_or(prompt_tokens.saturating_add(completion_tokens));
+        state.turn_prompt_tokens = state.turn_prompt_tokens.saturating_add(
+            completion
+                .usage()
+                .map_or(prompt_tokens, |usage| usage.prompt_tokens),
+        );
+        state.turn_completion_tokens = state
+            .turn_completion_tokens
+            .saturating_add(completion_tokens);
+
+        exec.budget.add_tokens(tokens_used);
+        state.total_tokens = exec.budget.total_tokens;
+        self.check_budget(exec.trace_id, exec.budget).await?;
+        record_llm_latency(latency_ms as f64, &model);
+        record_token_usage(tokens_used as u64, &model);
+
+        self.emit(AgentEvent::LlmResponse {
+            trace_id: exec.trace_id.clone(),
+            model,
+            completion_tokens: completion_tokens as usize,
+            latency_ms,
+            timestamp: Utc::now(),
+        })
+        .await;
+
+        state.turn_logprobs = completion.choices()[0].logprobs.clone();
+        if let Some(fingerprint) = completion.system_fingerprint() {
+            state.system_fingerprint = Some(fingerprint.to_string());
+        }
+        if !exec.options.extract_response_fields.is_empty() {
+            let completion_json = serde_json::to_value(&completion)?;
+            let fields = exec
+                .options
+                .extract_response_fields
+                .iter()
+                .filter_map(|(path, key)| {
+                    json_get_path(&completion_json, path).map(|value| (key.clone(), value))
+                })
+                .collect();
+            let fields = self.limit_context_variables(&state.context_variables, fields)?;
+            state.context_variables.extend(fields);
+        }
+        let mut message = completion.choices()[0].message.clone();
+        if let Some(cot_prefix) = exec.options.cot_prefix() {
+            // Keep repeated prefixes from piling up in history. With an assistant
+            // prefix the echo starts with the whole prefill, not just the CoT prefix.
+            let prefill = self
+                .assistant_prefill(&state.agent, exec.options)
+                .unwrap_or_default();
+            let echoed = if message
+                .content()
+                .is_some_and(|content| content.starts_with(prefill.as_str()))
+            {
+                prefill.as_str()
+            } else {
+                self.provider_prefill(cot_prefix)
+            };
+            message.strip_content_prefix(echoed);
+        }
+        if let Some(content) = message.content() {
+            self.enforce_content_policy(exec.trace_id, content, "llm_response")
+                .await?;
+        }
+        if !state.agent.expected_response_fields().is_empty() {
+            let content = message.content().ok_or_else(|| {
+                SwarmError::ValidationError(
+                    "Expected a structured JSON response but assistant content was empty"
+                        .to_string(),
+                )
+            })?;
+            let structured: Value = serde_json::from_str(content).map_err(|error| {
+                SwarmError::ValidationError(format!("Expected structured JSON response: {}", error))
+            })?;
+            let expected_fields = state
+                .agent
+                .expected_response_fields()
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            verify_structured_response(&structured, &expected_fields)?;
+        }
+        Ok((message, tokens_used))
+    }
+
+    /// The agent's response language when it enforces one and `message` has text
+    /// with none of that language's words. Languages without a word list pass.
+    fn wrong_language(&self, agent: &Agent, message: &Message) -> Option<String> {
+        let language = agent
+            .response_language()
+            .filter(|_| agent.enforce_language())?;
+        let content = message.content()?;
+        let words = self
+            .config
+            .language_word_lists()
+            .get(&language.trim().to_lowercase())?;
+        let found = content
+            .split(|c: char| !c.is_alphanumeric())
+            .any(|word| words.iter().any(|known| known == &word.to_lowercase()));
+        (!found).then(|| language.to_string())
+    }
+
+    /// Whether `content` is more similar than `semantic_dedup_threshold` to one of the
+    /// last `SEMANTIC_DEDUP_LOOKBACK` assistant messages in `history`.
+    fn is_semantic_duplicate(&self, history: &[Message], content: &str) -> bool {
+        let Some(threshold) = self.config.semantic_dedup_threshold() else {
+            return false;
+        };
+        history
+            .iter()
+            .rev()
+            .filter(|message| message.role() == MessageRole::Assistant)
+            .filter_map(Message::content)
+            .take(SEMANTIC_DEDUP_LOOKBACK)
+            .any(|previous| jaccard_similarity(previous, content) > threshold)
+    }
+
+    /// Executes a single round of conversation with the agent.
+    async fn single_execution(
+        &self,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<Response> {
+        self.limit_content_lengths(&mut state.history)?;
+        let timer = state.start_span(ProfileSpanKind::SingleExecution);
+        let start = Instant::now();
+        let agent_name = state.agent.name().to_string();
+        let model = exec
+            .options
+            .model_override
+            .as_deref()
+            .unwrap_or(state.agent.model())
+            .to_string();
+        let history_len = state.history.len();
+        state.turn_prompt_tokens = 0;
+        state.turn_completion_tokens = 0;
+        state.turn_logprobs = None;
+        state.turn_prompt_compression = None;
+
+        let mut result = self.run_turn(state, exec).await;
+        state.end_span(timer);
+        if result.is_ok() {
+            if let Err(err) = self
+                .summarize_context_variables(
+                    &mut state.context_variables,
+                    &mut state.total_tokens,
+                    exec,
+                )
+                .await
+            {
+                result = Err(err);
+            }
+        }
+        // Tag replies with their author so multi-agent histories can be split apart.
+        // Tool-call messages cannot carry a name.
+        if self.config.inject_agent_name_as_message_name() {
+            for message in state.history.iter_mut().skip(history_len) {
+                let calls_tools = message.calls_tools();
+                match message.role() {
+                    MessageRole::Assistant if !calls_tools && message.name().is_none() => {
+                        message.set_name(message_name(&agent_name));
+                    }
+                    MessageRole::Tool if message.author().is_none() => {
+                        message.set_author(agent_name.as_str());
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if result.is_ok() {
+            let function_calls = state
+                .history
+                .iter()
+                .skip(history_len)
+                .filter(|message| message.role() == MessageRole::Assistant)
+                .flat_map(|message| {
+                    let tool_calls = message.tool_calls().unwrap_or_default();
+                    let function_call = message.function_call().map(|call| call.name());
+                    function_call
+                        .into_iter()
+                        .chain(tool_calls.iter().map(|call| call.function().name()))
+                })
+                .map(str::to_string)
+                .collect();
+            let turn = state.turn_metadata.len() + 1;
+            if self.config.context_snapshot_interval() == ContextSnapshotInterval::AfterEachTurn {
+                state.snapshot_context(
+                    format!("turn {}", turn),
+                    self.config.max_context_snapshots(),
+                );
+            }
+            state.turn_metadata.push(TurnMetadata {
+                turn,
+                agent_name,
+                model,
+                prompt_tokens: state.turn_prompt_tokens,
+                completion_tokens: state.turn_completion_tokens,
+                duration_ms: start.elapsed().as_millis() as u64,
+                function_calls,
+                logprobs: state.turn_logprobs.take(),
+                prompt_compression: state.turn_prompt_compression.take(),
+            });
+        }
+        result
+    }
+
+    /// Body of [`Self::single_execution`].
+    async fn run_turn(
+        &self,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<Response> {
+        let mut dedup_retries = 0usize;
+        let mut language_retries = 0usize;
+        let mut arg_retries = 0u32;
+        let mut tokens_used = 0u32;
+        // Rejected replies and their retry prompts only steer the next attempt.
+        let attempts_start = state.history.len();
+        let message = loop {
+            let (message, request_tokens) = self.request_assistant_message(state, exec).await?;
+            tokens_used = tokens_used.saturating_add(request_tokens);
+            if let Some(retry_prompt) = self
+                .config
+                .function_arg_retry_prompt()
+                .filter(|_| arg_retries < self.config.function_arg_max_retries())
+            {
+                if let Some((error, results)) =
+                    self.invalid_argument_results(state.agent.functions(), &message)?
+                {
+                    arg_retries += 1;
+                    debug_print(
+                        exec.options.debug,
+                        &format!(
+                            "Function arguments failed validation; asking again: {}",
+                            error
+                        ),
+                    );
+                    let retry =
+                        Message::user(format!("{}\n\nValidation error: {}", retry_prompt, error))?;
+                    state.trace(|| TraceEvent::MessageReceived(message.clone()));
+                    state.history.push(message);
+                    state.history.extend(results);
+                    state.trace(|| TraceEvent::MessageSent(retry.clone()));
+                    state.history.push(retry);
+                    continue;
+                }
+            }
+            if language_retries < LANGUAGE_ENFORCEMENT_MAX_RETRIES {
+                if let Some(language) = self.wrong_language(&state.agent, &message) {
+                    language_retries += 1;
+                    debug_print(
+                        exec.options.debug,
+                        &format!("Assistant response is not in {}; asking again", language),
+                    );
+                    let retry = Message::user(fill_template(
+                        LANGUAGE_RETRY_PROMPT,
+                        &[("language", &language)],
+                    ))?;
+                    state.trace(|| TraceEvent::MessageReceived(message.clone()));
+                    state.history.push(message);
+                    state.trace(|| TraceEvent::MessageSent(retry.clone()));
+                    state.history.push(retry);
+                    continue;
+                }
+            }
+            // Tool calls are actions, not answers; repeating one is not a duplicate.
+            let duplicate = dedup_retries < SEMANTIC_DEDUP_MAX_RETRIES
+                && !message.calls_tools()
+                && message
+                    .content()
+                    .is_some_and(|content| self.is_semantic_duplicate(&state.history, content));
+            if !duplicate {
+                break message;
+            }
+            dedup_retries += 1;
+            debug_print(
+                exec.options.debug,
+                "Assistant response repeats an earlier answer; asking for a different one",
+            );
+            state.trace(|| TraceEvent::MessageReceived(message.clone()));
+            state.history.push(message);
+            let nudge = Message::user(SEMANTIC_DEDUP_RETRY_PROMPT)?;
+            state.trace(|| TraceEvent::MessageSent(nudge.clone()));
+            state.history.push(nudge);
+        };
+        state.history.truncate(attempts_start);
+
+        state.trace(|| TraceEvent::MessageReceived(message.clone()));
+        state.history.push(message.clone());
+        if let Some(content) = message.content() {
+            self.persist_memory_hook(
+                exec.trace_id,
+                &format!("assistant:{}:response", state.iterations),
+                content,
+                "assistant_response",
+            )
+            .await;
+        }
+
+        let mut termination_reason = None;
+        if let Some(function_call) = message.function_call() {
+            let known_tool_names = state
+                .agent
+                .functions()
+                .iter()
+                .map(|function| function.name().to_string())
+                .collect::<Vec<_>>();
+            let breaker = self.get_tool_breaker(function_call.name())?;
+
+            let tool_before = breaker.state_snapshot();
+            let tool_open = breaker.is_open();
+            let tool_after = breaker.state_snapshot();
+            if tool_after != tool_before {
+                self.emit_breaker_event(exec.trace_id, &breaker, tool_after.clone(), None)
+                    .await;
+            }
+            if tool_open {
+                return Err(SwarmError::Other(format!(
+                    "Tool circuit breaker '{}' is open",
+                    breaker.name()
+                )));
+            }
+
+            let arguments = serde_json::from_str(function_call.arguments()).unwrap_or(Value::Null);
+            let (_, sanitized_arguments) = self.sanitize_json_value(&arguments);
+            self.emit(AgentEvent::ToolCall {
+                trace_id: exec.trace_id.clone(),
+                tool_name: function_call.name().to_string(),
+                arguments: sanitized_arguments,
+                timestamp: Utc::now(),
+            })
+            .await;
+
+            self.check_budget(exec.trace_id, exec.budget).await?;
+            state.trace(|| {
+                TraceEvent::FunctionCalled(
+                    function_call.name().to_string(),
+                    self.trace_arguments(function_call.arguments()),
+                )
+            });
+            let known_tools: Vec<&str> = known_tool_names.iter().map(String::as_str).collect();
+            let tool_start = Instant::now();
+            let func_response = self
+                .call_function(
+                    function_call,
+                    state.agent.functions(),
+                    state.context_variables.clone(),
+                    exec.options,
+                )
+                .await;
+            let tool_duration_ms = tool_start.elapsed().as_millis() as u64;
+            exec.budget.increment_tool_calls();
+
+            match func_response {
+                Ok(func_response) => {
+                    self.account_tokens(func_response.tokens_used, &mut state.total_tokens, exec)
+                        .await?;
+                    let tool_result_content = func_response
+                        .messages
+                        .first()
+                        .and_then(|message| {
+                            message
+                                .content()
+                                .map(|content| Value::String(content.to_string()))
+                        })
+                        .unwrap_or(Value::Null);
+                    let tool_success = !tool_result_content
+                        .as_str()
+                        .map(|content| content.starts_with("Error:"))
+                        .unwrap_or(false);
+
+                    if tool_success {
+                        let tool_before = breaker.state_snapshot();
+                        breaker.record_success();
+                        let tool_after = breaker.state_snapshot();
+                        if tool_after != tool_before {
+                            self.emit_breaker_event(exec.trace_id, &breaker, tool_after, None)
+                                .await;
+                        }
+                    } else {
+                        let tool_before = breaker.state_snapshot();
+                        let tool_after = breaker.record_failure();
+                        if tool_after != tool_before {
+                            self.emit_breaker_event(
+                                exec.trace_id,
+                                &breaker,
+                                tool_after,
+                                Some("tool returned an error response".to_string()),
+                            )
+                            .await;
+                        }
+                    }
+                    let dead_lettered = !tool_success
+                        && self.dead_letter(
+                            state.agent.name(),
+                            function_call,
+                            tool_result_content.as_str().unwrap_or_default(),
+                        );
+
+                    let (classification, sanitized_result) =
+                        self.sanitize_json_value(&tool_result_content);
+                    self.emit(AgentEvent::ToolResult {
+                        trace_id: exec.trace_id.clone(),
+                        tool_name: function_call.name().to_string(),
+                        result: sanitized_result,
+                        success: tool_success,
+                        duration_ms: tool_duration_ms,
+                        timestamp: Utc::now(),
+                    })
+                    .await;
+                    record_tool_call(function_call.name(), tool_duration_ms as f64, tool_success);
+
+                    if let Some(content) = tool_result_content.as_str() {
+                        self.enforce_content_policy(exec.trace_id, content, "tool_result")
+                            .await?;
+                        self.persist_memory_hook(
+                            exec.trace_id,
+                            &format!("tool:{}:{}", function_call.name(), state.iterations),
+                            content,
+                            "tool_result",
+                        )
+                        .await;
+                    } else if let Some(classification) = classification {
+                        self.emit_guardrail_event(
+                            exec.trace_id,
+                            "tool_result_redaction",
+                            "redact",
+                            "non-string tool result redacted for audit storage",
+                            Some(classification),
+                        )
+                        .await;
+                    }
+
+                    if let Some(trigger) = exec.escalation.record_tool_call(
+                        function_call.name(),
+                        tool_success,
+                        &known_tools,
+                        function_call.arguments(),
+                    ) {
+                        if let Some(reason) =
+                            self.apply_escalation_trigger(state, exec, trigger).await?
+                        {
+                            termination_reason = Some(reason);
+                        }
+                    }
+
+                    state.trace_function_response(function_call.name(), &func_response, |value| {
+                        self.sanitize_text(value).1
+                    });
+                    state.history.extend(func_response.messages);
+                    if dead_lettered {
+                        state.history.push(Message::user(DEAD_LETTER_RETRY_PROMPT)?);
+                    }
+                    state
+                        .context_variables
+                        .extend(func_response.context_variables);
+                    if let Some(agent) = func_response.agent {
+                        exec.budget.increment_depth();
+                        self.check_budget(exec.trace_id, exec.budget).await?;
+                        self.hand_off(state, agent)?;
+                    }
+                    if let Some(reason) = func_response.termination_reason {
+                        termination_reason = Some(reason);
+                    }
+                }
+                Err(err) => {
+                    let tool_before = breaker.state_snapshot();
+                    let tool_after = breaker.record_failure();
+                    if tool_after != tool_before {
+                        self.emit_breaker_event(
+                            exec.trace_id,
+                            &breaker,
+                            tool_after,
+                            Some(err.to_string()),
+                        )
+                        .await;
+                    }
+                    self.emit(AgentEvent::ToolResult {
+                        trace_id: exec.trace_id.clone(),
+                        tool_name: function_call.name().to_string(),
+                        result: Value::String(err.to_string()),
+                        success: false,
+                        duration_ms: tool_duration_ms,
+                        timestamp: Utc::now(),
+                    })
+                    .await;
+                    record_tool_call(function_call.name(), tool_duration_ms as f64, false);
+                    if let Some(trigger) = exec.escalation.record_tool_call(
+                        function_call.name(),
+                        false,
+                        &known_tools,
+                        function_call.arguments(),
+                    ) {
+                        if let Some(reason) =
+                            self.apply_escalation_trigger(state, exec, trigger).await?
+                        {
+                            return Ok(Response {
+                                messages: vec![message],
+                                agent: Some(state.agent.clone()),
+                                context_variables: state.context_variables.clone(),
+                                termination_reason: Some(reason),
+                                tokens_used,
+                                handoff_history: state.handoff_history.clone(),
+                                ..Response::default()
+                            });
+                        }
+                    }
+                    if !self.dead_letter(state.agent.name(), function_call, &err.to_string()) {
+                        return Err(err);
+                    }
+                    let (_, error_text) = self.sanitize_text(&format!("Error: {}", err));
+                    state
+                        .history
+                        .push(Message::function(function_call.name(), error_text)?);
+                    state.history.push(Message::user(DEAD_LETTER_RETRY_PROMPT)?);
+                }
+            }
+        } else if let Some(tool_calls) = message.tool_calls() {
+            if !tool_calls.is_empty() {
+                // Snapshot immutable state before any await point or mutation of `state`.
+                // Cloning here avoids holding borrows of state.agent / state.context_variables
+                // across await points, which the borrow checker would reject.
+                let functions_snapshot = state.agent.functions().to_vec();
+                let ctx_snapshot = state.context_variables.clone();
+                let execution_mode = state.agent.tool_call_execution();
+                // known_tools borrows from functions_snapshot (local), not state.agent.
+                let known_tools: Vec<&str> = functions_snapshot.iter().map(|f| f.name()).collect();
+
+                // Pre-execution: circuit breaker check per call
+                for tc in tool_calls {
+                    let breaker = self.get_tool_breaker(tc.function().name())?;
+                    let snap_before = breaker.state_snapshot();
+                    let is_open = breaker.is_open();
+                    let snap_after = breaker.state_snapshot();
+                    if snap_after != snap_before {
+                        self.emit_breaker_event(exec.trace_id, &breaker, snap_after.clone(), None)
+                            .await;
+                    }
+                    if is_open {
+                        return Err(SwarmError::Other(format!(
+                            "Tool circuit breaker '{}' is open",
+                            breaker.name()
+                        )));
+                    }
+                }
+
+                // Emit ToolCall events
+                for tc in tool_calls {
+                    state.trace(|| {
+                        TraceEvent::FunctionCalled(
+                            tc.function().name().to_string(),
+                            self.trace_arguments(tc.function().arguments()),
+                        )
+                    });
+                    let arguments =
+                        serde_json::from_str(tc.function().arguments()).unwrap_or(Value::Null);
+                    let (_, sanitized_arguments) = self.sanitize_json_value(&arguments);
+                    self.emit(AgentEvent::ToolCall {
+                        trace_id: exec.trace_id.clone(),
+                        tool_name: tc.function().name().to_string(),
+                        arguments: sanitized_arguments,
+                        timestamp: Utc::now(),
+                    })
+                    .await;
+                }
+
+                self.check_budget(exec.trace_id, exec.budget).await?;
+
+                let tool_start = Instant::now();
+                let batch_results = match execution_mode {
+                    ToolCallExecution::Parallel => {
+                        self.handle_tool_calls_parallel(
+                            tool_calls,
+                            &functions_snapshot,
+                            &ctx_snapshot,
+                            exec.options,
+                        )
+                        .await
+                    }
+                    ToolCallExecution::Serial => {
+                        self.handle_tool_calls_serial(
+                            tool_calls,
+                            &functions_snapshot,
+                            &ctx_snapshot,
+                            exec.options,
+                        )
+                        .await
+                    }
+                };
+                let tool_duration_ms = tool_start.elapsed().as_millis() as u64;
+                let mut batch_error = None;
+                // Prompts go after every result, as tool results must follow their call.
+                let mut follow_ups = Vec::new();
+                let mut dead_lettered = false;
+
+                for outcome in batch_results {
+                    let tc = outcome.tool_call;
+                    exec.budget.increment_tool_calls();
+                    match outcome.response {
+                        Ok(func_response) => {
+                            self.account_tokens(
+                                func_response.tokens_used,
+                                &mut state.total_tokens,
+                                exec,
+                            )
+                            .await?;
+                            let tool_result_content = func_response
+                                .messages
+                                .first()
+                                .and_then(|m| m.content().map(|c| Value::String(c.to_string())))
+                                .unwrap_or(Value::Null);
+                            let tool_success = !tool_result_content
+                                .as_str()
+                                .map(|c| c.starts_with("Error:"))
+                                .unwrap_or(false);
+
+                            let breaker = self.get_tool_breaker(tc.function().name())?;
+                            if tool_success {
+                                let snap_before = breaker.state_snapshot();
+                                breaker.record_success();
+                                let snap_after = breaker.state_snapshot();
+                                if snap_after != snap_before {
+                                    self.emit_breaker_event(
+                                        exec.trace_id,
+                                        &breaker,
+                                        snap_after,
+                                        None,
+                                    )
+                                    .await;
+                                }
+                            } else {
+                                let snap_before = breaker.state_snapshot();
+                                let snap_after = breaker.record_failure();
+                                if snap_after != snap_before {
+                                    self.emit_breaker_event(
+                                        exec.trace_id,
+                                        &breaker,
+                                        snap_after,
+                                        Some("tool returned an error response".to_string()),
+                                    )
+                                    .await;
+                                }
+                                dead_lettered |= self.dead_letter(
+                                    state.agent.name(),
+                                    tc.function(),
+                                    tool_result_content.as_str().unwrap_or_default(),
+                                );
+                            }
+
+                            let (classification, sanitized_result) =
+                                self.sanitize_json_value(&tool_result_content);
+                            self.emit(AgentEvent::ToolResult {
+                                trace_id: exec.trace_id.clone(),
+                                tool_name: tc.function().name().to_string(),
+                                result: sanitized_result,
+                                success: tool_success,
+                                duration_ms: tool_duration_ms,
+                                timestamp: Utc::now(),
+                            })
+                            .await;
+                            record_tool_call(
+                                tc.function().name(),
+                                tool_duration_ms as f64,
+                                tool_success,
+                            );
+
+                            if let Some(content) = tool_result_content.as_str() {
+                                self.enforce_content_policy(exec.trace_id, content, "tool_result")
+                                    .await?;
+                                self.persist_memory_hook(
+                                    exec.trace_id,
+                                    &format!("tool:{}:{}", tc.function().name(), state.iterations),
+                                    content,
+                                    "tool_result",
+                                )
+                                .await;
+                            } else if let Some(cls) = classification {
+                                self.emit_guardrail_event(
+                                    exec.trace_id,
+                                    "tool_result_redaction",
+                                    "redact",
+                                    "non-string tool result redacted for audit storage",
+                                    Some(cls),
+                                )
+                                .await;
+                            }
+
+                            if let Some(trigger) = exec.escalation.record_tool_call(
+                                tc.function().name(),
+                                tool_success,
+                                &known_tools,
+                                tc.function().arguments(),
+                            ) {
+                                if let Some(reason) =
+                                    self.apply_escalation_trigger(state, exec, trigger).await?
+                                {
+                                    termination_reason = Some(reason);
+                                }
+                            }
+
+                            // Push tool result to history with tool_call_id linkage.
+                            let result_str = tool_result_content
+                                .as_str()
+                                .filter(|s| !s.is_empty())
+                                .unwrap_or("null")
+                                .to_string();
+                            state.trace_function_response(
+                                tc.function().name(),
+                                &func_response,
+                                |value| self.sanitize_text(value).1,
+                            );
+                            state
+                                .history
+                                .push(Message::tool_result(tc.id(), result_str)?);
+                            follow_ups.extend(
+                                func_response
+                                    .messages
+                                    .iter()
+                                    .skip(1)
+                                    .filter(|message| message.role() == MessageRole::User)
+                                    .cloned(),
+                            );
+                            state
+                                .context_variables
+                                .extend(func_response.context_variables);
+                            if let Some(agent) = func_response.agent {
+                                exec.budget.increment_depth();
+                                self.check_budget(exec.trace_id, exec.budget).await?;
+                                self.hand_off(state, agent)?;
+                            }
+                            if let Some(reason) = func_response.termination_reason {
+                                termination_reason = Some(reason);
+                            }
+                        }
+                        Err(err) => {
+                            let err_text = err.to_string();
+                            let breaker = self.get_tool_breaker(tc.function().name())?;
+                            let snap_before = breaker.state_snapshot();
+                            let snap_after = breaker.record_failure();
+                            if snap_after != snap_before {
+                                self.emit_breaker_event(
+                                    exec.trace_id,
+                                    &breaker,
+                                    snap_after,
+                                    Some(err_text.clone()),
+                                )
+                                .await;
+                            }
+                            self.emit(AgentEvent::ToolResult {
+                                trace_id: exec.trace_id.clone(),
+                                tool_name: tc.function().name().to_string(),
+                                result: Value::String(err_text.clone()),
+                                success: false,
+                                duration_ms: tool_duration_ms,
+                                timestamp: Utc::now(),
+                            })
+                            .await;
+                            record_tool_call(tc.function().name(), tool_duration_ms as f64, false);
+                            if let Some(trigger) = exec.escalation.record_tool_call(
+                                tc.function().name(),
+                                false,
+                                &known_tools,
+                                tc.function().arguments(),
+                            ) {
+                                if let Some(reason) =
+                                    self.apply_escalation_trigger(state, exec, trigger).await?
+                                {
+                                    termination_reason = Some(reason);
+                                }
+                            }
+                            if self.dead_letter(state.agent.name(), tc.function(), &err_text) {
+                                let (_, error_text) =
+                                    self.sanitize_text(&format!("Error: {}", err_text));
+                                state
+                                    .history
+                                    .push(Message::tool_result(tc.id(), error_text)?);
+                                dead_lettered = true;
+                            } else if batch_error.is_none() {
+                                batch_error = Some(err);
+                            }
+                        }
+                    }
+                }
+                state.history.extend(follow_ups);
+                if dead_lettered {
+                    // One retry prompt covers every failed call in the batch.
+                    state.history.push(Message::user(DEAD_LETTER_RETRY_PROMPT)?);
+                }
+                if let Some(err) = batch_error {
+                    if termination_reason.is_none() {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        Ok(Response {
+            messages: vec![message],
+            agent: Some(state.agent.clone()),
+            context_variables: state.context_variables.clone(),
+            termination_reason,
+            tokens_used,
+            handoff_history: state.handoff_history.clone(),
+            ..Response::default()
+        })
+    }
+
+    /// The messages sent with the next request under the configured history window
+    /// and the running step's context limit.
+    fn request_history<'a>(&self, state: &'a RunState) -> SwarmResult<Cow<'a, [Message]>> {
+        let strategy = self.config.history_window();
+        let mut start = strategy.window_start(&state.history);
+        let mut summaries = Vec::new();
+        if let HistoryWindowStrategy::SlidingWithOverlap { .. } = strategy {
+            summaries.extend(state.history_summary.as_ref().map(|s| s.text.as_str()));
+        }
+        if let Some(window) = &state.step_window {
+            start = start.max(window.start.min(state.history.len()));
+            summaries.extend(window.summary.as_deref());
+        }
+        if start == 0 && summaries.is_empty() {
+            return Ok(Cow::Borrowed(&state.history));
+        }
+        let mut messages = Vec::with_capacity(state.history.len() - start + summaries.len());
+        for summary in summaries {
+            messages.push(Message::system(format!(
+                "Summary of the earlier conversation:\n{}",
+                summary
+            ))?);
+        }
+        messages.extend_from_slice(&state.history[start..]);
+        Ok(Cow::Owned(messages))
+    }
+
+    /// Folds messages that have left the full window into the rolling summary.
+    ///
+    /// The summary runs ahead of the window by `overlap_summary_turns` messages, so
+    /// it is only regenerated once the window has moved past that overlap.
+    async fn refresh_history_summary(
+        &self,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<()> {
+        let strategy = self.config.history_window();
+        let HistoryWindowStrategy::SlidingWithOverlap {
+            overlap_summary_turns,
+            ..
+        } = strategy
+        else {
+            return Ok(());
+        };
+        let window_start = strategy.window_start(&state.history);
+        let summarized = state
+            .history_summary
+            .as_ref()
+            .map_or(0, |summary| summary.summarized);
+        if window_start <= summarized {
+            return Ok(());
+        }
+
+        let summarize_to = (window_start + overlap_summary_turns).min(state.history.len());
+        let text = self
+            .summarize_messages(
+                state
+                    .history_summary
+                    .as_ref()
+                    .map(|summary| summary.text.as_str()),
+                &state.history[summarized..summarize_to],
+                state.agent.model(),
+                &mut state.total_tokens,
+                exec,
+            )
+            .await?;
+        debug_print(
+            exec.options.debug,
+            &format!("Summarized {} history messages", summarize_to),
+        );
+        state.history_summary = Some(HistorySummary {
+            summarized: summarize_to,
+            text,
+        });
+        Ok(())
+    }
+
+    /// Asks the model for a summary of `messages`, extending `previous` if given.
+    async fn summarize_messages(
+        &self,
+        previous: Option<&str>,
+        messages: &[Message],
+        model: &str,
+        total_tokens: &mut u32,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<String> {
+        let mut transcript = String::new();
+        if let Some(previous) = previous {
+            transcript.push_str(&format!("Previous summary:\n{}\n\n", previous));
+        }
+        transcript.push_str("Transcript:\n");
+        for message in messages {
+            let role = message.role().as_str();
+            if let Some(content) = message.content() {
+                transcript.push_str(&format!("{}: {}\n", role, content));
+            }
+            if let Some(call) = message.function_call() {
+                transcript.push_str(&format!(
+                    "{} called {}({})\n",
+                    role,
+                    call.name(),
+                    call.arguments()
+                ));
+            }
+            for call in message.tool_calls().unwrap_or_default() {
+                transcript.push_str(&format!(
+                    "{} called {}({})\n",
+                    role,
+                    call.function().name(),
+                    call.function().arguments()
+                ));
+            }
+        }
+
+        let summarizer = Agent::new(
+            "history-summarizer",
+            exec.options.model_override.as_deref().unwrap_or(model),
+            Instructions::Text(HISTORY_SUMMARY_PROMPT.to_string()),
+        )?;
+        self.request_side_completion(
+            &summarizer,
+            &[Message::user(transcript)?],
+            &ContextVariables::new(),
+            "History summary",
+            total_tokens,
+            exec,
+        )
+        .await
+    }
+
+    /// Model picked by `task_complexity_scorer` for the first user message, if any.
+    fn model_for_task(&self, messages: &[Message]) -> Option<String> {
+        let scorer = self.config.task_complexity_scorer()?;
+        let task = messages
+            .iter()
+            .find(|message| message.role() == MessageRole::User)
+            .and_then(Message::content)?;
+        let score = scorer(task);
+        self.config.model_for_complexity(score).map(str::to_string)
+    }
+
+    /// Switches `state` to the agent returned by a function, checking the handoff
+    /// chain for cycles when `handoff_cycle_detection` is enabled. A handoff to the
+    /// current agent is not a cycle and leaves the chain unchanged.
+    fn hand_off(&self, state: &mut RunState, agent: Agent) -> SwarmResult<()> {
+        if self.config.handoff_cycle_detection() && agent.name() != state.agent.name() {
+            if state.handoff_chain.is_empty() {
+                state.handoff_chain.push(state.agent.name().to_string());
+            }
+            if state.handoff_chain.iter().any(|name| name == agent.name()) {
+                let chain = format!("{} -> {}", state.handoff_chain.join(" -> "), agent.name());
+                tracing::warn!(handoff_chain = %chain, "Circular agent handoff detected");
+                if self.config.on_circular_handoff() == CircularHandoffAction::Error {
+                    return Err(SwarmError::AgentError(format!(
+                        "circular handoff detected: {}",
+                        chain
+                    )));
+                }
+            }
+            state.handoff_chain.push(agent.name().to_string());
+        }
+        state.handoff_history.push(HandoffRecord::Agent {
+            from: state.agent.name().to_string(),
+            to: agent.name().to_string(),
+        });
+        state.trace(|| TraceEvent::AgentSelected(agent.name().to_string()));
+        if let Some(filter) = agent.handoff_context_filter() {
+            let before = state.context_variables.len();
+            state.context_variables.retain(|key, _| filter(key));
+            tracing::debug!(
+                agent = %agent.name(),
+                withheld = before - state.context_variables.len(),
+                "Filtered context variables on handoff"
+            );
+        }
+        state.agent = agent;
+        Self::apply_switched_model(state);
+        Ok(())
+    }
+
+    /// Arguments of `function_call`, repaired when they are not valid JSON and
+    /// `repair_function_arguments` is enabled. Unrepairable arguments are returned
+    /// unchanged so the usual validation error is reported.
+    fn repaired_arguments<'a>(&self, function_call: &'a FunctionCall) -> Cow<'a, str> {
+        let raw = function_call.arguments();
+        if !self.config.repair_function_arguments() || serde_json::from_str::<Value>(raw).is_ok() {
+            return Cow::Borrowed(raw);
+        }
+        match repair_json(raw) {
+            Ok(repaired) => {
+                tracing::warn!(
+                    function = %function_call.name(),
+                    original = %raw,
+                    repaired = %repaired,
+                    "Repaired malformed function call arguments"
+                );
+                Cow::Owned(repaired)
+            }
+            Err(_) => Cow::Borrowed(raw),
+        }
+    }
+
+    /// Arguments of `function_call` for `func`, repaired if enabled and checked
+    /// against its parameter schema.
+    fn function_arguments(
+        &self,
+        func: &AgentFunction,
+        function_call: &FunctionCall,
+    ) -> SwarmResult<ContextVariables> {
+        let arguments = self.repaired_arguments(function_call);
+        let invocation_args = InvocationArgs::from_json_str(&arguments)
+            .map_err(|error| SwarmError::ValidationError(error.to_string()))?;
+        invocation_args
+            .validate_against_schema(func.parameters_schema())
+            .map_err(|error| SwarmError::ValidationError(error.to_string()))?;
+        invocation_args
+            .to_context_variables()
+            .map_err(|error| SwarmError::ValidationError(error.to_string()))
+    }
+
+    /// The first argument validation error among `message`'s calls to `functions`,
+    /// with the result messages that answer every call in it. Calls to unknown
+    /// functions are left to the usual handling.
+    fn invalid_argument_results(
+        &self,
+        functions: &[AgentFunction],
+        message: &Message,
+    ) -> SwarmResult<Option<(String, Vec<Message>)>> {
+        let check = |call: &FunctionCall| {
+            let func = functions.iter().find(|func| func.name() == call.name())?;
+            self.function_arguments(func, call)
+                .err()
+                .map(|err| err.to_string())
+        };
+        if let Some(call) = message.function_call() {
+            return match check(call) {
+                Some(error) => {
+                    let result = Message::function(call.name(), format!("Error: {}", error))?;
+                    Ok(Some((error, vec![result])))
+                }
+                None => Ok(None),
+            };
+        }
+        let tool_calls = message.tool_calls().unwrap_or_default();
+        let errors = tool_calls
+            .iter()
+            .map(|call| check(call.function()))
+            .collect::<Vec<_>>();
+        let Some(first) = errors.iter().flatten().next().cloned() else {
+            return Ok(None);
+        };
+        let results = tool_calls
+            .iter()
+            .zip(errors)
+            .map(|(call, error)| {
+                let content = match error {
+                    Some(error) => format!("Error: {}", error),
+                    None => "Not run: another call in this turn had invalid arguments.".to_string(),
+                };
+                Message::tool_result(call.id(), content)
+            })
+            .collect::<SwarmResult<Vec<_>>>()?;
+        Ok(Some((first, results)))
+    }
+
+    /// Function arguments as shown in the execution trace, sanitized like event
+    /// payloads; empty when unparseable.
+    fn trace_arguments(&self, arguments: &str) -> ContextVariables {
+        let arguments = serde_json::from_str(arguments).unwrap_or(Value::Null);
+        let (_, arguments) = self.sanitize_json_value(&arguments);
+        InvocationArgs::from_value(arguments)
+            .and_then(|args| args.to_context_variables())
+            .unwrap_or_default()
+    }
+
+    /// Keeps a `switch_model` step in effect after the active agent changes.
+    fn apply_switched_model(state: &mut RunState) {
+        if let Some(model) = &state.switched_model {
+            state.agent.model = model.clone();
+        }
+    }
+
+    /// Fails unless `key` holds a non-empty value in `context_variables`.
+    fn check_step_condition(
+        context_variables: &ContextVariables,
+        kind: &str,
+        key: &str,
+        step: &Step,
+    ) -> SwarmResult<()> {
+        let satisfied = context_variables
+            .get(key)
+            .is_some_and(|value| !value.trim().is_empty());
+        if satisfied {
+            Ok(())
+        } else {
+            Err(SwarmError::ValidationError(format!(
+                "{} '{}' not met for step {}",
+                kind, key, step.number
+            )))
+        }
+    }
+
+    /// Saves a `branch` step's snapshot or applies a `merge` step's branch.
+    fn branch_or_merge(&self, state: &mut RunState, step: &Step, debug: bool) -> SwarmResult<()> {
+        if step.action == crate::types::StepAction::Branch {
+            let name = step.branch_name.as_deref().ok_or_else(|| {
+                SwarmError::ValidationError(format!(
+                    "Step {} uses branch without a branch_name",
+                    step.number
+                ))
+            })?;
+            debug_print(debug, &format!("Saving branch point '{}'", name));
+            state.branches.insert(
+                name.to_string(),
+                BranchPoint {
+                    history: state.history.clone(),
+                    context_variables: state.context_variables.clone(),
+                },
+            );
+            return Ok(());
+        }
+
+        let name = step.from_branch.as_deref().ok_or_else(|| {
+            SwarmError::ValidationError(format!(
+                "Step {} uses merge without a from_branch",
+                step.number
+            ))
+        })?;
+        let branch = state.branches.get(name).ok_or_else(|| {
+            SwarmError::ValidationError(format!(
+                "Step {} merges unknown branch '{}'",
+                step.number, name
+            ))
+        })?;
+        debug_print(debug, &format!("Merging branch '{}'", name));
+        for (key, value) in &branch.context_variables {
+            match self.config.branch_merge_strategy() {
+                BranchMergeStrategy::KeepCurrent => {
+                    state
+                        .context_variables
+                        .entry(key.clone())
+                        .or_insert_with(|| value.clone());
+                }
+                BranchMergeStrategy::PreferBranch => {
+                    state.context_variables.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a step's `assert_context` and `assert_contains` against the context.
+    fn check_step_assertions(context_variables: &ContextVariables, step: &Step) -> SwarmResult<()> {
+        let mut equals = step.assert_context.iter().collect::<Vec<_>>();
+        equals.sort();
+        for (key, expected) in equals {
+            let actual = context_variables.get(key);
+            if actual != Some(expected) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Assertion failed: expected context['{}'] == '{}', got '{:?}'",
+                    key, expected, actual
+                )));
+            }
+        }
+        for (key, expected) in &step.assert_contains {
+            let actual = context_variables.get(key);
+            if !actual.is_some_and(|value| value.contains(expected.as_str())) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Assertion failed: expected context['{}'] to contain '{}', got '{:?}'",
+                    key, expected, actual
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stores the last assistant text of a step's response under `output_var`
+    /// so later steps and function-based instructions can read it.
+    fn capture_step_output(
+        context_variables: &mut ContextVariables,
+        output_var: &str,
+        response: &Response,
+        debug: bool,
+    ) {
+        let output = response
+            .messages
+            .iter()
+            .rev()
+            .filter(|message| message.role() == MessageRole::Assistant)
+            .find_map(Message::content);
+        match output {
+            Some(content) => {
+                debug_print(
+                    debug,
+                    &format!("Captured step output into '{}'", output_var),
+                );
+                context_variables.insert(output_var.to_string(), content.to_string());
+            }
+            None => debug_print(
+                debug,
+                &format!("No assistant text to capture into '{}'", output_var),
+            ),
+        }
+    }
+
+    /// Narrows the step's request window so it and `step`'s prompt fit the step's
+    /// context limit, per `SwarmConfig::context_overflow_strategy`. The history
+    /// itself is kept whole; `request_history` applies the window.
+    async fn enforce_step_context_limit(
+        &self,
+        state: &mut RunState,
+        step: &Step,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<()> {
+        let Some(limit) = step
+            .max_context_tokens_per_step
+            .or(self.config.max_context_tokens_per_step())
+        else {
+            return Ok(());
+        };
+        let prompt_tokens = estimate_prompt_tokens(&[Message::user(step.prompt.clone())?]);
+        let window_start = self.config.history_window().window_start(&state.history);
+        let window = self.request_history(state)?;
+        let estimate = estimate_prompt_tokens(window.iter()).saturating_add(prompt_tokens);
+        // Tokens of the rolling summary `request_history` puts ahead of the window.
+        let summary_tokens =
+            estimate_prompt_tokens(&window[..window.len() - (state.history.len() - window_start)]);
+        if estimate <= limit {
+            return Ok(());
+        }
+        let strategy = self.config.context_overflow_strategy();
+        if strategy == ContextOverflowStrategy::Error {
+            return Err(SwarmError::ContextError(format!(
+                "Step {} would start with about {} prompt tokens, over its limit of {}",
+                step.number, estimate, limit
+            )));
+        }
 
-            self.check_budget(exec.trace_id, exec.budget).await?;
-            let tool_start = Instant::now();
-            let func_response = self
-                .handle_function_call(
-                    function_call,
-                    state.agent.functions(),
-                    state.context_variables.clone(),
-                    exec.options.debug,
+        // Keep the newest messages that fit, never starting on an orphaned result.
+        let budget = limit.saturating_sub(prompt_tokens.saturating_add(summary_tokens));
+        let mut start = state.history.len();
+        let mut kept_tokens = 0u32;
+        while start > window_start {
+            let tokens = estimate_prompt_tokens(&state.history[start - 1..start]);
+            if kept_tokens.saturating_add(tokens) > budget {
+                break;
+            }
+            kept_tokens += tokens;
+            start -= 1;
+        }
+        while start < state.history.len()
+            && matches!(
+                state.history[start].role(),
+                MessageRole::Function | MessageRole::Tool
+            )
+        {
+            start += 1;
+        }
+        tracing::warn!(
+            step = step.number,
+            limit,
+            estimate,
+            dropped = start - window_start,
+            ?strategy,
+            "Step context limit exceeded"
+        );
+        let summary = if strategy == ContextOverflowStrategy::Summarize && start > window_start {
+            Some(
+                self.summarize_messages(
+                    None,
+                    &state.history[window_start..start],
+                    state.agent.model(),
+                    &mut state.total_tokens,
+                    exec,
                 )
-                .await;
-            let tool_duration_ms = tool_start.elapsed().as_millis() as u64;
-            exec.budget.increment_tool_calls();
+                .await?,
+            )
+        } else {
+            None
+        };
+        state.step_window = Some(StepWindow { start, summary });
+        Ok(())
+    }
 
-            match func_response {
-                Ok(func_response) => {
-                    let tool_result_content = func_response
-                        .messages
-                        .first()
-                        .and_then(|message| {
-                            message
-                                .content()
-                                .map(|content| Value::String(content.to_string()))
-                        })
-                        .unwrap_or(Value::Null);
-                    let tool_success = !tool_result_content
-                        .as_str()
-                        .map(|content| content.starts_with("Error:"))
-                        .unwrap_or(false);
+    /// Runs one step with its precondition, output capture and postcondition,
+    /// under the step's `debug` override when it has one.
+    ///
+    /// `previous_step` is the step an `evaluate` step re-runs.
+    async fn run_step(
+        &self,
+        state: &mut RunState,
+        step: &Step,
+        previous_step: Option<&Step>,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<Response> {
+        let step_options = step
+            .debug
+            .filter(|&debug| debug != exec.options.debug)
+            .map(|debug| exec.options.clone().with_debug(debug));
+        let exec = &mut ExecutionContext {
+            trace_id: exec.trace_id,
+            options: step_options.as_ref().unwrap_or(exec.options),
+            budget: &mut *exec.budget,
+            escalation: &mut *exec.escalation,
+        };
+        if let Some(precondition) = &step.precondition {
+            Self::check_step_condition(
+                &state.context_variables,
+                "Precondition",
+                precondition,
+                step,
+            )?;
+        }
+        self.enforce_step_context_limit(state, step, exec).await?;
+        let response = self.execute_step(state, step, previous_step, exec).await;
+        state.step_window = None;
+        let response = response?;
+        if let Some(output_var) = &step.output_var {
+            Self::capture_step_output(
+                &mut state.context_variables,
+                output_var,
+                &response,
+                exec.options.debug,
+            );
+        }
+        if let Some(postcondition) = &step.postcondition {
+            Self::check_step_condition(
+                &state.context_variables,
+                "Postcondition",
+                postcondition,
+                step,
+            )?;
+        }
+        Self::check_step_assertions(&state.context_variables, step)?;
+        Ok(response)
+    }
 
-                    if tool_success {
-                        let tool_before = breaker.state_snapshot();
-                        breaker.record_success();
-                        let tool_after = breaker.state_snapshot();
-                        if tool_after != tool_before {
-                            self.emit_breaker_event(exec.trace_id, &breaker, tool_after, None)
-                                .await;
-                        }
-                    } else {
-                        let tool_before = breaker.state_snapshot();
-                        let tool_after = breaker.record_failure();
-                        if tool_after != tool_before {
-                            self.emit_breaker_event(
-                                exec.trace_id,
-                                &breaker,
-                                tool_after,
-                                Some("tool returned an error response".to_string()),
-                            )
-                            .await;
-                        }
-                    }
+    /// The system prompt sent for `agent`: its resolved instructions plus any
+    /// response language directive, in the configured `system_message_format`.
+    fn system_instructions(&self, agent: &Agent, context_variables: &ContextVariables) -> String {
+        let mut instructions = match &agent.instructions {
+            Instructions::Text(text) => text.clone(),
+            Instructions::Function(func) => func(context_variables.clone()),
+        };
+        if let Some(language) = agent.response_language() {
+            instructions.push_str(&format!(" Always respond in {}.", language));
+        }
+        self.config.system_message_format().format(&instructions)
+    }
 
-                    let (classification, sanitized_result) =
-                        self.sanitize_json_value(&tool_result_content);
-                    self.emit(AgentEvent::ToolResult {
-                        trace_id: exec.trace_id.clone(),
-                        tool_name: function_call.name().to_string(),
-                        result: sanitized_result,
-                        success: tool_success,
-                        duration_ms: tool_duration_ms,
-                        timestamp: Utc::now(),
-                    })
-                    .await;
-                    record_tool_call(function_call.name(), tool_duration_ms as f64, tool_success);
+    /// Moves each `old_key` value to its `new_key`. All old keys are taken out
+    /// before any new key is written, so renames may swap keys.
+    fn rename_context_keys(
+        context_variables: &mut ContextVariables,
+        renames: &HashMap<String, String>,
+    ) {
+        let moved = renames
+            .iter()
+            .filter_map(|(old, new)| {
+                context_variables
+                    .remove(old)
+                    .map(|value| (new.clone(), value))
+            })
+            .collect::<Vec<_>>();
+        for (new, value) in moved {
+            tracing::debug!(key = %new, "Renamed context variable on agent switch");
+            context_variables.insert(new, value);
+        }
+    }
 
-                    if let Some(content) = tool_result_content.as_str() {
-                        self.enforce_content_policy(exec.trace_id, content, "tool_result")
-                            .await?;
-                        self.persist_memory_hook(
-                            exec.trace_id,
-                            &format!("tool:{}:{}", function_call.name(), state.iterations),
-                            content,
-                            "tool_result",
-                        )
-                        .await;
-                    } else if let Some(classification) = classification {
-                        self.emit_guardrail_event(
-                            exec.trace_id,
-                            "tool_result_redaction",
-                            "redact",
-                            "non-string tool result redacted for audit storage",
-                            Some(classification),
-                        )
-                        .await;
-                    }
+    /// Replaces the most recent system message carrying the previous agent's
+    /// instructions with the current agent's. Other system messages, such as
+    /// history summaries, are left untouched.
+    fn update_system_message(&self, state: &mut RunState, previous_instructions: &str) {
+        let instructions = self.system_instructions(&state.agent, &state.context_variables);
+        let Some(message) = state.history.iter_mut().rev().find(|message| {
+            message.role() == MessageRole::System
+                && message.content().map(str::trim) == Some(previous_instructions.trim())
+        }) else {
+            return;
+        };
+        tracing::debug!(
+            agent = state.agent.name(),
+            before = message.content().map_or(0, str::len),
+            after = instructions.len(),
+            "Updated system message for agent switch"
+        );
+        message.set_content(instructions);
+    }
 
-                    if let Some(trigger) = exec.escalation.record_tool_call(
-                        function_call.name(),
-                        tool_success,
-                        &known_tools,
-                        function_call.arguments(),
-                    ) {
-                        if let Some(reason) =
-                            self.apply_escalation_trigger(state, exec, trigger).await?
-                        {
-                            termination_reason = Some(reason);
-                        }
-                    }
+    /// Executes one XML-defined step: validates it, switches agent if the step
+    /// names one, then dispatches on the step's action.
+    async fn execute_step(
+        &self,
+        state: &mut RunState,
+        step: &Step,
+        previous_step: Option<&Step>,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<Response> {
+        let needs_prompt = !matches!(
+            step.action,
+            crate::types::StepAction::SwitchModel
+                | crate::types::StepAction::Evaluate
+                | crate::types::StepAction::Branch
+                | crate::types::StepAction::Merge
+                | crate::types::StepAction::SummarizeHistory
+        );
+        if needs_prompt && step.prompt.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Step prompt cannot be empty".to_string(),
+            ));
+        }
+        if step.number == 0 {
+            return Err(SwarmError::ValidationError(
+                "Step number must be greater than 0".to_string(),
+            ));
+        }
 
-                    state.history.extend(func_response.messages);
-                    state
-                        .context_variables
-                        .extend(func_response.context_variables);
-                    if let Some(agent) = func_response.agent {
-                        exec.budget.increment_depth();
-                        self.check_budget(exec.trace_id, exec.budget).await?;
-                        state.agent = agent;
-                    }
-                    if let Some(reason) = func_response.termination_reason {
-                        termination_reason = Some(reason);
-                    }
-                }
-                Err(err) => {
-                    let tool_before = breaker.state_snapshot();
-                    let tool_after = breaker.record_failure();
-                    if tool_after != tool_before {
-                        self.emit_breaker_event(
-                            exec.trace_id,
-                            &breaker,
-                            tool_after,
-                            Some(err.to_string()),
-                        )
-                        .await;
-                    }
-                    self.emit(AgentEvent::ToolResult {
-                        trace_id: exec.trace_id.clone(),
-                        tool_name: function_call.name().to_string(),
-                        result: Value::String(err.to_string()),
-                        success: false,
-                        duration_ms: tool_duration_ms,
-                        timestamp: Utc::now(),
-                    })
-                    .await;
-                    record_tool_call(function_call.name(), tool_duration_ms as f64, false);
-                    if let Some(trigger) = exec.escalation.record_tool_call(
-                        function_call.name(),
-                        false,
-                        &known_tools,
-                        function_call.arguments(),
-                    ) {
-                        if let Some(reason) =
-                            self.apply_escalation_trigger(state, exec, trigger).await?
-                        {
-                            return Ok(Response {
-                                messages: vec![message],
-                                agent: Some(state.agent.clone()),
-                                context_variables: state.context_variables.clone(),
-                                termination_reason: Some(reason),
-                                tokens_used,
-                            });
-                        }
-                    }
-                    return Err(err);
-                }
+        debug_print(
+            exec.options.debug,
+            &format!("Executing Step {}", step.number),
+        );
+
+        if let Some(agent_name) = &step.agent {
+            debug_print(
+                exec.options.debug,
+                &format!("Switching to agent: {}", agent_name),
+            );
+            let previous_instructions = self
+                .config
+                .update_system_message_on_switch()
+                .then(|| self.system_instructions(&state.agent, &state.context_variables));
+            state.agent = self.get_agent_by_name(agent_name)?;
+            Self::apply_switched_model(state);
+            if let Some(previous_instructions) = previous_instructions {
+                self.update_system_message(state, &previous_instructions);
             }
-        } else if let Some(tool_calls) = message.tool_calls() {
-            if !tool_calls.is_empty() {
-                // Snapshot immutable state before any await point or mutation of `state`.
-                // Cloning here avoids holding borrows of state.agent / state.context_variables
-                // across await points, which the borrow checker would reject.
-                let functions_snapshot = state.agent.functions().to_vec();
-                let ctx_snapshot = state.context_variables.clone();
-                let execution_mode = state.agent.tool_call_execution();
-                // known_tools borrows from functions_snapshot (local), not state.agent.
-                let known_tools: Vec<&str> = functions_snapshot.iter().map(|f| f.name()).collect();
+            if let Some(renames) = self.config.context_rename_on_switch().get(agent_name) {
+                Self::rename_context_keys(&mut state.context_variables, renames);
+            }
+            exec.budget.increment_depth();
+            self.check_budget(exec.trace_id, exec.budget).await?;
+        }
 
-                // Pre-execution: circuit breaker check per call
-                for tc in tool_calls {
-                    let breaker = self.get_tool_breaker(tc.function().name())?;
-                    let snap_before = breaker.state_snapshot();
-                    let is_open = breaker.is_open();
-                    let snap_after = breaker.state_snapshot();
-                    if snap_after != snap_before {
-                        self.emit_breaker_event(exec.trace_id, &breaker, snap_after.clone(), None)
-                            .await;
+        match step.action {
+            crate::types::StepAction::SwitchModel => {
+                let new_model = step.new_model.as_deref().ok_or_else(|| {
+                    SwarmError::ValidationError(format!(
+                        "Step {} uses switch_model without a new_model",
+                        step.number
+                    ))
+                })?;
+                let new_model = ModelId::new(
+                    self.config.resolve_model_alias(new_model),
+                    self.config.valid_model_prefixes(),
+                )?;
+                let from =
+                    std::mem::replace(&mut state.agent.model, new_model.as_str().to_string());
+                debug_print(
+                    exec.options.debug,
+                    &format!("Switching model: {} -> {}", from, new_model.as_str()),
+                );
+                if exec.options.model_override.is_some() {
+                    tracing::warn!(
+                        step = step.number,
+                        "switch_model has no effect on requests while a model override is set"
+                    );
+                }
+                state.switched_model = Some(new_model.as_str().to_string());
+                state.handoff_history.push(HandoffRecord::Model {
+                    step: step.number,
+                    from,
+                    to: new_model.as_str().to_string(),
+                });
+                Ok(Response::from_state(state, None))
+            }
+            crate::types::StepAction::Evaluate => {
+                self.evaluate_step(state, step, previous_step, exec).await
+            }
+            crate::types::StepAction::Branch | crate::types::StepAction::Merge => {
+                self.branch_or_merge(state, step, exec.options.debug)?;
+                Ok(Response {
+                    branches: state.branches.clone(),
+                    ..Response::from_state(state, None)
+                })
+            }
+            crate::types::StepAction::SummarizeHistory => {
+                self.summarize_history_step(state, step, exec).await?;
+                Ok(Response::from_state(state, None))
+            }
+            crate::types::StepAction::RunOnce => {
+                state.step_turns += 1;
+                let prompt =
+                    Message::user(Self::render_step_prompt(step, &state.context_variables)?)?;
+                state.trace(|| TraceEvent::MessageSent(prompt.clone()));
+                state.history.push(prompt);
+                let response = self.single_execution(state, exec).await?;
+                self.persist_iteration_state(exec.trace_id, state).await;
+                let retried = self.retry_short_response(state, step, exec).await?;
+                Ok(retried.unwrap_or(response))
+            }
+            crate::types::StepAction::Loop => {
+                let remaining = state.max_turns.saturating_sub(state.step_turns);
+                let step_limit = step
+                    .max_turns_per_step
+                    .or(exec.options.max_turns_per_step)
+                    .filter(|limit| *limit < remaining);
+                let mut loop_iterations = 0usize;
+                let termination_reason = loop {
+                    if let Some(limit) = step_limit {
+                        if loop_iterations >= limit {
+                            // Hand control to the next step rather than failing the run.
+                            tracing::warn!(
+                                step = step.number,
+                                max_turns_per_step = limit,
+                                "Loop step reached its turn limit"
+                            );
+                            break None;
+                        }
+                    } else if loop_iterations >= remaining {
+                        return Err(SwarmError::MaxIterationsError {
+                            max: state.max_turns,
+                            actual: state.step_turns,
+                            last_response: None,
+                        });
                     }
-                    if is_open {
-                        return Err(SwarmError::Other(format!(
-                            "Tool circuit breaker '{}' is open",
-                            breaker.name()
-                        )));
+                    loop_iterations += 1;
+                    state.step_turns += 1;
+                    let prompt =
+                        Message::user(Self::render_step_prompt(step, &state.context_variables)?)?;
+                    state.trace(|| TraceEvent::MessageSent(prompt.clone()));
+                    state.history.push(prompt);
+                    let response = self.single_execution(state, exec).await?;
+                    self.persist_iteration_state(exec.trace_id, state).await;
+                    if let Some(reason) = response.termination_reason {
+                        debug_print(exec.options.debug, &format!("Loop terminated: {}", reason));
+                        break Some(reason);
                     }
-                }
-
-                // Emit ToolCall events
-                for tc in tool_calls {
-                    let arguments =
-                        serde_json::from_str(tc.function().arguments()).unwrap_or(Value::Null);
-                    let (_, sanitized_arguments) = self.sanitize_json_value(&arguments);
-                    self.emit(AgentEvent::ToolCall {
-                        trace_id: exec.trace_id.clone(),
-                        tool_name: tc.function().name().to_string(),
-                        arguments: sanitized_arguments,
-                        timestamp: Utc::now(),
-                    })
-                    .await;
-                }
-
-                self.check_budget(exec.trace_id, exec.budget).await?;
-
-                let tool_start = Instant::now();
-                let batch_results = match execution_mode {
-                    ToolCallExecution::Parallel => {
-                        self.handle_tool_calls_parallel(
-                            tool_calls,
-                            &functions_snapshot,
-                            &ctx_snapshot,
+                };
+                self.retry_short_response(state, step, exec).await?;
+                Ok(Response::from_state(state, termination_reason))
+            }
+            crate::types::StepAction::RepeatUntil => {
+                let pattern = step.until_pattern.as_deref().unwrap_or_default();
+                let until = Regex::new(pattern).map_err(|err| {
+                    SwarmError::ValidationError(format!(
+                        "Step {} has an invalid until_pattern: {}",
+                        step.number, err
+                    ))
+                })?;
+                let remaining = state.max_turns.saturating_sub(state.step_turns);
+                let max_iterations = step
+                    .max_turns_per_step
+                    .or(exec.options.max_turns_per_step)
+                    .map_or(remaining, |limit| limit.min(remaining));
+                let mut last_content = String::new();
+                for _ in 0..max_iterations {
+                    state.step_turns += 1;
+                    let prompt =
+                        Message::user(Self::render_step_prompt(step, &state.context_variables)?)?;
+                    state.trace(|| TraceEvent::MessageSent(prompt.clone()));
+                    state.history.push(prompt);
+                    let response = self.single_execution(state, exec).await?;
+                    self.persist_iteration_state(exec.trace_id, state).await;
+                    last_content = state
+                        .history
+                        .iter()
+                        .rev()
+                        .find(|message| message.role() == MessageRole::Assistant)
+                        .and_then(Message::content)
+                        .unwrap_or_default()
+                        .to_string();
+                    if until.is_match(&last_content) {
+                        debug_print(
                             exec.options.debug,
-                        )
-                        .await
+                            &format!("repeat_until matched: {}", pattern),
+                        );
+                        return Ok(Response::from_state(state, None));
                     }
-                    ToolCallExecution::Serial => {
-                        self.handle_tool_calls_serial(
-                            tool_calls,
-                            &functions_snapshot,
-                            &ctx_snapshot,
-                            exec.options.debug,
-                        )
-                        .await
+                    if let Some(reason) = response.termination_reason {
+                        return Ok(Response::from_state(state, Some(reason)));
                     }
-                };
-                let tool_duration_ms = tool_start.elapsed().as_millis() as u64;
-                let mut batch_error = None;
-
-                for outcome in batch_results {
-                    let tc = outcome.tool_call;
-                    exec.budget.increment_tool_calls();
-                    match outcome.response {
-                        Ok(func_response) => {
-                            let tool_result_content = func_response
-                                .messages
-                                .first()
-                                .and_then(|m| m.content().map(|c| Value::String(c.to_string())))
-                                .unwrap_or(Value::Null);
-                            let tool_success = !tool_result_content
-                                .as_str()
-                                .map(|c| c.starts_with("Error:"))
-                                .unwrap_or(false);
+                }
+                let last_response = safe_truncate(&last_content, 200);
+                tracing::warn!(
+                    step = step.number,
+                    until_pattern = pattern,
+                    last_response = %last_response,
+                    "repeat_until step ran out of iterations without a match"
+                );
+                Err(SwarmError::MaxIterationsError {
+                    max: max_iterations,
+                    actual: max_iterations,
+                    last_response: Some(last_response),
+                })
+            }
+        }
+    }
 
-                            let breaker = self.get_tool_breaker(tc.function().name())?;
-                            if tool_success {
-                                let snap_before = breaker.state_snapshot();
-                                breaker.record_success();
-                                let snap_after = breaker.state_snapshot();
-                                if snap_after != snap_before {
-                                    self.emit_breaker_event(
-                                        exec.trace_id,
-                                        &breaker,
-                                        snap_after,
-                                        None,
-                                    )
-                                    .await;
-                                }
-                            } else {
-                                let snap_before = breaker.state_snapshot();
-                                let snap_after = breaker.record_failure();
-                                if snap_after != snap_before {
-                                    self.emit_breaker_event(
-                                        exec.trace_id,
-                                        &breaker,
-                                        snap_after,
-                                        Some("tool returned an error response".to_string()),
-                                    )
-                                    .await;
-                                }
-                            }
+    /// The step's prompt with `{{key}}` placeholders filled from the context.
+    fn render_step_prompt(
+        step: &Step,
+        context_variables: &ContextVariables,
+    ) -> SwarmResult<String> {
+        if step.template_strict {
+            let missing = unresolved_placeholders(&step.prompt, context_variables);
+            if !missing.is_empty() {
+                return Err(SwarmError::XmlError(format!(
+                    "Step {} prompt has unresolved placeholders: {}",
+                    step.number,
+                    missing.join(", ")
+                )));
+            }
+        }
+        let values: Vec<(&str, &str)> = context_variables
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+        Ok(fill_template(&step.prompt, &values))
+    }
 
-                            let (classification, sanitized_result) =
-                                self.sanitize_json_value(&tool_result_content);
-                            self.emit(AgentEvent::ToolResult {
-                                trace_id: exec.trace_id.clone(),
-                                tool_name: tc.function().name().to_string(),
-                                result: sanitized_result,
-                                success: tool_success,
-                                duration_ms: tool_duration_ms,
-                                timestamp: Utc::now(),
-                            })
-                            .await;
-                            record_tool_call(
-                                tc.function().name(),
-                                tool_duration_ms as f64,
-                                tool_success,
-                            );
+    /// Asks the current agent to summarize the whole history, then replaces it with a
+    /// leading system message (if any), `Summary: {summary}` and the step's
+    /// `preserve_last` newest messages, never starting those on an orphaned result.
+    async fn summarize_history_step(
+        &self,
+        state: &mut RunState,
+        step: &Step,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<()> {
+        let system = state
+            .history
+            .first()
+            .filter(|message| message.role() == MessageRole::System)
+            .cloned();
+        let conversation_start = usize::from(system.is_some());
+        if state.history.len() <= conversation_start {
+            debug_print(exec.options.debug, "No conversation to summarize");
+            return Ok(());
+        }
+        let mut tail_start = state
+            .history
+            .len()
+            .saturating_sub(step.preserve_last.unwrap_or(0))
+            .max(conversation_start);
+        while tail_start < state.history.len()
+            && matches!(
+                state.history[tail_start].role(),
+                MessageRole::Function | MessageRole::Tool
+            )
+        {
+            tail_start += 1;
+        }
 
-                            if let Some(content) = tool_result_content.as_str() {
-                                self.enforce_content_policy(exec.trace_id, content, "tool_result")
-                                    .await?;
-                                self.persist_memory_hook(
-                                    exec.trace_id,
-                                    &format!("tool:{}:{}", tc.function().name(), state.iterations),
-                                    content,
-                                    "tool_result",
-                                )
-                                .await;
-                            } else if let Some(cls) = classification {
-                                self.emit_guardrail_event(
-                                    exec.trace_id,
-                                    "tool_result_redaction",
-                                    "redact",
-                                    "non-string tool result redacted for audit storage",
-                                    Some(cls),
-                                )
-                                .await;
-                            }
+        let prompt = match step.prompt.trim() {
+            "" => SUMMARIZE_HISTORY_PROMPT,
+            prompt => prompt,
+        };
+        let mut request = state.history.clone();
+        request.push(Message::user(prompt)?);
+        let summary = self
+            .request_side_completion(
+                &state.agent,
+                &request,
+                &state.context_variables,
+                &format!("Step {} summary", step.number),
+                &mut state.total_tokens,
+                exec,
+            )
+            .await?;
 
-                            if let Some(trigger) = exec.escalation.record_tool_call(
-                                tc.function().name(),
-                                tool_success,
-                                &known_tools,
-                                tc.function().arguments(),
-                            ) {
-                                if let Some(reason) =
-                                    self.apply_escalation_trigger(state, exec, trigger).await?
-                                {
-                                    termination_reason = Some(reason);
-                                }
-                            }
+        let tail = state.history.split_off(tail_start);
+        debug_print(
+            exec.options.debug,
+            &format!(
+                "Summarized {} messages, keeping {}",
+                state.history.len() - conversation_start,
+                tail.len()
+            ),
+        );
+        state.history = system
+            .into_iter()
+            .chain([Message::system(format!("Summary: {}", summary))?])
+            .chain(tail)
+            .collect();
+        // The rolling window summary referred to messages that no longer exist.
+        state.history_summary = None;
+        Ok(())
+    }
 
-                            // Push tool result to history with tool_call_id linkage.
-                            let result_str = tool_result_content
-                                .as_str()
-                                .filter(|s| !s.is_empty())
-                                .unwrap_or("null")
-                                .to_string();
-                            state
-                                .history
-                                .push(Message::tool_result(tc.id(), result_str)?);
-                            state
-                                .context_variables
-                                .extend(func_response.context_variables);
-                            if let Some(agent) = func_response.agent {
-                                exec.budget.increment_depth();
-                                self.check_budget(exec.trace_id, exec.budget).await?;
-                                state.agent = agent;
-                            }
-                            if let Some(reason) = func_response.termination_reason {
-                                termination_reason = Some(reason);
-                            }
-                        }
-                        Err(err) => {
-                            let err_text = err.to_string();
-                            let breaker = self.get_tool_breaker(tc.function().name())?;
-                            let snap_before = breaker.state_snapshot();
-                            let snap_after = breaker.record_failure();
-                            if snap_after != snap_before {
-                                self.emit_breaker_event(
-                                    exec.trace_id,
-                                    &breaker,
-                                    snap_after,
-                                    Some(err_text.clone()),
-                                )
-                                .await;
-                            }
-                            self.emit(AgentEvent::ToolResult {
-                                trace_id: exec.trace_id.clone(),
-                                tool_name: tc.function().name().to_string(),
-                                result: Value::String(err_text.clone()),
-                                success: false,
-                                duration_ms: tool_duration_ms,
-                                timestamp: Utc::now(),
-                            })
-                            .await;
-                            record_tool_call(tc.function().name(), tool_duration_ms as f64, false);
-                            if let Some(trigger) = exec.escalation.record_tool_call(
-                                tc.function().name(),
-                                false,
-                                &known_tools,
-                                tc.function().arguments(),
-                            ) {
-                                if let Some(reason) =
-                                    self.apply_escalation_trigger(state, exec, trigger).await?
-                                {
-                                    termination_reason = Some(reason);
-                                }
-                            }
-                            if batch_error.is_none() {
-                                batch_error = Some(err);
-                            }
-                        }
-                    }
-                }
-                if let Some(err) = batch_error {
-                    if termination_reason.is_none() {
-                        return Err(err);
-                    }
-                }
+    /// Re-asks for a longer reply while the last assistant message is shorter than the
+    /// step's `min_response_length`, stopping early when the run is out of turns.
+    /// Returns the last retry's response, if any.
+    async fn retry_short_response(
+        &self,
+        state: &mut RunState,
+        step: &Step,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<Option<Response>> {
+        let Some(min_length) = step.min_response_length else {
+            return Ok(None);
+        };
+        let max_retries = step
+            .short_response_max_retries
+            .unwrap_or(DEFAULT_SHORT_RESPONSE_MAX_RETRIES);
+        let mut retried = None;
+        for _ in 0..max_retries {
+            let length = state
+                .history
+                .iter()
+                .rev()
+                .find(|message| message.role() == MessageRole::Assistant)
+                .and_then(Message::content)
+                .map_or(0, |content| content.trim().chars().count());
+            if length >= min_length {
+                break;
+            }
+            if state.step_turns >= state.max_turns {
+                tracing::warn!(
+                    step = step.number,
+                    "No turns left to retry a too-short step response"
+                );
+                break;
             }
+            debug_print(
+                exec.options.debug,
+                &format!(
+                    "Step {} response has {} characters, fewer than {}; retrying",
+                    step.number, length, min_length
+                ),
+            );
+            state.step_turns += 1;
+            let prompt = Message::user(SHORT_RESPONSE_RETRY_PROMPT)?;
+            state.trace(|| TraceEvent::MessageSent(prompt.clone()));
+            state.history.push(prompt);
+            retried = Some(self.single_execution(state, exec).await?);
+            self.persist_iteration_state(exec.trace_id, state).await;
         }
-
-        Ok(Response {
-            messages: vec![message],
-            agent: Some(state.agent.clone()),
-            context_variables: state.context_variables.clone(),
-            termination_reason,
-            tokens_used,
-        })
+        Ok(retried)
     }
 
-    /// Executes a step based on the provided XML-defined step.
-    async fn execute_step(
+    /// Scores the last assistant message with the step's evaluator, re-running
+    /// `previous_step` with the evaluator's feedback while the score is below
+    /// `min_score`. Each score is stored as `__step_{number}_score`.
+    async fn evaluate_step(
         &self,
         state: &mut RunState,
         step: &Step,
+        previous_step: Option<&Step>,
         exec: &mut ExecutionContext<'_>,
     ) -> SwarmResult<Response> {
-        if step.prompt.trim().is_empty() {
-            return Err(SwarmError::ValidationError(
-                "Step prompt cannot be empty".to_string(),
-            ));
-        }
-        if step.number == 0 {
-            return Err(SwarmError::ValidationError(
-                "Step number must be greater than 0".to_string(),
-            ));
-        }
-
-        debug_print(
-            exec.options.debug,
-            &format!("Executing Step {}", step.number),
-        );
-
-        if let Some(agent_name) = &step.agent {
+        let evaluator_name = step.evaluator_agent.as_deref().ok_or_else(|| {
+            SwarmError::ValidationError(format!(
+                "Step {} uses evaluate without an evaluator_agent",
+                step.number
+            ))
+        })?;
+        let evaluator = self.get_agent_by_name(evaluator_name)?;
+        let max_retry = step.max_retry.unwrap_or(DEFAULT_EVALUATE_MAX_RETRY);
+        let score_key = format!("__step_{}_score", step.number);
+        let mut retries = 0usize;
+        loop {
+            let output = state
+                .history
+                .iter()
+                .rev()
+                .find(|message| message.role() == MessageRole::Assistant)
+                .and_then(Message::content)
+                .ok_or_else(|| {
+                    SwarmError::ValidationError(format!(
+                        "Step {} has no assistant output to evaluate",
+                        step.number
+                    ))
+                })?
+                .to_string();
+            let (score, feedback) = self
+                .request_evaluation(&evaluator, step, &output, state, exec)
+                .await?;
             debug_print(
                 exec.options.debug,
-                &format!("Switching to agent: {}", agent_name),
+                &format!("Step {} scored {}: {}", step.number, score, feedback),
             );
-            state.agent = self.get_agent_by_name(agent_name)?;
-            exec.budget.increment_depth();
-            self.check_budget(exec.trace_id, exec.budget).await?;
-        }
+            state
+                .context_variables
+                .insert(score_key.clone(), score.to_string());
+            state.trace(|| TraceEvent::ContextUpdated(score_key.clone(), score.to_string()));
 
-        match step.action {
-            crate::types::StepAction::RunOnce => {
-                state.history.push(Message::user(step.prompt.clone())?);
-                let response = self.single_execution(state, exec).await?;
-                self.persist_iteration_state(exec.trace_id, state).await;
-                Ok(response)
-            }
-            crate::types::StepAction::Loop => {
-                let mut loop_iterations = 0usize;
-                let termination_reason = loop {
-                    if loop_iterations >= exec.options.max_turns {
-                        return Err(SwarmError::MaxIterationsError {
-                            max: exec.options.max_turns,
-                            actual: loop_iterations,
-                        });
-                    }
-                    loop_iterations += 1;
-                    state.history.push(Message::user(step.prompt.clone())?);
-                    let response = self.single_execution(state, exec).await?;
-                    self.persist_iteration_state(exec.trace_id, state).await;
-                    if let Some(reason) = response.termination_reason {
-                        debug_print(exec.options.debug, &format!("Loop terminated: {}", reason));
-                        break Some(reason);
-                    }
-                };
-                Ok(Response {
-                    messages: state.history.clone(),
-                    agent: Some(state.agent.clone()),
-                    context_variables: state.context_variables.clone(),
-                    termination_reason,
-                    tokens_used: state.total_tokens,
-                })
+            let Some(min_score) = step.min_score.filter(|min_score| score < *min_score) else {
+                break;
+            };
+            if retries >= max_retry {
+                tracing::warn!(
+                    step = step.number,
+                    score,
+                    min_score,
+                    "Accepting output below min_score after max_retry re-runs"
+                );
+                break;
             }
+            let previous_step = previous_step.ok_or_else(|| {
+                SwarmError::ValidationError(format!(
+                    "Step {} has no previous step to re-run",
+                    step.number
+                ))
+            })?;
+            retries += 1;
+            let feedback = if feedback.trim().is_empty() {
+                format!(
+                    "Your answer scored {} but at least {} is required. Improve it.",
+                    score, min_score
+                )
+            } else {
+                feedback
+            };
+            let feedback = Message::user(feedback)?;
+            state.trace(|| TraceEvent::MessageSent(feedback.clone()));
+            state.history.push(feedback);
+            Box::pin(self.run_step(state, previous_step, None, exec)).await?;
+        }
+
+        Ok(Response::from_state(state, None))
+    }
+
+    /// Asks `evaluator` to score `output`, returning the score and feedback.
+    async fn request_evaluation(
+        &self,
+        evaluator: &Agent,
+        step: &Step,
+        output: &str,
+        state: &mut RunState,
+        exec: &mut ExecutionContext<'_>,
+    ) -> SwarmResult<(f32, String)> {
+        let mut prompt = STEP_EVALUATION_PROMPT.to_string();
+        if !step.prompt.trim().is_empty() {
+            prompt.push_str(&format!("\n\nCriteria: {}", step.prompt.trim()));
+        }
+        prompt.push_str(&format!("\n\nOutput:\n{}", output));
+        let reply = self
+            .request_side_completion(
+                evaluator,
+                &[Message::user(prompt)?],
+                &state.context_variables,
+                &format!("Step {} evaluation", step.number),
+                &mut state.total_tokens,
+                exec,
+            )
+            .await?;
+        // Tolerate prose or code fences around the JSON object.
+        let json = match (reply.find('{'), reply.rfind('}')) {
+            (Some(start), Some(end)) if start < end => &reply[start..=end],
+            _ => reply.as_str(),
+        };
+        let evaluation: StepEvaluation = serde_json::from_str(json).map_err(|e| {
+            SwarmError::DeserializationError(format!(
+                "Evaluator '{}' returned an invalid evaluation for step {}: {}",
+                evaluator.name(),
+                step.number,
+                e
+            ))
+        })?;
+        if !evaluation.score.is_finite() {
+            return Err(SwarmError::ValidationError(format!(
+                "Evaluator '{}' returned a non-finite score",
+                evaluator.name()
+            )));
         }
+        Ok((evaluation.score, evaluation.feedback))
     }
 
     /// Executes a multi-turn conversation with the AI agent.
     #[allow(clippy::too_many_arguments)]
     pub async fn run(
         &self,
-        mut agent: Agent,
+        agent: Agent,
         messages: Vec<Message>,
         context_variables: ContextVariables,
         model_override: Option<String>,
@@ -2471,23 +5804,79 @@ impl Swarm {
         debug: bool,
         max_turns: usize,
     ) -> SwarmResult<Response> {
-        validate_api_request(&agent, &messages, &model_override, max_turns)?;
+        let mut options = RunOptions::new(max_turns)
+            .with_stream(stream)
+            .with_debug(debug);
+        options.model_override = model_override;
+        self.run_with_options(agent, messages, context_variables, options)
+            .await
+    }
+
+    /// Executes a multi-turn conversation with the AI agent using explicit [`RunOptions`].
+    pub async fn run_with_options(
+        &self,
+        mut agent: Agent,
+        messages: Vec<Message>,
+        mut context_variables: ContextVariables,
+        mut options: RunOptions,
+    ) -> SwarmResult<Response> {
+        options.api_calls = Arc::default();
+        let routed_model = if options.model_override.is_none() {
+            self.model_for_task(&messages)
+        } else {
+            None
+        };
+        // A routed model goes through the same checks as an override.
+        validate_api_request_with_config(
+            &agent,
+            &messages,
+            &options.priming_messages,
+            &options
+                .model_override
+                .clone()
+                .or_else(|| routed_model.clone()),
+            options.max_turns,
+            &self.config,
+        )?;
+        if options
+            .cot_prefix
+            .as_ref()
+            .is_some_and(|prefix| prefix.trim().is_empty())
+        {
+            return Err(SwarmError::ValidationError(
+                "cot_prefix cannot be empty".to_string(),
+            ));
+        }
+        if options.max_turns_per_step == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "max_turns_per_step must be greater than 0".to_string(),
+            ));
+        }
+
+        if let ErrorRecoveryStrategy::FallbackAgent(name) = &options.error_recovery_strategy {
+            self.get_agent_by_name(name)?;
+        }
+        if let Some(model) = routed_model {
+            debug_print(
+                options.debug,
+                &format!("Routing task to model {} by complexity", model),
+            );
+            agent.model = model;
+        }
 
-        if max_turns > self.config.max_loop_iterations() as usize {
+        if options.max_turns > self.config.max_loop_iterations() as usize {
             return Err(SwarmError::ValidationError(format!(
                 "max_turns ({}) exceeds configured max_loop_iterations ({})",
-                max_turns,
+                options.max_turns,
                 self.config.max_loop_iterations()
             )));
         }
 
+        for (key, value) in inject_context(self.config.context_injectors())? {
+            context_variables.entry(key).or_insert(value);
+        }
+
         let trace_id = TraceId::from(uuid::Uuid::new_v4().to_string());
-        let options = RunOptions {
-            model_override,
-            stream,
-            debug,
-            max_turns,
-        };
 
         self.create_session_if_configured(&trace_id, agent.name())
             .await;
@@ -2498,16 +5887,38 @@ impl Swarm {
         })
         .await;
 
+        if agent.inject_metadata_to_context() {
+            for (key, value) in agent.metadata() {
+                let value = serde_json::to_string(value)?;
+                context_variables
+                    .entry(format!("__agent_{}", key))
+                    .or_insert(value);
+            }
+        }
+
         let instructions = match &agent.instructions {
             Instructions::Text(text) => text.clone(),
             Instructions::Function(func) => func(context_variables.clone()),
         };
-        let (instructions_without_xml, xml_steps) = extract_xml_steps(&instructions)?;
-        let steps = if let Some(xml_content) = xml_steps {
-            parse_steps_from_xml(&xml_content)?
+        let (instructions_without_xml, xml_steps) =
+            extract_namespaced_xml_steps(&instructions, self.config.xml_namespace())?;
+        let mut steps = if let Some(xml_content) = xml_steps {
+            let base_dir = options.steps_base_dir().unwrap_or(Path::new("."));
+            let xml_content = resolve_xml_includes_with_encoding(
+                &xml_content,
+                base_dir,
+                self.config.xml_encoding(),
+            )?;
+            let xml_content = self.strip_xml_namespace(xml_content);
+            self.steps_parser.parse(&xml_content)?
         } else {
             Steps { steps: Vec::new() }
         };
+        self.fit_max_turns_to_steps(
+            &mut options.max_turns,
+            steps.steps.len(),
+            options.auto_adjust_max_turns,
+        )?;
 
         // If the entire instructions block was XML steps, fall back to a minimal
         // system prompt rather than producing an empty string that fails validation.
@@ -2524,7 +5935,36 @@ impl Swarm {
             context_variables,
             iterations: 0,
             total_tokens: 0,
+            step_turns: 0,
+            max_turns: options.max_turns,
+            handoff_chain: Vec::new(),
+            history_summary: None,
+            step_window: None,
+            handoff_history: Vec::new(),
+            switched_model: None,
+            profiler: options.profiling_enabled.then(Profiler::new),
+            turn_metadata: Vec::new(),
+            turn_prompt_tokens: 0,
+            turn_completion_tokens: 0,
+            turn_logprobs: None,
+            turn_prompt_compression: None,
+            trace: options.execution_trace.then(Vec::new),
+            context_snapshots: VecDeque::new(),
+            branches: HashMap::new(),
+            system_fingerprint: None,
         };
+        if let Some(trace) = state.trace.as_mut() {
+            trace.push(TraceEntry::now(TraceEvent::AgentSelected(
+                state.agent.name().to_string(),
+            )));
+            trace.extend(
+                state
+                    .history
+                    .iter()
+                    .filter(|message| message.role() == MessageRole::User)
+                    .map(|message| TraceEntry::now(TraceEvent::MessageSent(message.clone()))),
+            );
+        }
         let mut budget = BudgetEnforcer::new(self.config.runtime_limits().clone());
         let mut escalation = EscalationDetector::new(self.escalation_config.clone());
         let mut exec = ExecutionContext {
@@ -2545,9 +5985,108 @@ impl Swarm {
             }
 
             let mut termination_reason = None;
+            let mut injections = 0usize;
+            self.inject_dynamic_steps(&mut state, &mut steps, &mut injections, exec.options)?;
             if !steps.steps.is_empty() {
-                for step in &steps.steps {
-                    let response = self.execute_step(&mut state, step, &mut exec).await?;
+                let mut skips = 0usize;
+                let mut index = 0usize;
+                while index < steps.steps.len() {
+                    let step = &steps.steps[index];
+                    let mut retries = 0usize;
+                    let mut fell_back = false;
+                    state.trace(|| TraceEvent::StepStarted(step.number, step.action.to_string()));
+                    let response = loop {
+                        let history_len = state.history.len();
+                        let context_variables = state.context_variables.clone();
+                        let step_turns = state.step_turns;
+                        let timer = state.start_span(ProfileSpanKind::Step {
+                            number: step.number,
+                        });
+                        let previous_step = index.checked_sub(1).map(|i| &steps.steps[i]);
+                        let step_result = self
+                            .run_step(&mut state, step, previous_step, &mut exec)
+                            .await;
+                        state.end_span(timer);
+                        let error = match step_result {
+                            Ok(response) => {
+                                state.trace(|| TraceEvent::StepCompleted(step.number));
+                                let prefix = self.config.ephemeral_key_prefix();
+                                state
+                                    .context_variables
+                                    .retain(|key, _| !key.starts_with(prefix));
+                                if self.config.context_snapshot_interval()
+                                    == ContextSnapshotInterval::AfterEachStep
+                                {
+                                    state.snapshot_context(
+                                        format!("step {}", step.number),
+                                        self.config.max_context_snapshots(),
+                                    );
+                                }
+                                break Some(response);
+                            }
+                            Err(error) => error,
+                        };
+                        let action = match &options.error_recovery_strategy {
+                            ErrorRecoveryStrategy::SkipStep { max_skips } if skips < *max_skips => {
+                                skips += 1;
+                                StepRecoveryAction::Skip
+                            }
+                            ErrorRecoveryStrategy::RetryStep { max_retries }
+                                if retries < *max_retries && error.is_retriable() =>
+                            {
+                                retries += 1;
+                                StepRecoveryAction::Retry
+                            }
+                            ErrorRecoveryStrategy::FallbackAgent(name) if !fell_back => {
+                                fell_back = true;
+                                state.agent = self.get_agent_by_name(name)?;
+                                state.trace(|| TraceEvent::AgentSelected(name.clone()));
+                                StepRecoveryAction::Fallback
+                            }
+                            _ => return Err(error),
+                        };
+                        // Undo the failed attempt: its prompt, partial replies, context
+                        // writes and counted turns.
+                        state.history.truncate(history_len);
+                        state.context_variables = context_variables;
+                        state.step_turns = step_turns;
+                        tracing::warn!(
+                            step = step.number,
+                            %action,
+                            error = %error,
+                            "Recovering from failed step"
+                        );
+                        let agent_name = if action == StepRecoveryAction::Skip {
+                            String::new()
+                        } else {
+                            state.agent.name().to_string()
+                        };
+                        self.emit(AgentEvent::StepRecovery {
+                            trace_id: trace_id.clone(),
+                            step_number: step.number,
+                            action,
+                            error: error.to_string(),
+                            agent_name,
+                            timestamp: Utc::now(),
+                        })
+                        .await;
+                        if action == StepRecoveryAction::Skip {
+                            if let Some(callback) = &options.on_step_failure {
+                                callback(index, step, &error).await;
+                            }
+                            break None;
+                        }
+                    };
+                    index += 1;
+                    self.inject_dynamic_steps(
+                        &mut state,
+                        &mut steps,
+                        &mut injections,
+                        exec.options,
+                    )?;
+                    let Some(response) = response else {
+                        continue;
+                    };
                     if let Some(reason) = response.termination_reason {
                         termination_reason = Some(reason);
                         break;
@@ -2591,11 +6130,17 @@ impl Swarm {
                 .await;
 
             Ok(Response {
-                messages: state.history.clone(),
-                agent: Some(state.agent.clone()),
-                context_variables: state.context_variables.clone(),
-                termination_reason,
-                tokens_used: state.total_tokens,
+                profile: state
+                    .profiler
+                    .as_ref()
+                    .map(|profiler| profiler.report(exec.budget.tool_calls as usize)),
+                turn_metadata: state.turn_metadata.clone(),
+                trace: state.trace.clone(),
+                context_snapshots: state.context_snapshots.clone(),
+                branches: state.branches.clone(),
+                system_fingerprint: state.system_fingerprint.clone(),
+                api_calls: exec.options.api_calls.load(Ordering::Relaxed),
+                ..Response::from_state(&state, termination_reason)
             })
         }
         .await;
@@ -2619,6 +6164,210 @@ impl Swarm {
         }
     }
 
+    /// Checks that a workflow of `step_count` steps fits in `max_turns`, raising it
+    /// with a warning when `auto_adjust` is set. A raised `max_turns` must still stay
+    /// within `max_loop_iterations`.
+    fn fit_max_turns_to_steps(
+        &self,
+        max_turns: &mut usize,
+        step_count: usize,
+        auto_adjust: bool,
+    ) -> SwarmResult<()> {
+        if step_count <= *max_turns {
+            return Ok(());
+        }
+        if !auto_adjust {
+            return Err(SwarmError::ValidationError(format!(
+                "Workflow has {} steps but max_turns is {}",
+                step_count, max_turns
+            )));
+        }
+        let max_loop_iterations = self.config.max_loop_iterations() as usize;
+        if step_count > max_loop_iterations {
+            return Err(SwarmError::ValidationError(format!(
+                "Workflow has {} steps, more than the configured max_loop_iterations ({})",
+                step_count, max_loop_iterations
+            )));
+        }
+        tracing::warn!(
+            steps = step_count,
+            max_turns = *max_turns,
+            "Raising max_turns to the number of workflow steps"
+        );
+        *max_turns = step_count;
+        Ok(())
+    }
+
+    /// Appends the `<steps>` block stored under `__dynamic_steps`, if any, after the
+    /// last step, numbering the new steps on from it. The key is consumed, and the
+    /// combined workflow is validated again, including against `max_turns`.
+    fn inject_dynamic_steps(
+        &self,
+        state: &mut RunState,
+        steps: &mut Steps,
+        injections: &mut usize,
+        options: &RunOptions,
+    ) -> SwarmResult<()> {
+        let Some(xml) = state.context_variables.remove(DYNAMIC_STEPS_KEY) else {
+            return Ok(());
+        };
+        if *injections >= self.config.max_dynamic_step_injections() {
+            return Err(SwarmError::ValidationError(format!(
+                "Dynamic step injection exceeded the limit of {}",
+                self.config.max_dynamic_step_injections()
+            )));
+        }
+        *injections += 1;
+        let Some(xml) = extract_namespaced_xml_steps(&xml, self.config.xml_namespace())?.1 else {
+            return Err(SwarmError::XmlError(format!(
+                "{} must hold a <steps> block",
+                DYNAMIC_STEPS_KEY
+            )));
+        };
+        let xml = self.strip_xml_namespace(xml);
+        let dynamic = self.steps_parser.parse(&xml)?;
+        let configured = steps.steps.len();
+        let last = steps.steps.last().map_or(0, |step| step.number);
+        steps.steps.extend(
+            dynamic
+                .steps
+                .into_iter()
+                .enumerate()
+                .map(|(offset, mut step)| {
+                    step.number = last + offset + 1;
+                    step
+                }),
+        );
+        if let Err(err) = validate_steps(steps).and_then(|()| {
+            self.fit_max_turns_to_steps(
+                &mut state.max_turns,
+                steps.steps.len(),
+                options.auto_adjust_max_turns,
+            )
+        }) {
+            steps.steps.truncate(configured);
+            return Err(err);
+        }
+        tracing::info!(
+            added = steps.steps.len() - configured,
+            "Appended dynamic steps from context"
+        );
+        Ok(())
+    }
+
+    /// Removes the configured `xml_namespace` prefix from the tags of a steps block.
+    fn strip_xml_namespace(&self, xml: String) -> String {
+        match self.config.xml_namespace() {
+            Some(namespace) => strip_xml_namespace(&xml, namespace),
+            None => xml,
+        }
+    }
+
+    /// Has `planner_agent` write a `<steps>` workflow for `task`, then runs it with
+    /// the first of `executor_agents`.
+    ///
+    /// Every executor must be registered, and the plan may only name executors as
+    /// step agents. Plans may not `<include>` step files. `tokens_used` includes the
+    /// planning request.
+    pub async fn plan_and_run(
+        &self,
+        planner_agent: Agent,
+        task: &str,
+        executor_agents: &[Agent],
+        context_variables: ContextVariables,
+        options: RunOptions,
+    ) -> SwarmResult<Response> {
+        if task.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Task cannot be empty".to_string(),
+            ));
+        }
+        let Some(executor) = executor_agents.first() else {
+            return Err(SwarmError::ValidationError(
+                "plan_and_run needs at least one executor agent".to_string(),
+            ));
+        };
+        for agent in executor_agents {
+            self.get_agent_by_name(agent.name())?;
+        }
+
+        let agent_names: Vec<&str> = executor_agents.iter().map(Agent::name).collect();
+        let prompt = format!(
+            "{}\n\nAgents: {}\n\nTask: {}",
+            PLAN_STEPS_PROMPT,
+            agent_names.join(", "),
+            task
+        );
+        let plan = self
+            .run_with_options(
+                planner_agent,
+                vec![Message::user(prompt)?],
+                context_variables.clone(),
+                RunOptions::new(1).with_debug(options.debug),
+            )
+            .await?;
+        let reply = plan
+            .messages
+            .iter()
+            .rev()
+            .find(|message| message.role() == MessageRole::Assistant)
+            .and_then(Message::content)
+            .unwrap_or_default();
+        let (rest, xml_steps) = extract_namespaced_xml_steps(reply, self.config.xml_namespace())?;
+        let xml_steps = match xml_steps {
+            Some(xml_steps) if rest.is_empty() => self.strip_xml_namespace(xml_steps),
+            _ => {
+                return Err(SwarmError::XmlError(
+                    "Planner reply must contain only a <steps> block".to_string(),
+                ))
+            }
+        };
+        // Includes read files from disk; a model-written plan may not name them.
+        if xml_steps.contains("<include") {
+            return Err(SwarmError::XmlError(
+                "Planner reply must not include step files".to_string(),
+            ));
+        }
+        let steps = self.steps_parser.parse(&xml_steps)?;
+        if steps.steps.is_empty() {
+            return Err(SwarmError::XmlError(
+                "Planner returned a workflow without steps".to_string(),
+            ));
+        }
+        for step in &steps.steps {
+            for name in [&step.agent, &step.evaluator_agent].into_iter().flatten() {
+                if !agent_names.contains(&name.as_str()) {
+                    return Err(SwarmError::ValidationError(format!(
+                        "Planned step {} uses agent '{}', which is not an executor",
+                        step.number, name
+                    )));
+                }
+            }
+        }
+        debug_print(
+            options.debug,
+            &format!("Planned {} steps for task", steps.steps.len()),
+        );
+
+        let mut executor = executor.clone();
+        let instructions = match &executor.instructions {
+            Instructions::Text(text) => text.clone(),
+            Instructions::Function(func) => func(context_variables.clone()),
+        };
+        let (instructions, _) = extract_xml_steps(&instructions)?;
+        executor.instructions = Instructions::Text(format!("{}\n{}", instructions, xml_steps));
+        let mut response = self
+            .run_with_options(
+                executor,
+                vec![Message::user(task)?],
+                context_variables,
+                options,
+            )
+            .await?;
+        response.tokens_used = response.tokens_used.saturating_add(plan.tokens_used);
+        Ok(response)
+    }
+
     /// Saves a checkpoint if a `CheckpointStore` is configured.
     ///
     /// Failures are non-fatal — they are traced at WARN level but do not abort
@@ -2708,6 +6457,7 @@ impl Swarm {
             return Err(SwarmError::MaxIterationsError {
                 max: self.config.max_loop_iterations() as usize,
                 actual: envelope.payload.iteration as usize,
+                last_response: None,
             });
         }
 
@@ -2723,11 +6473,110 @@ impl Swarm {
         .await
     }
 
+    /// Rolls `response` back to the newest context snapshot labelled `step_label`
+    /// (e.g. `"step 2"` or `"turn 3"`) and returns the restored variables.
+    ///
+    /// Snapshots taken after it are discarded, so a later restore cannot move forward again.
+    pub fn restore_context_snapshot(
+        response: &mut Response,
+        step_label: &str,
+    ) -> SwarmResult<ContextVariables> {
+        let index = response
+            .context_snapshots
+            .iter()
+            .rposition(|(label, _)| label == step_label)
+            .ok_or_else(|| {
+                SwarmError::ContextError(format!("No context snapshot labelled '{}'", step_label))
+            })?;
+        response.context_snapshots.truncate(index + 1);
+        let (_, context_variables) = &response.context_snapshots[index];
+        response.context_variables = context_variables.clone();
+        Ok(context_variables.clone())
+    }
+
     pub fn get_agent_by_name(&self, name: &str) -> SwarmResult<Agent> {
-        self.agent_directory
+        let agent = self
+            .agent_directory
             .get(&AgentRef::new(name))
             .map(|agent| (*agent).clone())
-            .ok_or_else(|| SwarmError::AgentNotFoundError(name.to_string()))
+            .ok_or_else(|| SwarmError::AgentNotFoundError(name.to_string()))?;
+        self.agent_last_access()?
+            .insert(name.to_string(), Instant::now());
+        Ok(agent)
+    }
+
+    fn agent_last_access(
+        &self,
+    ) -> SwarmResult<std::sync::MutexGuard<'_, HashMap<String, Instant>>> {
+        self.agent_last_access
+            .lock()
+            .map_err(|_| SwarmError::Other("agent_last_access lock poisoned".into()))
+    }
+
+    /// Adds `agent` after validating it against the config, replacing any agent
+    /// with the same name. Fails once `max_registered_agents` new names are held.
+    pub fn register_agent(&mut self, agent: Agent) -> SwarmResult<()> {
+        agent.validate(&self.config)?;
+        let is_new = !self.agent_registry.contains_key(agent.name());
+        if is_new
+            && self
+                .config
+                .max_registered_agents()
+                .is_some_and(|limit| self.agent_registry.len() >= limit)
+        {
+            return Err(SwarmError::ConfigError(
+                "agent registry is full".to_string(),
+            ));
+        }
+        self.agent_last_access()?
+            .insert(agent.name().to_string(), Instant::now());
+        self.agent_directory.register(Arc::new(agent.clone()));
+        self.agent_registry.insert(agent.name().to_string(), agent);
+        Ok(())
+    }
+
+    /// Removes the agent that was registered or looked up by name least recently,
+    /// returning its name. Agents the config names as the tool or context
+    /// summarizer, or as a `context_rename_on_switch` target, are never evicted.
+    pub fn evict_lru_agent(&mut self) -> SwarmResult<String> {
+        let name = {
+            let last_access = self.agent_last_access()?;
+            let referenced: HashSet<&str> = self
+                .config
+                .tool_summarizer_agent()
+                .into_iter()
+                .chain(self.config.context_summarizer_agent())
+                .chain(
+                    self.config
+                        .context_rename_on_switch()
+                        .keys()
+                        .map(String::as_str),
+                )
+                .collect();
+            if self.agent_registry.is_empty() {
+                return Err(SwarmError::ConfigError(
+                    "agent registry is empty".to_string(),
+                ));
+            }
+            self.agent_registry
+                .keys()
+                .filter(|name| !referenced.contains(name.as_str()))
+                .min_by(|left, right| {
+                    let left_at = last_access.get(*left);
+                    let right_at = last_access.get(*right);
+                    left_at.cmp(&right_at).then_with(|| left.cmp(right))
+                })
+                .cloned()
+                .ok_or_else(|| {
+                    SwarmError::ConfigError(
+                        "every registered agent is referenced by the config".to_string(),
+                    )
+                })?
+        };
+        self.agent_last_access()?.remove(&name);
+        self.agent_directory.remove(&AgentRef::new(name.as_str()));
+        self.agent_registry.remove(&name);
+        Ok(name)
     }
 }
 
@@ -2756,6 +6605,9 @@ impl SwarmConfig {
                 "default_max_iterations must be greater than 0".to_string(),
             ));
         }
+        for (_, model) in self.complexity_model_map() {
+            ModelId::new(self.resolve_model_alias(model), self.valid_model_prefixes())?;
+        }
         Ok(())
     }
 }
@@ -2763,9 +6615,19 @@ impl SwarmConfig {
 impl Agent {
     pub fn validate(&self, config: &SwarmConfig) -> SwarmResult<()> {
         self.validate_intrinsic_fields()?;
-        ModelId::new(self.model.clone(), config.valid_model_prefixes())?;
+        ModelId::new(
+            config.resolve_model_alias(&self.model),
+            config.valid_model_prefixes(),
+        )?;
+        if let Some(stop_sequences) = self.stop_sequences() {
+            validate_stop_sequences(stop_sequences)?;
+            validate_stop_sequences(&merge_stop_sequences(
+                config.global_stop_sequences(),
+                stop_sequences,
+            ))?;
+        }
         match self.function_call() {
-            FunctionCallPolicy::Disabled => {}
+            FunctionCallPolicy::Disabled | FunctionCallPolicy::None => {}
             FunctionCallPolicy::Auto => {
                 if self.functions().is_empty() {
                     return Err(SwarmError::ValidationError(
@@ -2776,18 +6638,33 @@ impl Agent {
             }
             FunctionCallPolicy::Named(name) => {
                 if name.trim().is_empty() {
-                    return Err(SwarmError::ValidationError(
-                        "Named function call policy cannot be empty".to_string(),
-                    ));
+                    return Err(SwarmError::ValidationError(format!(
+                        "Named function call policy cannot be empty; expected {}",
+                        FUNCTION_CALL_OPTIONS
+                    )));
+                }
+                if self.functions().is_empty() {
+                    return Err(SwarmError::ValidationError(format!(
+                        "Named function call policy '{}' requires registered functions; \
+                         leave function_call unset for an agent without functions",
+                        name
+                    )));
                 }
                 if !self
                     .functions()
                     .iter()
                     .any(|function| function.name() == *name)
                 {
+                    let registered = self
+                        .functions()
+                        .iter()
+                        .map(|function| function.name())
+                        .collect::<Vec<_>>()
+                        .join(", ");
                     return Err(SwarmError::ValidationError(format!(
-                        "Named function call policy references unknown function: {}",
-                        name
+                        "Named function call policy references unknown function: {}; \
+                         expected {} naming one of: {}",
+                        name, FUNCTION_CALL_OPTIONS, registered
                     )));
                 }
             }
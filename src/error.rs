@@ -92,9 +92,17 @@ pub enum SwarmError {
     #[error("Context variables error: {0}")]
     ContextError(String),
 
-    /// Maximum iterations exceeded errors
-    #[error("Maximum iterations exceeded: reached {actual} of {max} allowed")]
-    MaxIterationsError { max: usize, actual: usize },
+    /// Maximum iterations exceeded errors; `last_response` is the final reply of a
+    /// `repeat_until` step that never matched its pattern
+    #[error(
+        "Maximum iterations exceeded: reached {actual} of {max} allowed{}",
+        .last_response.as_ref().map(|reply| format!("; last response: {}", reply)).unwrap_or_default()
+    )]
+    MaxIterationsError {
+        max: usize,
+        actual: usize,
+        last_response: Option<String>,
+    },
 
     /// JSON processing errors
     #[error(transparent)]
@@ -5,6 +5,7 @@ use crate::escalation::{EscalationAction, EscalationTrigger};
 use crate::guardrails::DataClassification;
 use crate::phase::{AgentLoopPhase, PhaseResult, TerminationReason};
 use crate::team::{AgentTeam, TeamDecision};
+use crate::types::StepRecoveryAction;
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -189,6 +190,17 @@ pub enum AgentEvent {
         decision: TeamDecision,
         timestamp: DateTime<Utc>,
     },
+    /// A failed step was handled by the run's `ErrorRecoveryStrategy`.
+    #[serde(rename = "step_recovery")]
+    StepRecovery {
+        trace_id: TraceId,
+        step_number: usize,
+        action: StepRecoveryAction,
+        error: String,
+        /// Agent that runs the step next; empty for `skip`.
+        agent_name: String,
+        timestamp: DateTime<Utc>,
+    },
 }
 
 impl AgentEvent {
@@ -213,6 +225,7 @@ impl AgentEvent {
             Self::ReplyTimeout { trace_id, .. } => trace_id.as_str(),
             Self::TeamFormed { trace_id, .. } => trace_id.as_str(),
             Self::ConsensusReached { trace_id, .. } => trace_id.as_str(),
+            Self::StepRecovery { trace_id, .. } => trace_id.as_str(),
         }
     }
 
@@ -237,6 +250,7 @@ impl AgentEvent {
             Self::ReplyTimeout { timestamp, .. } => *timestamp,
             Self::TeamFormed { timestamp, .. } => *timestamp,
             Self::ConsensusReached { timestamp, .. } => *timestamp,
+            Self::StepRecovery { timestamp, .. } => *timestamp,
         }
     }
 }
@@ -300,6 +314,11 @@ impl fmt::Display for AgentEvent {
             Self::ConsensusReached { decision, .. } => {
                 write!(f, "ConsensusReached({})", decision.selected_option())
             }
+            Self::StepRecovery {
+                step_number,
+                action,
+                ..
+            } => write!(f, "StepRecovery(step {}, {})", step_number, action),
         }
     }
 }
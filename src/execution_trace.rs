@@ -0,0 +1,129 @@
+//! Human-readable record of a run, collected when
+//! [`RunOptions::with_execution_trace`] is on.
+//!
+//! [`RunOptions::with_execution_trace`]: crate::core::RunOptions::with_execution_trace
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use std::time::SystemTime;
+
+use crate::types::{ContextVariables, Message};
+
+/// Something that happened during a run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// Step number and action.
+    StepStarted(usize, String),
+    /// The run started with, or handed off to, this agent.
+    AgentSelected(String),
+    /// A user message added to the conversation.
+    MessageSent(Message),
+    /// An assistant message returned by the model.
+    MessageReceived(Message),
+    /// Function name and arguments.
+    FunctionCalled(String, ContextVariables),
+    /// Function name and the result added to history.
+    FunctionReturned(String, String),
+    /// Context variable key and new value.
+    ContextUpdated(String, String),
+    StepCompleted(usize),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub timestamp: SystemTime,
+    pub event: TraceEvent,
+}
+
+impl TraceEntry {
+    pub(crate) fn now(event: TraceEvent) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            event,
+        }
+    }
+
+    /// One line such as `2024-05-01T12:00:00.000Z function called: lookup {"id": "7"}`.
+    pub fn to_log_line(&self) -> String {
+        let timestamp =
+            DateTime::<Utc>::from(self.timestamp).to_rfc3339_opts(SecondsFormat::Millis, true);
+        let event = match &self.event {
+            TraceEvent::StepStarted(number, action) => {
+                format!("step {} started ({})", number, action)
+            }
+            TraceEvent::AgentSelected(name) => format!("agent selected: {}", name),
+            TraceEvent::MessageSent(message) => format!(
+                "message sent ({}): {}",
+                message.role().as_str(),
+                message.content().unwrap_or_default()
+            ),
+            TraceEvent::MessageReceived(message) => {
+                let mut line = format!(
+                    "message received ({}): {}",
+                    message.role().as_str(),
+                    message.content().unwrap_or_default()
+                );
+                if let Some(call) = message.function_call() {
+                    line.push_str(&format!(" [calls {}]", call.name()));
+                }
+                for call in message.tool_calls().unwrap_or_default() {
+                    line.push_str(&format!(" [calls {}]", call.function().name()));
+                }
+                line
+            }
+            TraceEvent::FunctionCalled(name, arguments) => {
+                let mut arguments = arguments.iter().collect::<Vec<_>>();
+                arguments.sort();
+                let arguments = arguments
+                    .iter()
+                    .map(|(key, value)| format!("{:?}: {:?}", key, value))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("function called: {} {{{}}}", name, arguments)
+            }
+            TraceEvent::FunctionReturned(name, result) => {
+                format!("function returned: {} -> {}", name, result)
+            }
+            TraceEvent::ContextUpdated(key, value) => {
+                format!("context updated: {} = {}", key, value)
+            }
+            TraceEvent::StepCompleted(number) => format!("step {} completed", number),
+        };
+        format!("{} {}", timestamp, event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(event: TraceEvent) -> TraceEntry {
+        TraceEntry {
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_500),
+            event,
+        }
+    }
+
+    #[test]
+    fn test_log_lines() {
+        let mut arguments = ContextVariables::new();
+        arguments.insert("id".to_string(), "7".to_string());
+        arguments.insert("depth".to_string(), "2".to_string());
+
+        assert_eq!(
+            entry(TraceEvent::StepStarted(1, "run_once".to_string())).to_log_line(),
+            "1970-01-01T00:00:01.500Z step 1 started (run_once)"
+        );
+        assert_eq!(
+            entry(TraceEvent::FunctionCalled("lookup".to_string(), arguments)).to_log_line(),
+            r#"1970-01-01T00:00:01.500Z function called: lookup {"depth": "2", "id": "7"}"#
+        );
+        assert_eq!(
+            entry(TraceEvent::MessageSent(
+                Message::user("hi").expect("message")
+            ))
+            .to_log_line(),
+            "1970-01-01T00:00:01.500Z message sent (user): hi"
+        );
+    }
+}
@@ -0,0 +1,181 @@
+//! Round-robin API key rotation with per-key rate-limit cooldowns.
+//!
+//! Provider rate limits are enforced per API key, so spreading requests over
+//! several keys raises the effective throughput. An `ApiKeyPool` hands out keys
+//! in round-robin order and skips any key that recently returned a rate-limit
+//! error until its cooldown deadline has passed.
+
+use crate::error::{SwarmError, SwarmResult};
+use crate::types::ApiKey;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Default time a rate-limited key is skipped before it is tried again.
+pub const DEFAULT_API_KEY_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// A shared, thread-safe pool of API keys.
+///
+/// Clone is cheap (internally backed by `Arc`s); clones share the rotation
+/// counter and cooldown table.
+#[derive(Clone)]
+pub struct ApiKeyPool {
+    keys: Arc<Vec<ApiKey>>,
+    next: Arc<AtomicUsize>,
+    cooldowns: Arc<Mutex<HashMap<String, Instant>>>,
+    cooldown: Duration,
+}
+
+impl ApiKeyPool {
+    /// Create a pool over `keys`. At least one key is required.
+    pub fn new(keys: Vec<ApiKey>, cooldown: Duration) -> SwarmResult<Self> {
+        if keys.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "API key pool requires at least one key".to_string(),
+            ));
+        }
+        Ok(Self {
+            keys: Arc::new(keys),
+            next: Arc::new(AtomicUsize::new(0)),
+            cooldowns: Arc::new(Mutex::new(HashMap::new())),
+            cooldown,
+        })
+    }
+
+    fn lock_cooldowns(&self) -> MutexGuard<'_, HashMap<String, Instant>> {
+        self.cooldowns.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("API key cooldown lock poisoned; continuing with recovered state");
+            poisoned.into_inner()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// The first configured key; used wherever a single representative key is needed.
+    pub fn primary(&self) -> &ApiKey {
+        &self.keys[0]
+    }
+
+    pub fn key(&self, index: usize) -> &ApiKey {
+        &self.keys[index]
+    }
+
+    /// Returns the index of the next key in round-robin order, skipping keys
+    /// that are still cooling down.
+    ///
+    /// Fails with [`SwarmError::RateLimitError`] when every key is cooling down.
+    pub fn next_index(&self) -> SwarmResult<usize> {
+        let now = Instant::now();
+        let mut cooldowns = self.lock_cooldowns();
+        cooldowns.retain(|_, deadline| *deadline > now);
+
+        for _ in 0..self.keys.len() {
+            let index = self.next.fetch_add(1, Ordering::Relaxed) % self.keys.len();
+            if !cooldowns.contains_key(self.keys[index].as_str()) {
+                return Ok(index);
+            }
+        }
+        Err(SwarmError::RateLimitError(format!(
+            "all {} API keys are cooling down after rate limiting",
+            self.keys.len()
+        )))
+    }
+
+    /// Skip the key at `index` until the configured cooldown has elapsed.
+    pub fn mark_rate_limited(&self, index: usize) {
+        let deadline = Instant::now() + self.cooldown;
+        self.lock_cooldowns()
+            .insert(self.keys[index].as_str().to_string(), deadline);
+        tracing::warn!(
+            api_key = %self.keys[index],
+            cooldown_ms = self.cooldown.as_millis() as u64,
+            "API key rate limited; rotating to the next key"
+        );
+    }
+
+    /// Number of keys currently cooling down.
+    pub fn cooling_down(&self) -> usize {
+        let now = Instant::now();
+        self.lock_cooldowns()
+            .values()
+            .filter(|deadline| **deadline > now)
+            .count()
+    }
+}
+
+impl std::fmt::Debug for ApiKeyPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // ApiKey's Display is redacted; never print raw keys.
+        let keys = self.keys.iter().map(ApiKey::redacted).collect::<Vec<_>>();
+        f.debug_struct("ApiKeyPool")
+            .field("keys", &keys)
+            .field("cooldown", &self.cooldown)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(keys: &[&str], cooldown: Duration) -> ApiKeyPool {
+        let keys = keys
+            .iter()
+            .map(|key| ApiKey::new(*key).expect("valid key"))
+            .collect();
+        ApiKeyPool::new(keys, cooldown).expect("pool")
+    }
+
+    #[test]
+    fn test_rejects_empty_pool() {
+        assert!(ApiKeyPool::new(Vec::new(), DEFAULT_API_KEY_COOLDOWN).is_err());
+    }
+
+    #[test]
+    fn test_round_robin_order() {
+        let pool = pool(&["sk-a", "sk-b", "sk-c"], DEFAULT_API_KEY_COOLDOWN);
+        let picked = (0..6)
+            .map(|_| pool.next_index().expect("key available"))
+            .collect::<Vec<_>>();
+        assert_eq!(picked, vec![0, 1, 2, 0, 1, 2]);
+    }
+
+    #[test]
+    fn test_rate_limited_key_is_skipped() {
+        let pool = pool(&["sk-a", "sk-b"], DEFAULT_API_KEY_COOLDOWN);
+        pool.mark_rate_limited(0);
+        for _ in 0..4 {
+            assert_eq!(pool.next_index().expect("key available"), 1);
+        }
+        assert_eq!(pool.cooling_down(), 1);
+    }
+
+    #[test]
+    fn test_all_keys_cooling_down_is_rate_limit_error() {
+        let pool = pool(&["sk-a", "sk-b"], DEFAULT_API_KEY_COOLDOWN);
+        pool.mark_rate_limited(0);
+        pool.mark_rate_limited(1);
+        let err = pool.next_index().expect_err("no key available");
+        assert!(matches!(err, SwarmError::RateLimitError(_)));
+        assert!(err.is_retriable());
+    }
+
+    #[test]
+    fn test_cooldown_expires() {
+        let pool = pool(&["sk-a"], Duration::from_millis(1));
+        pool.mark_rate_limited(0);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(pool.next_index().expect("cooldown elapsed"), 0);
+    }
+}
@@ -7,18 +7,25 @@ pub mod validation;
 
 pub mod agent_comm;
 pub mod agent_registry;
+pub mod api_provider;
 pub mod checkpoint;
 pub mod circuit_breaker;
+pub mod context_injectors;
 pub mod distribution;
 pub mod error;
 pub mod escalation;
 pub mod event;
+pub mod execution_trace;
 pub mod guardrails;
+pub mod key_pool;
 pub mod memory;
 pub mod observability;
 pub mod persistence;
 pub mod phase;
+pub mod profile;
 pub mod provider;
+pub mod response_cache;
+pub mod steps_parser;
 pub mod team;
 pub mod tool;
 
@@ -28,9 +35,13 @@ pub use crate::agent_comm::{
     AgentChannel, AgentMessage, ChannelRegistry, InProcessChannel, MessageId,
 };
 pub use crate::agent_registry::AgentRegistry;
+pub use crate::api_provider::{AnthropicApiProvider, ApiProvider, OpenAiApiProvider};
 pub use crate::checkpoint::{CheckpointData, CheckpointEnvelope, CURRENT_CHECKPOINT_VERSION};
 pub use crate::circuit_breaker::{CircuitBreaker, CircuitStateSnapshot};
-pub use crate::core::Swarm;
+pub use crate::context_injectors::{
+    EnvContextInjector, FileContextInjector, StaticContextInjector,
+};
+pub use crate::core::{RunOptions, StepFailureCallback, Swarm};
 pub use crate::distribution::{
     AgentAddress, DistributedMessage, DistributedTransport, HttpDistributedTransport,
 };
@@ -39,12 +50,14 @@ pub use crate::escalation::{
     EscalationAction, EscalationConfig, EscalationDetector, EscalationTrigger,
 };
 pub use crate::event::{AgentEvent, EventSubscriber, LoggingSubscriber, TraceId};
+pub use crate::execution_trace::{TraceEntry, TraceEvent};
 pub use crate::guardrails::{
     apply_redaction_policy, check_injection_with_policy, classify_and_redact, classify_text,
     contains_pii, detect_prompt_injection, detect_prompt_injection_with_sanitization, find_pii,
     redact_pii, redact_pii_with, ContentPolicy, DataClassification, DefaultContentPolicy,
     InjectionCheckResult, InjectionOutcome, InjectionPolicy, PolicyResult, RedactionPolicy,
 };
+pub use crate::key_pool::ApiKeyPool;
 pub use crate::memory::vector::{InMemoryVectorStore, MemoryEntry, RetrievalPolicy, VectorMemory};
 pub use crate::memory::{Memory, SlidingWindowMemory};
 #[cfg(feature = "postgres")]
@@ -57,9 +70,12 @@ pub use crate::persistence::{
 pub use crate::phase::{
     AgentLoop, AgentLoopPhase, PhaseResult, PlannedAction, TerminationReason, TokenUsage,
 };
+pub use crate::profile::{ProfileReport, ProfileSpan, ProfileSpanKind};
 pub use crate::provider::{
     Chunk, CompletionRequest, CompletionResponse, LlmProvider, OpenAiProvider,
 };
+pub use crate::response_cache::{InMemoryResponseCache, ResponseCache};
+pub use crate::steps_parser::{JsonStepsParser, QuickXmlStepsParser, StepsParser};
 pub use crate::team::{
     AgentTeam, ConsensusStrategy, TeamAssignment, TeamDecision, TeamFormationPolicy, TeamRole,
     TeamVote, VoteTally,
@@ -70,9 +86,16 @@ pub use crate::tool::{
 };
 pub use crate::types::RuntimeLimits;
 pub use crate::types::{
-    Agent, AgentFunction, AgentRef, ContextVariables, FunctionCall, FunctionCallPolicy,
-    Instructions, Message, MessageRole, Response, ResultType, SwarmConfig, ToolCall,
-    ToolCallExecution,
+    Agent, AgentFunction, AgentRef, BranchMergeStrategy, BranchPoint, CircularHandoffAction,
+    CompressionStrategy, ContentOverflowAction, ContentPart, ContextInjector, ContextOverflow,
+    ContextOverflowStrategy, ContextSnapshotInterval, ContextVariables, DeadLetterEntry,
+    ErrorRecoveryStrategy, FunctionCall, FunctionCallFormat, FunctionCallPolicy,
+    HandoffContextFilter, HandoffRecord, HistoryWindowStrategy, Instructions, JitterStrategy,
+    LogprobsConfig, Message, MessageRole, MessageSerializationAdapter, MessageSerializer,
+    ModelContextWindow, ModelParameters, PromptCompressionConfig, PromptCompressionStats, Response,
+    ResultType, SafetyPlacement, StepRecoveryAction, SwarmConfig, SwarmConfigDiff,
+    SystemMessageFormat, SystemMessageFormatter, TaskComplexityScorer, ToolCall, ToolCallExecution,
+    TurnMetadata, UserIdProvider, XmlEncoding,
 };
 pub use crate::validation::{
     verify_structured_response, verify_tool_arguments, BudgetEnforcer, BudgetExhausted,
@@ -0,0 +1,156 @@
+//! Timing spans collected during a run when [`RunOptions::with_profiling`] is on.
+//!
+//! [`RunOptions::with_profiling`]: crate::core::RunOptions::with_profiling
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// What a [`ProfileSpan`] measured.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum ProfileSpanKind {
+    /// One workflow step, including its pre- and postcondition checks.
+    Step { number: usize },
+    /// One round of conversation: the completion plus any function calls it triggers.
+    SingleExecution,
+    /// One chat completion request, including failed attempts.
+    ChatCompletion { model: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileSpan {
+    pub kind: ProfileSpanKind,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub duration_ms: u64,
+}
+
+/// Where a run spent its time, attached to [`Response::profile`](crate::types::Response::profile).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileReport {
+    /// Spans in the order they finished.
+    pub spans: Vec<ProfileSpan>,
+    pub total_duration_ms: u64,
+    /// Number of the longest-running step; 0 when the run had no steps.
+    pub slowest_step: usize,
+    pub api_call_count: usize,
+    pub function_call_count: usize,
+}
+
+/// An open span; [`SpanTimer::finish`] closes it.
+pub(crate) struct SpanTimer {
+    kind: ProfileSpanKind,
+    started_at: DateTime<Utc>,
+    start: Instant,
+}
+
+impl SpanTimer {
+    pub(crate) fn start(kind: ProfileSpanKind) -> Self {
+        Self {
+            kind,
+            started_at: Utc::now(),
+            start: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish(self) -> ProfileSpan {
+        ProfileSpan {
+            kind: self.kind,
+            started_at: self.started_at,
+            ended_at: Utc::now(),
+            duration_ms: self.start.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+pub(crate) struct Profiler {
+    start: Instant,
+    spans: Vec<ProfileSpan>,
+}
+
+impl Profiler {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, span: ProfileSpan) {
+        self.spans.push(span);
+    }
+
+    pub(crate) fn report(&self, function_call_count: usize) -> ProfileReport {
+        let slowest_step = self
+            .spans
+            .iter()
+            .filter_map(|span| match span.kind {
+                ProfileSpanKind::Step { number } => Some((number, span.duration_ms)),
+                _ => None,
+            })
+            // Ties go to the earlier step.
+            .fold(
+                None,
+                |slowest: Option<(usize, u64)>, (number, duration)| match slowest {
+                    Some((_, longest)) if longest >= duration => slowest,
+                    _ => Some((number, duration)),
+                },
+            )
+            .map_or(0, |(number, _)| number);
+        let api_call_count = self
+            .spans
+            .iter()
+            .filter(|span| matches!(span.kind, ProfileSpanKind::ChatCompletion { .. }))
+            .count();
+        ProfileReport {
+            spans: self.spans.clone(),
+            total_duration_ms: self.start.elapsed().as_millis() as u64,
+            slowest_step,
+            api_call_count,
+            function_call_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn span(kind: ProfileSpanKind, duration_ms: u64) -> ProfileSpan {
+        let now = Utc::now();
+        ProfileSpan {
+            kind,
+            started_at: now,
+            ended_at: now,
+            duration_ms,
+        }
+    }
+
+    #[test]
+    fn test_report_picks_slowest_step_and_counts_api_calls() {
+        let mut profiler = Profiler::new();
+        profiler.record(span(
+            ProfileSpanKind::ChatCompletion {
+                model: "gpt-4".to_string(),
+            },
+            5,
+        ));
+        profiler.record(span(ProfileSpanKind::Step { number: 1 }, 10));
+        profiler.record(span(ProfileSpanKind::SingleExecution, 40));
+        profiler.record(span(ProfileSpanKind::Step { number: 2 }, 30));
+        profiler.record(span(ProfileSpanKind::Step { number: 3 }, 30));
+
+        let report = profiler.report(2);
+
+        assert_eq!(report.slowest_step, 2);
+        assert_eq!(report.api_call_count, 1);
+        assert_eq!(report.function_call_count, 2);
+        assert_eq!(report.spans.len(), 5);
+    }
+
+    #[test]
+    fn test_report_without_steps_has_no_slowest_step() {
+        assert_eq!(Profiler::new().report(0).slowest_step, 0);
+    }
+}
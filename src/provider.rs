@@ -1,13 +1,53 @@
 use crate::error::SwarmError;
 use crate::tool::ToolSchema;
-use crate::types::Message;
+use crate::types::{Message, MessageSerializationAdapter};
 use async_trait::async_trait;
 use futures::Stream;
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::pin::Pin;
 
+/// (De)serializes `tools` in the chat completions wire shape:
+/// `[{"type": "function", "function": {"name", "description", "parameters"}}]`.
+mod tool_wire_format {
+    use crate::tool::ToolSchema;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct WireTool {
+        #[serde(rename = "type")]
+        kind: String,
+        function: ToolSchema,
+    }
+
+    pub fn serialize<S>(tools: &Option<Vec<ToolSchema>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        tools
+            .as_ref()
+            .map(|tools| {
+                tools
+                    .iter()
+                    .map(|tool| WireTool {
+                        kind: "function".to_string(),
+                        function: tool.clone(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<ToolSchema>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let tools = Option::<Vec<WireTool>>::deserialize(deserializer)?;
+        Ok(tools.map(|tools| tools.into_iter().map(|tool| tool.function).collect()))
+    }
+}
+
 #[async_trait]
 pub trait LlmProvider: Send + Sync {
     async fn complete(&self, request: CompletionRequest) -> Result<CompletionResponse, SwarmError>;
@@ -22,8 +62,14 @@ pub trait LlmProvider: Send + Sync {
 pub struct CompletionRequest {
     pub messages: Vec<Message>,
     pub model: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "tool_wire_format"
+    )]
     pub tools: Option<Vec<ToolSchema>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<Value>,
     /// Legacy OpenAI functions format (used for streaming and function_call responses).
     #[serde(skip_serializing_if = "Option::is_none")]
     pub functions: Option<Vec<Value>>,
@@ -39,6 +85,15 @@ pub struct CompletionRequest {
     pub stop: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parallel_tool_calls: Option<bool>,
+    /// End-user identifier forwarded for provider-side abuse tracking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
 }
 
 impl CompletionRequest {
@@ -47,6 +102,7 @@ impl CompletionRequest {
             messages,
             model: model.into(),
             tools: None,
+            tool_choice: None,
             functions: None,
             function_call: None,
             stream: false,
@@ -54,6 +110,10 @@ impl CompletionRequest {
             max_tokens: None,
             stop: None,
             parallel_tool_calls: None,
+            user: None,
+            logprobs: None,
+            top_logprobs: None,
+            seed: None,
         }
     }
 
@@ -67,6 +127,11 @@ impl CompletionRequest {
         self
     }
 
+    pub fn with_tool_choice(mut self, tool_choice: Value) -> Self {
+        self.tool_choice = Some(tool_choice);
+        self
+    }
+
     pub fn with_functions(mut self, functions: Vec<Value>, function_call: Option<Value>) -> Self {
         self.functions = Some(functions);
         self.function_call = function_call;
@@ -88,6 +153,22 @@ impl CompletionRequest {
         self
     }
 
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn with_logprobs(mut self, top_logprobs: Option<u32>) -> Self {
+        self.logprobs = Some(true);
+        self.top_logprobs = top_logprobs;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
     pub fn with_stop(mut self, stop: Vec<String>) -> Self {
         self.stop = Some(stop);
         self
@@ -146,6 +227,8 @@ pub struct CompletionResponse {
     pub choices: Vec<CompletionChoice>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub usage: Option<CompletionUsage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_fingerprint: Option<String>,
 }
 
 impl CompletionResponse {
@@ -166,6 +249,8 @@ pub struct CompletionChoice {
     pub message: CompletionMessage,
     #[serde(rename = "finish_reason")]
     pub finish_reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Value>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -268,6 +353,7 @@ pub struct OpenAiProvider {
     client: Client,
     api_key: String,
     api_url: String,
+    message_serialization: MessageSerializationAdapter,
 }
 
 impl OpenAiProvider {
@@ -276,9 +362,16 @@ impl OpenAiProvider {
             client,
             api_key: api_key.into(),
             api_url: api_url.into(),
+            message_serialization: MessageSerializationAdapter::default(),
         }
     }
 
+    /// Writes the `messages` array with `adapter` instead of the OpenAI format.
+    pub fn with_message_serialization(mut self, adapter: MessageSerializationAdapter) -> Self {
+        self.message_serialization = adapter;
+        self
+    }
+
     pub fn api_url(&self) -> &str {
         &self.api_url
     }
@@ -289,19 +382,29 @@ impl LlmProvider for OpenAiProvider {
     async fn complete(&self, request: CompletionRequest) -> Result<CompletionResponse, SwarmError> {
         request.validate()?;
 
+        let mut body = serde_json::to_value(&request)
+            .map_err(|e| SwarmError::SerializationError(e.to_string()))?;
+        body["messages"] = self
+            .message_serialization
+            .serialize_messages(&request.messages)?;
+
         let response = self
             .client
             .post(&self.api_url)
             .bearer_auth(&self.api_key)
-            .json(&request)
+            .json(&body)
             .send()
             .await
             .map_err(|e| SwarmError::NetworkError(e.to_string()))?;
 
         if !response.status().is_success() {
+            let status = response.status();
             let text = response.text().await.map_err(|e| {
                 SwarmError::NetworkError(format!("failed to read error response body: {}", e))
             })?;
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                return Err(SwarmError::RateLimitError(text));
+            }
             return Err(SwarmError::ApiError(text));
         }
 
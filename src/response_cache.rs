@@ -0,0 +1,170 @@
+//! Response caching for idempotent chat completion requests.
+//!
+//! Deterministic workflows send byte-identical requests and receive identical
+//! answers; a [`ResponseCache`] lets `Swarm` skip the network round-trip for
+//! those repeats.
+
+use crate::error::{SwarmError, SwarmResult};
+use crate::types::ChatCompletionResponse;
+use hashlink::LinkedHashMap;
+use serde::Serialize;
+use serde_json::json;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+pub trait ResponseCache: Send + Sync {
+    fn get(&self, key: &str) -> Option<ChatCompletionResponse>;
+    fn set(&self, key: &str, response: ChatCompletionResponse, ttl: Option<Duration>);
+}
+
+/// Cache key for a request: the endpoint plus the fully built request body, so
+/// any field that changes the completion (functions, stop, seed, ...) changes the key.
+pub fn response_cache_key(endpoint: &str, request: &impl Serialize) -> String {
+    json!([endpoint, request]).to_string()
+}
+
+struct CacheEntry {
+    response: ChatCompletionResponse,
+    expires_at: Option<Instant>,
+}
+
+/// Bounded in-process LRU cache with optional per-entry TTL.
+pub struct InMemoryResponseCache {
+    capacity: usize,
+    /// Entries from least to most recently used.
+    state: Mutex<LinkedHashMap<String, CacheEntry>>,
+}
+
+impl InMemoryResponseCache {
+    pub fn new(capacity: usize) -> SwarmResult<Self> {
+        if capacity == 0 {
+            return Err(SwarmError::ValidationError(
+                "InMemoryResponseCache capacity must be greater than zero".to_string(),
+            ));
+        }
+        Ok(Self {
+            capacity,
+            state: Mutex::new(LinkedHashMap::new()),
+        })
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, LinkedHashMap<String, CacheEntry>> {
+        self.state.lock().unwrap_or_else(|poisoned| {
+            tracing::warn!("Response cache lock poisoned; continuing with recovered state");
+            poisoned.into_inner()
+        })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock_state().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.lock_state().clear();
+    }
+}
+
+impl ResponseCache for InMemoryResponseCache {
+    fn get(&self, key: &str) -> Option<ChatCompletionResponse> {
+        let mut state = self.lock_state();
+        let expired = state
+            .get(key)?
+            .expires_at
+            .is_some_and(|deadline| deadline <= Instant::now());
+        if expired {
+            state.remove(key);
+            return None;
+        }
+        state.to_back(key).map(|entry| entry.response.clone())
+    }
+
+    fn set(&self, key: &str, response: ChatCompletionResponse, ttl: Option<Duration>) {
+        let mut state = self.lock_state();
+        let entry = CacheEntry {
+            response,
+            expires_at: ttl.map(|ttl| Instant::now() + ttl),
+        };
+        // Inserting moves an existing key to the most recently used end.
+        state.insert(key.to_string(), entry);
+        while state.len() > self.capacity {
+            state.pop_front();
+        }
+    }
+}
+
+impl std::fmt::Debug for InMemoryResponseCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InMemoryResponseCache")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(id: &str) -> ChatCompletionResponse {
+        serde_json::from_value(json!({
+            "id": id,
+            "object": "chat.completion",
+            "created": 0,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": "cached"},
+                "finish_reason": "stop"
+            }],
+            "usage": null
+        }))
+        .expect("response")
+    }
+
+    #[test]
+    fn test_rejects_zero_capacity() {
+        assert!(InMemoryResponseCache::new(0).is_err());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = InMemoryResponseCache::new(2).expect("cache");
+        cache.set("a", response("a"), None);
+        cache.set("b", response("b"), None);
+        assert!(cache.get("a").is_some());
+        cache.set("c", response("c"), None);
+
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_expired_entries_are_dropped() {
+        let cache = InMemoryResponseCache::new(2).expect("cache");
+        cache.set("a", response("a"), Some(Duration::from_millis(1)));
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(cache.get("a").is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_key_depends_on_endpoint_and_request_body() {
+        let body = json!({"model": "gpt-4", "messages": [{"role": "user", "content": "hi"}]});
+        let key = response_cache_key("https://a", &body);
+        assert_eq!(key, response_cache_key("https://a", &body));
+        assert_ne!(key, response_cache_key("https://b", &body));
+
+        let mut with_stop = body.clone();
+        with_stop["stop"] = json!(["END"]);
+        assert_ne!(key, response_cache_key("https://a", &with_stop));
+    }
+}
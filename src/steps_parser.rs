@@ -0,0 +1,58 @@
+//! Pluggable parsers for the `<steps>` block embedded in agent instructions.
+//!
+//! `Swarm` still locates the block with [`extract_xml_steps`](crate::util::extract_xml_steps);
+//! the configured [`StepsParser`] turns its content into [`Steps`].
+
+use crate::error::{SwarmError, SwarmResult};
+use crate::types::{Step, Steps};
+use crate::util::{parse_steps_from_xml, validate_steps};
+
+pub trait StepsParser: Send + Sync {
+    fn parse(&self, content: &str) -> SwarmResult<Steps>;
+}
+
+/// The default parser: `<steps><step number=".." action=".."><prompt>..</prompt></step></steps>`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct QuickXmlStepsParser;
+
+impl StepsParser for QuickXmlStepsParser {
+    fn parse(&self, content: &str) -> SwarmResult<Steps> {
+        parse_steps_from_xml(content)
+    }
+}
+
+/// Parses a JSON array of step objects, optionally wrapped in `<steps>…</steps>`:
+///
+/// ```text
+/// <steps>[{"number": 1, "action": "run_once", "prompt": "Draft the plan"}]</steps>
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonStepsParser;
+
+impl JsonStepsParser {
+    /// Returns the text between the opening `<steps …>` tag and `</steps>`, or
+    /// `content` unchanged when it is not wrapped.
+    fn unwrap_steps_tag(content: &str) -> &str {
+        let trimmed = content.trim();
+        if !trimmed.starts_with("<steps") {
+            return trimmed;
+        }
+        let Some(open_end) = trimmed.find('>') else {
+            return trimmed;
+        };
+        let inner = &trimmed[open_end + 1..];
+        inner.strip_suffix("</steps>").unwrap_or(inner).trim()
+    }
+}
+
+impl StepsParser for JsonStepsParser {
+    fn parse(&self, content: &str) -> SwarmResult<Steps> {
+        let steps: Vec<Step> =
+            serde_json::from_str(Self::unwrap_steps_tag(content)).map_err(|e| {
+                SwarmError::DeserializationError(format!("Failed to parse JSON steps: {}", e))
+            })?;
+        let steps = Steps { steps };
+        validate_steps(&steps)?;
+        Ok(steps)
+    }
+}
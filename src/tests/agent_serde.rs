@@ -1,6 +1,9 @@
 #[cfg(test)]
 mod tests {
-    use crate::{Agent, Instructions, ToolCallExecution};
+    use crate::{
+        Agent, FunctionCallFormat, FunctionCallPolicy, Instructions, ModelParameters,
+        ToolCallExecution,
+    };
     use serde_json::json;
     use std::sync::Arc;
 
@@ -80,4 +83,85 @@ mod tests {
             serde_json::to_value(&agent).expect_err("Function instructions should not serialize");
         assert!(error.to_string().contains("function-based instructions"));
     }
+
+    #[test]
+    fn test_agent_serde_round_trip_keeps_function_format_and_model_parameters() {
+        let agent = Agent::new(
+            "serde_agent",
+            "gpt-4",
+            Instructions::Text("Round-trip me".to_string()),
+        )
+        .expect("Failed to create agent")
+        .with_function_format(FunctionCallFormat::Tools)
+        .with_model_parameters(
+            ModelParameters::default()
+                .with_temperature(0.5)
+                .with_max_tokens(256),
+        )
+        .expect("model parameters");
+
+        let serialized = serde_json::to_value(&agent).expect("Agent should serialize");
+        assert_eq!(serialized["function_format"], "tools");
+        let deserialized: Agent =
+            serde_json::from_value(serialized).expect("Agent should deserialize");
+
+        assert_eq!(
+            deserialized.function_format(),
+            Some(FunctionCallFormat::Tools)
+        );
+        assert_eq!(deserialized.model_parameters().max_tokens, Some(256));
+    }
+
+    #[test]
+    fn test_agent_serde_round_trip_keeps_stop_sequences() {
+        let agent = Agent::new(
+            "serde_agent",
+            "gpt-4",
+            Instructions::Text("Round-trip me".to_string()),
+        )
+        .expect("Failed to create agent")
+        .with_stop_sequences(vec!["END".to_string()]);
+
+        let serialized = serde_json::to_value(&agent).expect("Agent should serialize");
+        let deserialized: Agent =
+            serde_json::from_value(serialized).expect("Agent should deserialize");
+
+        assert_eq!(
+            deserialized.stop_sequences(),
+            Some(&["END".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn test_agent_deserialize_validates_function_call_values() {
+        let agent_with = |function_call: &str| {
+            serde_json::from_value::<Agent>(json!({
+                "name": "serde_agent",
+                "model": "gpt-4",
+                "instructions": { "text": "Hello" },
+                "function_call": function_call
+            }))
+        };
+
+        let none = agent_with("none").expect("none is valid");
+        assert_eq!(none.function_call(), &FunctionCallPolicy::None);
+        let named = agent_with(r#"{"name": "lookup"}"#).expect("named object is valid");
+        assert_eq!(
+            named.function_call(),
+            &FunctionCallPolicy::Named("lookup".to_string())
+        );
+        let serialized = serde_json::to_value(&named).expect("Agent should serialize");
+        assert_eq!(
+            serde_json::from_value::<Agent>(serialized)
+                .expect("Agent should deserialize")
+                .function_call(),
+            named.function_call()
+        );
+
+        let bare = agent_with("lookup").expect("bare names from older configs are valid");
+        assert_eq!(bare.function_call(), named.function_call());
+
+        let error = agent_with(r#"{"function": "lookup"}"#).expect_err("object without a name");
+        assert!(error.to_string().contains(r#""auto", "none", or {"name""#));
+    }
 }
@@ -1,8 +1,9 @@
 #[cfg(test)]
 mod tests {
     use crate::constants::OPENAI_DEFAULT_API_URL;
-    use crate::{Agent, Instructions, Swarm, SwarmConfig, SwarmError};
+    use crate::{Agent, FunctionCallPolicy, Instructions, Swarm, SwarmConfig, SwarmError};
     use reqwest::Client;
+    use std::collections::HashMap;
     use std::time::Duration;
 
     #[test]
@@ -121,6 +122,24 @@ mod tests {
         let _ = swarm.client();
     }
     #[test]
+    fn test_builder_with_api_keys() {
+        let swarm = Swarm::builder()
+            .with_api_keys(vec!["sk-first".to_string(), "sk-second".to_string()])
+            .expect("valid keys")
+            .build()
+            .expect("Failed to build Swarm");
+
+        assert_eq!(swarm.api_key_pool().len(), 2);
+        assert_eq!(swarm.api_key().as_str(), "sk-first");
+    }
+    #[test]
+    fn test_builder_with_api_keys_rejects_invalid_keys() {
+        assert!(Swarm::builder().with_api_keys(Vec::new()).is_err());
+        assert!(Swarm::builder()
+            .with_api_keys(vec!["sk-valid".to_string(), "not-a-key".to_string()])
+            .is_err());
+    }
+    #[test]
     fn test_builder_default_values() {
         let swarm = Swarm::builder()
             .with_api_key("sk-test123456789".to_string())
@@ -146,4 +165,142 @@ mod tests {
         );
         assert!(swarm.agents().is_empty());
     }
+
+    #[test]
+    fn test_builder_rejects_invalid_ca_cert() {
+        let result = Swarm::builder().with_ca_cert(b"not a certificate".to_vec());
+        assert!(matches!(result, Err(SwarmError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_builder_tls_settings_configure_default_client() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_tls_min_version(reqwest::tls::Version::TLS_1_2)
+            .with_danger_accept_invalid_certs(true)
+            .build();
+        assert!(swarm.is_ok());
+    }
+
+    #[test]
+    fn test_builder_tls_settings_conflict_with_custom_client() {
+        for builder in [
+            Swarm::builder().with_tls_min_version(reqwest::tls::Version::TLS_1_2),
+            Swarm::builder().with_danger_accept_invalid_certs(true),
+        ] {
+            let result = builder
+                .with_api_key("sk-test123456789".to_string())
+                .with_client(Client::new())
+                .build();
+            match result {
+                Err(SwarmError::ConfigError(msg)) => assert!(msg.contains("custom HTTP client")),
+                _ => panic!("Expected ConfigError for TLS settings with a custom client"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_builder_rejects_named_function_call_without_functions() {
+        let agent = Agent::new(
+            "test_agent",
+            "gpt-4",
+            Instructions::Text("Test instructions".to_string()),
+        )
+        .expect("Failed to create Agent")
+        .with_function_call_policy(FunctionCallPolicy::Named("lookup".to_string()));
+
+        let result = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_agent(agent)
+            .build();
+
+        match result {
+            Err(SwarmError::ValidationError(message)) => {
+                assert!(message.contains("requires registered functions"))
+            }
+            _ => panic!("Expected ValidationError for a named policy without functions"),
+        }
+    }
+
+    fn named_agent(name: &str) -> Agent {
+        Agent::new(
+            name,
+            "gpt-4",
+            Instructions::Text("Test instructions".to_string()),
+        )
+        .expect("Failed to create Agent")
+    }
+
+    #[test]
+    fn test_register_agent_respects_max_registered_agents() {
+        let mut swarm = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_agent(named_agent("first"))
+            .with_max_registered_agents(2)
+            .build()
+            .expect("Failed to build Swarm");
+
+        swarm
+            .register_agent(named_agent("second"))
+            .expect("second agent fits");
+        swarm
+            .register_agent(named_agent("second"))
+            .expect("re-registering replaces the agent");
+        let error = swarm
+            .register_agent(named_agent("third"))
+            .expect_err("registry is full");
+
+        assert!(
+            matches!(error, SwarmError::ConfigError(message) if message == "agent registry is full")
+        );
+        assert!(swarm.get_agent_by_name("third").is_err());
+    }
+
+    #[test]
+    fn test_evict_lru_agent_removes_the_least_recently_used_agent() {
+        let mut swarm = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_agent(named_agent("first"))
+            .build()
+            .expect("Failed to build Swarm");
+        swarm
+            .register_agent(named_agent("second"))
+            .expect("register second");
+        swarm.get_agent_by_name("first").expect("first agent");
+
+        assert_eq!(swarm.evict_lru_agent().expect("evict"), "second");
+        assert!(!swarm.agents().contains_key("second"));
+        assert!(swarm.get_agent_by_name("second").is_err());
+        assert_eq!(swarm.evict_lru_agent().expect("evict"), "first");
+        assert!(swarm.evict_lru_agent().is_err());
+    }
+
+    #[test]
+    fn test_evict_lru_agent_skips_agents_the_config_references() {
+        let mut swarm = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_agent(named_agent("summarizer"))
+            .with_agent(named_agent("renamed"))
+            .with_agent(named_agent("worker"))
+            .with_tool_summarizer_agent("summarizer")
+            .with_context_rename_on_switch(
+                "renamed",
+                HashMap::from([("old".to_string(), "new".to_string())]),
+            )
+            .build()
+            .expect("Failed to build Swarm");
+        swarm.get_agent_by_name("worker").expect("worker agent");
+
+        assert_eq!(swarm.evict_lru_agent().expect("evict"), "worker");
+        let error = swarm
+            .evict_lru_agent()
+            .expect_err("only referenced agents remain");
+        assert!(matches!(
+            error,
+            SwarmError::ConfigError(message)
+                if message == "every registered agent is referenced by the config"
+        ));
+        assert!(swarm.agents().contains_key("summarizer"));
+        assert!(swarm.agents().contains_key("renamed"));
+    }
 }
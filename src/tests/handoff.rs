@@ -0,0 +1,249 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::core::Swarm;
+    use crate::error::SwarmError;
+    use crate::types::{
+        Agent, AgentFunction, AgentFunctionHandler, CircularHandoffAction, ContextVariables,
+        FunctionCallPolicy, Instructions, Message, ResultType,
+    };
+
+    const LOOP_STEPS: &str =
+        r#"<steps><step number="1" action="loop"><prompt>Continue</prompt></step></steps>"#;
+
+    /// Every completion asks to call `transfer`, so each turn hands off again.
+    async fn mock_transfer_server() -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-test",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_transfer",
+                            "type": "function",
+                            "function": {"name": "transfer", "arguments": "{}"}
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            })))
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn transfer_to(target: Agent) -> AgentFunction {
+        let handler: Arc<AgentFunctionHandler> = Arc::new(move |_ctx: ContextVariables| {
+            let target = target.clone();
+            Box::pin(async move { Ok(ResultType::Agent(target)) })
+        });
+        AgentFunction::new("transfer", handler, false).expect("function")
+    }
+
+    /// `alpha` hands off to `beta`, which hands straight back to `alpha`.
+    fn ping_pong_agents() -> Agent {
+        let alpha_base = Agent::new(
+            "alpha",
+            "gpt-4",
+            Instructions::Text(format!("You are alpha.\n{}", LOOP_STEPS)),
+        )
+        .expect("alpha");
+        let beta = Agent::new("beta", "gpt-4", Instructions::Text("You are beta.".into()))
+            .expect("beta")
+            .with_functions(vec![transfer_to(alpha_base.clone())])
+            .with_function_call_policy(FunctionCallPolicy::Auto);
+        alpha_base
+            .with_functions(vec![transfer_to(beta)])
+            .with_function_call_policy(FunctionCallPolicy::Auto)
+    }
+
+    #[tokio::test]
+    async fn test_circular_handoff_returns_agent_error() {
+        let mock_server = mock_transfer_server().await;
+        let alpha = ping_pong_agents();
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(alpha.clone())
+            .with_handoff_cycle_detection(true)
+            .with_on_circular_handoff(CircularHandoffAction::Error)
+            .build()
+            .expect("swarm");
+
+        let error = swarm
+            .run(
+                alpha,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                5,
+            )
+            .await
+            .expect_err("cycle should abort the run");
+
+        match error {
+            SwarmError::AgentError(message) => {
+                assert!(message.contains("circular handoff detected"));
+                assert!(message.contains("alpha -> beta -> alpha"));
+            }
+            other => panic!("expected AgentError, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_self_handoff_is_not_a_cycle() {
+        let mock_server = mock_transfer_server().await;
+        let base = Agent::new("solo", "gpt-4", Instructions::Text("You are solo.".into()))
+            .expect("solo")
+            .with_function_call_policy(FunctionCallPolicy::Auto);
+        let again = base.clone().with_functions(vec![transfer_to(base.clone())]);
+        let solo = base.with_functions(vec![transfer_to(again)]);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(solo.clone())
+            .with_handoff_cycle_detection(true)
+            .with_on_circular_handoff(CircularHandoffAction::Error)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run(
+                solo,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                2,
+            )
+            .await
+            .expect("handing off to the same agent should not abort the run");
+
+        assert_eq!(response.agent.expect("agent").name(), "solo");
+    }
+
+    #[test]
+    fn test_handoff_cycle_detection_defaults() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .build()
+            .expect("swarm");
+        assert!(!swarm.config().handoff_cycle_detection());
+        assert_eq!(
+            swarm.config().on_circular_handoff(),
+            CircularHandoffAction::Warn
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handoff_context_filter_withholds_keys_from_new_agent() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-test",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": {
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_transfer",
+                            "type": "function",
+                            "function": {"name": "transfer", "arguments": "{}"}
+                        }]
+                    },
+                    "finish_reason": "tool_calls"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            })))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "chatcmpl-test",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "done"},
+                    "finish_reason": "stop"
+                }],
+                "usage": {"prompt_tokens": 1, "completion_tokens": 1, "total_tokens": 2}
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let beta = Agent::new("beta", "gpt-4", Instructions::Text("You are beta.".into()))
+            .expect("beta")
+            .with_handoff_context_filter(|key| key.starts_with("public_"));
+        let alpha = Agent::new(
+            "alpha",
+            "gpt-4",
+            Instructions::Text("You are alpha.".into()),
+        )
+        .expect("alpha")
+        .with_functions(vec![transfer_to(beta)])
+        .with_function_call_policy(FunctionCallPolicy::Auto);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(alpha.clone())
+            .build()
+            .expect("swarm");
+        let mut context = ContextVariables::new();
+        context.insert("public_topic".to_string(), "weather".to_string());
+        context.insert("raw_tool_output".to_string(), "secret".to_string());
+
+        let response = swarm
+            .run(
+                alpha,
+                vec![Message::user("start").expect("user message")],
+                context,
+                None,
+                false,
+                false,
+                3,
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.agent.as_ref().map(Agent::name), Some("beta"));
+        assert_eq!(
+            response
+                .context_variables
+                .get("public_topic")
+                .map(String::as_str),
+            Some("weather")
+        );
+        assert!(!response.context_variables.contains_key("raw_tool_output"));
+    }
+
+    #[test]
+    fn test_agent_with_handoff_context_filter_cannot_be_serialized() {
+        let agent = Agent::new("beta", "gpt-4", Instructions::Text("You are beta.".into()))
+            .expect("beta")
+            .with_handoff_context_filter(|_| true);
+        assert!(serde_json::to_string(&agent).is_err());
+    }
+}
@@ -1,8 +1,13 @@
 #[cfg(test)]
 mod tests {
     use crate::constants::{MAX_REQUEST_TIMEOUT, MIN_REQUEST_TIMEOUT, OPENAI_DEFAULT_API_URL};
-    use crate::{Agent, Instructions, Swarm, SwarmConfig, SwarmError};
-    use std::sync::Mutex;
+    use crate::types::{ApiSettings, LoopControl, RetryStrategy, TimeoutSettings};
+    use crate::{
+        Agent, Instructions, JitterStrategy, MessageSerializationAdapter, Swarm, SwarmConfig,
+        SwarmError, SystemMessageFormat,
+    };
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     // Serializes tests that read/write OPENAI_API_KEY to prevent races.
     static ENV_LOCK: Mutex<()> = Mutex::new(());
@@ -156,4 +161,191 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_swarm_config_diff_and_apply() {
+        let dev = SwarmConfig::default();
+        let mut prod = SwarmConfig::default();
+        prod.set_valid_api_url_prefixes(vec![
+            "https://api.openai.com".to_string(),
+            "https://llm.internal.example".to_string(),
+        ])
+        .unwrap();
+        prod.set_api_url("https://llm.internal.example/v1/chat/completions")
+            .unwrap();
+        let retry = RetryStrategy::new(3, Duration::from_millis(200), Duration::from_secs(5), 3.0)
+            .unwrap()
+            .with_jitter(JitterStrategy::Equal);
+        let timeouts = TimeoutSettings::new(
+            Duration::from_secs(30),
+            Duration::from_secs(5),
+            Duration::from_secs(45),
+            Duration::from_secs(45),
+        )
+        .unwrap();
+        prod.set_api_settings(ApiSettings::new(retry.clone(), timeouts))
+            .unwrap();
+        prod.set_loop_control(LoopControl::new(20, Duration::ZERO, Vec::new()).unwrap())
+            .unwrap();
+        prod.set_request_timeout(60).unwrap();
+        prod.set_deduplicate_system_messages(false);
+
+        let diff = dev.diff(&prod);
+        assert_eq!(diff.request_timeout, Some((30, 60)));
+        assert_eq!(diff.connect_timeout, Some((10, 5)));
+        assert_eq!(diff.max_loop_iterations, Some((10, 20)));
+        assert_eq!(diff.deduplicate_system_messages, Some((true, false)));
+        assert!(diff.max_retries.is_none());
+        assert!(diff.loop_control.is_some());
+        let (_, api_settings) = diff.api_settings.clone().expect("api settings changed");
+        assert_eq!(api_settings.retry_strategy(), &retry);
+        assert_eq!(
+            api_settings.timeout_settings().request_timeout(),
+            Duration::from_secs(60)
+        );
+        assert!(dev.diff(&dev).is_empty());
+        assert_eq!(dev.diff(&dev).to_string(), "no differences");
+
+        let table = diff.to_string();
+        assert!(table.starts_with("field"));
+        assert!(table.contains("request_timeout"));
+        assert!(table.contains("30s"));
+        assert!(table.contains("60s"));
+        assert!(table.contains("loop_control"));
+        assert!(table.contains("api_settings"));
+
+        let mut migrated = dev.clone();
+        migrated.apply_diff(&diff).unwrap();
+        assert!(migrated.diff(&prod).is_empty());
+        assert_eq!(
+            migrated.api_settings().retry_strategy().initial_delay(),
+            Duration::from_millis(200)
+        );
+        assert_eq!(migrated.loop_control().iteration_delay(), Duration::ZERO);
+        assert_eq!(
+            migrated.api_url(),
+            "https://llm.internal.example/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_swarm_config_diff_covers_retry_jitter() {
+        let dev = SwarmConfig::default();
+        let mut prod = SwarmConfig::default();
+        prod.set_retry_jitter(JitterStrategy::Full);
+
+        let diff = dev.diff(&prod);
+        assert_eq!(
+            diff.retry_jitter,
+            Some((JitterStrategy::None, JitterStrategy::Full))
+        );
+        assert!(diff.to_string().contains("retry_jitter"));
+
+        let mut migrated = dev.clone();
+        migrated.apply_diff(&diff).unwrap();
+        assert!(migrated.diff(&prod).is_empty());
+    }
+
+    #[test]
+    fn test_swarm_config_diff_covers_message_serialization() {
+        let dev = SwarmConfig::default();
+        let mut prod = SwarmConfig::default();
+        prod.set_message_serialization(MessageSerializationAdapter::Ollama);
+
+        let diff = dev.diff(&prod);
+        let table = diff.to_string();
+        assert!(table.contains("message_serialization"));
+        assert!(table.contains("Ollama"));
+        assert!(prod.diff(&prod.clone()).is_empty());
+
+        let mut migrated = dev.clone();
+        migrated.apply_diff(&diff).unwrap();
+        assert!(migrated.diff(&prod).is_empty());
+    }
+
+    #[test]
+    fn test_swarm_config_diff_covers_system_message_format() {
+        let dev = SwarmConfig::default();
+        let mut prod = SwarmConfig::default();
+        prod.set_system_message_format(SystemMessageFormat::Custom(Arc::new(|instructions| {
+            format!("# {}", instructions)
+        })));
+
+        let diff = dev.diff(&prod);
+        let table = diff.to_string();
+        assert!(table.contains("system_message_format"));
+        assert!(table.contains("Custom(..)"));
+        assert!(prod.diff(&prod.clone()).is_empty());
+
+        let mut migrated = dev.clone();
+        migrated.apply_diff(&diff).unwrap();
+        assert!(migrated.diff(&prod).is_empty());
+        assert_eq!(migrated.system_message_format().format("Hi"), "# Hi");
+    }
+
+    #[test]
+    fn test_swarm_config_apply_diff_is_atomic() {
+        let mut config = SwarmConfig::default();
+        let mut diff = SwarmConfig::default().diff(&SwarmConfig::default());
+        diff.request_timeout = Some((30, 45));
+        diff.max_retries = Some((3, 0));
+
+        assert!(config.apply_diff(&diff).is_err());
+        assert_eq!(config.request_timeout(), 30);
+    }
+
+    fn retry_strategy(jitter: JitterStrategy) -> RetryStrategy {
+        RetryStrategy::new(5, Duration::from_millis(100), Duration::from_secs(1), 2.0)
+            .expect("retry strategy")
+            .with_jitter(jitter)
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_and_caps_without_jitter() {
+        let strategy = retry_strategy(JitterStrategy::None);
+        let delays: Vec<_> = (0..5)
+            .map(|attempt| strategy.compute_delay(attempt))
+            .collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(strategy.compute_delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_retry_delay_jitter_stays_in_range() {
+        let full = retry_strategy(JitterStrategy::Full);
+        let equal = retry_strategy(JitterStrategy::Equal);
+        for _ in 0..100 {
+            assert!(full.compute_delay(2) <= Duration::from_millis(400));
+            let delay = equal.compute_delay(2);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+        }
+        let distinct: std::collections::HashSet<_> =
+            (0..20).map(|_| full.compute_delay(2)).collect();
+        assert!(distinct.len() > 1, "full jitter should vary the delay");
+    }
+
+    #[test]
+    fn test_full_jitter_covers_the_lower_half() {
+        let full = retry_strategy(JitterStrategy::Full);
+        assert!(
+            (0..100).any(|_| full.compute_delay(2) < Duration::from_millis(200)),
+            "full jitter should reach below half the delay"
+        );
+    }
+
+    #[test]
+    fn test_builder_sets_retry_jitter() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test123456789".to_string())
+            .with_retry_jitter(JitterStrategy::Equal)
+            .build()
+            .expect("swarm");
+        assert_eq!(
+            swarm.config().api_settings().retry_strategy().jitter(),
+            JitterStrategy::Equal
+        );
+    }
 }
@@ -69,12 +69,16 @@ mod tests {
     // 2. MaxIterationsError carries structured max/actual fields
     #[test]
     fn test_max_iterations_error_fields() {
-        let err = SwarmError::MaxIterationsError { max: 5, actual: 7 };
+        let err = SwarmError::MaxIterationsError {
+            max: 5,
+            actual: 7,
+            last_response: None,
+        };
         let msg = err.to_string();
         assert!(msg.contains('5'), "expected max in message: {}", msg);
         assert!(msg.contains('7'), "expected actual in message: {}", msg);
         match err {
-            SwarmError::MaxIterationsError { max, actual } => {
+            SwarmError::MaxIterationsError { max, actual, .. } => {
                 assert_eq!(max, 5);
                 assert_eq!(actual, 7);
             }
@@ -2,8 +2,8 @@
 mod tests {
     use crate::types::{FunctionCall, MessageRole};
     use crate::util::merge_chunk_message;
-    use crate::validation::validate_api_request;
-    use crate::{Agent, Instructions, Message, SwarmError};
+    use crate::validation::{validate_api_request, validate_api_request_with_config};
+    use crate::{Agent, ContentPart, Instructions, Message, SwarmConfig, SwarmError};
     use serde_json::json;
 
     fn test_agent() -> Agent {
@@ -247,4 +247,148 @@ mod tests {
         assert_eq!(function_call.name(), "lookup_docs");
         assert_eq!(function_call.arguments(), "{\"query\":\"rust\"}");
     }
+
+    fn image_message() -> Message {
+        Message::user_with_parts(vec![
+            ContentPart::text("What is in this picture?"),
+            ContentPart::ImageUrl {
+                url: "https://example.com/cat.png".to_string(),
+                detail: Some("low".to_string()),
+            },
+        ])
+        .expect("Expected a valid multi-modal message")
+    }
+
+    #[test]
+    fn test_content_parts_serialize_as_content_array() {
+        let message = image_message();
+        assert_eq!(message.content(), Some("What is in this picture?"));
+        assert!(message.has_images());
+
+        let value = serde_json::to_value(&message).expect("serialize");
+        assert_eq!(
+            value,
+            json!({
+                "role": "user",
+                "content": [
+                    {"type": "text", "text": "What is in this picture?"},
+                    {
+                        "type": "image_url",
+                        "image_url": {"url": "https://example.com/cat.png", "detail": "low"}
+                    }
+                ]
+            })
+        );
+        let round_trip: Message = serde_json::from_value(value).expect("deserialize");
+        assert_eq!(round_trip, message);
+
+        let plain = serde_json::to_value(Message::user("hi").expect("message")).expect("json");
+        assert_eq!(plain, json!({"role": "user", "content": "hi"}));
+    }
+
+    #[test]
+    fn test_content_parts_are_validated() {
+        assert!(Message::user_with_parts(Vec::new()).is_err());
+        assert!(Message::user_with_parts(vec![ContentPart::text(" ")]).is_err());
+        assert!(Message::user_with_parts(vec![ContentPart::image_url("")]).is_err());
+
+        let image_only =
+            Message::user_with_parts(vec![ContentPart::image_url("data:image/png;base64,AAAA")])
+                .expect("Image-only user messages are valid");
+        assert_eq!(image_only.content(), None);
+    }
+
+    #[test]
+    fn test_validate_api_request_requires_vision_model_for_images() {
+        let agent = test_agent();
+        let messages = [image_message()];
+
+        let error = validate_api_request(&agent, &messages, &None, 1)
+            .expect_err("gpt-4 is not vision capable");
+        assert!(error.to_string().contains("image inputs"));
+        assert!(validate_api_request(&agent, &messages, &Some("gpt-4o".to_string()), 1).is_ok());
+        assert!(validate_api_request(
+            &agent,
+            &[Message::user("text only").expect("message")],
+            &None,
+            1
+        )
+        .is_ok());
+
+        let mut config = SwarmConfig::default();
+        config
+            .set_vision_capable_model_patterns(vec!["gpt-4".to_string()])
+            .expect("patterns");
+        assert!(
+            validate_api_request_with_config(&agent, &messages, &[], &None, 1, &config).is_ok()
+        );
+        assert!(validate_api_request_with_config(
+            &agent,
+            &messages,
+            &[],
+            &Some("gpt-4o".to_string()),
+            1,
+            &config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate_api_request_checks_priming_messages() {
+        let agent =
+            Agent::new("vision", "gpt-4o", Instructions::Text("Look".to_string())).expect("agent");
+        let messages = vec![Message::user("Hello").expect("user")];
+        let config = SwarmConfig::default();
+
+        let priming = [Message::function("lookup", "result").expect("function")];
+        let error =
+            validate_api_request_with_config(&agent, &messages, &priming, &None, 1, &config)
+                .expect_err("function role is not allowed");
+        assert!(error.to_string().contains("function role"));
+
+        let priming = [image_message()];
+        assert!(validate_api_request_with_config(
+            &agent,
+            &messages,
+            &priming,
+            &Some("gpt-3.5-turbo".to_string()),
+            1,
+            &config
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_content_limits_apply_to_text_parts() {
+        let mut truncated = image_message();
+        truncated.truncate_content(10);
+        let text = truncated.content().expect("text remains");
+        assert!(text.len() <= 10 && text.ends_with('…'));
+        let value = serde_json::to_value(&truncated).expect("serialize");
+        assert_eq!(value["content"][0]["text"], text);
+        assert!(truncated.has_images());
+
+        let mut compressed = image_message();
+        compressed.set_content("A picture?");
+        let value = serde_json::to_value(&compressed).expect("serialize");
+        assert_eq!(value["content"][0]["text"], "A picture?");
+        assert_eq!(value["content"].as_array().map(Vec::len), Some(2));
+    }
+
+    #[test]
+    fn test_vision_patterns_match_whole_name_parts() {
+        let config = SwarmConfig::default();
+        for model in [
+            "gpt-4o",
+            "gpt-4o-mini",
+            "o1-preview",
+            "openai/o3",
+            "gpt-4-vision-preview",
+        ] {
+            assert!(config.is_vision_capable_model(model), "{model}");
+        }
+        for model in ["gpt-3.5-turbo", "gpt-4", "text-davinci-003", "llama3:8b"] {
+            assert!(!config.is_vision_capable_model(model), "{model}");
+        }
+    }
 }
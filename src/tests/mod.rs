@@ -1,12 +1,15 @@
 pub mod agent;
 pub mod agent_serde;
 pub mod builder;
+pub mod handoff;
 pub mod initialization;
 pub mod integration;
 pub mod message;
 pub mod parallel_tool_calls;
 pub mod phase3;
+pub mod run_config;
 pub mod runtime_enforcement;
+pub mod steps;
 pub mod stream;
 pub mod swarm_run;
 pub mod tool_args;
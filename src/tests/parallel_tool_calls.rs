@@ -578,4 +578,84 @@ mod tests {
             vec![("tool_a".to_string(), true), ("explode".to_string(), false)]
         );
     }
+
+    #[tokio::test]
+    async fn test_tool_results_carry_the_agent_as_author_when_enabled() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(two_tool_calls_response()))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "cmpl-done",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4",
+                "choices": [{
+                    "index": 0,
+                    "message": {"role": "assistant", "content": "both done"},
+                    "finish_reason": "stop"
+                }],
+                "usage": null
+            })))
+            .mount(&mock_server)
+            .await;
+        let agent = parallel_agent("named-runner");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_inject_agent_name_as_message_name(true)
+            .build()
+            .expect("swarm build");
+
+        let response = swarm
+            .run(
+                agent.clone(),
+                vec![Message::user("run both tools").expect("user msg")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                5,
+            )
+            .await
+            .expect("run should succeed");
+
+        let tool_results: Vec<_> = response
+            .messages
+            .iter()
+            .filter(|m| m.tool_call_id().is_some())
+            .collect();
+        assert_eq!(tool_results.len(), 2);
+        assert!(tool_results
+            .iter()
+            .all(|m| m.author() == Some("named-runner") && m.name().is_none()));
+        assert_eq!(response.messages_by_agent("named-runner").len(), 2);
+
+        let json = serde_json::to_string(&response.messages).expect("serialize");
+        let restored: Vec<Message> = serde_json::from_str(&json).expect("deserialize");
+        assert_eq!(restored.len(), response.messages.len());
+
+        // The attributed tool results must still be valid when sent back.
+        let follow_up = swarm
+            .run(
+                agent,
+                response.messages,
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("follow-up run should succeed");
+        assert_eq!(
+            follow_up.messages.last().and_then(Message::content),
+            Some("both done")
+        );
+        assert_eq!(follow_up.messages_by_agent("named-runner").len(), 3);
+    }
 }
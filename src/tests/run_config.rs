@@ -0,0 +1,2845 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use wiremock::matchers::{header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::api_provider::AnthropicApiProvider;
+    use crate::constants::{
+        ANTHROPIC_DEFAULT_API_URL, DEAD_LETTER_RETRY_PROMPT, DEFAULT_MODEL_ALIASES,
+        SEMANTIC_DEDUP_RETRY_PROMPT,
+    };
+    use crate::context_injectors::{
+        EnvContextInjector, FileContextInjector, StaticContextInjector,
+    };
+    use crate::core::{RunOptions, Swarm};
+    use crate::execution_trace::TraceEvent;
+    use crate::response_cache::InMemoryResponseCache;
+    use crate::types::{
+        Agent, AgentFunction, AgentFunctionHandler, CompressionStrategy, ContentOverflowAction,
+        ContentPart, ContextOverflow, ContextVariables, FunctionCall, FunctionCallFormat,
+        HistoryWindowStrategy, Instructions, LogprobsConfig, Message, MessageRole,
+        MessageSerializationAdapter, ModelContextWindow, ModelParameters, PromptCompressionConfig,
+        Response, ResultType, SafetyPlacement, SystemMessageFormat,
+    };
+    use crate::util::{fill_template, jaccard_similarity, json_get_path, repair_json};
+    use std::sync::Arc;
+
+    const INSTRUCTIONS: &str = "You are a helpful assistant.";
+
+    fn mock_chat_response(content: Value) -> Value {
+        json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4",
+            "choices": [{
+                "index": 0,
+                "message": content,
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 1,
+                "completion_tokens": 1,
+                "total_tokens": 2
+            }
+        })
+    }
+
+    async fn mock_text_server(content: &str) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": content
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    /// Returns the JSON bodies of every request the mock server received, in order.
+    async fn sent_bodies(mock_server: &MockServer) -> Vec<Value> {
+        mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled")
+            .iter()
+            .map(|request| request.body_json::<Value>().expect("json request body"))
+            .collect()
+    }
+
+    fn text_agent(name: &str) -> Agent {
+        Agent::new(name, "gpt-4", Instructions::Text(INSTRUCTIONS.to_string())).expect("agent")
+    }
+
+    fn count_system_messages(body: &Value) -> usize {
+        body["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .filter(|message| message["role"] == "system")
+            .count()
+    }
+
+    #[tokio::test]
+    async fn test_reused_system_message_is_not_duplicated() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("dedup");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run(
+                agent,
+                vec![
+                    Message::system(INSTRUCTIONS).expect("system message"),
+                    Message::user("hello").expect("user message"),
+                ],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(count_system_messages(&bodies[0]), 1);
+    }
+
+    #[tokio::test]
+    async fn test_system_message_dedup_can_be_disabled() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("no-dedup");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_deduplicate_system_messages(false)
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run(
+                agent,
+                vec![
+                    Message::system(INSTRUCTIONS).expect("system message"),
+                    Message::user("hello").expect("user message"),
+                ],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(count_system_messages(&bodies[0]), 2);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_key_rotates_to_next_key() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer sk-limited"))
+            .respond_with(ResponseTemplate::new(429).set_body_string("rate limited"))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("authorization", "Bearer sk-healthy"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": "done"
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+
+        let agent = text_agent("rotating");
+        let swarm = Swarm::builder()
+            .with_api_keys(vec!["sk-limited".to_string(), "sk-healthy".to_string()])
+            .expect("keys")
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("second key should serve the request");
+
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("done")
+        );
+        assert_eq!(swarm.api_key_pool().cooling_down(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_priming_messages_are_sent_but_not_stored() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("primed");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let options = RunOptions::new(1).with_priming_messages(vec![
+            Message::user("Example: 2 + 2?").expect("priming user"),
+            Message::assistant("4").expect("priming assistant"),
+        ]);
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("3 + 3?").expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        let contents = bodies[0]["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .map(|message| message["content"].as_str().unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            vec![INSTRUCTIONS, "Example: 2 + 2?", "4", "3 + 3?"]
+        );
+        assert!(response
+            .messages
+            .iter()
+            .all(|message| message.content() != Some("Example: 2 + 2?")));
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_provider_uses_messages_format() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("x-api-key", "sk-ant-test"))
+            .and(header("anthropic-version", "2023-06-01"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "id": "msg_test",
+                "type": "message",
+                "role": "assistant",
+                "content": [{"type": "text", "text": "done"}],
+                "stop_reason": "end_turn",
+                "usage": {"input_tokens": 5, "output_tokens": 1}
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let agent = Agent::new(
+            "claude",
+            "claude-3-5-sonnet",
+            Instructions::Text(INSTRUCTIONS.to_string()),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-ant-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_provider(AnthropicApiProvider::new())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("done")
+        );
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies[0]["system"], INSTRUCTIONS);
+        assert_eq!(bodies[0]["messages"][0]["role"], "user");
+        assert_eq!(count_system_messages(&bodies[0]), 0);
+    }
+
+    #[test]
+    fn test_provider_supplies_its_default_api_url() {
+        let build = |builder: crate::core::SwarmBuilder| {
+            builder
+                .with_api_key("sk-ant-test".to_string())
+                .build()
+                .expect("swarm")
+        };
+
+        let swarm = build(Swarm::builder().with_provider(AnthropicApiProvider::new()));
+        assert_eq!(swarm.config().api_url(), ANTHROPIC_DEFAULT_API_URL);
+
+        let custom = "https://api.anthropic.com/v1/custom";
+        let swarm = build(
+            Swarm::builder()
+                .with_api_url(custom.to_string())
+                .with_provider(AnthropicApiProvider::new()),
+        );
+        assert_eq!(swarm.config().api_url(), custom);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_are_served_from_response_cache() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("cached");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_response_cache(Arc::new(InMemoryResponseCache::new(8).expect("cache")))
+            .build()
+            .expect("swarm");
+
+        for _ in 0..2 {
+            let response = swarm
+                .run(
+                    agent.clone(),
+                    vec![Message::user("hello").expect("user message")],
+                    ContextVariables::new(),
+                    None,
+                    false,
+                    false,
+                    1,
+                )
+                .await
+                .expect("run");
+            assert_eq!(
+                response.messages.last().and_then(Message::content),
+                Some("done")
+            );
+        }
+
+        assert_eq!(sent_bodies(&mock_server).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_keys_on_the_full_request() {
+        let mock_server = mock_text_server("done").await;
+        let plain = text_agent("plain");
+        let tooling = text_agent("tooling").with_functions(vec![context_writer()]);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(plain.clone())
+            .with_agent(tooling.clone())
+            .with_response_cache(Arc::new(InMemoryResponseCache::new(8).expect("cache")))
+            .build()
+            .expect("swarm");
+
+        for agent in [plain, tooling] {
+            swarm
+                .run(
+                    agent,
+                    vec![Message::user("hello").expect("user message")],
+                    ContextVariables::new(),
+                    None,
+                    false,
+                    false,
+                    1,
+                )
+                .await
+                .expect("run");
+        }
+
+        assert_eq!(sent_bodies(&mock_server).await.len(), 2);
+    }
+
+    fn long_history() -> Vec<Message> {
+        vec![
+            Message::user("first question").expect("user"),
+            Message::assistant("first answer").expect("assistant"),
+            Message::user("second question").expect("user"),
+            Message::assistant("second answer").expect("assistant"),
+            Message::user("third question").expect("user"),
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_sliding_history_window_drops_old_messages() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("sliding");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_history_window(HistoryWindowStrategy::Sliding { window: 2 })
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                long_history(),
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        let contents = bodies[0]["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .map(|message| message["content"].as_str().unwrap_or_default().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            vec![INSTRUCTIONS, "second answer", "third question"]
+        );
+        // Windowing only affects the request; the returned history is complete.
+        assert_eq!(response.messages.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn test_sliding_with_overlap_prepends_rolling_summary() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("overlap");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_history_window(HistoryWindowStrategy::SlidingWithOverlap {
+                full_window: 2,
+                overlap_summary_turns: 1,
+            })
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                long_history(),
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.tokens_used, 4, "summary usage is counted");
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 2, "one summary request, one completion");
+        let transcript = bodies[0]["messages"][1]["content"]
+            .as_str()
+            .expect("transcript");
+        assert!(transcript.contains("first question"));
+        assert!(transcript.contains("second answer"));
+        assert!(!transcript.contains("third question"));
+
+        let messages = bodies[1]["messages"].as_array().expect("messages array");
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1]["role"], "system");
+        assert_eq!(
+            messages[1]["content"],
+            "Summary of the earlier conversation:\ndone"
+        );
+        assert_eq!(messages[3]["content"], "third question");
+    }
+
+    #[test]
+    fn test_history_window_overlap_must_fit_in_window() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_history_window(HistoryWindowStrategy::SlidingWithOverlap {
+                full_window: 2,
+                overlap_summary_turns: 2,
+            })
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_id_from_run_options_takes_precedence() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("tracked");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_user_id_provider(|ctx| ctx.get("__session_user_id").cloned())
+            .build()
+            .expect("swarm");
+
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("__session_user_id".to_string(), "session-user".to_string());
+
+        swarm
+            .run_with_options(
+                agent.clone(),
+                vec![Message::user("hello").expect("user message")],
+                context_variables.clone(),
+                RunOptions::new(1).with_user_id("explicit-user"),
+            )
+            .await
+            .expect("run");
+        swarm
+            .run_with_options(
+                agent.clone(),
+                vec![Message::user("hello again").expect("user message")],
+                context_variables,
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("anonymous").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies[0]["user"], "explicit-user");
+        assert_eq!(bodies[1]["user"], "session-user");
+        assert!(bodies[2].get("user").is_none());
+    }
+
+    fn capped_agent(max_tokens: u32) -> Agent {
+        text_agent("capped")
+            .with_model_parameters(
+                ModelParameters::default()
+                    .with_temperature(0.2)
+                    .with_max_tokens(max_tokens),
+            )
+            .expect("model parameters")
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_max_tokens_caps_to_remaining_context_window() {
+        let mock_server = mock_text_server("done").await;
+        let agent = capped_agent(4_000);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_adaptive_max_tokens(true)
+            .with_model_context_window("gpt-4", 1_000)
+            .build()
+            .expect("swarm");
+
+        // 800 bytes of user content is estimated at 200 prompt tokens.
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("x".repeat(800)).expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        let system_tokens = (INSTRUCTIONS.len() / 4) as u64;
+        assert_eq!(
+            bodies[0]["max_tokens"].as_u64(),
+            Some(1_000 - 200 - system_tokens - 50)
+        );
+        assert!(bodies[0]["temperature"].is_number());
+    }
+
+    #[tokio::test]
+    async fn test_max_tokens_is_sent_unchanged_without_adaptive_mode() {
+        let mock_server = mock_text_server("done").await;
+        let agent = capped_agent(4_000);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_model_context_window("gpt-4", 1_000)
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("x".repeat(800)).expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies[0]["max_tokens"], 4_000);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_max_tokens_rejects_prompt_that_fills_the_window() {
+        let mock_server = mock_text_server("done").await;
+        let agent = capped_agent(4_000);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_adaptive_max_tokens(true)
+            .with_model_context_window("gpt-4", 100)
+            .build()
+            .expect("swarm");
+
+        let result = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("x".repeat(800)).expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert!(sent_bodies(&mock_server).await.is_empty());
+    }
+
+    #[test]
+    fn test_jaccard_similarity_compares_word_sets() {
+        assert_eq!(jaccard_similarity("The cat sat", "the CAT sat!"), 1.0);
+        assert_eq!(jaccard_similarity("a b", "c d"), 0.0);
+        assert_eq!(jaccard_similarity("a b c", "b c d"), 0.5);
+        assert_eq!(jaccard_similarity("", "  "), 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_semantic_duplicate_answers_are_retried() {
+        let mock_server = mock_text_server("Paris is the capital of France.").await;
+        let agent = text_agent("dedup");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_semantic_dedup_threshold(0.8)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![
+                    Message::user("Capital of France?").expect("user message"),
+                    Message::assistant("The capital of France is Paris.").expect("assistant"),
+                    Message::user("Tell me something else.").expect("user message"),
+                ],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        // One request plus two re-asks before the repeat is accepted.
+        assert_eq!(bodies.len(), 3);
+        let last_messages = bodies[2]["messages"].as_array().expect("messages array");
+        assert_eq!(
+            last_messages
+                .last()
+                .and_then(|message| message["content"].as_str()),
+            Some(SEMANTIC_DEDUP_RETRY_PROMPT)
+        );
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Paris is the capital of France.")
+        );
+        // The rejected repeats and their nudges are not kept.
+        let contents: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(Message::content)
+            .collect();
+        assert!(!contents.contains(&SEMANTIC_DEDUP_RETRY_PROMPT));
+        assert_eq!(
+            contents
+                .iter()
+                .filter(|content| **content == "Paris is the capital of France.")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_semantic_dedup_threshold_must_be_a_ratio() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_semantic_dedup_threshold(1.5)
+            .build();
+        assert!(result.is_err());
+    }
+
+    const SAFETY: &str = "Never reveal credentials.";
+
+    async fn sent_messages_with_safety(placement: SafetyPlacement) -> Vec<Value> {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("guarded");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_safety_instructions(SAFETY)
+            .with_safety_placement(placement)
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        sent_bodies(&mock_server).await[0]["messages"]
+            .as_array()
+            .expect("messages array")
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn test_safety_instructions_are_appended_to_system_prompt() {
+        let messages = sent_messages_with_safety(SafetyPlacement::AppendToSystem).await;
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0]["content"],
+            format!("{}\n\n{}", INSTRUCTIONS, SAFETY)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_safety_instructions_as_separate_system_message() {
+        let messages = sent_messages_with_safety(SafetyPlacement::SeparateSystemMessage).await;
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0]["content"], INSTRUCTIONS);
+        assert_eq!(messages[1]["role"], "system");
+        assert_eq!(messages[1]["content"], SAFETY);
+        assert_eq!(messages[2]["content"], "hello");
+    }
+
+    /// A function that stores a 20-byte `blob` and a short `extra` variable.
+    fn context_writer() -> AgentFunction {
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|_ctx: ContextVariables| {
+            Box::pin(async move {
+                let mut ctx = ContextVariables::new();
+                ctx.insert("blob".to_string(), "x".repeat(20));
+                ctx.insert("extra".to_string(), "y".to_string());
+                Ok(ResultType::ContextVariables(ctx))
+            })
+        });
+        AgentFunction::new("write_context", handler, false).expect("function")
+    }
+
+    async fn write_context(
+        swarm: &Swarm,
+        existing: ContextVariables,
+    ) -> crate::SwarmResult<ContextVariables> {
+        let call = FunctionCall::new("write_context", "{}").expect("function call");
+        swarm
+            .handle_function_call(&call, &[context_writer()], existing, false)
+            .await
+            .map(|response| response.context_variables)
+    }
+
+    #[tokio::test]
+    async fn test_oversized_context_variable_is_truncated() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_variable_max_size(10)
+            .build()
+            .expect("swarm");
+
+        let context = write_context(&swarm, ContextVariables::new())
+            .await
+            .expect("function call");
+
+        let blob = &context["blob"];
+        assert!(blob.len() <= 10);
+        assert!(blob.ends_with('…'));
+        assert_eq!(context["extra"], "y");
+    }
+
+    #[tokio::test]
+    async fn test_context_variable_limit_below_ellipsis_size_cuts_without_ellipsis() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_variable_max_size(2)
+            .build()
+            .expect("swarm");
+
+        let context = write_context(&swarm, ContextVariables::new())
+            .await
+            .expect("function call");
+
+        let blob = &context["blob"];
+        assert_eq!(blob.len(), 2);
+        assert!(!blob.contains('…'));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_context_variable_errors_when_configured() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_variable_max_size(10)
+            .with_context_overflow(ContextOverflow::Error)
+            .build()
+            .expect("swarm");
+
+        let error = write_context(&swarm, ContextVariables::new())
+            .await
+            .expect_err("blob exceeds max size");
+
+        assert_eq!(
+            error.to_string(),
+            crate::SwarmError::ContextError(
+                "context variable 'blob' value exceeds max size".into()
+            )
+            .to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_context_variable_count_limit() {
+        let mut existing = ContextVariables::new();
+        existing.insert("blob".to_string(), "old".to_string());
+
+        let truncating = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_variable_max_count(1)
+            .build()
+            .expect("swarm");
+        let context = write_context(&truncating, existing.clone())
+            .await
+            .expect("function call");
+        // Replacing an existing variable is allowed; adding a second one is not.
+        assert_eq!(context.len(), 1);
+        assert!(context.contains_key("blob"));
+
+        let strict = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_variable_max_count(1)
+            .with_context_overflow(ContextOverflow::Error)
+            .build()
+            .expect("swarm");
+        assert!(matches!(
+            write_context(&strict, existing).await,
+            Err(crate::SwarmError::ContextError(_))
+        ));
+    }
+
+    async fn first_body_for(agent: Agent, default_format: FunctionCallFormat) -> Value {
+        let mock_server = mock_text_server("done").await;
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_default_function_format(default_format)
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        sent_bodies(&mock_server).await.remove(0)
+    }
+
+    fn agent_with_function() -> Agent {
+        text_agent("tooling")
+            .with_functions(vec![context_writer()])
+            .with_function_call_policy(crate::types::FunctionCallPolicy::Named(
+                "write_context".to_string(),
+            ))
+    }
+
+    #[tokio::test]
+    async fn test_tools_function_format_sends_tools_and_tool_choice() {
+        let body = first_body_for(agent_with_function(), FunctionCallFormat::Tools).await;
+
+        assert!(body.get("functions").is_none());
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["tools"][0]["function"]["name"], "write_context");
+        assert_eq!(
+            body["tool_choice"],
+            json!({"type": "function", "function": {"name": "write_context"}})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_agent_function_format_overrides_config_default() {
+        let agent = agent_with_function().with_function_format(FunctionCallFormat::Legacy);
+        let body = first_body_for(agent, FunctionCallFormat::Tools).await;
+
+        assert!(body.get("tools").is_none());
+        assert_eq!(body["functions"][0]["name"], "write_context");
+        assert_eq!(body["function_call"], "write_context");
+    }
+
+    /// A function that returns 400 bytes of page text.
+    fn page_fetcher() -> AgentFunction {
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|_ctx: ContextVariables| {
+            Box::pin(async move { Ok(ResultType::Value("page ".repeat(80))) })
+        });
+        AgentFunction::new("fetch_page", handler, false).expect("function")
+    }
+
+    async fn fetch_page(swarm: &Swarm) -> String {
+        let call = FunctionCall::new("fetch_page", "{}").expect("function call");
+        let response = swarm
+            .handle_function_call(&call, &[page_fetcher()], ContextVariables::new(), false)
+            .await
+            .expect("function call");
+        response.messages[0]
+            .content()
+            .expect("function result")
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_large_tool_result_is_truncated_without_summarizer() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_tool_result_max_tokens(10)
+            .build()
+            .expect("swarm");
+
+        let result = fetch_page(&swarm).await;
+
+        assert!(result.len() <= 40);
+        assert!(result.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn test_large_tool_result_is_summarized_by_configured_agent() {
+        let mock_server = mock_text_server("A page of text.").await;
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(text_agent("summarizer"))
+            .with_tool_result_max_tokens(10)
+            .with_tool_summarizer_agent("summarizer")
+            .build()
+            .expect("swarm");
+
+        assert_eq!(fetch_page(&swarm).await, "A page of text.");
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 1);
+        let prompt = bodies[0]["messages"][1]["content"]
+            .as_str()
+            .expect("summary prompt");
+        assert!(prompt.contains("Result of `fetch_page`"));
+        assert!(prompt.contains(&"page ".repeat(80)));
+    }
+
+    #[tokio::test]
+    async fn test_small_tool_result_is_not_summarized() {
+        let mock_server = mock_text_server("unused").await;
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(text_agent("summarizer"))
+            .with_tool_result_max_tokens(1000)
+            .with_tool_summarizer_agent("summarizer")
+            .build()
+            .expect("swarm");
+
+        assert_eq!(fetch_page(&swarm).await, "page ".repeat(80));
+        assert!(sent_bodies(&mock_server).await.is_empty());
+    }
+
+    #[test]
+    fn test_tool_summarizer_agent_must_be_registered() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_tool_summarizer_agent("missing")
+            .build();
+
+        assert!(matches!(result, Err(crate::SwarmError::ValidationError(_))));
+    }
+
+    /// Runs a two-step workflow whose first reply calls `write_context` and whose
+    /// second reply is plain text.
+    async fn run_function_then_text(options: RunOptions) -> crate::Response {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_write_context",
+                        "type": "function",
+                        "function": {"name": "write_context", "arguments": "{}"}
+                    }]
+                }))),
+            )
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": "done"
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        let agent = Agent::new(
+            "worker",
+            "gpt-4",
+            Instructions::Text(
+                r#"<steps><step number="1" action="run_once"><prompt>Store</prompt></step><step number="2" action="run_once"><prompt>Report</prompt></step></steps>"#
+                    .to_string(),
+            ),
+        )
+        .expect("agent")
+        .with_functions(vec![context_writer()]);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+            .await
+            .expect("run")
+    }
+
+    #[tokio::test]
+    async fn test_total_api_calls_counts_retried_requests() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(429).set_body_string("slow down"))
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": "done"
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        let agent = text_agent("worker");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_max_retries(1)
+            .with_api_key_cooldown(std::time::Duration::ZERO)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.turn_metadata.len(), 1);
+        assert_eq!(response.total_api_calls(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_turn_metadata_records_each_turn() {
+        let response = run_function_then_text(RunOptions::new(5)).await;
+
+        assert_eq!(response.total_api_calls(), 2);
+        assert_eq!(response.agents_used(), vec!["worker".to_string()]);
+        let turns = &response.turn_metadata;
+        assert_eq!((turns[0].turn, turns[1].turn), (1, 2));
+        assert_eq!(turns[0].model, "gpt-4");
+        assert_eq!(turns[0].function_calls, vec!["write_context".to_string()]);
+        assert!(turns[1].function_calls.is_empty());
+        assert_eq!(turns[1].prompt_tokens, 1);
+        assert_eq!(turns[1].completion_tokens, 1);
+    }
+
+    async fn routed_model(prompt: &str, options: RunOptions) -> Value {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("router");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_task_complexity_scorer(|task: &str| task.len() as f32 / 100.0)
+            .with_complexity_model_map(vec![
+                (0.5, "gpt-4o".to_string()),
+                (0.1, "gpt-4o-mini".to_string()),
+            ])
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user(prompt).expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+            .await
+            .expect("run");
+        sent_bodies(&mock_server).await[0]["model"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_task_complexity_selects_model() {
+        let simple = routed_model(&"a".repeat(20), RunOptions::new(1)).await;
+        let complex = routed_model(&"a".repeat(80), RunOptions::new(1)).await;
+        let trivial = routed_model("hi", RunOptions::new(1)).await;
+
+        assert_eq!(simple, "gpt-4o-mini");
+        assert_eq!(complex, "gpt-4o");
+        assert_eq!(trivial, "gpt-4");
+    }
+
+    #[tokio::test]
+    async fn test_model_override_beats_complexity_routing() {
+        let model = routed_model(
+            &"a".repeat(80),
+            RunOptions::new(1).with_model_override("gpt-3.5-turbo"),
+        )
+        .await;
+
+        assert_eq!(model, "gpt-3.5-turbo");
+    }
+
+    #[tokio::test]
+    async fn test_routed_model_is_validated_like_an_override() {
+        let agent = Agent::new(
+            "router",
+            "gpt-4o",
+            Instructions::Text(INSTRUCTIONS.to_string()),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_agent(agent.clone())
+            .with_task_complexity_scorer(|_: &str| 1.0)
+            .with_complexity_model_map(vec![(0.5, "gpt-3.5-turbo".to_string())])
+            .build()
+            .expect("swarm");
+        let message = Message::user_with_parts(vec![
+            ContentPart::text("What is in this picture?"),
+            ContentPart::image_url("https://example.com/cat.png"),
+        ])
+        .expect("user message");
+
+        let error = swarm
+            .run_with_options(
+                agent,
+                vec![message],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect_err("routed model cannot read images");
+
+        assert!(matches!(
+            error,
+            crate::SwarmError::ValidationError(message)
+                if message == "Model 'gpt-3.5-turbo' does not accept image inputs"
+        ));
+    }
+
+    #[test]
+    fn test_complexity_model_map_rejects_non_finite_thresholds() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_complexity_model_map(vec![(f32::NAN, "gpt-4o".to_string())])
+            .build();
+
+        assert!(matches!(result, Err(crate::SwarmError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_execution_trace_records_run_events() {
+        let untraced = run_function_then_text(RunOptions::new(5)).await;
+        assert!(untraced.trace.is_none());
+
+        let response = run_function_then_text(RunOptions::new(5).with_execution_trace(true)).await;
+        let trace = response.trace.expect("execution trace");
+        let lines: Vec<String> = trace
+            .iter()
+            .map(|entry| {
+                // Drop the timestamp.
+                let line = entry.to_log_line();
+                line.split_once(' ').expect("timestamp").1.to_string()
+            })
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                "agent selected: worker",
+                "message sent (user): start",
+                "step 1 started (run_once)",
+                "message sent (user): Store",
+                "message received (assistant):  [calls write_context]",
+                "function called: write_context {}",
+                "function returned: write_context -> ",
+                "context updated: blob = xxxxxxxxxxxxxxxxxxxx",
+                "context updated: extra = y",
+                "step 1 completed",
+                "step 2 started (run_once)",
+                "message sent (user): Report",
+                "message received (assistant): done",
+                "step 2 completed",
+            ]
+        );
+        assert!(matches!(
+            trace[5].event,
+            TraceEvent::FunctionCalled(ref name, _) if name == "write_context"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_execution_trace_redacts_function_arguments_and_results() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_notify",
+                        "type": "function",
+                        "function": {"name": "notify", "arguments": "{\"to\":\"jane@example.com\"}"}
+                    }]
+                }))),
+            )
+            .up_to_n_times(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": "done"
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|ctx: ContextVariables| {
+            Box::pin(async move {
+                Ok(ResultType::Value(format!(
+                    "Sent to {}",
+                    ctx.get("to").cloned().unwrap_or_default()
+                )))
+            })
+        });
+        let agent = text_agent("worker")
+            .with_functions(vec![
+                AgentFunction::new("notify", handler, true).expect("function")
+            ]);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(3).with_execution_trace(true),
+            )
+            .await
+            .expect("run");
+
+        let lines: Vec<String> = response
+            .trace
+            .expect("execution trace")
+            .iter()
+            .map(|entry| entry.to_log_line())
+            .filter(|line| line.contains("function"))
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|line| !line.contains("jane@example.com")));
+        assert!(lines.iter().all(|line| line.contains("[REDACTED_email]")));
+    }
+
+    fn stop_sequences(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_global_and_agent_stop_sequences_are_merged() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("sentinel").with_stop_sequences(stop_sequences(&["END", "</json>"]));
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_global_stop_sequences(stop_sequences(&["###", "END"]))
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let body = sent_bodies(&mock_server).await.remove(0);
+        assert_eq!(body["stop"], json!(["###", "END", "</json>"]));
+    }
+
+    #[tokio::test]
+    async fn test_stop_is_omitted_without_sequences() {
+        let body = first_body_for(text_agent("plain"), FunctionCallFormat::Legacy).await;
+        assert!(body.get("stop").is_none());
+    }
+
+    #[test]
+    fn test_merged_stop_sequences_are_limited_to_four() {
+        let too_many = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_global_stop_sequences(stop_sequences(&["a", "b"]))
+            .with_agent(text_agent("chatty").with_stop_sequences(stop_sequences(&["c", "d", "e"])))
+            .build();
+        assert!(matches!(
+            too_many,
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+
+        let duplicates_fit = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_global_stop_sequences(stop_sequences(&["a", "b"]))
+            .with_agent(text_agent("chatty").with_stop_sequences(stop_sequences(&["a", "b", "c"])))
+            .build();
+        assert!(duplicates_fit.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_assistant_prefix_is_sent_as_last_message() {
+        let agent = text_agent("prefixed")
+            .with_assistant_prefix("ANSWER:")
+            .expect("prefix");
+        let body = first_body_for(agent, FunctionCallFormat::Legacy).await;
+
+        let messages = body["messages"].as_array().expect("messages");
+        let last = messages.last().expect("last message");
+        assert_eq!(last["role"], "assistant");
+        assert_eq!(last["content"], "ANSWER:");
+        assert_eq!(messages[messages.len() - 2]["content"], "hello");
+    }
+
+    #[test]
+    fn test_assistant_prefix_cannot_be_blank() {
+        assert!(matches!(
+            text_agent("prefixed").with_assistant_prefix("  "),
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+    }
+
+    #[test]
+    fn test_repair_json_fixes_common_mistakes() {
+        let cases = [
+            (r#"{city: "Paris"}"#, json!({"city": "Paris"})),
+            (r#"{"city": 'Paris'}"#, json!({"city": "Paris"})),
+            (r#"{'quote': 'say "hi"'}"#, json!({"quote": "say \"hi\""})),
+            (r#"{"tags": ["a", "b",],}"#, json!({"tags": ["a", "b"]})),
+            (r#"{"a": {"b": [1, 2"#, json!({"a": {"b": [1, 2]}})),
+            (r#"{"city": "Par"#, json!({"city": "Par"})),
+            (
+                r#"{live: true, note: null}"#,
+                json!({"live": true, "note": null}),
+            ),
+        ];
+        for (input, expected) in cases {
+            let repaired = repair_json(input).expect(input);
+            assert_eq!(
+                serde_json::from_str::<Value>(&repaired).expect("valid JSON"),
+                expected,
+                "{}",
+                input
+            );
+        }
+
+        assert!(matches!(
+            repair_json("{city: Paris London}"),
+            Err(crate::SwarmError::DeserializationError(_))
+        ));
+    }
+
+    fn city_echo() -> AgentFunction {
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|ctx: ContextVariables| {
+            Box::pin(async move {
+                Ok(ResultType::Value(
+                    ctx.get("city").cloned().unwrap_or_default(),
+                ))
+            })
+        });
+        AgentFunction::new("echo_city", handler, false).expect("function")
+    }
+
+    async fn call_with_malformed_arguments(swarm: &Swarm) -> crate::SwarmResult<Response> {
+        let call: FunctionCall = serde_json::from_value(json!({
+            "name": "echo_city",
+            "arguments": "{city: 'Paris',"
+        }))
+        .expect("function call");
+        swarm
+            .handle_function_call(&call, &[city_echo()], ContextVariables::new(), false)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_malformed_function_arguments_are_repaired_when_enabled() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_repair_function_arguments(true)
+            .build()
+            .expect("swarm");
+
+        let response = call_with_malformed_arguments(&swarm)
+            .await
+            .expect("repaired call");
+        assert_eq!(response.messages[0].content(), Some("Paris"));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_function_arguments_are_rejected_by_default() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .build()
+            .expect("swarm");
+
+        assert!(matches!(
+            call_with_malformed_arguments(&swarm).await,
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+    }
+
+    async fn first_body_with_serialization(adapter: MessageSerializationAdapter) -> Value {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("viewer");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_vision_capable_model_patterns(vec!["gpt-4".to_string()])
+            .with_message_serialization(adapter)
+            .build()
+            .expect("swarm");
+        let message = Message::user_with_parts(vec![
+            ContentPart::text("Describe this"),
+            ContentPart::image_url("data:image/png;base64,iVBORw0KGgo="),
+        ])
+        .expect("image message");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![message],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        sent_bodies(&mock_server).await.remove(0)
+    }
+
+    #[tokio::test]
+    async fn test_openai_serialization_sends_content_parts() {
+        let body = first_body_with_serialization(MessageSerializationAdapter::OpenAI).await;
+
+        let user = &body["messages"][1];
+        assert_eq!(
+            user["content"][0],
+            json!({"type": "text", "text": "Describe this"})
+        );
+        assert_eq!(user["content"][1]["type"], "image_url");
+        assert!(user.get("images").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ollama_serialization_sends_images_field() {
+        let body = first_body_with_serialization(MessageSerializationAdapter::Ollama).await;
+
+        let user = &body["messages"][1];
+        assert_eq!(user["role"], "user");
+        assert_eq!(user["content"], "Describe this");
+        assert_eq!(user["images"], json!(["iVBORw0KGgo="]));
+        assert_eq!(body["messages"][0]["content"], INSTRUCTIONS);
+    }
+
+    #[test]
+    fn test_ollama_serialization_rejects_remote_images() {
+        let message = Message::user_with_parts(vec![
+            ContentPart::text("Describe this"),
+            ContentPart::image_url("https://example.com/cat.png"),
+        ])
+        .expect("image message");
+
+        let error = MessageSerializationAdapter::Ollama
+            .serialize_message(&message)
+            .expect_err("remote image");
+        assert!(matches!(error, crate::SwarmError::ValidationError(_)));
+        assert!(MessageSerializationAdapter::OpenAI
+            .serialize_message(&message)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_custom_serialization_shapes_each_message() {
+        let adapter = MessageSerializationAdapter::Custom(Arc::new(
+            |message: &Message| json!({"speaker": message.role().as_str(), "text": message.content()}),
+        ));
+        let body = first_body_with_serialization(adapter).await;
+
+        assert_eq!(
+            body["messages"][1],
+            json!({"speaker": "user", "text": "Describe this"})
+        );
+    }
+
+    const COT_PREFIX: &str = "Let me think step by step:\n";
+
+    #[tokio::test]
+    async fn test_cot_prefix_is_sent_but_not_stored() {
+        let mock_server = mock_text_server("Let me think step by step:\n2 + 2 = 4.").await;
+        let agent = Agent::new(
+            "reasoner",
+            "gpt-4",
+            Instructions::Text(
+                r#"<steps><step number="1" action="run_once"><prompt>Add 2 and 2</prompt></step><step number="2" action="run_once"><prompt>Check it</prompt></step></steps>"#
+                    .to_string(),
+            ),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(3).with_cot_prefix(COT_PREFIX),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 2);
+        for body in &bodies {
+            let last = body["messages"]
+                .as_array()
+                .expect("messages")
+                .last()
+                .cloned();
+            assert_eq!(
+                last,
+                Some(json!({"role": "assistant", "content": COT_PREFIX}))
+            );
+        }
+        let second_request = bodies[1]["messages"].as_array().expect("messages");
+        assert_eq!(
+            second_request[second_request.len() - 3]["content"],
+            "2 + 2 = 4."
+        );
+
+        let assistant_replies: Vec<_> = response
+            .messages
+            .iter()
+            .filter(|message| message.role() == crate::types::MessageRole::Assistant)
+            .filter_map(Message::content)
+            .collect();
+        assert_eq!(assistant_replies, vec!["2 + 2 = 4.", "2 + 2 = 4."]);
+    }
+
+    #[tokio::test]
+    async fn test_cot_prefix_strips_the_whole_prefill_after_an_assistant_prefix() {
+        let mock_server = mock_text_server("ANSWER: Let me think step by step:\n2 + 2 = 4.").await;
+        let agent = text_agent("reasoner")
+            .with_assistant_prefix("ANSWER: ")
+            .expect("prefix");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Add 2 and 2").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1).with_cot_prefix(COT_PREFIX),
+            )
+            .await
+            .expect("run");
+
+        let last = sent_bodies(&mock_server).await[0]["messages"]
+            .as_array()
+            .expect("messages")
+            .last()
+            .cloned();
+        assert_eq!(
+            last,
+            Some(json!({"role": "assistant", "content": format!("ANSWER: {}", COT_PREFIX)}))
+        );
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("2 + 2 = 4.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_anthropic_prefill_is_sent_without_trailing_whitespace() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "content": [{"type": "text", "text": "Let me think step by step: 2 + 2 = 4."}],
+                "stop_reason": "end_turn",
+                "usage": {"input_tokens": 5, "output_tokens": 1}
+            })))
+            .mount(&mock_server)
+            .await;
+        let agent = text_agent("claude");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-ant-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_provider(AnthropicApiProvider::new())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Add 2 and 2").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1).with_cot_prefix(COT_PREFIX),
+            )
+            .await
+            .expect("run");
+
+        let last = sent_bodies(&mock_server).await[0]["messages"]
+            .as_array()
+            .expect("messages")
+            .last()
+            .cloned();
+        assert_eq!(
+            last,
+            Some(json!({
+                "role": "assistant",
+                "content": [{"type": "text", "text": "Let me think step by step:"}]
+            }))
+        );
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("2 + 2 = 4.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cot_prefix_cannot_be_blank() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .build()
+            .expect("swarm");
+        let result = swarm
+            .run_with_options(
+                text_agent("reasoner"),
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1).with_cot_prefix(" "),
+            )
+            .await;
+        assert!(matches!(result, Err(crate::SwarmError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_are_requested_and_stored_per_turn() {
+        let logprobs = json!({
+            "content": [{"token": "Hi", "logprob": -0.01, "top_logprobs": []}]
+        });
+        let mut body = mock_chat_response(json!({"role": "assistant", "content": "Hi"}));
+        body["choices"][0]["logprobs"] = logprobs.clone();
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&mock_server)
+            .await;
+
+        let agent = text_agent("scorer")
+            .with_logprobs_config(LogprobsConfig {
+                enabled: true,
+                top_logprobs: Some(3),
+            })
+            .expect("logprobs config");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let response = swarm
+            .run(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies[0]["logprobs"], json!(true));
+        assert_eq!(bodies[0]["top_logprobs"], json!(3));
+        assert_eq!(response.extract_logprobs(), vec![Some(&logprobs)]);
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_are_not_requested_by_default() {
+        let mock_server = mock_text_server("Hi").await;
+        let agent = text_agent("plain");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let response = swarm
+            .run(
+                agent,
+                vec![Message::user("hello").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert!(bodies[0].get("logprobs").is_none());
+        assert_eq!(response.extract_logprobs(), vec![None]);
+    }
+
+    #[test]
+    fn test_logprobs_config_is_validated() {
+        let too_many = text_agent("a").with_logprobs_config(LogprobsConfig {
+            enabled: true,
+            top_logprobs: Some(21),
+        });
+        assert!(matches!(
+            too_many,
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+        let disabled = text_agent("a").with_logprobs_config(LogprobsConfig {
+            enabled: false,
+            top_logprobs: Some(2),
+        });
+        assert!(matches!(
+            disabled,
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+    }
+
+    async fn run_with_long_user_message(
+        mock_server: &MockServer,
+        action: ContentOverflowAction,
+    ) -> crate::SwarmResult<Response> {
+        let agent = text_agent("bounded");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_max_content_length(MessageRole::User, 20, action)
+            .build()
+            .expect("swarm");
+        swarm
+            .run(
+                agent,
+                vec![Message::user("x".repeat(50)).expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                1,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_long_user_message_is_truncated_for_its_role() {
+        let mock_server = mock_text_server("ok").await;
+
+        let response =
+            run_with_long_user_message(&mock_server, ContentOverflowAction::Truncate(10))
+                .await
+                .expect("run");
+
+        let expected = format!("{}…", "x".repeat(7));
+        let bodies = sent_bodies(&mock_server).await;
+        let sent_user = bodies[0]["messages"]
+            .as_array()
+            .expect("messages")
+            .iter()
+            .find(|message| message["role"] == "user")
+            .cloned()
+            .expect("user message");
+        assert_eq!(sent_user["content"], json!(expected));
+        assert_eq!(response.messages[0].content(), Some(expected.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_long_user_message_can_fail_the_turn() {
+        let mock_server = mock_text_server("ok").await;
+
+        let error = run_with_long_user_message(&mock_server, ContentOverflowAction::Error)
+            .await
+            .expect_err("message over its role limit");
+        assert!(matches!(error, crate::SwarmError::ValidationError(_)));
+        assert!(sent_bodies(&mock_server).await.is_empty());
+    }
+
+    #[test]
+    fn test_content_length_limits_are_validated() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_max_content_length(MessageRole::Assistant, 0, ContentOverflowAction::Error)
+            .build();
+        assert!(matches!(result, Err(crate::SwarmError::ValidationError(_))));
+
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_max_content_length(
+                MessageRole::Assistant,
+                10,
+                ContentOverflowAction::Truncate(0),
+            )
+            .build();
+        assert!(matches!(result, Err(crate::SwarmError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_post_function_call_prompt_follows_the_result() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_post_function_call_prompt(
+                "Use the {{function_name}} result ({{function_result}}) to answer.",
+            )
+            .build()
+            .expect("swarm");
+        let call = FunctionCall::new("echo_city", r#"{"city": "Paris"}"#).expect("call");
+
+        let response = swarm
+            .handle_function_call(&call, &[city_echo()], ContextVariables::new(), false)
+            .await
+            .expect("function call");
+
+        assert_eq!(response.messages.len(), 2);
+        assert_eq!(response.messages[0].content(), Some("Paris"));
+        assert_eq!(response.messages[1].role(), MessageRole::User);
+        assert_eq!(
+            response.messages[1].content(),
+            Some("Use the echo_city result (Paris) to answer.")
+        );
+    }
+
+    #[test]
+    fn test_fill_template_keeps_unknown_placeholders() {
+        assert_eq!(
+            fill_template(
+                "{{function_result}} from {{function_name}} at {{time}}",
+                &[("function_name", "lookup"), ("function_result", "42")]
+            ),
+            "42 from lookup at {{time}}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_function_context_is_prefixed() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .build()
+            .expect("swarm");
+        let call = FunctionCall::new("write_context", "{}").expect("function call");
+
+        let context = swarm
+            .handle_function_call(
+                &call,
+                &[context_writer().with_ephemeral_context(true)],
+                ContextVariables::new(),
+                false,
+            )
+            .await
+            .expect("function call")
+            .context_variables;
+
+        let mut keys = context.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["__temp_blob", "__temp_extra"]);
+    }
+
+    #[test]
+    fn test_json_get_path_reads_pointers() {
+        let value = json!({"id": "req-1", "usage": {"total_tokens": 7}, "note": null});
+        assert_eq!(json_get_path(&value, "/id").as_deref(), Some("req-1"));
+        assert_eq!(
+            json_get_path(&value, "/usage/total_tokens").as_deref(),
+            Some("7")
+        );
+        assert_eq!(json_get_path(&value, "/note"), None);
+        assert_eq!(json_get_path(&value, "/missing"), None);
+    }
+
+    #[tokio::test]
+    async fn test_response_fields_are_extracted_into_context() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("assistant");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let options = RunOptions::new(1).with_extract_response_fields(vec![
+            (
+                "/usage/total_tokens".to_string(),
+                "__total_tokens".to_string(),
+            ),
+            ("/id".to_string(), "__request_id".to_string()),
+            ("/missing".to_string(), "missing".to_string()),
+        ]);
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hi").expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.context_variables["__total_tokens"], "2");
+        assert_eq!(response.context_variables["__request_id"], "chatcmpl-test");
+        assert!(!response.context_variables.contains_key("missing"));
+    }
+
+    #[tokio::test]
+    async fn test_extracted_response_fields_obey_context_limits() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("assistant");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_context_variable_max_size(8)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("hi").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1).with_extract_response_fields(vec![(
+                    "/id".to_string(),
+                    "__request_id".to_string(),
+                )]),
+            )
+            .await
+            .expect("run");
+
+        let request_id = &response.context_variables["__request_id"];
+        assert!(request_id.len() <= 8);
+        assert!(request_id.starts_with("chat") && request_id.ends_with('…'));
+    }
+
+    fn failing_lookup() -> AgentFunction {
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|_: ContextVariables| {
+            Box::pin(
+                async move { Err(crate::SwarmError::Other("lookup service down".to_string())) },
+            )
+        });
+        AgentFunction::new("lookup", handler, false).expect("function")
+    }
+
+    #[tokio::test]
+    async fn test_failed_function_calls_go_to_the_dead_letter_queue() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_lookup",
+                        "type": "function",
+                        "function": {"name": "lookup", "arguments": "{\"city\": \"Paris\"}"}
+                    }]
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        let agent = text_agent("researcher").with_functions(vec![failing_lookup()]);
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_dead_letter_queue(sender)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Weather in Paris?").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(3),
+            )
+            .await
+            .expect("run continues past the failed call");
+
+        let entry = receiver.try_recv().expect("dead-letter entry");
+        assert_eq!(entry.agent_name, "researcher");
+        assert_eq!(entry.function_name, "lookup");
+        assert_eq!(entry.arguments["city"], "Paris");
+        assert!(entry.error.contains("lookup service down"));
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some(DEAD_LETTER_RETRY_PROMPT)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dead_lettered_batch_gets_one_sanitized_retry_prompt() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [
+                        {
+                            "id": "call_paris",
+                            "type": "function",
+                            "function": {"name": "lookup", "arguments": "{\"city\": \"Paris\"}"}
+                        },
+                        {
+                            "id": "call_rome",
+                            "type": "function",
+                            "function": {"name": "lookup", "arguments": "{\"city\": \"Rome\"}"}
+                        }
+                    ]
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|_: ContextVariables| {
+            Box::pin(async move {
+                Err(crate::SwarmError::Other(
+                    "lookup failed; contact jane@example.com".to_string(),
+                ))
+            })
+        });
+        let agent = text_agent("researcher")
+            .with_functions(vec![
+                AgentFunction::new("lookup", handler, false).expect("function")
+            ])
+            .with_tool_call_execution(crate::types::ToolCallExecution::Parallel);
+        let (sender, _receiver) = tokio::sync::mpsc::channel(4);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_dead_letter_queue(sender)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Weather in Paris and Rome?").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run continues past the failed calls");
+
+        let retry_prompts = response
+            .messages
+            .iter()
+            .filter(|message| message.content() == Some(DEAD_LETTER_RETRY_PROMPT))
+            .count();
+        assert_eq!(retry_prompts, 1);
+        let errors: Vec<&str> = response
+            .messages
+            .iter()
+            .filter(|message| message.role() == crate::types::MessageRole::Tool)
+            .filter_map(Message::content)
+            .collect();
+        assert_eq!(errors.len(), 2);
+        assert!(errors
+            .iter()
+            .all(|error| error.starts_with("Error:") && !error.contains("jane@example.com")));
+    }
+
+    #[tokio::test]
+    async fn test_response_language_is_requested_in_the_system_message() {
+        let mock_server = mock_text_server("Bonjour").await;
+        let agent = text_agent("translator")
+            .with_response_language("French")
+            .expect("language");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hi").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(
+            bodies[0]["messages"][0]["content"],
+            format!("{} Always respond in French.", INSTRUCTIONS)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enforced_language_retries_replies_in_another_language() {
+        let mock_server = MockServer::start().await;
+        for reply in ["Hello there", "Bonjour le monde"] {
+            Mock::given(method("POST"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                        "role": "assistant",
+                        "content": reply
+                    }))),
+                )
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+        let agent = text_agent("translator")
+            .with_response_language("French")
+            .expect("language")
+            .with_enforce_language(true);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_language_word_list(
+                "french",
+                vec!["le".to_string(), "la".to_string(), "Bonjour".to_string()],
+            )
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hi").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Bonjour le monde")
+        );
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 2);
+        let retry = bodies[1]["messages"]
+            .as_array()
+            .expect("messages array")
+            .last()
+            .expect("retry prompt")["content"]
+            .as_str()
+            .expect("text")
+            .to_string();
+        assert!(retry.starts_with("Your last reply was not in French."));
+        assert!(response.messages.iter().all(|message| {
+            message.content().is_none_or(|content| {
+                content != "Hello there" && !content.starts_with("Your last reply")
+            })
+        }));
+    }
+
+    #[test]
+    fn test_language_word_lists_reject_empty_lists() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_language_word_list("french", Vec::new())
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_large_context_values_are_summarized_over_the_size_limit() {
+        let mock_server = mock_text_server("Short summary.").await;
+        let agent = text_agent("assistant");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_agent(text_agent("summarizer"))
+            .with_context_size_limit(100)
+            .with_context_value_max_bytes(50)
+            .with_context_summarizer_agent("summarizer")
+            .build()
+            .expect("swarm");
+        let report = "word ".repeat(40);
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("report".to_string(), report.clone());
+        context_variables.insert("city".to_string(), "Paris".to_string());
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hi").expect("user message")],
+                context_variables,
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response
+                .context_variables
+                .get("report_summarized")
+                .map(String::as_str),
+            Some("Short summary.")
+        );
+        assert!(!response.context_variables.contains_key("report"));
+        assert_eq!(response.context_variables["city"], "Paris");
+        assert_eq!(response.tokens_used, 4);
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(
+            bodies[1]["messages"][1]["content"],
+            format!("Summarize this in 2 sentences: {}", report)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reserved_context_keys_are_not_summarized() {
+        let mock_server = mock_text_server("done").await;
+        let agent = text_agent("assistant");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_agent(text_agent("summarizer"))
+            .with_context_size_limit(100)
+            .with_context_value_max_bytes(50)
+            .with_context_summarizer_agent("summarizer")
+            .with_ephemeral_key_prefix("tmp_")
+            .build()
+            .expect("swarm");
+        let notes = "word ".repeat(40);
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("__agent_notes".to_string(), notes.clone());
+        context_variables.insert("tmp_scratch".to_string(), notes.clone());
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hi").expect("user message")],
+                context_variables,
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.context_variables["__agent_notes"], notes);
+        assert_eq!(response.context_variables["tmp_scratch"], notes);
+        assert_eq!(sent_bodies(&mock_server).await.len(), 1);
+    }
+
+    #[test]
+    fn test_context_summarizer_agent_must_be_registered() {
+        let result = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_agent(text_agent("assistant"))
+            .with_context_size_limit(100)
+            .with_context_summarizer_agent("missing")
+            .build();
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_agent_metadata_is_injected_into_context() {
+        let mock_server = mock_text_server("done").await;
+        let metadata = [
+            ("team".to_string(), json!("billing")),
+            ("tier".to_string(), json!(2)),
+        ]
+        .into_iter()
+        .collect();
+        let agent = text_agent("assistant")
+            .with_metadata(metadata)
+            .with_inject_metadata_to_context(true);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("__agent_tier".to_string(), "gold".to_string());
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hi").expect("user message")],
+                context_variables,
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(response.context_variables["__agent_team"], "\"billing\"");
+        assert_eq!(response.context_variables["__agent_tier"], "gold");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_function_arguments_are_retried_with_the_error() {
+        let mock_server = MockServer::start().await;
+        for arguments in ["{}", "{\"city\": \"Paris\"}"] {
+            Mock::given(method("POST"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                        "role": "assistant",
+                        "content": null,
+                        "tool_calls": [{
+                            "id": "call_echo_city",
+                            "type": "function",
+                            "function": {"name": "echo_city", "arguments": arguments}
+                        }]
+                    }))),
+                )
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+        let function = city_echo()
+            .with_parameters_schema(json!({
+                "type": "object",
+                "properties": {"city": {"type": "string"}},
+                "required": ["city"]
+            }))
+            .expect("schema");
+        let agent = text_agent("assistant").with_functions(vec![function]);
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_function_arg_retry_prompt("Fix the function arguments.")
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Which city?").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(3),
+            )
+            .await
+            .expect("run");
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(bodies.len(), 2);
+        let retry = bodies[1]["messages"]
+            .as_array()
+            .expect("messages array")
+            .last()
+            .expect("retry prompt")["content"]
+            .as_str()
+            .expect("text")
+            .to_string();
+        assert!(retry.starts_with("Fix the function arguments.\n\nValidation error:"));
+        assert!(response
+            .messages
+            .iter()
+            .any(|message| message.role() == MessageRole::Function
+                && message.content() == Some("Paris")));
+        // Only the corrected call and its result remain.
+        assert_eq!(
+            response
+                .messages
+                .iter()
+                .filter(|message| message.calls_tools())
+                .count(),
+            1
+        );
+        assert!(response.messages.iter().all(|message| message
+            .content()
+            .is_none_or(|content| !content.contains("Validation error"))));
+    }
+
+    #[tokio::test]
+    async fn test_seed_is_sent_and_system_fingerprint_is_reported() {
+        let mock_server = MockServer::start().await;
+        let mut body = mock_chat_response(json!({"role": "assistant", "content": "4"}));
+        body["system_fingerprint"] = json!("fp_44709d6fcb");
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .mount(&mock_server)
+            .await;
+        let agent = text_agent("seeded")
+            .with_model_parameters(ModelParameters::default().with_seed(42))
+            .expect("model parameters");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("2 + 2?").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(sent_bodies(&mock_server).await[0]["seed"], 42);
+        assert_eq!(
+            response.system_fingerprint.as_deref(),
+            Some("fp_44709d6fcb")
+        );
+    }
+
+    #[test]
+    fn test_default_model_aliases_keep_the_context_window() {
+        let windows = ModelContextWindow::default();
+        for (alias, model) in DEFAULT_MODEL_ALIASES {
+            assert!(
+                windows.context_window(model) >= windows.context_window(alias),
+                "{} is aliased to {}, which has a smaller context window",
+                alias,
+                model
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_model_aliases_resolve_before_validation_and_request() {
+        let mock_server = mock_text_server("Hi").await;
+        let agent = Agent::new(
+            "aliased",
+            "fast",
+            Instructions::Text(INSTRUCTIONS.to_string()),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_model_alias("fast", "gpt-4o-mini")
+            .with_agent(agent.clone())
+            .build()
+            .expect("alias satisfies the model prefix check");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("Hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(sent_bodies(&mock_server).await[0]["model"], "gpt-4o-mini");
+        assert_eq!(
+            swarm.config().resolve_model_alias("gpt-4-turbo-preview"),
+            "gpt-4-turbo"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_system_message_format_wraps_instructions() {
+        let mock_server = mock_text_server("Hi").await;
+        let agent = text_agent("formatted");
+        let first_system_message = |format: SystemMessageFormat| {
+            let agent = agent.clone();
+            let uri = mock_server.uri();
+            async move {
+                let swarm = Swarm::builder()
+                    .with_api_key("sk-test".to_string())
+                    .with_api_url(uri)
+                    .with_agent(agent.clone())
+                    .with_system_message_format(format)
+                    .build()
+                    .expect("swarm");
+                swarm
+                    .run_with_options(
+                        agent,
+                        vec![Message::user("Hello").expect("user message")],
+                        ContextVariables::new(),
+                        RunOptions::new(1),
+                    )
+                    .await
+                    .expect("run");
+            }
+        };
+
+        first_system_message(SystemMessageFormat::AnthropicXml).await;
+        first_system_message(SystemMessageFormat::Custom(Arc::new(|text: &str| {
+            format!("You are role-playing.\n{}", text)
+        })))
+        .await;
+
+        let bodies = sent_bodies(&mock_server).await;
+        assert_eq!(
+            bodies[0]["messages"][0]["content"],
+            format!("<instructions>{}</instructions>", INSTRUCTIONS)
+        );
+        assert_eq!(
+            bodies[1]["messages"][0]["content"],
+            format!("You are role-playing.\n{}", INSTRUCTIONS)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_context_injectors_merge_in_order_under_caller_context() {
+        let mock_server = mock_text_server("Hi").await;
+        let context_file =
+            std::env::temp_dir().join(format!("rswarm-context-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(
+            &context_file,
+            r#"{"region": "eu-west", "retries": 3, "tier": "file"}"#,
+        )
+        .expect("write context file");
+        std::env::set_var("RSWARM_INJECT_TEST_TIER", "env");
+
+        let static_context: ContextVariables = [
+            ("tier".to_string(), "static".to_string()),
+            ("user".to_string(), "static".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(text_agent("injected"))
+            .with_context_injector(StaticContextInjector(static_context).into())
+            .with_context_injector(FileContextInjector(context_file.clone()).into())
+            .with_context_injector(
+                EnvContextInjector::new("RSWARM_INJECT_TEST_")
+                    .expect("prefix")
+                    .into(),
+            )
+            .build()
+            .expect("swarm");
+
+        let caller_context: ContextVariables = [("user".to_string(), "caller".to_string())]
+            .into_iter()
+            .collect();
+        let response = swarm
+            .run_with_options(
+                text_agent("injected"),
+                vec![Message::user("Hello").expect("user message")],
+                caller_context,
+                RunOptions::new(1),
+            )
+            .await
+            .expect("run");
+        std::env::remove_var("RSWARM_INJECT_TEST_TIER");
+        std::fs::remove_file(&context_file).expect("remove context file");
+
+        let context = &response.context_variables;
+        assert_eq!(context.get("TIER").map(String::as_str), Some("env"));
+        assert_eq!(context.get("tier").map(String::as_str), Some("file"));
+        assert_eq!(context.get("region").map(String::as_str), Some("eu-west"));
+        assert_eq!(context.get("retries").map(String::as_str), Some("3"));
+        assert_eq!(context.get("user").map(String::as_str), Some("caller"));
+    }
+
+    #[tokio::test]
+    async fn test_context_injector_failure_aborts_run() {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_agent(text_agent("injected"))
+            .with_context_injector(
+                FileContextInjector(std::env::temp_dir().join("rswarm-missing-context.json"))
+                    .into(),
+            )
+            .build()
+            .expect("swarm");
+
+        let error = swarm
+            .run_with_options(
+                text_agent("injected"),
+                vec![Message::user("Hello").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(1),
+            )
+            .await
+            .expect_err("missing context file");
+        assert!(error.to_string().contains("rswarm-missing-context.json"));
+    }
+
+    #[test]
+    fn test_env_context_injector_requires_a_prefix() {
+        assert!(matches!(
+            EnvContextInjector::new(""),
+            Err(crate::SwarmError::ValidationError(_))
+        ));
+        assert_eq!(
+            EnvContextInjector::new("APP_").expect("prefix").prefix(),
+            "APP_"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prompt_compression_drops_middle_messages() {
+        let mock_server = mock_text_server("Hi").await;
+        let long = "x".repeat(400);
+        let history = vec![
+            Message::user("first task").expect("user message"),
+            Message::assistant(long.clone()).expect("assistant message"),
+            Message::user(long.clone()).expect("user message"),
+            Message::assistant(long).expect("assistant message"),
+            Message::user("latest").expect("user message"),
+        ];
+        let run = |strategy: CompressionStrategy| {
+            let history = history.clone();
+            let uri = mock_server.uri();
+            async move {
+                let swarm = Swarm::builder()
+                    .with_api_key("sk-test".to_string())
+                    .with_api_url(uri)
+                    .with_agent(text_agent("compressed"))
+                    .with_prompt_compression(PromptCompressionConfig {
+                        max_tokens: 50,
+                        strategy,
+                    })
+                    .build()
+                    .expect("swarm");
+                swarm
+                    .run_with_options(
+                        text_agent("compressed"),
+                        history,
+                        ContextVariables::new(),
+                        RunOptions::new(1),
+                    )
+                    .await
+                    .expect("run")
+            }
+        };
+
+        let response = run(CompressionStrategy::TruncateMiddle).await;
+        let stats = response.turn_metadata[0]
+            .prompt_compression
+            .expect("compression stats");
+        assert!(stats.original_tokens > 300);
+        assert!(stats.compressed_tokens <= 50);
+
+        run(CompressionStrategy::Selective(vec![MessageRole::Assistant])).await;
+
+        let bodies = sent_bodies(&mock_server).await;
+        let contents = |body: &Value| -> Vec<String> {
+            body["messages"]
+                .as_array()
+                .expect("messages array")
+                .iter()
+                .map(|message| message["content"].as_str().unwrap_or_default().to_string())
+                .collect()
+        };
+        assert_eq!(
+            contents(&bodies[0]),
+            vec![INSTRUCTIONS, "first task", "latest"]
+        );
+        let selective = contents(&bodies[1]);
+        assert_eq!(selective.len(), 4);
+        assert_eq!(selective[2], "x".repeat(400));
+    }
+
+    #[cfg(feature = "llmlingua")]
+    #[tokio::test]
+    async fn test_llmlingua_compression_replaces_middle_messages() {
+        let chat_server = mock_text_server("Hi").await;
+        let compressor = MockServer::start().await;
+        let long = "x".repeat(400);
+        let history = vec![
+            Message::user("first task").expect("user message"),
+            Message::assistant(long.clone()).expect("assistant message"),
+            Message::user(long.clone()).expect("user message"),
+            Message::user("latest").expect("user message"),
+        ];
+        let run = |compressed_prompts: Value| {
+            let history = history.clone();
+            let chat_uri = chat_server.uri();
+            let compressor = &compressor;
+            async move {
+                compressor.reset().await;
+                Mock::given(method("POST"))
+                    .respond_with(
+                        ResponseTemplate::new(200)
+                            .set_body_json(json!({ "compressed_prompts": compressed_prompts })),
+                    )
+                    .mount(compressor)
+                    .await;
+                let swarm = Swarm::builder()
+                    .with_api_key("sk-test".to_string())
+                    .with_api_url(chat_uri)
+                    .with_agent(text_agent("compressed"))
+                    .with_prompt_compression(PromptCompressionConfig {
+                        max_tokens: 50,
+                        strategy: CompressionStrategy::LLMLingua(compressor.uri()),
+                    })
+                    .build()
+                    .expect("swarm");
+                swarm
+                    .run_with_options(
+                        text_agent("compressed"),
+                        history,
+                        ContextVariables::new(),
+                        RunOptions::new(1),
+                    )
+                    .await
+            }
+        };
+
+        run(json!(["short answer", "short question"]))
+            .await
+            .expect("run");
+        let sent = sent_bodies(&compressor).await;
+        assert_eq!(sent[0]["prompts"], json!([long, long]));
+        assert_eq!(sent[0]["target_token"], 50);
+        let contents: Vec<_> = sent_bodies(&chat_server).await[0]["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .map(|message| message["content"].clone())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                json!(INSTRUCTIONS),
+                json!("first task"),
+                json!("short answer"),
+                json!("short question"),
+                json!("latest"),
+            ]
+        );
+
+        let error = run(json!(["only one"]))
+            .await
+            .expect_err("one compressed prompt for two messages");
+        assert!(error
+            .to_string()
+            .contains("one compressed prompt per message"));
+    }
+
+    #[test]
+    fn test_prompt_compression_rejects_invalid_config() {
+        let build = |compression: PromptCompressionConfig| {
+            Swarm::builder()
+                .with_api_key("sk-test".to_string())
+                .with_prompt_compression(compression)
+                .build()
+        };
+        assert!(build(PromptCompressionConfig {
+            max_tokens: 0,
+            strategy: CompressionStrategy::TruncateMiddle,
+        })
+        .is_err());
+        assert!(build(PromptCompressionConfig {
+            max_tokens: 100,
+            strategy: CompressionStrategy::Selective(Vec::new()),
+        })
+        .is_err());
+        #[cfg(not(feature = "llmlingua"))]
+        assert!(build(PromptCompressionConfig {
+            max_tokens: 100,
+            strategy: CompressionStrategy::LLMLingua("http://localhost:8000".to_string()),
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,2161 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::constants::SHORT_RESPONSE_RETRY_PROMPT;
+    use crate::core::{RunOptions, Swarm};
+    use crate::profile::ProfileSpanKind;
+    use crate::steps_parser::{JsonStepsParser, StepsParser};
+    use crate::types::{
+        Agent, AgentFunction, AgentFunctionHandler, BranchMergeStrategy, ContextOverflowStrategy,
+        ContextSnapshotInterval, ContextVariables, ErrorRecoveryStrategy, HandoffRecord,
+        Instructions, Message, MessageRole, ResultType, StepAction, XmlEncoding,
+    };
+    use crate::util::{
+        fill_template, parse_steps_from_xml, resolve_xml_includes,
+        resolve_xml_includes_with_encoding, strip_xml_namespace, unresolved_placeholders,
+    };
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn mock_chat_response(content: Value) -> Value {
+        json!({
+            "id": "chatcmpl-test",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "gpt-4",
+            "choices": [{
+                "index": 0,
+                "message": content,
+                "finish_reason": "stop"
+            }],
+            "usage": {
+                "prompt_tokens": 1,
+                "completion_tokens": 1,
+                "total_tokens": 2
+            }
+        })
+    }
+
+    async fn mock_text_server(content: &str) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": content
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    fn steps_agent(name: &str, steps_xml: &str) -> Agent {
+        Agent::new(
+            name,
+            "gpt-4",
+            Instructions::Text(format!("You are a workflow agent.\n{}", steps_xml)),
+        )
+        .expect("agent")
+    }
+
+    async fn run_steps(
+        mock_server: &MockServer,
+        agent: Agent,
+    ) -> crate::SwarmResult<crate::Response> {
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        swarm
+            .run(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                5,
+            )
+            .await
+    }
+
+    #[test]
+    fn test_parse_step_output_var() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" output_var="draft"><prompt>Write</prompt></step></steps>"#,
+        )
+        .expect("steps");
+        assert_eq!(steps.steps[0].output_var.as_deref(), Some("draft"));
+
+        let error = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" output_var=" "><prompt>Write</prompt></step></steps>"#,
+        )
+        .expect_err("blank output_var");
+        assert!(error.to_string().contains("empty output_var"));
+    }
+
+    #[tokio::test]
+    async fn test_step_output_is_captured_into_context_variables() {
+        let mock_server = mock_text_server("captured draft").await;
+        let agent = steps_agent(
+            "capturing",
+            r#"<steps><step number="1" action="run_once" output_var="draft"><prompt>Write a draft</prompt></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        assert_eq!(
+            response.context_variables.get("draft").map(String::as_str),
+            Some("captured draft")
+        );
+    }
+
+    #[test]
+    fn test_parse_step_max_turns_per_step() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="loop" max_turns_per_step="2"><prompt>Refine</prompt></step></steps>"#,
+        )
+        .expect("steps");
+        assert_eq!(steps.steps[0].max_turns_per_step, Some(2));
+
+        let error = parse_steps_from_xml(
+            r#"<steps><step number="1" action="loop" max_turns_per_step="0"><prompt>Refine</prompt></step></steps>"#,
+        )
+        .expect_err("zero max_turns_per_step");
+        assert!(error.to_string().contains("max_turns_per_step"));
+    }
+
+    #[tokio::test]
+    async fn test_loop_step_limit_leaves_turns_for_later_steps() {
+        let mock_server = mock_text_server("still refining").await;
+        let agent = steps_agent(
+            "bounded",
+            r#"<steps><step number="1" action="loop" max_turns_per_step="2"><prompt>Refine</prompt></step><step number="2" action="run_once"><prompt>Summarize</prompt></step></steps>"#,
+        );
+
+        run_steps(&mock_server, agent).await.expect("run");
+
+        let requests = mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled");
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_loop_step_without_limit_is_bounded_by_remaining_turns() {
+        let mock_server = mock_text_server("still refining").await;
+        let agent = steps_agent(
+            "unbounded",
+            r#"<steps><step number="1" action="run_once"><prompt>Draft</prompt></step><step number="2" action="loop"><prompt>Refine</prompt></step></steps>"#,
+        );
+
+        let error = run_steps(&mock_server, agent)
+            .await
+            .expect_err("loop exhausts the run");
+
+        assert!(matches!(
+            error,
+            crate::SwarmError::MaxIterationsError {
+                max: 5,
+                actual: 5,
+                last_response: None
+            }
+        ));
+    }
+
+    #[test]
+    fn test_json_steps_parser_reads_wrapped_array() {
+        let steps = JsonStepsParser
+            .parse(
+                r#"<steps>[{"number": 1, "action": "loop", "agent": "writer", "max_turns_per_step": 2, "prompt": "Refine"}]</steps>"#,
+            )
+            .expect("steps");
+        assert_eq!(steps.steps.len(), 1);
+        assert_eq!(steps.steps[0].action, StepAction::Loop);
+        assert_eq!(steps.steps[0].agent.as_deref(), Some("writer"));
+        assert_eq!(steps.steps[0].max_turns_per_step, Some(2));
+
+        let error = JsonStepsParser
+            .parse(r#"[{"number": 1, "action": "run_once", "prompt": " "}]"#)
+            .expect_err("empty prompt");
+        assert!(error.to_string().contains("empty prompt"));
+    }
+
+    #[tokio::test]
+    async fn test_swarm_uses_configured_steps_parser() {
+        let mock_server = mock_text_server("json draft").await;
+        let agent = steps_agent(
+            "json-steps",
+            r#"<steps>[{"number": 1, "action": "run_once", "output_var": "draft", "prompt": "Write"}]</steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_steps_parser(JsonStepsParser)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                None,
+                false,
+                false,
+                5,
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response.context_variables.get("draft").map(String::as_str),
+            Some("json draft")
+        );
+    }
+
+    #[test]
+    fn test_parse_step_conditions() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" precondition="topic" postcondition="draft" output_var="draft"><prompt>Write</prompt></step></steps>"#,
+        )
+        .expect("steps");
+        assert_eq!(steps.steps[0].precondition.as_deref(), Some("topic"));
+        assert_eq!(steps.steps[0].postcondition.as_deref(), Some("draft"));
+
+        let error = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" precondition=""><prompt>Write</prompt></step></steps>"#,
+        )
+        .expect_err("blank precondition");
+        assert!(error.to_string().contains("empty precondition"));
+    }
+
+    #[tokio::test]
+    async fn test_unmet_precondition_fails_before_the_step_runs() {
+        let mock_server = mock_text_server("unused").await;
+        let agent = steps_agent(
+            "guarded",
+            r#"<steps><step number="1" action="run_once" precondition="topic"><prompt>Write</prompt></step></steps>"#,
+        );
+
+        let error = run_steps(&mock_server, agent)
+            .await
+            .expect_err("precondition unmet");
+
+        assert_eq!(
+            error.to_string(),
+            crate::SwarmError::ValidationError("Precondition 'topic' not met for step 1".into())
+                .to_string()
+        );
+        assert!(mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled")
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_postcondition_checks_context_after_the_step() {
+        let mock_server = mock_text_server("a draft").await;
+        let satisfied = steps_agent(
+            "satisfied",
+            r#"<steps><step number="1" action="run_once" output_var="draft" postcondition="draft"><prompt>Write</prompt></step><step number="2" action="run_once" precondition="draft"><prompt>Review</prompt></step></steps>"#,
+        );
+        run_steps(&mock_server, satisfied)
+            .await
+            .expect("postcondition met");
+
+        let unmet = steps_agent(
+            "unmet",
+            r#"<steps><step number="1" action="run_once" postcondition="review"><prompt>Write</prompt></step></steps>"#,
+        );
+        let error = run_steps(&mock_server, unmet)
+            .await
+            .expect_err("postcondition unmet");
+        assert!(error
+            .to_string()
+            .contains("Postcondition 'review' not met for step 1"));
+    }
+
+    /// Fails the first `failures` chat completions with `status`, then answers normally.
+    async fn mock_flaky_server(status: u16, failures: u64, content: &str) -> MockServer {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(status).set_body_string("request failed"))
+            .up_to_n_times(failures)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                    "role": "assistant",
+                    "content": content
+                }))),
+            )
+            .mount(&mock_server)
+            .await;
+        mock_server
+    }
+
+    async fn run_steps_with_recovery(
+        mock_server: &MockServer,
+        agents: Vec<Agent>,
+        strategy: ErrorRecoveryStrategy,
+    ) -> crate::SwarmResult<crate::Response> {
+        // One request-level retry, so a rate limit reaches step recovery after two 429s.
+        let mut builder = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_max_retries(1)
+            .with_api_key_cooldown(std::time::Duration::ZERO);
+        for agent in &agents {
+            builder = builder.with_agent(agent.clone());
+        }
+        let swarm = builder.build().expect("swarm");
+        swarm
+            .run_with_options(
+                agents[0].clone(),
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5).with_error_recovery_strategy(strategy),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_skip_step_continues_with_the_next_step() {
+        let mock_server = mock_text_server("reviewed").await;
+        let agent = steps_agent(
+            "skipping",
+            r#"<steps><step number="1" action="run_once" precondition="topic"><prompt>Write</prompt></step><step number="2" action="run_once"><prompt>Review</prompt></step></steps>"#,
+        );
+
+        let response = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::SkipStep { max_skips: 1 },
+        )
+        .await
+        .expect("failed step skipped");
+
+        let prompts: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(Message::content)
+            .collect();
+        assert_eq!(prompts, vec!["start", "Review", "reviewed"]);
+    }
+
+    #[tokio::test]
+    async fn test_skip_step_fails_once_skips_are_used_up() {
+        let mock_server = mock_text_server("unused").await;
+        let agent = steps_agent(
+            "skipping",
+            r#"<steps><step number="1" action="run_once" precondition="topic"><prompt>Write</prompt></step><step number="2" action="run_once" precondition="draft"><prompt>Review</prompt></step></steps>"#,
+        );
+
+        let error = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::SkipStep { max_skips: 1 },
+        )
+        .await
+        .expect_err("second failure exceeds max_skips");
+
+        assert!(error
+            .to_string()
+            .contains("Precondition 'draft' not met for step 2"));
+    }
+
+    #[tokio::test]
+    async fn test_skipped_step_leaves_no_context_writes() {
+        let mock_server = mock_text_server("a draft").await;
+        let agent = steps_agent(
+            "skipping",
+            r#"<steps><step number="1" action="run_once" output_var="draft" postcondition="review"><prompt>Write</prompt></step></steps>"#,
+        );
+
+        let response = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::SkipStep { max_skips: 1 },
+        )
+        .await
+        .expect("failed step skipped");
+
+        assert!(!response.context_variables.contains_key("draft"));
+        let contents: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(Message::content)
+            .collect();
+        assert_eq!(contents, vec!["start"]);
+    }
+
+    #[tokio::test]
+    async fn test_retry_step_reruns_the_failed_step() {
+        let mock_server = mock_flaky_server(429, 2, "a draft").await;
+        let agent = steps_agent(
+            "retrying",
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step></steps>"#,
+        );
+
+        let response = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::RetryStep { max_retries: 1 },
+        )
+        .await
+        .expect("retry succeeds");
+
+        let prompts: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(Message::content)
+            .collect();
+        assert_eq!(prompts, vec!["start", "Write", "a draft"]);
+        assert_eq!(
+            mock_server
+                .received_requests()
+                .await
+                .expect("request recording enabled")
+                .len(),
+            3
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retry_step_does_not_retry_permanent_errors() {
+        let mock_server = mock_flaky_server(400, 1, "a draft").await;
+        let agent = steps_agent(
+            "retrying",
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step></steps>"#,
+        );
+
+        let error = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::RetryStep { max_retries: 1 },
+        )
+        .await
+        .expect_err("a bad request is not retried");
+
+        assert!(!error.is_retriable());
+        assert_eq!(
+            mock_server
+                .received_requests()
+                .await
+                .expect("request recording enabled")
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_agent_takes_over_the_failed_step() {
+        let mock_server = mock_flaky_server(400, 1, "a draft").await;
+        let agent = steps_agent(
+            "primary",
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step></steps>"#,
+        );
+        let backup = Agent::new(
+            "backup",
+            "gpt-4",
+            Instructions::Text("You are a backup writer.".to_string()),
+        )
+        .expect("agent");
+
+        let response = run_steps_with_recovery(
+            &mock_server,
+            vec![agent, backup],
+            ErrorRecoveryStrategy::FallbackAgent("backup".to_string()),
+        )
+        .await
+        .expect("fallback succeeds");
+
+        assert_eq!(
+            response.agent.as_ref().map(|agent| agent.name()),
+            Some("backup")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fallback_agent_must_be_registered() {
+        let mock_server = mock_text_server("unused").await;
+        let agent = steps_agent(
+            "primary",
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step></steps>"#,
+        );
+
+        let result = run_steps_with_recovery(
+            &mock_server,
+            vec![agent],
+            ErrorRecoveryStrategy::FallbackAgent("missing".to_string()),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(crate::SwarmError::AgentNotFoundError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_switch_model_step_without_prompt() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="switch_model" new_model="gpt-4o-mini"/></steps>"#,
+        )
+        .expect("steps");
+        assert_eq!(steps.steps[0].action, StepAction::SwitchModel);
+        assert_eq!(steps.steps[0].new_model.as_deref(), Some("gpt-4o-mini"));
+
+        let missing =
+            parse_steps_from_xml(r#"<steps><step number="1" action="switch_model"/></steps>"#);
+        assert!(missing.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_switch_model_applies_to_later_steps() {
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "switching",
+            r#"<steps><step number="1" action="run_once"><prompt>Plan</prompt></step><step number="2" action="switch_model" new_model="gpt-4o-mini"/><step number="3" action="run_once"><prompt>Finish</prompt></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        let models: Vec<_> = mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled")
+            .iter()
+            .map(|request| {
+                request.body_json::<Value>().expect("json request body")["model"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(models, vec!["gpt-4", "gpt-4o-mini"]);
+        assert_eq!(
+            response.handoff_history,
+            vec![HandoffRecord::Model {
+                step: 2,
+                from: "gpt-4".to_string(),
+                to: "gpt-4o-mini".to_string(),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_switch_model_rejects_invalid_model_prefix() {
+        let mock_server = mock_text_server("unused").await;
+        let agent = steps_agent(
+            "switching",
+            r#"<steps><step number="1" action="switch_model" new_model="llama-3"/></steps>"#,
+        );
+
+        let error = run_steps(&mock_server, agent)
+            .await
+            .expect_err("invalid model");
+        assert!(error.to_string().contains("Invalid model prefix"));
+    }
+
+    #[tokio::test]
+    async fn test_profiling_reports_steps_and_api_calls() {
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "profiled",
+            r#"<steps><step number="1" action="run_once"><prompt>Plan</prompt></step><step number="2" action="run_once"><prompt>Finish</prompt></step></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent.clone(),
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5).with_profiling(true),
+            )
+            .await
+            .expect("run");
+
+        let profile = response.profile.expect("profile report");
+        assert_eq!(profile.api_call_count, 2);
+        assert_eq!(profile.function_call_count, 0);
+        assert!([1, 2].contains(&profile.slowest_step));
+        let step_numbers: Vec<_> = profile
+            .spans
+            .iter()
+            .filter_map(|span| match span.kind {
+                ProfileSpanKind::Step { number } => Some(number),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(step_numbers, vec![1, 2]);
+
+        let unprofiled = run_steps(&mock_server, agent).await.expect("run");
+        assert!(unprofiled.profile.is_none());
+    }
+
+    /// Writes `files` (relative path, content) under a fresh temporary directory.
+    fn steps_dir(files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rswarm-steps-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).expect("create steps dir");
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().expect("file has a parent"))
+                .expect("create steps dir");
+            std::fs::write(path, content).expect("write steps file");
+        }
+        dir
+    }
+
+    #[test]
+    fn test_includes_are_inlined_relative_to_each_file() {
+        let dir = steps_dir(&[
+            (
+                "review.xml",
+                r#"<steps><step number="2" action="run_once"><prompt>Review</prompt></step><include file="shared/publish.xml"/></steps>"#,
+            ),
+            (
+                "shared/publish.xml",
+                r#"<step number="3" action="run_once"><prompt>Publish</prompt></step>"#,
+            ),
+        ]);
+
+        let xml = resolve_xml_includes(
+            r#"<steps><step number="1" action="run_once"><prompt>Draft</prompt></step><include file="review.xml"/></steps>"#,
+            &dir,
+        )
+        .expect("includes resolve");
+        let steps = parse_steps_from_xml(&xml).expect("steps parse");
+
+        let prompts: Vec<_> = steps
+            .steps
+            .iter()
+            .map(|step| step.prompt.as_str())
+            .collect();
+        assert_eq!(prompts, vec!["Draft", "Review", "Publish"]);
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_circular_includes_are_rejected() {
+        let dir = steps_dir(&[
+            ("a.xml", r#"<steps><include file="b.xml"/></steps>"#),
+            (
+                "b.xml",
+                r#"<steps><include file="a.xml"></include></steps>"#,
+            ),
+        ]);
+
+        let error = resolve_xml_includes(r#"<steps><include file="a.xml"/></steps>"#, &dir)
+            .expect_err("a.xml includes itself through b.xml");
+
+        assert!(error.to_string().contains("Circular steps include"));
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_absolute_includes_are_rejected() {
+        let outside = steps_dir(&[(
+            "secret.xml",
+            r#"<step number="1" action="run_once"><prompt>Secret</prompt></step>"#,
+        )]);
+        let dir = steps_dir(&[]);
+        let content = format!(
+            r#"<steps><include file="{}"/></steps>"#,
+            outside.join("secret.xml").display()
+        );
+
+        let error = resolve_xml_includes(&content, &dir).expect_err("absolute include");
+
+        assert!(error.to_string().contains("must be a relative path"));
+        std::fs::remove_dir_all(outside).expect("clean up");
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_includes_cannot_escape_the_base_dir() {
+        let dir = steps_dir(&[
+            (
+                "secret.xml",
+                r#"<step number="1" action="run_once"><prompt>Secret</prompt></step>"#,
+            ),
+            (
+                "steps/nested.xml",
+                r#"<steps><include file="../secret.xml"/></steps>"#,
+            ),
+        ]);
+        let base = dir.join("steps");
+
+        let error =
+            resolve_xml_includes(r#"<steps><include file="../secret.xml"/></steps>"#, &base)
+                .expect_err("parent include");
+        assert!(error
+            .to_string()
+            .contains("outside the steps base directory"));
+
+        let error = resolve_xml_includes(r#"<steps><include file="nested.xml"/></steps>"#, &base)
+            .expect_err("nested parent include");
+        assert!(error
+            .to_string()
+            .contains("outside the steps base directory"));
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_missing_include_is_an_error() {
+        let dir = steps_dir(&[]);
+        assert!(matches!(
+            resolve_xml_includes(r#"<steps><include file="missing.xml"/></steps>"#, &dir),
+            Err(crate::SwarmError::XmlError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run_resolves_includes_from_steps_base_dir() {
+        let dir = steps_dir(&[(
+            "finish.xml",
+            r#"<steps><step number="2" action="run_once"><prompt>Finish</prompt></step></steps>"#,
+        )]);
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "including",
+            r#"<steps><step number="1" action="run_once"><prompt>Plan</prompt></step><include file="finish.xml"/></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5).with_steps_base_dir(&dir),
+            )
+            .await
+            .expect("run");
+
+        let requests = mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled");
+        assert_eq!(requests.len(), 2);
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[tokio::test]
+    async fn test_skipped_step_notifies_failure_callback() {
+        let mock_server = mock_text_server("reviewed").await;
+        let agent = steps_agent(
+            "notifying",
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step><step number="7" action="run_once" precondition="topic"><prompt>Review</prompt></step></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let options = RunOptions::new(5)
+            .with_error_recovery_strategy(ErrorRecoveryStrategy::SkipStep { max_skips: 1 })
+            .with_on_step_failure(move |index, step, error| {
+                let report = (index, step.number, error.to_string());
+                let sender = sender.clone();
+                Box::pin(async move {
+                    let _ = sender.send(report);
+                })
+            });
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+            .await
+            .expect("failed step skipped");
+
+        let (index, number, error) =
+            tokio::time::timeout(std::time::Duration::from_secs(5), receiver.recv())
+                .await
+                .expect("callback ran")
+                .expect("failure reported");
+        assert_eq!((index, number), (1, 7));
+        assert!(error.contains("topic"));
+    }
+
+    async fn run_with_snapshots(
+        interval: ContextSnapshotInterval,
+        max_snapshots: usize,
+    ) -> (Swarm, crate::Response) {
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "snapshotting",
+            r#"<steps><step number="1" action="run_once"><prompt>Plan</prompt></step><step number="2" action="run_once"><prompt>Finish</prompt></step></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_context_snapshot_interval(interval)
+            .with_max_context_snapshots(max_snapshots)
+            .build()
+            .expect("swarm");
+        let mut context = ContextVariables::new();
+        context.insert("topic".to_string(), "rust".to_string());
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context,
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run");
+        (swarm, response)
+    }
+
+    fn snapshot_labels(response: &crate::Response) -> Vec<&str> {
+        response
+            .context_snapshots
+            .iter()
+            .map(|(label, _)| label.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_context_snapshots_follow_interval_and_cap() {
+        let (_, disabled) = run_with_snapshots(ContextSnapshotInterval::Disabled, 10).await;
+        assert!(disabled.context_snapshots.is_empty());
+
+        let (_, steps) = run_with_snapshots(ContextSnapshotInterval::AfterEachStep, 10).await;
+        assert_eq!(snapshot_labels(&steps), vec!["step 1", "step 2"]);
+
+        let (_, turns) = run_with_snapshots(ContextSnapshotInterval::AfterEachTurn, 1).await;
+        assert_eq!(snapshot_labels(&turns), vec!["turn 2"]);
+    }
+
+    #[tokio::test]
+    async fn test_restore_context_snapshot_rolls_back_context() {
+        let (_, mut response) =
+            run_with_snapshots(ContextSnapshotInterval::AfterEachStep, 10).await;
+        response
+            .context_variables
+            .insert("topic".to_string(), "corrupted".to_string());
+
+        let restored =
+            Swarm::restore_context_snapshot(&mut response, "step 1").expect("snapshot exists");
+
+        assert_eq!(restored["topic"], "rust");
+        assert_eq!(response.context_variables["topic"], "rust");
+        assert_eq!(snapshot_labels(&response), vec!["step 1"]);
+        assert!(matches!(
+            Swarm::restore_context_snapshot(&mut response, "step 2"),
+            Err(crate::SwarmError::ContextError(_))
+        ));
+    }
+
+    async fn mock_reply_sequence(replies: &[&str]) -> MockServer {
+        let mock_server = MockServer::start().await;
+        for reply in replies {
+            Mock::given(method("POST"))
+                .respond_with(
+                    ResponseTemplate::new(200).set_body_json(mock_chat_response(json!({
+                        "role": "assistant",
+                        "content": reply
+                    }))),
+                )
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+        mock_server
+    }
+
+    async fn run_evaluated_steps(
+        mock_server: &MockServer,
+        evaluate_step: &str,
+    ) -> crate::SwarmResult<crate::Response> {
+        let agent = steps_agent(
+            "writer",
+            &format!(
+                r#"<steps><step number="1" action="run_once"><prompt>Write a haiku</prompt></step>{}</steps>"#,
+                evaluate_step
+            ),
+        );
+        let critic = Agent::new(
+            "critic",
+            "gpt-4",
+            Instructions::Text("You grade writing.".to_string()),
+        )
+        .expect("critic");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_agent(critic)
+            .build()
+            .expect("swarm");
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5),
+            )
+            .await
+    }
+
+    #[test]
+    fn test_parse_evaluate_step() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step><step number="2" action="evaluate" evaluator_agent="critic" min_score="0.7" max_retry="2"/></steps>"#,
+        )
+        .expect("steps");
+        let evaluate = &steps.steps[1];
+        assert_eq!(evaluate.action, StepAction::Evaluate);
+        assert_eq!(evaluate.evaluator_agent.as_deref(), Some("critic"));
+        assert_eq!(evaluate.min_score, Some(0.7));
+        assert_eq!(evaluate.max_retry, Some(2));
+
+        let no_evaluator = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once"><prompt>Write</prompt></step><step number="2" action="evaluate"/></steps>"#,
+        )
+        .expect_err("evaluator_agent is required");
+        assert!(no_evaluator.to_string().contains("evaluator_agent"));
+
+        let nothing_to_rerun = parse_steps_from_xml(
+            r#"<steps><step number="1" action="evaluate" evaluator_agent="critic" min_score="0.5"/></steps>"#,
+        )
+        .expect_err("first step cannot re-run anything");
+        assert!(nothing_to_rerun.to_string().contains("re-run"));
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_step_reruns_previous_step_with_feedback() {
+        let mock_server = mock_reply_sequence(&[
+            "first draft",
+            r#"{"score": 0.2, "feedback": "Mention the season."}"#,
+            "second draft",
+            r#"```json
+{"score": 0.9, "feedback": "Good."}
+```"#,
+        ])
+        .await;
+
+        let response = run_evaluated_steps(
+            &mock_server,
+            r#"<step number="2" action="evaluate" evaluator_agent="critic" min_score="0.7"/>"#,
+        )
+        .await
+        .expect("run");
+
+        assert_eq!(
+            response
+                .context_variables
+                .get("__step_2_score")
+                .map(String::as_str),
+            Some("0.9")
+        );
+        let contents: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(|message| message.content())
+            .collect();
+        assert_eq!(
+            contents,
+            vec![
+                "start",
+                "Write a haiku",
+                "first draft",
+                "Mention the season.",
+                "Write a haiku",
+                "second draft"
+            ]
+        );
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 4);
+        let evaluation = requests[1].body_json::<Value>().expect("json body");
+        let prompt = evaluation["messages"][1]["content"]
+            .as_str()
+            .expect("prompt");
+        assert!(prompt.ends_with("Output:\nfirst draft"));
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_step_stops_after_max_retry() {
+        let mock_server = mock_reply_sequence(&[
+            "first draft",
+            r#"{"score": 0.1, "feedback": "Try again."}"#,
+            "second draft",
+            r#"{"score": 0.3, "feedback": "Still weak."}"#,
+        ])
+        .await;
+
+        let response = run_evaluated_steps(
+            &mock_server,
+            r#"<step number="2" action="evaluate" evaluator_agent="critic" min_score="0.7" max_retry="1"/>"#,
+        )
+        .await
+        .expect("run");
+
+        assert_eq!(
+            response
+                .context_variables
+                .get("__step_2_score")
+                .map(String::as_str),
+            Some("0.3")
+        );
+        assert_eq!(
+            mock_server
+                .received_requests()
+                .await
+                .expect("requests")
+                .len(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_evaluate_step_rejects_non_json_evaluation() {
+        let mock_server = mock_reply_sequence(&["draft", "Looks fine to me"]).await;
+
+        let error = run_evaluated_steps(
+            &mock_server,
+            r#"<step number="2" action="evaluate" evaluator_agent="critic"/>"#,
+        )
+        .await
+        .expect_err("evaluation must be JSON");
+        assert!(matches!(error, crate::SwarmError::DeserializationError(_)));
+    }
+
+    async fn run_context_limited_steps(
+        mock_server: &MockServer,
+        strategy: ContextOverflowStrategy,
+    ) -> crate::SwarmResult<crate::Response> {
+        let agent = steps_agent(
+            "essayist",
+            r#"<steps><step number="1" action="run_once"><prompt>Write an essay</prompt></step><step number="2" action="run_once" max_context_tokens_per_step="20"><prompt>Give it a title</prompt></step></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_context_overflow_strategy(strategy)
+            .build()
+            .expect("swarm");
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5),
+            )
+            .await
+    }
+
+    fn sent_contents(request: &wiremock::Request) -> Vec<String> {
+        request.body_json::<Value>().expect("json body")["messages"]
+            .as_array()
+            .expect("messages")
+            .iter()
+            .filter_map(|message| message["content"].as_str().map(str::to_string))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_step_context_limit() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" max_context_tokens_per_step="500"><prompt>Go</prompt></step></steps>"#,
+        )
+        .expect("steps");
+        assert_eq!(steps.steps[0].max_context_tokens_per_step, Some(500));
+
+        let zero = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" max_context_tokens_per_step="0"><prompt>Go</prompt></step></steps>"#,
+        );
+        assert!(matches!(zero, Err(crate::SwarmError::ValidationError(_))));
+    }
+
+    #[tokio::test]
+    async fn test_step_context_limit_truncates_old_messages() {
+        let essay = "word ".repeat(100);
+        let mock_server = mock_reply_sequence(&[&essay, "A Title"]).await;
+
+        let response = run_context_limited_steps(&mock_server, ContextOverflowStrategy::Truncate)
+            .await
+            .expect("run");
+
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 2);
+        assert!(sent_contents(&requests[0]).contains(&"start".to_string()));
+        let second = sent_contents(&requests[1]);
+        assert!(!second.contains(&essay));
+        assert!(!second.contains(&"start".to_string()));
+        assert!(second.contains(&"Give it a title".to_string()));
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("A Title")
+        );
+        let kept: Vec<_> = response
+            .messages
+            .iter()
+            .filter_map(Message::content)
+            .collect();
+        assert!(
+            kept.contains(&"start"),
+            "history outside the window is kept"
+        );
+        assert!(kept.contains(&essay.as_str()));
+    }
+
+    #[tokio::test]
+    async fn test_step_context_limit_summarizes_old_messages() {
+        let essay = "word ".repeat(100);
+        let mock_server = mock_reply_sequence(&[&essay, "An essay about words.", "A Title"]).await;
+
+        run_context_limited_steps(&mock_server, ContextOverflowStrategy::Summarize)
+            .await
+            .expect("run");
+
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 3);
+        assert!(sent_contents(&requests[1])
+            .iter()
+            .any(|content| content.contains(&essay)));
+        assert!(sent_contents(&requests[2])
+            .contains(&"Summary of the earlier conversation:\nAn essay about words.".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_step_context_limit_can_fail_the_step() {
+        let essay = "word ".repeat(100);
+        let mock_server = mock_reply_sequence(&[&essay]).await;
+
+        let error = run_context_limited_steps(&mock_server, ContextOverflowStrategy::Error)
+            .await
+            .expect_err("step over its context limit");
+        assert!(matches!(error, crate::SwarmError::ContextError(_)));
+    }
+
+    async fn plan_and_run_with(
+        mock_server: &MockServer,
+        executors: &[Agent],
+    ) -> crate::SwarmResult<crate::Response> {
+        let planner = Agent::new(
+            "planner",
+            "gpt-4",
+            Instructions::Text("You plan workflows.".to_string()),
+        )
+        .expect("planner");
+        let mut builder = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri());
+        for executor in executors {
+            builder = builder.with_agent(executor.clone());
+        }
+        let swarm = builder.build().expect("swarm");
+        swarm
+            .plan_and_run(
+                planner,
+                "Write a haiku about rust",
+                executors,
+                ContextVariables::new(),
+                RunOptions::new(5),
+            )
+            .await
+    }
+
+    fn writer_agent() -> Agent {
+        Agent::new(
+            "writer",
+            "gpt-4",
+            Instructions::Text("You write poems.".to_string()),
+        )
+        .expect("writer")
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_run_executes_planned_steps() {
+        let plan = r#"<steps><step number="1" action="run_once" agent="writer"><prompt>Draft the haiku</prompt></step></steps>"#;
+        let mock_server = mock_reply_sequence(&[plan, "Iron turns to red"]).await;
+
+        let response = plan_and_run_with(&mock_server, &[writer_agent()])
+            .await
+            .expect("run");
+
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 2);
+        let planner_body: Value = requests[0].body_json().expect("json body");
+        assert!(planner_body.to_string().contains("Agents: writer"));
+        let executor_body: Value = requests[1].body_json().expect("json body");
+        assert!(executor_body.to_string().contains("Draft the haiku"));
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Iron turns to red")
+        );
+        assert_eq!(response.agent.expect("agent").name(), "writer");
+        assert_eq!(response.tokens_used, 4);
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_run_rejects_unknown_step_agents() {
+        let plan = r#"<steps><step number="1" action="run_once" agent="critic"><prompt>Judge</prompt></step></steps>"#;
+        let mock_server = mock_reply_sequence(&[plan]).await;
+
+        let error = plan_and_run_with(&mock_server, &[writer_agent()])
+            .await
+            .expect_err("critic is not an executor");
+        assert!(matches!(error, crate::SwarmError::ValidationError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_run_requires_a_bare_steps_block() {
+        let plan = r#"Here is the plan: <steps><step number="1" action="run_once"><prompt>Draft</prompt></step></steps>"#;
+        let mock_server = mock_reply_sequence(&[plan]).await;
+
+        let error = plan_and_run_with(&mock_server, &[writer_agent()])
+            .await
+            .expect_err("plan has extra text");
+        assert!(matches!(error, crate::SwarmError::XmlError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_plan_and_run_rejects_includes() {
+        let plan = r#"<steps><step number="1" action="run_once" agent="writer"><prompt>Draft</prompt></step><include file="../secrets.xml"/></steps>"#;
+        let mock_server = mock_reply_sequence(&[plan]).await;
+
+        let error = plan_and_run_with(&mock_server, &[writer_agent()])
+            .await
+            .expect_err("plan reads a file");
+        assert!(matches!(error, crate::SwarmError::XmlError(_)));
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 1, "the executor never runs");
+    }
+
+    #[test]
+    fn test_latin1_includes_are_decoded() {
+        let dir = steps_dir(&[]);
+        std::fs::write(
+            dir.join("cafe.xml"),
+            b"<step number=\"1\" action=\"run_once\"><prompt>Caf\xe9</prompt></step>",
+        )
+        .expect("write steps file");
+        let content = r#"<steps><include file="cafe.xml"/></steps>"#;
+
+        let xml = resolve_xml_includes_with_encoding(content, &dir, XmlEncoding::Latin1)
+            .expect("includes resolve");
+        let steps = parse_steps_from_xml(&xml).expect("steps parse");
+        assert_eq!(steps.steps[0].prompt, "Café");
+
+        assert!(matches!(
+            resolve_xml_includes(content, &dir),
+            Err(crate::SwarmError::XmlError(_))
+        ));
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_strip_xml_namespace_only_touches_the_prefix() {
+        assert_eq!(
+            strip_xml_namespace(
+                r#"<my:steps><my:step number="1"><prompt>a:b <other:x/></prompt></my:step></my:steps>"#,
+                "my"
+            ),
+            r#"<steps><step number="1"><prompt>a:b <other:x/></prompt></step></steps>"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_accepts_namespaced_steps() {
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "namespaced",
+            r#"<my:steps><my:step number="1" action="run_once"><my:prompt>Say done</my:prompt></my:step></my:steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_xml_namespace("my")
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run");
+
+        assert!(response
+            .messages
+            .iter()
+            .any(|message| message.content() == Some("Say done")));
+    }
+
+    #[tokio::test]
+    async fn test_namespace_is_stripped_from_the_resolved_steps_block_only() {
+        let dir = steps_dir(&[(
+            "shared.xml",
+            r#"<my:step number="1" action="run_once"><my:prompt>Say done</my:prompt></my:step>"#,
+        )]);
+        let mock_server = mock_text_server("done").await;
+        let agent = steps_agent(
+            "namespaced",
+            r#"Answer in <my:style> tags. <my:steps><include file="shared.xml"/></my:steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_xml_namespace("my")
+            .build()
+            .expect("swarm");
+
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(5).with_steps_base_dir(&dir),
+            )
+            .await
+            .expect("run");
+
+        let requests = mock_server.received_requests().await.expect("requests");
+        let contents = sent_contents(&requests[0]);
+        assert!(contents[0].ends_with("Answer in <my:style> tags."));
+        assert!(contents.contains(&"Say done".to_string()));
+        std::fs::remove_dir_all(dir).expect("clean up");
+    }
+
+    #[test]
+    fn test_parse_step_assertions() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="run_once" output_var="draft"><prompt>Write</prompt><assert key="status" value="ok"/><assert_contains key="draft" value="rust"/><assert_contains key="draft" value="fast"/></step></steps>"#,
+        )
+        .expect("steps");
+        let step = &steps.steps[0];
+        assert_eq!(
+            step.assert_context.get("status").map(String::as_str),
+            Some("ok")
+        );
+        assert_eq!(
+            step.assert_contains,
+            vec![
+                ("draft".to_string(), "rust".to_string()),
+                ("draft".to_string(), "fast".to_string())
+            ]
+        );
+
+        let json_steps = JsonStepsParser
+            .parse(
+                r#"[{"number": 1, "action": "run_once", "prompt": "Write", "assert": [{"key": "status", "value": "ok"}]}]"#,
+            )
+            .expect("json steps");
+        assert_eq!(json_steps.steps[0].assert_context.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_step_assertions_check_context_after_the_step() {
+        let mock_server = mock_text_server("rust is fast").await;
+        let agent = steps_agent(
+            "asserted",
+            r#"<steps><step number="1" action="run_once" output_var="draft"><prompt>Write</prompt><assert key="draft" value="rust is fast"/><assert_contains key="draft" value="fast"/></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+        assert_eq!(response.context_variables["draft"], "rust is fast");
+    }
+
+    #[tokio::test]
+    async fn test_failed_step_assertion_stops_the_run() {
+        let mock_server = mock_text_server("rust is fast").await;
+        let agent = steps_agent(
+            "asserted",
+            r#"<steps><step number="1" action="run_once" output_var="draft"><prompt>Write</prompt><assert_contains key="draft" value="slow"/></step></steps>"#,
+        );
+
+        let error = run_steps(&mock_server, agent)
+            .await
+            .expect_err("draft does not mention slow");
+        assert_eq!(
+            error.to_string(),
+            crate::SwarmError::ValidationError(
+                r#"Assertion failed: expected context['draft'] to contain 'slow', got 'Some("rust is fast")'"#
+                    .to_string()
+            )
+            .to_string()
+        );
+    }
+
+    async fn run_with_context(
+        mock_server: &MockServer,
+        swarm_prefix: Option<&str>,
+        context_variables: ContextVariables,
+    ) -> crate::Response {
+        let agent = steps_agent(
+            "cleaner",
+            r#"<steps><step number="1" action="run_once"><prompt>Search</prompt></step></steps>"#,
+        );
+        let mut builder = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone());
+        if let Some(prefix) = swarm_prefix {
+            builder = builder.with_ephemeral_key_prefix(prefix);
+        }
+        builder
+            .build()
+            .expect("swarm")
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context_variables,
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run")
+    }
+
+    #[tokio::test]
+    async fn test_ephemeral_context_keys_are_removed_after_each_step() {
+        let mock_server = mock_text_server("found it").await;
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("__temp_search_page".to_string(), "2".to_string());
+        context_variables.insert("tmp_cursor".to_string(), "abc".to_string());
+        context_variables.insert("topic".to_string(), "rust".to_string());
+
+        let response = run_with_context(&mock_server, None, context_variables.clone()).await;
+        let mut keys = response
+            .context_variables
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["tmp_cursor", "topic"]);
+
+        let response = run_with_context(&mock_server, Some("tmp_"), context_variables).await;
+        let mut keys = response
+            .context_variables
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["__temp_search_page", "topic"]);
+    }
+
+    async fn run_branch_steps(
+        mock_server: &MockServer,
+        strategy: Option<BranchMergeStrategy>,
+    ) -> crate::Response {
+        let agent = steps_agent(
+            "brancher",
+            r#"<steps>
+                <step number="1" action="branch" branch_name="start"/>
+                <step number="2" action="run_once" output_var="topic"><prompt>Explore</prompt></step>
+                <step number="3" action="merge" from_branch="start"/>
+            </steps>"#,
+        );
+        let mut builder = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone());
+        if let Some(strategy) = strategy {
+            builder = builder.with_branch_merge_strategy(strategy);
+        }
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("topic".to_string(), "rust".to_string());
+        builder
+            .build()
+            .expect("swarm")
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context_variables,
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run")
+    }
+
+    #[tokio::test]
+    async fn test_branch_and_merge_steps_apply_the_merge_strategy() {
+        let mock_server = mock_text_server("explored").await;
+
+        let response = run_branch_steps(&mock_server, None).await;
+        let branch = &response.branches["start"];
+        assert_eq!(branch.context_variables["topic"], "rust");
+        assert_eq!(branch.history.len(), 1);
+        assert_eq!(response.context_variables["topic"], "explored");
+
+        let response =
+            run_branch_steps(&mock_server, Some(BranchMergeStrategy::PreferBranch)).await;
+        assert_eq!(response.context_variables["topic"], "rust");
+    }
+
+    #[test]
+    fn test_parse_rejects_merges_without_an_earlier_branch() {
+        let error = parse_steps_from_xml(
+            r#"<steps>
+                <step number="1" action="merge" from_branch="start"/>
+                <step number="2" action="branch" branch_name="start"/>
+            </steps>"#,
+        )
+        .expect_err("merge before branch");
+        assert!(error.to_string().contains("no earlier branch step"));
+
+        let error = parse_steps_from_xml(
+            r#"<steps><step number="1" action="branch" branch_name=" "/></steps>"#,
+        )
+        .expect_err("blank branch_name");
+        assert!(error.to_string().contains("without a branch_name"));
+    }
+
+    #[tokio::test]
+    async fn test_responses_split_history_by_agent() {
+        let mock_server = mock_text_server("noted").await;
+        let writer = steps_agent(
+            "writer",
+            r#"<steps>
+                <step number="1" action="run_once"><prompt>Write</prompt></step>
+                <step number="2" action="run_once" agent="reviewer"><prompt>Review</prompt></step>
+            </steps>"#,
+        );
+        let reviewer = steps_agent("reviewer", "Review drafts.");
+        let run = |inject_names: bool| {
+            let swarm = Swarm::builder()
+                .with_api_key("sk-test".to_string())
+                .with_api_url(mock_server.uri())
+                .with_agent(writer.clone())
+                .with_agent(reviewer.clone())
+                .with_inject_agent_name_as_message_name(inject_names)
+                .build()
+                .expect("swarm");
+            let writer = writer.clone();
+            async move {
+                swarm
+                    .run_with_options(
+                        writer,
+                        vec![Message::user("start").expect("user message")],
+                        ContextVariables::new(),
+                        RunOptions::new(5),
+                    )
+                    .await
+                    .expect("run")
+            }
+        };
+
+        let unnamed = run(false).await;
+        assert!(unnamed.messages_by_agent("writer").is_empty());
+
+        let response = run(true).await;
+        assert_eq!(response.messages_by_agent("writer").len(), 1);
+        assert_eq!(response.messages_by_agent("reviewer").len(), 1);
+        let authors = response
+            .conversation_timeline()
+            .into_iter()
+            .map(|(author, _)| author)
+            .collect::<Vec<_>>();
+        assert_eq!(authors, vec!["user", "user", "writer", "user", "reviewer"]);
+    }
+
+    #[tokio::test]
+    async fn test_message_names_only_use_characters_the_api_accepts() {
+        let mock_server = mock_text_server("noted").await;
+        let agent = steps_agent("Lead Writer (EU)", "Write drafts.");
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        let reply = response.messages.last().expect("reply");
+        assert_eq!(reply.name(), Some("Lead_Writer__EU_"));
+        assert_eq!(response.messages_by_agent("Lead Writer (EU)").len(), 1);
+        assert_eq!(crate::util::message_name(&"a".repeat(80)).len(), 64);
+    }
+
+    #[tokio::test]
+    async fn test_short_step_responses_are_retried() {
+        let mock_server =
+            mock_reply_sequence(&["Ok.", "Sure.", "Rust is a systems language."]).await;
+        let agent = steps_agent(
+            "explainer",
+            r#"<steps><step number="1" action="run_once" min_response_length="10" short_response_max_retries="3"><prompt>Explain Rust</prompt></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Rust is a systems language.")
+        );
+        let retries = response
+            .messages
+            .iter()
+            .filter(|message| message.content() == Some(SHORT_RESPONSE_RETRY_PROMPT))
+            .count();
+        assert_eq!(retries, 2);
+    }
+
+    #[tokio::test]
+    async fn test_short_response_retries_stop_at_the_turn_budget() {
+        let mock_server = mock_text_server("Ok.").await;
+        let agent = steps_agent(
+            "explainer",
+            r#"<steps><step number="1" action="run_once" min_response_length="10" short_response_max_retries="10"><prompt>Explain Rust</prompt></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        let requests = mock_server.received_requests().await.expect("requests");
+        assert_eq!(requests.len(), 5);
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Ok.")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_history_step_replaces_the_conversation() {
+        let mock_server =
+            mock_reply_sequence(&["First answer", "Second answer", "- asked two questions"]).await;
+        let agent = steps_agent(
+            "writer",
+            r#"<steps>
+                <step number="1" action="run_once"><prompt>Question one</prompt></step>
+                <step number="2" action="run_once"><prompt>Question two</prompt></step>
+                <step number="3" action="summarize_history" preserve_last="2"/>
+            </steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        let contents = response
+            .messages
+            .iter()
+            .map(|message| (message.role(), message.content().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            contents,
+            vec![
+                (MessageRole::System, "Summary: - asked two questions"),
+                (MessageRole::User, "Question two"),
+                (MessageRole::Assistant, "Second answer"),
+            ]
+        );
+        let requests = mock_server.received_requests().await.expect("requests");
+        let body = requests[2].body_json::<Value>().expect("json body");
+        let messages = body["messages"].as_array().expect("messages");
+        assert_eq!(
+            messages.last().expect("summary prompt")["content"],
+            "Summarize the conversation above in bullet points."
+        );
+        assert_eq!(messages.len(), 7);
+    }
+
+    #[test]
+    fn test_fill_template_fills_every_known_placeholder() {
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("city".to_string(), "Paris".to_string());
+        context_variables.insert("days".to_string(), "3".to_string());
+
+        assert_eq!(
+            fill_template(
+                "Plan {{days}} days in {{ city }}; budget {{budget}}.",
+                &[("city", "Paris"), ("days", "3")]
+            ),
+            "Plan 3 days in Paris; budget {{budget}}."
+        );
+        assert_eq!(
+            unresolved_placeholders("{{budget}} {{city}} {{budget}}", &context_variables),
+            vec!["budget"]
+        );
+    }
+
+    async fn run_templated_step(strict: bool) -> crate::SwarmResult<crate::Response> {
+        let mock_server = mock_text_server("planned").await;
+        let agent = steps_agent(
+            "planner",
+            &format!(
+                r#"<steps><step number="1" action="run_once" template_strict="{}"><prompt>Plan {{{{days}}}} days in {{{{city}}}}</prompt></step></steps>"#,
+                strict
+            ),
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("city".to_string(), "Paris".to_string());
+        swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context_variables,
+                RunOptions::new(5),
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_step_prompts_substitute_context_variables() {
+        let response = run_templated_step(false).await.expect("lenient run");
+        assert!(response
+            .messages
+            .iter()
+            .any(|message| message.content() == Some("Plan {{days}} days in Paris")));
+
+        let error = run_templated_step(true).await.expect_err("strict run");
+        assert!(error
+            .to_string()
+            .contains("Step 1 prompt has unresolved placeholders: days"));
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_steps_from_context_are_appended_mid_run() {
+        let mock_server = MockServer::start().await;
+        let replies = [
+            json!({
+                "role": "assistant",
+                "content": null,
+                "tool_calls": [{
+                    "id": "call_add_steps",
+                    "type": "function",
+                    "function": {"name": "add_steps", "arguments": "{}"}
+                }]
+            }),
+            json!({"role": "assistant", "content": "Reported."}),
+        ];
+        for reply in replies {
+            Mock::given(method("POST"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(mock_chat_response(reply)))
+                .up_to_n_times(1)
+                .mount(&mock_server)
+                .await;
+        }
+        let handler: Arc<AgentFunctionHandler> = Arc::new(|_ctx: ContextVariables| {
+            Box::pin(async move {
+                let mut ctx = ContextVariables::new();
+                ctx.insert(
+                    "__dynamic_steps".to_string(),
+                    r#"<steps><step number="1" action="run_once"><prompt>Report</prompt></step></steps>"#
+                        .to_string(),
+                );
+                Ok(ResultType::ContextVariables(ctx))
+            })
+        });
+        let agent = steps_agent(
+            "planner",
+            r#"<steps><step number="1" action="run_once"><prompt>Search</prompt></step></steps>"#,
+        )
+        .with_functions(vec![
+            AgentFunction::new("add_steps", handler, false).expect("function")
+        ]);
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        assert!(response
+            .messages
+            .iter()
+            .any(|message| message.role() == MessageRole::User
+                && message.content() == Some("Report")));
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("Reported.")
+        );
+        assert!(!response.context_variables.contains_key("__dynamic_steps"));
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_step_injection_limit_fails_the_run() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "planner",
+            r#"<steps><step number="1" action="run_once"><prompt>Search</prompt></step></steps>"#,
+        );
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert(
+            "__dynamic_steps".to_string(),
+            r#"<steps><step number="1" action="run_once"><prompt>Report</prompt></step></steps>"#
+                .to_string(),
+        );
+        let error = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_max_dynamic_step_injections(0)
+            .build()
+            .expect("swarm")
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context_variables,
+                RunOptions::new(5),
+            )
+            .await
+            .expect_err("injection over the limit");
+
+        assert!(error.to_string().contains("exceeded the limit of 0"));
+    }
+
+    #[tokio::test]
+    async fn test_agent_switch_can_update_the_system_message() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "router",
+            r#"<steps><step number="1" action="run_once" agent="specialist"><prompt>Go</prompt></step></steps>"#,
+        );
+        let specialist = Agent::new(
+            "specialist",
+            "gpt-4",
+            Instructions::Text("You are a specialist.".to_string()),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_agent(specialist)
+            .with_update_system_message_on_switch(true)
+            .build()
+            .expect("swarm");
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![
+                    Message::system("You are a workflow agent.").expect("system message"),
+                    Message::user("start").expect("user message"),
+                    Message::system("Summary: the user said hello.").expect("summary message"),
+                ],
+                ContextVariables::new(),
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response.messages[0].content(),
+            Some("You are a specialist.")
+        );
+        assert_eq!(
+            response.messages[2].content(),
+            Some("Summary: the user said hello.")
+        );
+        let requests = mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled");
+        let body: Value = requests[0].body_json().expect("json request body");
+        let system_messages = body["messages"]
+            .as_array()
+            .expect("messages array")
+            .iter()
+            .filter(|message| message["role"] == "system")
+            .count();
+        assert_eq!(system_messages, 2);
+    }
+
+    #[tokio::test]
+    async fn test_workflow_longer_than_max_turns_is_rejected_or_adjusted() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "worker",
+            r#"<steps><step number="1" action="run_once"><prompt>One</prompt></step><step number="2" action="run_once"><prompt>Two</prompt></step><step number="3" action="run_once"><prompt>Three</prompt></step></steps>"#,
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let run = |options: RunOptions| {
+            swarm.run_with_options(
+                agent.clone(),
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                options,
+            )
+        };
+
+        let error = run(RunOptions::new(2))
+            .await
+            .expect_err("more steps than turns");
+        assert!(matches!(
+            error,
+            crate::SwarmError::ValidationError(message)
+                if message == "Workflow has 3 steps but max_turns is 2"
+        ));
+
+        let response = run(RunOptions::new(2).with_auto_adjust_max_turns(true))
+            .await
+            .expect("max_turns raised to the step count");
+        let prompts = response
+            .messages
+            .iter()
+            .filter(|message| message.role() == MessageRole::User)
+            .count();
+        assert_eq!(prompts, 4);
+    }
+
+    #[tokio::test]
+    async fn test_adjusted_max_turns_respects_max_loop_iterations() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "worker",
+            r#"<steps><step number="1" action="run_once"><prompt>One</prompt></step><step number="2" action="run_once"><prompt>Two</prompt></step><step number="3" action="run_once"><prompt>Three</prompt></step></steps>"#,
+        );
+        let error = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_max_loop_iterations(2)
+            .build()
+            .expect("swarm")
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                ContextVariables::new(),
+                RunOptions::new(2).with_auto_adjust_max_turns(true),
+            )
+            .await
+            .expect_err("adjustment past max_loop_iterations");
+
+        assert!(error
+            .to_string()
+            .contains("more than the configured max_loop_iterations (2)"));
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_steps_are_checked_against_max_turns() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "planner",
+            r#"<steps><step number="1" action="run_once"><prompt>Search</prompt></step></steps>"#,
+        );
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert(
+            "__dynamic_steps".to_string(),
+            r#"<steps><step number="1" action="run_once"><prompt>Report</prompt></step><step number="2" action="run_once"><prompt>Review</prompt></step></steps>"#
+                .to_string(),
+        );
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .build()
+            .expect("swarm");
+        let run = |options: RunOptions| {
+            swarm.run_with_options(
+                agent.clone(),
+                vec![Message::user("start").expect("user message")],
+                context_variables.clone(),
+                options,
+            )
+        };
+
+        let error = run(RunOptions::new(2))
+            .await
+            .expect_err("injected steps exceed max_turns");
+        assert!(error
+            .to_string()
+            .contains("Workflow has 3 steps but max_turns is 2"));
+
+        let response = run(RunOptions::new(2).with_auto_adjust_max_turns(true))
+            .await
+            .expect("max_turns raised for the injected steps");
+        assert_eq!(
+            response
+                .messages
+                .iter()
+                .filter(|message| message.role() == MessageRole::User)
+                .count(),
+            4
+        );
+    }
+
+    #[tokio::test]
+    async fn test_context_keys_are_renamed_when_switching_agents() {
+        let mock_server = mock_text_server("Done").await;
+        let agent = steps_agent(
+            "searcher",
+            r#"<steps><step number="1" action="run_once" agent="reader"><prompt>Read it</prompt></step></steps>"#,
+        );
+        let reader = Agent::new(
+            "reader",
+            "gpt-4",
+            Instructions::Text("You read search results.".to_string()),
+        )
+        .expect("agent");
+        let swarm = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_api_url(mock_server.uri())
+            .with_agent(agent.clone())
+            .with_agent(reader)
+            .with_context_rename_on_switch(
+                "reader",
+                std::collections::HashMap::from([(
+                    "search_result".to_string(),
+                    "input_data".to_string(),
+                )]),
+            )
+            .build()
+            .expect("swarm");
+        let mut context_variables = ContextVariables::new();
+        context_variables.insert("search_result".to_string(), "rust docs".to_string());
+
+        let response = swarm
+            .run_with_options(
+                agent,
+                vec![Message::user("start").expect("user message")],
+                context_variables,
+                RunOptions::new(5),
+            )
+            .await
+            .expect("run");
+
+        assert_eq!(
+            response
+                .context_variables
+                .get("input_data")
+                .map(String::as_str),
+            Some("rust docs")
+        );
+        assert!(!response.context_variables.contains_key("search_result"));
+    }
+
+    #[test]
+    fn test_context_rename_on_switch_requires_a_registered_agent() {
+        let error = Swarm::builder()
+            .with_api_key("sk-test".to_string())
+            .with_context_rename_on_switch(
+                "ghost",
+                std::collections::HashMap::from([("a".to_string(), "b".to_string())]),
+            )
+            .build()
+            .err()
+            .expect("unknown agent");
+
+        assert!(error
+            .to_string()
+            .contains("'ghost', which is not a registered agent"));
+    }
+
+    #[test]
+    fn test_parse_repeat_until_requires_a_valid_pattern() {
+        let steps = parse_steps_from_xml(
+            r#"<steps><step number="1" action="repeat_until" until_pattern="^FINAL"><prompt>Refine</prompt></step></steps>"#,
+        )
+        .expect("repeat_until step");
+        assert_eq!(steps.steps[0].action, StepAction::RepeatUntil);
+        assert_eq!(steps.steps[0].until_pattern.as_deref(), Some("^FINAL"));
+
+        let missing = parse_steps_from_xml(
+            r#"<steps><step number="1" action="repeat_until"><prompt>Refine</prompt></step></steps>"#,
+        )
+        .expect_err("missing until_pattern");
+        assert!(missing.to_string().contains("without an until_pattern"));
+        let invalid = parse_steps_from_xml(
+            r#"<steps><step number="1" action="repeat_until" until_pattern="(unclosed"><prompt>Refine</prompt></step></steps>"#,
+        )
+        .expect_err("invalid until_pattern");
+        assert!(invalid.to_string().contains("invalid until_pattern"));
+    }
+
+    #[tokio::test]
+    async fn test_repeat_until_stops_when_the_reply_matches() {
+        let mock_server = mock_reply_sequence(&["draft one", "FINAL: done", "unused"]).await;
+        let agent = steps_agent(
+            "refiner",
+            r#"<steps><step number="1" action="repeat_until" until_pattern="^FINAL:"><prompt>Refine</prompt></step></steps>"#,
+        );
+
+        let response = run_steps(&mock_server, agent).await.expect("run");
+
+        assert_eq!(
+            response.messages.last().and_then(Message::content),
+            Some("FINAL: done")
+        );
+        let requests = mock_server
+            .received_requests()
+            .await
+            .expect("request recording enabled");
+        assert_eq!(requests.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_repeat_until_without_a_match_reports_the_last_reply() {
+        let mock_server = mock_text_server("still drafting").await;
+        let agent = steps_agent(
+            "refiner",
+            r#"<steps><step number="1" action="repeat_until" until_pattern="^FINAL:" max_turns_per_step="2"><prompt>Refine</prompt></step></steps>"#,
+        );
+
+        let error = run_steps(&mock_server, agent)
+            .await
+            .expect_err("pattern never matches");
+
+        assert!(error.to_string().contains("still drafting"));
+        assert!(matches!(
+            error,
+            crate::SwarmError::MaxIterationsError {
+                max: 2,
+                actual: 2,
+                last_response: Some(ref last_response),
+            } if last_response == "still drafting"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_step_debug_override() {
+        let xml = r#"<steps><step number="1" action="run_once" debug="true"><prompt>Trace this</prompt></step><step number="2" action="run_once" debug="false"><prompt>Quiet</prompt></step><step number="3" action="run_once"><prompt>Inherit</prompt></step></steps>"#;
+        let steps = parse_steps_from_xml(xml).expect("steps");
+        let overrides: Vec<_> = steps.steps.iter().map(|step| step.debug).collect();
+        assert_eq!(overrides, vec![Some(true), Some(false), None]);
+
+        let mock_server = mock_text_server("done").await;
+        let response = run_steps(&mock_server, steps_agent("debugged", xml))
+            .await
+            .expect("run");
+        assert_eq!(
+            response.messages.last().and_then(|m| m.content()),
+            Some("done")
+        );
+        assert_eq!(
+            mock_server
+                .received_requests()
+                .await
+                .expect("requests")
+                .len(),
+            3
+        );
+    }
+}
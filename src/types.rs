@@ -1,22 +1,30 @@
 // File: rswarm/src/types.rs
 
 use crate::constants::{
-    DEFAULT_API_VERSION, DEFAULT_CONNECT_TIMEOUT, DEFAULT_MAX_LOOP_ITERATIONS,
-    DEFAULT_REQUEST_TIMEOUT, OPENAI_DEFAULT_API_URL, VALID_API_URL_PREFIXES,
+    DEFAULT_API_VERSION, DEFAULT_CONNECT_TIMEOUT, DEFAULT_EPHEMERAL_KEY_PREFIX,
+    DEFAULT_FUNCTION_ARG_MAX_RETRIES, DEFAULT_MAX_CONTEXT_SNAPSHOTS,
+    DEFAULT_MAX_DYNAMIC_STEP_INJECTIONS, DEFAULT_MAX_LOOP_ITERATIONS, DEFAULT_MODEL_ALIASES,
+    DEFAULT_REQUEST_TIMEOUT, DEFAULT_VISION_CAPABLE_MODEL_PATTERNS, MAX_STOP_SEQUENCES,
+    MAX_TOP_LOGPROBS, OPENAI_DEFAULT_API_URL, VALID_API_URL_PREFIXES,
 };
+use crate::core::RunState;
 use crate::error::{SwarmError, SwarmResult};
+use crate::execution_trace::TraceEntry;
 use crate::phase::TerminationReason;
+use crate::profile::ProfileReport;
+use crate::util::{message_name, truncate_to_size};
 use serde::{
     de::{self},
+    ser::SerializeMap,
     Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use url::Url;
 
 /// A map of string key–value pairs used for context variables in agent interactions.
@@ -345,17 +353,76 @@ pub enum Instructions {
 pub enum FunctionCallPolicy {
     Disabled,
     Auto,
+    /// Functions are described but the model is told not to call any.
+    None,
     Named(String),
 }
 
+/// The `function_call` values an agent accepts, for validation errors.
+pub(crate) const FUNCTION_CALL_OPTIONS: &str = "\"auto\", \"none\", or {\"name\": \"<function>\"}";
+
 impl FunctionCallPolicy {
+    /// Parses a serialized `function_call` value: `"auto"`, `"none"`, or a JSON
+    /// object whose `"name"` field names the function to call. A bare function
+    /// name, the form older configs were saved with, is read as that name.
+    pub fn parse(value: &str) -> SwarmResult<Self> {
+        match value.trim() {
+            "auto" => return Ok(Self::Auto),
+            "none" => return Ok(Self::None),
+            "" => {}
+            bare if !bare.starts_with('{') => return Ok(Self::Named(bare.to_string())),
+            _ => {}
+        }
+        let name = serde_json::from_str::<Value>(value)
+            .ok()
+            .and_then(|value| value.get("name")?.as_str().map(str::to_string))
+            .filter(|name| !name.trim().is_empty())
+            .ok_or_else(|| {
+                SwarmError::ValidationError(format!(
+                    "Invalid function_call value '{}'; expected {}",
+                    value, FUNCTION_CALL_OPTIONS
+                ))
+            })?;
+        Ok(Self::Named(name))
+    }
+
+    /// The serialized form read back by [`FunctionCallPolicy::parse`].
+    pub fn to_config_value(&self) -> Option<String> {
+        match self {
+            Self::Named(name) => Some(json!({ "name": name }).to_string()),
+            policy => policy.to_wire_value(),
+        }
+    }
+
     pub fn to_wire_value(&self) -> Option<String> {
         match self {
             Self::Disabled => None,
             Self::Auto => Some("auto".to_string()),
+            Self::None => Some("none".to_string()),
             Self::Named(name) => Some(name.clone()),
         }
     }
+
+    /// The policy as a tools API `tool_choice` value.
+    pub fn to_tool_choice(&self) -> Option<Value> {
+        match self {
+            Self::Disabled => None,
+            Self::Auto => Some(json!("auto")),
+            Self::None => Some(json!("none")),
+            Self::Named(name) => Some(json!({"type": "function", "function": {"name": name}})),
+        }
+    }
+}
+
+/// How an agent's functions are described in chat completion requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionCallFormat {
+    /// `functions` and `function_call`.
+    #[default]
+    Legacy,
+    /// `tools` and `tool_choice`.
+    Tools,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -381,6 +448,145 @@ pub struct Agent {
     pub(crate) parallel_tool_calls: ToolCallExecution,
     pub(crate) expected_response_fields: Vec<String>,
     pub(crate) capabilities: Vec<String>,
+    pub(crate) model_parameters: ModelParameters,
+    /// Overrides `SwarmConfig::default_function_format` when set.
+    pub(crate) function_format: Option<FunctionCallFormat>,
+    /// Sent as `stop` after `SwarmConfig::global_stop_sequences`.
+    pub(crate) stop_sequences: Option<Vec<String>>,
+    /// Partial assistant message sent last in every request for the model to continue.
+    pub(crate) assistant_prefix: Option<String>,
+    /// Context variable keys kept when a function hands off to this agent.
+    pub(crate) handoff_context_filter: Option<Arc<HandoffContextFilter>>,
+    /// Requests token log probabilities; see [`Response::extract_logprobs`].
+    pub(crate) logprobs_config: Option<LogprobsConfig>,
+    /// Language every reply must be in; see [`Agent::with_response_language`].
+    pub(crate) response_language: Option<String>,
+    /// Re-ask replies that contain no word of the language's word list.
+    pub(crate) enforce_language: bool,
+    /// Static values describing the agent, such as an owner or a product area.
+    pub(crate) metadata: HashMap<String, Value>,
+    /// Copy `metadata` into the context as `__agent_{key}` when a run starts.
+    pub(crate) inject_metadata_to_context: bool,
+}
+
+/// Decides, by key, which context variables an agent receives on handoff.
+pub type HandoffContextFilter = dyn Fn(&str) -> bool + Send + Sync;
+
+/// Rejects blank stop sequences and lists longer than [`MAX_STOP_SEQUENCES`].
+pub(crate) fn validate_stop_sequences(sequences: &[String]) -> SwarmResult<()> {
+    if sequences.len() > MAX_STOP_SEQUENCES {
+        return Err(SwarmError::ValidationError(format!(
+            "At most {} stop sequences are allowed, got {}",
+            MAX_STOP_SEQUENCES,
+            sequences.len()
+        )));
+    }
+    if sequences.iter().any(|sequence| sequence.is_empty()) {
+        return Err(SwarmError::ValidationError(
+            "Stop sequences cannot be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Case-insensitive match of `model` against `patterns`.
+///
+/// A pattern must cover whole `-`, `.`, `/`, `:` or `_` separated parts of the
+/// name, so `o1` matches `o1-mini` and `openai/o1` but not `gpt-4o1`.
+pub(crate) fn is_vision_capable_model(model: &str, patterns: &[String]) -> bool {
+    let model = model.to_lowercase();
+    let is_separator = |c: char| matches!(c, '-' | '.' | '/' | ':' | '_');
+    patterns.iter().any(|pattern| {
+        let pattern = pattern.to_lowercase();
+        model.match_indices(&pattern).any(|(start, matched)| {
+            let end = start + matched.len();
+            model[..start].chars().next_back().is_none_or(is_separator)
+                && model[end..].chars().next().is_none_or(is_separator)
+        })
+    })
+}
+
+/// Sampling parameters sent with every completion request an agent makes.
+///
+/// Build one from [`ModelParameters::default`] with the `with_*` methods; new
+/// parameters may be added in later releases.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ModelParameters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Completion token cap; see `SwarmConfig::adaptive_max_tokens`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    /// Sampling seed for approximately deterministic completions; compare
+    /// [`Response::system_fingerprint`] across runs to spot backend changes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<i64>,
+}
+
+impl ModelParameters {
+    pub fn with_temperature(mut self, temperature: f32) -> Self {
+        self.temperature = Some(temperature);
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: u32) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: i64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn is_unset(&self) -> bool {
+        self.temperature.is_none() && self.max_tokens.is_none() && self.seed.is_none()
+    }
+
+    pub fn validate(&self) -> SwarmResult<()> {
+        if let Some(temperature) = self.temperature {
+            if !temperature.is_finite() || !(0.0..=2.0).contains(&temperature) {
+                return Err(SwarmError::ValidationError(
+                    "temperature must be between 0.0 and 2.0".to_string(),
+                ));
+            }
+        }
+        if self.max_tokens == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "max_tokens must be greater than 0".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Token log probability settings sent with an agent's completion requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogprobsConfig {
+    pub enabled: bool,
+    /// Most likely alternatives returned per token, at most [`MAX_TOP_LOGPROBS`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+}
+
+impl LogprobsConfig {
+    pub fn validate(&self) -> SwarmResult<()> {
+        if let Some(top_logprobs) = self.top_logprobs {
+            if !self.enabled {
+                return Err(SwarmError::ValidationError(
+                    "top_logprobs requires logprobs to be enabled".to_string(),
+                ));
+            }
+            if top_logprobs > MAX_TOP_LOGPROBS {
+                return Err(SwarmError::ValidationError(format!(
+                    "top_logprobs cannot exceed {}",
+                    MAX_TOP_LOGPROBS
+                )));
+            }
+        }
+        Ok(())
+    }
 }
 
 // Custom Debug implementation for Agent.
@@ -413,6 +619,16 @@ impl Agent {
             parallel_tool_calls: ToolCallExecution::Serial,
             expected_response_fields: Vec::new(),
             capabilities: Vec::new(),
+            model_parameters: ModelParameters::default(),
+            function_format: None,
+            stop_sequences: None,
+            assistant_prefix: None,
+            handoff_context_filter: None,
+            logprobs_config: None,
+            response_language: None,
+            enforce_language: false,
+            metadata: HashMap::new(),
+            inject_metadata_to_context: false,
         };
         agent.validate_intrinsic_fields()?;
         Ok(agent)
@@ -433,6 +649,82 @@ impl Agent {
         self
     }
 
+    pub fn with_function_format(mut self, format: FunctionCallFormat) -> Self {
+        self.function_format = Some(format);
+        self
+    }
+
+    /// Sequences that end generation, e.g. a sentinel marking structured output complete.
+    /// Together with the config's global sequences at most four are allowed.
+    pub fn with_stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.stop_sequences = Some(stop_sequences);
+        self
+    }
+
+    /// Start every response with `prefix` (e.g. `"ANSWER:"`) by ending each request
+    /// with a partial assistant message. Replies are stored as the model returns them.
+    pub fn with_assistant_prefix(mut self, prefix: impl Into<String>) -> SwarmResult<Self> {
+        let prefix = prefix.into();
+        if prefix.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Assistant prefix cannot be empty".to_string(),
+            ));
+        }
+        self.assistant_prefix = Some(prefix);
+        Ok(self)
+    }
+
+    /// Keep only the context variables whose key passes `filter` when a function
+    /// hands off to this agent, e.g. to withhold raw tool output from it.
+    pub fn with_handoff_context_filter(
+        mut self,
+        filter: impl Fn(&str) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.handoff_context_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Ask the model for token log probabilities, stored per turn in
+    /// [`Response::turn_metadata`].
+    pub fn with_logprobs_config(mut self, logprobs_config: LogprobsConfig) -> SwarmResult<Self> {
+        logprobs_config.validate()?;
+        self.logprobs_config = Some(logprobs_config);
+        Ok(self)
+    }
+
+    /// Ask for every reply in `language` (e.g. `"French"`) by appending
+    /// `" Always respond in {language}."` to the system message.
+    pub fn with_response_language(mut self, language: impl Into<String>) -> SwarmResult<Self> {
+        let language = language.into();
+        if language.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "Response language cannot be empty".to_string(),
+            ));
+        }
+        self.response_language = Some(language);
+        Ok(self)
+    }
+
+    /// Check each reply against the response language's word list
+    /// ([`SwarmBuilder::with_language_word_list`](crate::SwarmBuilder::with_language_word_list))
+    /// and re-ask once with a stronger instruction when it contains none of the words.
+    pub fn with_enforce_language(mut self, enforce: bool) -> Self {
+        self.enforce_language = enforce;
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: HashMap<String, Value>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// At the start of a run, add each `metadata` entry to the context variables as
+    /// `__agent_{key}`, holding the value as JSON text. Caller-supplied keys win.
+    pub fn with_inject_metadata_to_context(mut self, inject: bool) -> Self {
+        self.inject_metadata_to_context = inject;
+        self
+    }
+
     pub fn with_expected_response_fields(
         mut self,
         expected_response_fields: Vec<String>,
@@ -449,6 +741,12 @@ impl Agent {
         Ok(self)
     }
 
+    pub fn with_model_parameters(mut self, model_parameters: ModelParameters) -> SwarmResult<Self> {
+        model_parameters.validate()?;
+        self.model_parameters = model_parameters;
+        Ok(self)
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
@@ -457,6 +755,10 @@ impl Agent {
         &self.model
     }
 
+    pub fn model_parameters(&self) -> &ModelParameters {
+        &self.model_parameters
+    }
+
     pub fn instructions(&self) -> &Instructions {
         &self.instructions
     }
@@ -473,6 +775,42 @@ impl Agent {
         self.parallel_tool_calls
     }
 
+    pub fn function_format(&self) -> Option<FunctionCallFormat> {
+        self.function_format
+    }
+
+    pub fn stop_sequences(&self) -> Option<&[String]> {
+        self.stop_sequences.as_deref()
+    }
+
+    pub fn assistant_prefix(&self) -> Option<&str> {
+        self.assistant_prefix.as_deref()
+    }
+
+    pub fn handoff_context_filter(&self) -> Option<&Arc<HandoffContextFilter>> {
+        self.handoff_context_filter.as_ref()
+    }
+
+    pub fn logprobs_config(&self) -> Option<&LogprobsConfig> {
+        self.logprobs_config.as_ref()
+    }
+
+    pub fn response_language(&self) -> Option<&str> {
+        self.response_language.as_deref()
+    }
+
+    pub fn enforce_language(&self) -> bool {
+        self.enforce_language
+    }
+
+    pub fn metadata(&self) -> &HashMap<String, Value> {
+        &self.metadata
+    }
+
+    pub fn inject_metadata_to_context(&self) -> bool {
+        self.inject_metadata_to_context
+    }
+
     pub fn expected_response_fields(&self) -> &[String] {
         &self.expected_response_fields
     }
@@ -531,6 +869,24 @@ struct AgentTransport {
     parallel_tool_calls: bool,
     #[serde(default)]
     expected_response_fields: Vec<String>,
+    #[serde(default, skip_serializing_if = "ModelParameters::is_unset")]
+    model_parameters: ModelParameters,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    function_format: Option<FunctionCallFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    assistant_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logprobs_config: Option<LogprobsConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_language: Option<String>,
+    #[serde(default)]
+    enforce_language: bool,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    metadata: HashMap<String, Value>,
+    #[serde(default)]
+    inject_metadata_to_context: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -556,16 +912,15 @@ impl TryFrom<AgentTransport> for Agent {
 
         let function_call = match value.function_call {
             None => FunctionCallPolicy::Disabled,
-            Some(policy) if policy == "auto" => FunctionCallPolicy::Auto,
             Some(policy) if policy.trim().is_empty() => {
                 return Err(SwarmError::ValidationError(
                     "Agent function_call policy cannot be empty".to_string(),
                 ));
             }
-            Some(policy) => FunctionCallPolicy::Named(policy),
+            Some(policy) => FunctionCallPolicy::parse(&policy)?,
         };
 
-        Agent::new(
+        let mut agent = Agent::new(
             value.name,
             value.model,
             Instructions::Text(value.instructions.text),
@@ -576,7 +931,23 @@ impl TryFrom<AgentTransport> for Agent {
         } else {
             ToolCallExecution::Serial
         })
-        .with_expected_response_fields(value.expected_response_fields)
+        .with_expected_response_fields(value.expected_response_fields)?
+        .with_model_parameters(value.model_parameters)?;
+        agent.function_format = value.function_format;
+        agent.stop_sequences = value.stop_sequences;
+        if let Some(prefix) = value.assistant_prefix {
+            agent = agent.with_assistant_prefix(prefix)?;
+        }
+        if let Some(logprobs_config) = value.logprobs_config {
+            agent = agent.with_logprobs_config(logprobs_config)?;
+        }
+        if let Some(language) = value.response_language {
+            agent = agent.with_response_language(language)?;
+        }
+        Ok(agent
+            .with_enforce_language(value.enforce_language)
+            .with_metadata(value.metadata)
+            .with_inject_metadata_to_context(value.inject_metadata_to_context))
     }
 }
 
@@ -590,6 +961,11 @@ impl Serialize for Agent {
                 "Agent serialization does not support runtime function closures",
             ));
         }
+        if self.handoff_context_filter.is_some() {
+            return Err(serde::ser::Error::custom(
+                "Agent serialization does not support handoff context filters",
+            ));
+        }
 
         let instructions = match &self.instructions {
             Instructions::Text(text) => AgentInstructionsTransport { text: text.clone() },
@@ -605,9 +981,18 @@ impl Serialize for Agent {
             model: self.model.clone(),
             instructions,
             functions: Vec::new(),
-            function_call: self.function_call.to_wire_value(),
+            function_call: self.function_call.to_config_value(),
             parallel_tool_calls: self.parallel_tool_calls.is_parallel(),
             expected_response_fields: self.expected_response_fields.clone(),
+            model_parameters: self.model_parameters,
+            function_format: self.function_format,
+            stop_sequences: self.stop_sequences.clone(),
+            assistant_prefix: self.assistant_prefix.clone(),
+            logprobs_config: self.logprobs_config,
+            response_language: self.response_language.clone(),
+            enforce_language: self.enforce_language,
+            metadata: self.metadata.clone(),
+            inject_metadata_to_context: self.inject_metadata_to_context,
         }
         .serialize(serializer)
     }
@@ -624,6 +1009,8 @@ impl<'de> Deserialize<'de> for Agent {
 }
 
 /// The result of an agent function execution.
+// Boxing `Agent` would break every function that returns `ResultType::Agent(agent)`.
+#[allow(clippy::large_enum_variant)]
 #[derive(Clone, Debug)]
 pub enum ResultType {
     Value(String),
@@ -676,6 +1063,8 @@ pub struct AgentFunction {
     accepts_context_variables: bool,
     description: String,
     parameters_schema: Value,
+    /// Returned context variables get `SwarmConfig::ephemeral_key_prefix`.
+    ephemeral_context: bool,
 }
 
 impl AgentFunction {
@@ -700,6 +1089,7 @@ impl AgentFunction {
                 "properties": {},
                 "required": [],
             }),
+            ephemeral_context: false,
         })
     }
 
@@ -719,6 +1109,17 @@ impl AgentFunction {
         &self.parameters_schema
     }
 
+    pub fn ephemeral_context(&self) -> bool {
+        self.ephemeral_context
+    }
+
+    /// Mark the context variables this function returns as ephemeral, so they are
+    /// dropped once the current step completes.
+    pub fn with_ephemeral_context(mut self, ephemeral: bool) -> Self {
+        self.ephemeral_context = ephemeral;
+        self
+    }
+
     pub fn with_description(mut self, description: impl Into<String>) -> Self {
         self.description = description.into();
         self
@@ -764,7 +1165,7 @@ impl AgentFunction {
 ///
 /// All fields are `Option<_>`: `None` means "no limit enforced". Defaults to
 /// all limits disabled so existing in-memory workflows are unaffected.
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct RuntimeLimits {
     /// Maximum total tokens (prompt + completion) allowed across the run.
     pub token_budget: Option<u32>,
@@ -798,8 +1199,152 @@ impl RuntimeLimits {
     }
 }
 
+/// Derives the OpenAI `user` field from a run's context variables.
+pub type UserIdProvider = dyn Fn(&ContextVariables) -> Option<String> + Send + Sync;
+
+/// Supplies context variables at the start of every run; see
+/// [`SwarmBuilder::with_context_injector`](crate::core::SwarmBuilder::with_context_injector).
+pub type ContextInjector = dyn Fn() -> SwarmResult<ContextVariables> + Send + Sync;
+
+/// Scores how complex a task is from its first user message; higher is harder.
+pub type TaskComplexityScorer = dyn Fn(&str) -> f32 + Send + Sync;
+
+/// Score thresholds, each with the model used for tasks scoring at or above it.
+pub type ComplexityModelMap = Vec<(f32, String)>;
+
+/// Marker words per language code, used to check a reply's language.
+pub type LanguageWordLists = HashMap<String, Vec<String>>;
+
+/// Context key renames (`old_key` to `new_key`) keyed by the agent switched to.
+pub type ContextRenames = HashMap<String, HashMap<String, String>>;
+
+/// Turns one message into its request-body JSON for [`MessageSerializationAdapter::Custom`].
+pub type MessageSerializer = dyn Fn(&Message) -> Value + Send + Sync;
+
+/// How messages are written into the `messages` array of a request body.
+#[derive(Clone, Default)]
+pub enum MessageSerializationAdapter {
+    /// OpenAI chat completions format; content parts become a `content` array.
+    #[default]
+    OpenAI,
+    /// Ollama chat format; `content` stays text and images go in `images` as base64.
+    Ollama,
+    Custom(Arc<MessageSerializer>),
+}
+
+impl MessageSerializationAdapter {
+    /// Fails for Ollama when an image is not a `data:` URL, since Ollama only
+    /// takes inline base64 images.
+    pub fn serialize_message(&self, message: &Message) -> SwarmResult<Value> {
+        match self {
+            Self::OpenAI => Ok(json!(message)),
+            Self::Ollama => {
+                let mut value = json!(message);
+                if let Some(parts) = message.content_parts() {
+                    let images = parts
+                        .iter()
+                        .filter_map(|part| match part {
+                            ContentPart::ImageUrl { url, .. } => Some(
+                                url.strip_prefix("data:")
+                                    .and_then(|data| data.split_once(";base64,"))
+                                    .map(|(_, data)| data)
+                                    .ok_or_else(|| {
+                                        SwarmError::ValidationError(format!(
+                                            "Ollama needs images as base64 data: URLs, got '{}'",
+                                            url
+                                        ))
+                                    }),
+                            ),
+                            ContentPart::Text(_) => None,
+                        })
+                        .collect::<SwarmResult<Vec<&str>>>()?;
+                    value["content"] = json!(message.content().unwrap_or_default());
+                    value["images"] = json!(images);
+                }
+                Ok(value)
+            }
+            Self::Custom(serializer) => Ok(serializer(message)),
+        }
+    }
+
+    pub fn serialize_messages(&self, messages: &[Message]) -> SwarmResult<Value> {
+        messages
+            .iter()
+            .map(|message| self.serialize_message(message))
+            .collect::<SwarmResult<Vec<_>>>()
+            .map(Value::Array)
+    }
+}
+
+impl fmt::Debug for MessageSerializationAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OpenAI => f.write_str("OpenAI"),
+            Self::Ollama => f.write_str("Ollama"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Custom serializers compare equal only when they share the same closure.
+impl PartialEq for MessageSerializationAdapter {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::OpenAI, Self::OpenAI) | (Self::Ollama, Self::Ollama) => true,
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+/// Builds the system prompt from an agent's resolved instructions for
+/// [`SystemMessageFormat::Custom`].
+pub type SystemMessageFormatter = dyn Fn(&str) -> String + Send + Sync;
+
+/// How agent instructions are written into the system message of each request.
+#[derive(Clone, Default)]
+pub enum SystemMessageFormat {
+    /// The instructions as they are.
+    #[default]
+    Plain,
+    /// `<instructions>…</instructions>`, which Anthropic models give special weight.
+    AnthropicXml,
+    Custom(Arc<SystemMessageFormatter>),
+}
+
+impl SystemMessageFormat {
+    pub fn format(&self, instructions: &str) -> String {
+        match self {
+            Self::Plain => instructions.to_string(),
+            Self::AnthropicXml => format!("<instructions>{}</instructions>", instructions),
+            Self::Custom(formatter) => formatter(instructions),
+        }
+    }
+}
+
+impl fmt::Debug for SystemMessageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain => f.write_str("Plain"),
+            Self::AnthropicXml => f.write_str("AnthropicXml"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Custom formatters compare equal only when they share the same closure.
+impl PartialEq for SystemMessageFormat {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Plain, Self::Plain) | (Self::AnthropicXml, Self::AnthropicXml) => true,
+            (Self::Custom(a), Self::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
 /// Configuration settings for the Swarm instance.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct SwarmConfig {
     api_url: ApiUrl,
     api_version: String,
@@ -813,72 +1358,554 @@ pub struct SwarmConfig {
     api_settings: ApiSettings,
     /// Optional per-run resource caps enforced by the budget enforcer.
     pub runtime_limits: RuntimeLimits,
+    /// Skip prepending the agent instructions when the history already starts
+    /// with an identical system message (e.g. when a previous run's history is reused).
+    deduplicate_system_messages: bool,
+    /// Track the chain of agents reached through handoffs and flag repeats.
+    handoff_cycle_detection: bool,
+    on_circular_handoff: CircularHandoffAction,
+    history_window: HistoryWindowStrategy,
+    /// Fallback for `RunOptions::user_id`, e.g. reading `__session_user_id`.
+    user_id_provider: Option<Arc<UserIdProvider>>,
+    /// Cap an agent's `max_tokens` to what the model's context window leaves after the prompt.
+    adaptive_max_tokens: bool,
+    model_context_windows: ModelContextWindow,
+    /// Word-set Jaccard similarity above which an answer counts as a repeat.
+    semantic_dedup_threshold: Option<f32>,
+    /// Appended to the system prompt of every request; see [`SafetyPlacement`].
+    safety_instructions: Option<String>,
+    safety_placement: SafetyPlacement,
+    /// Largest value, in bytes, a function may store in a context variable.
+    context_variable_max_size: Option<usize>,
+    /// Most context variables a run may hold.
+    context_variable_max_count: Option<usize>,
+    context_overflow: ContextOverflow,
+    /// Function format for agents that do not set their own.
+    default_function_format: FunctionCallFormat,
+    /// Estimated size above which a function's return value is summarized or truncated.
+    tool_result_max_tokens: Option<u32>,
+    /// Registered agent that compresses oversized function results.
+    tool_summarizer_agent: Option<String>,
+    /// Scores the first user message for `complexity_model_map`.
+    task_complexity_scorer: Option<Arc<TaskComplexityScorer>>,
+    /// `(threshold, model)` pairs sorted by ascending threshold.
+    complexity_model_map: ComplexityModelMap,
+    /// Stop sequences sent with every request, ahead of the agent's own.
+    global_stop_sequences: Vec<String>,
+    context_snapshot_interval: ContextSnapshotInterval,
+    /// Snapshots kept per run; the oldest is dropped first.
+    max_context_snapshots: usize,
+    /// Try `util::repair_json` on function call arguments that are not valid JSON.
+    repair_function_arguments: bool,
+    /// Substrings of model names that accept image content parts.
+    vision_capable_model_patterns: Vec<String>,
+    message_serialization: MessageSerializationAdapter,
+    /// Estimated prompt tokens a workflow step may start with; see
+    /// [`ContextOverflowStrategy`]. `Step::max_context_tokens_per_step` overrides it.
+    max_context_tokens_per_step: Option<u32>,
+    context_overflow_strategy: ContextOverflowStrategy,
+    /// Longest content, in bytes, a message of each role may have when sent.
+    max_content_length_per_role: HashMap<String, usize>,
+    /// What happens to a message over its role's length; roles without an entry error.
+    content_overflow_per_role: HashMap<String, ContentOverflowAction>,
+    /// User message added after each function result; may use `{{function_name}}`
+    /// and `{{function_result}}`.
+    post_function_call_prompt: Option<String>,
+    xml_encoding: XmlEncoding,
+    /// Prefix of namespaced step tags such as `<my:steps>`, stripped before parsing.
+    xml_namespace: Option<String>,
+    /// Context variables whose key starts with this are removed after every step.
+    ephemeral_key_prefix: String,
+    branch_merge_strategy: BranchMergeStrategy,
+    language_word_lists: HashMap<String, Vec<String>>,
+    context_size_limit: Option<usize>,
+    context_value_max_bytes: Option<usize>,
+    context_summarizer_agent: Option<String>,
+    function_arg_retry_prompt: Option<String>,
+    function_arg_max_retries: u32,
+    model_aliases: HashMap<String, String>,
+    max_dynamic_step_injections: usize,
+    update_system_message_on_switch: bool,
+    max_registered_agents: Option<usize>,
+    context_rename_on_switch: HashMap<String, HashMap<String, String>>,
+    system_message_format: SystemMessageFormat,
+    inject_agent_name_as_message_name: bool,
+    context_injectors: Vec<Arc<ContextInjector>>,
+    prompt_compression: Option<PromptCompressionConfig>,
 }
 
-/// Controls the execution of loops in agent interactions.
-#[derive(Clone, Debug)]
-pub struct LoopControl {
-    default_max_iterations: u32,
-    iteration_delay: Duration,
-    break_conditions: Vec<String>,
+/// Context window sizes (prompt + completion tokens) keyed by model prefix.
+///
+/// Lookups use the longest matching prefix, so `gpt-4o` wins over `gpt-4`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModelContextWindow {
+    windows: Vec<(String, u32)>,
 }
 
-impl LoopControl {
-    pub fn new(
-        default_max_iterations: u32,
-        iteration_delay: Duration,
-        break_conditions: Vec<String>,
-    ) -> SwarmResult<Self> {
-        if default_max_iterations == 0 {
-            return Err(SwarmError::ValidationError(
-                "default_max_iterations must be greater than 0".to_string(),
-            ));
+impl ModelContextWindow {
+    pub fn empty() -> Self {
+        Self {
+            windows: Vec::new(),
         }
-        Ok(Self {
-            default_max_iterations,
-            iteration_delay,
-            break_conditions,
-        })
-    }
-
-    pub fn default_max_iterations(&self) -> u32 {
-        self.default_max_iterations
-    }
-
-    pub fn iteration_delay(&self) -> Duration {
-        self.iteration_delay
-    }
-
-    pub fn break_conditions(&self) -> &[String] {
-        &self.break_conditions
     }
 
-    pub(crate) fn set_default_max_iterations(&mut self, value: u32) -> SwarmResult<()> {
-        if value == 0 {
+    /// Registers (or replaces) the window for models starting with `prefix`.
+    pub fn with_window(mut self, prefix: impl Into<String>, tokens: u32) -> SwarmResult<Self> {
+        let prefix = prefix.into();
+        if prefix.trim().is_empty() {
             return Err(SwarmError::ValidationError(
-                "default_max_iterations must be greater than 0".to_string(),
+                "Context window model prefix cannot be empty".to_string(),
             ));
         }
-        self.default_max_iterations = value;
-        Ok(())
+        if tokens == 0 {
+            return Err(SwarmError::ValidationError(format!(
+                "Context window for '{}' must be greater than 0",
+                prefix
+            )));
+        }
+        self.windows.retain(|(existing, _)| *existing != prefix);
+        self.windows.push((prefix, tokens));
+        Ok(self)
+    }
+
+    pub fn context_window(&self, model: &str) -> Option<u32> {
+        self.windows
+            .iter()
+            .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, tokens)| *tokens)
     }
 }
 
-impl Default for LoopControl {
+impl Default for ModelContextWindow {
     fn default() -> Self {
-        Self::new(10, Duration::from_millis(100), vec!["end_loop".to_string()])
-            .expect("SAFETY: 10 > 0")
+        let windows = [
+            ("gpt-3.5-turbo", 16_385),
+            ("gpt-4", 8_192),
+            ("gpt-4-32k", 32_768),
+            ("gpt-4-turbo", 128_000),
+            ("gpt-4o", 128_000),
+            ("gpt-4.1", 1_047_576),
+            ("claude-", 200_000),
+            ("deepseek-", 64_000),
+        ];
+        Self {
+            windows: windows
+                .into_iter()
+                .map(|(prefix, tokens)| (prefix.to_string(), tokens))
+                .collect(),
+        }
     }
 }
 
-/// API related settings for request handling.
-#[derive(Clone, Debug)]
-pub struct ApiSettings {
-    retry_strategy: RetryStrategy,
-    timeout_settings: TimeoutSettings,
+impl fmt::Debug for SwarmConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SwarmConfig")
+            .field("api_url", &self.api_url)
+            .field("api_version", &self.api_version)
+            .field("request_timeout", &self.request_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("max_retries", &self.max_retries)
+            .field("max_loop_iterations", &self.max_loop_iterations)
+            .field("valid_model_prefixes", &self.valid_model_prefixes)
+            .field("valid_api_url_prefixes", &self.valid_api_url_prefixes)
+            .field("loop_control", &self.loop_control)
+            .field("api_settings", &self.api_settings)
+            .field("runtime_limits", &self.runtime_limits)
+            .field(
+                "deduplicate_system_messages",
+                &self.deduplicate_system_messages,
+            )
+            .field("handoff_cycle_detection", &self.handoff_cycle_detection)
+            .field("on_circular_handoff", &self.on_circular_handoff)
+            .field("history_window", &self.history_window)
+            // The provider is a closure; only report whether one is set.
+            .field("user_id_provider", &self.user_id_provider.is_some())
+            .field("adaptive_max_tokens", &self.adaptive_max_tokens)
+            .field("model_context_windows", &self.model_context_windows)
+            .field("semantic_dedup_threshold", &self.semantic_dedup_threshold)
+            .field("safety_instructions", &self.safety_instructions)
+            .field("safety_placement", &self.safety_placement)
+            .field("context_variable_max_size", &self.context_variable_max_size)
+            .field(
+                "context_variable_max_count",
+                &self.context_variable_max_count,
+            )
+            .field("context_overflow", &self.context_overflow)
+            .field("default_function_format", &self.default_function_format)
+            .field("tool_result_max_tokens", &self.tool_result_max_tokens)
+            .field("tool_summarizer_agent", &self.tool_summarizer_agent)
+            // The scorer is a closure; only report whether one is set.
+            .field(
+                "task_complexity_scorer",
+                &self.task_complexity_scorer.is_some(),
+            )
+            .field("complexity_model_map", &self.complexity_model_map)
+            .field("global_stop_sequences", &self.global_stop_sequences)
+            .field("context_snapshot_interval", &self.context_snapshot_interval)
+            .field("max_context_snapshots", &self.max_context_snapshots)
+            .field("repair_function_arguments", &self.repair_function_arguments)
+            .field(
+                "vision_capable_model_patterns",
+                &self.vision_capable_model_patterns,
+            )
+            .field("message_serialization", &self.message_serialization)
+            .field(
+                "max_context_tokens_per_step",
+                &self.max_context_tokens_per_step,
+            )
+            .field("context_overflow_strategy", &self.context_overflow_strategy)
+            .field(
+                "max_content_length_per_role",
+                &self.max_content_length_per_role,
+            )
+            .field("content_overflow_per_role", &self.content_overflow_per_role)
+            .field("post_function_call_prompt", &self.post_function_call_prompt)
+            .field("xml_encoding", &self.xml_encoding)
+            .field("xml_namespace", &self.xml_namespace)
+            .field("ephemeral_key_prefix", &self.ephemeral_key_prefix)
+            .field("branch_merge_strategy", &self.branch_merge_strategy)
+            .field("language_word_lists", &self.language_word_lists)
+            .field("context_size_limit", &self.context_size_limit)
+            .field("context_value_max_bytes", &self.context_value_max_bytes)
+            .field("context_summarizer_agent", &self.context_summarizer_agent)
+            .field("function_arg_retry_prompt", &self.function_arg_retry_prompt)
+            .field("function_arg_max_retries", &self.function_arg_max_retries)
+            .field("model_aliases", &self.model_aliases)
+            .field(
+                "max_dynamic_step_injections",
+                &self.max_dynamic_step_injections,
+            )
+            .field(
+                "update_system_message_on_switch",
+                &self.update_system_message_on_switch,
+            )
+            .field("max_registered_agents", &self.max_registered_agents)
+            .field("context_rename_on_switch", &self.context_rename_on_switch)
+            .field("system_message_format", &self.system_message_format)
+            .field(
+                "inject_agent_name_as_message_name",
+                &self.inject_agent_name_as_message_name,
+            )
+            .field("context_injectors", &self.context_injectors.len())
+            .field("prompt_compression", &self.prompt_compression)
+            .finish()
+    }
+}
+
+/// How much of the run history is sent with each completion request.
+///
+/// The full history is always kept in `Response::messages`; windowing only
+/// affects what the model sees.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum HistoryWindowStrategy {
+    /// Send the entire history.
+    #[default]
+    Full,
+    /// Send only the last `window` messages; older messages are dropped.
+    Sliding { window: usize },
+    /// Send the last `full_window` messages verbatim, preceded by a rolling summary
+    /// of everything older. The summary also covers the first `overlap_summary_turns`
+    /// messages of the window, so it is only refreshed once the window has moved past them.
+    SlidingWithOverlap {
+        full_window: usize,
+        overlap_summary_turns: usize,
+    },
+}
+
+impl HistoryWindowStrategy {
+    pub fn validate(&self) -> SwarmResult<()> {
+        match *self {
+            Self::Full => Ok(()),
+            Self::Sliding { window: 0 } => Err(SwarmError::ValidationError(
+                "Sliding history window must be greater than 0".to_string(),
+            )),
+            Self::Sliding { .. } => Ok(()),
+            Self::SlidingWithOverlap {
+                full_window,
+                overlap_summary_turns,
+            } => {
+                if full_window == 0 {
+                    return Err(SwarmError::ValidationError(
+                        "full_window must be greater than 0".to_string(),
+                    ));
+                }
+                if overlap_summary_turns >= full_window {
+                    return Err(SwarmError::ValidationError(format!(
+                        "overlap_summary_turns ({}) must be smaller than full_window ({})",
+                        overlap_summary_turns, full_window
+                    )));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Index of the first message sent verbatim.
+    ///
+    /// The cut never lands on a function or tool result, which would be orphaned
+    /// from the assistant message that requested it.
+    pub fn window_start(&self, history: &[Message]) -> usize {
+        let window = match *self {
+            Self::Full => return 0,
+            Self::Sliding { window } => window,
+            Self::SlidingWithOverlap { full_window, .. } => full_window,
+        };
+        let mut start = history.len().saturating_sub(window);
+        while start > 0
+            && matches!(
+                history[start].role(),
+                MessageRole::Function | MessageRole::Tool
+            )
+        {
+            start -= 1;
+        }
+        start
+    }
+}
+
+/// What `run` does when an agent handoff returns to an agent already in the chain.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircularHandoffAction {
+    /// Log a warning and continue with the handoff.
+    #[default]
+    Warn,
+    /// Abort the run with `SwarmError::AgentError`.
+    Error,
+}
+
+/// How `run` reacts when a step fails.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorRecoveryStrategy {
+    /// Return the first step error from `run`.
+    #[default]
+    FailFast,
+    /// Drop the failed step and continue with the next one, at most `max_skips` times per run.
+    SkipStep { max_skips: usize },
+    /// Run the failed step again, at most `max_retries` times per step. Only
+    /// retriable errors ([`SwarmError::is_retriable`]) are retried.
+    RetryStep { max_retries: usize },
+    /// Switch to the named registered agent and run the failed step once more.
+    FallbackAgent(String),
+}
+
+/// What an [`ErrorRecoveryStrategy`] did with a failed step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepRecoveryAction {
+    Skip,
+    Retry,
+    Fallback,
+}
+
+impl fmt::Display for StepRecoveryAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Skip => write!(f, "skip"),
+            Self::Retry => write!(f, "retry"),
+            Self::Fallback => write!(f, "fallback"),
+        }
+    }
+}
+
+/// When a run copies its context variables into [`Response::context_snapshots`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextSnapshotInterval {
+    #[default]
+    Disabled,
+    /// After every completed workflow step, labelled `step {number}`.
+    AfterEachStep,
+    /// After every conversation turn, labelled `turn {number}`.
+    AfterEachTurn,
+}
+
+/// What happens when a function result breaks a context variable limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflow {
+    /// Cut oversized values to fit, ending them with an ellipsis, and drop
+    /// variables beyond the count limit. Both are logged as warnings.
+    #[default]
+    Truncate,
+    /// Fail the function call with `SwarmError::ContextError`.
+    Error,
+}
+
+/// What a request does with a message longer than its role's
+/// `max_content_length_per_role`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentOverflowAction {
+    /// Cut the content to at most this many bytes, ending it with an ellipsis.
+    Truncate(usize),
+    /// Fail the turn with `SwarmError::ValidationError`.
+    Error,
+}
+
+/// Encoding of step XML read from files, such as `<include>`d steps.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum XmlEncoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1.
+    Latin1,
+}
+
+/// What a workflow step does when the history would start it above
+/// `max_context_tokens_per_step`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextOverflowStrategy {
+    /// Drop the oldest messages until the history fits.
+    #[default]
+    Truncate,
+    /// Replace the oldest messages with a model-written summary.
+    Summarize,
+    /// Fail the step with `SwarmError::ContextError`.
+    Error,
+}
+
+/// Shrinks the messages of a completion request whose estimated prompt size is
+/// over `max_tokens`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PromptCompressionConfig {
+    pub max_tokens: u32,
+    pub strategy: CompressionStrategy,
+}
+
+impl PromptCompressionConfig {
+    pub fn validate(&self) -> SwarmResult<()> {
+        if self.max_tokens == 0 {
+            return Err(SwarmError::ValidationError(
+                "Prompt compression max_tokens must be greater than 0".to_string(),
+            ));
+        }
+        match &self.strategy {
+            CompressionStrategy::LLMLingua(endpoint) if endpoint.trim().is_empty() => Err(
+                SwarmError::ValidationError("LLMLingua endpoint cannot be empty".to_string()),
+            ),
+            CompressionStrategy::LLMLingua(_) if !cfg!(feature = "llmlingua") => {
+                Err(SwarmError::ConfigError(
+                    "LLMLingua prompt compression requires the `llmlingua` feature".to_string(),
+                ))
+            }
+            CompressionStrategy::Selective(roles) if roles.is_empty() => {
+                Err(SwarmError::ValidationError(
+                    "Selective prompt compression needs at least one role".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How [`PromptCompressionConfig`] shrinks a request.
+///
+/// Leading system messages, the first message after them and the newest message
+/// are always sent. Tool and function results are dropped together with the
+/// call that produced them.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CompressionStrategy {
+    /// Drop the oldest messages after the first one until the prompt fits.
+    TruncateMiddle,
+    /// POST the text of the droppable user and assistant messages to an
+    /// LLMLingua-style endpoint as `{"prompts": [...], "target_token": n}` and
+    /// replace each with the matching entry of the reply's `compressed_prompts`.
+    /// Requires the `llmlingua` feature.
+    #[serde(rename = "llmlingua")]
+    LLMLingua(String),
+    /// Like `TruncateMiddle`, but only drops messages with these roles.
+    Selective(Vec<MessageRole>),
+}
+
+/// Where `SwarmConfig::safety_instructions` go in each request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SafetyPlacement {
+    /// Append to the content of every system message.
+    #[default]
+    AppendToSystem,
+    /// Send as its own system message after the leading system messages.
+    SeparateSystemMessage,
+}
+
+/// Controls the execution of loops in agent interactions.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoopControl {
+    default_max_iterations: u32,
+    iteration_delay: Duration,
+    break_conditions: Vec<String>,
+}
+
+impl LoopControl {
+    pub fn new(
+        default_max_iterations: u32,
+        iteration_delay: Duration,
+        break_conditions: Vec<String>,
+    ) -> SwarmResult<Self> {
+        if default_max_iterations == 0 {
+            return Err(SwarmError::ValidationError(
+                "default_max_iterations must be greater than 0".to_string(),
+            ));
+        }
+        Ok(Self {
+            default_max_iterations,
+            iteration_delay,
+            break_conditions,
+        })
+    }
+
+    pub fn default_max_iterations(&self) -> u32 {
+        self.default_max_iterations
+    }
+
+    pub fn iteration_delay(&self) -> Duration {
+        self.iteration_delay
+    }
+
+    pub fn break_conditions(&self) -> &[String] {
+        &self.break_conditions
+    }
+
+    pub(crate) fn set_default_max_iterations(&mut self, value: u32) -> SwarmResult<()> {
+        if value == 0 {
+            return Err(SwarmError::ValidationError(
+                "default_max_iterations must be greater than 0".to_string(),
+            ));
+        }
+        self.default_max_iterations = value;
+        Ok(())
+    }
+}
+
+impl Default for LoopControl {
+    fn default() -> Self {
+        Self::new(10, Duration::from_millis(100), vec!["end_loop".to_string()])
+            .expect("SAFETY: 10 > 0")
+    }
+}
+
+/// API related settings for request handling.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ApiSettings {
+    retry_strategy: RetryStrategy,
+    timeout_settings: TimeoutSettings,
 }
 
 impl ApiSettings {
+    pub fn new(retry_strategy: RetryStrategy, timeout_settings: TimeoutSettings) -> Self {
+        Self {
+            retry_strategy,
+            timeout_settings,
+        }
+    }
+
     pub fn retry_strategy(&self) -> &RetryStrategy {
         &self.retry_strategy
     }
@@ -953,6 +1980,60 @@ impl Default for SwarmConfig {
             loop_control: LoopControl::default(),
             api_settings: ApiSettings::default(),
             runtime_limits: RuntimeLimits::default(),
+            deduplicate_system_messages: true,
+            handoff_cycle_detection: false,
+            on_circular_handoff: CircularHandoffAction::Warn,
+            history_window: HistoryWindowStrategy::Full,
+            user_id_provider: None,
+            adaptive_max_tokens: false,
+            model_context_windows: ModelContextWindow::default(),
+            semantic_dedup_threshold: None,
+            safety_instructions: None,
+            safety_placement: SafetyPlacement::AppendToSystem,
+            context_variable_max_size: None,
+            context_variable_max_count: None,
+            context_overflow: ContextOverflow::Truncate,
+            default_function_format: FunctionCallFormat::Legacy,
+            tool_result_max_tokens: None,
+            tool_summarizer_agent: None,
+            task_complexity_scorer: None,
+            complexity_model_map: Vec::new(),
+            global_stop_sequences: Vec::new(),
+            context_snapshot_interval: ContextSnapshotInterval::Disabled,
+            max_context_snapshots: DEFAULT_MAX_CONTEXT_SNAPSHOTS,
+            repair_function_arguments: false,
+            vision_capable_model_patterns: DEFAULT_VISION_CAPABLE_MODEL_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            message_serialization: MessageSerializationAdapter::default(),
+            max_context_tokens_per_step: None,
+            context_overflow_strategy: ContextOverflowStrategy::Truncate,
+            max_content_length_per_role: HashMap::new(),
+            content_overflow_per_role: HashMap::new(),
+            post_function_call_prompt: None,
+            xml_encoding: XmlEncoding::Utf8,
+            xml_namespace: None,
+            ephemeral_key_prefix: DEFAULT_EPHEMERAL_KEY_PREFIX.to_string(),
+            branch_merge_strategy: BranchMergeStrategy::KeepCurrent,
+            language_word_lists: HashMap::new(),
+            context_size_limit: None,
+            context_value_max_bytes: None,
+            context_summarizer_agent: None,
+            function_arg_retry_prompt: None,
+            function_arg_max_retries: DEFAULT_FUNCTION_ARG_MAX_RETRIES,
+            model_aliases: DEFAULT_MODEL_ALIASES
+                .iter()
+                .map(|(from, to)| (from.to_string(), to.to_string()))
+                .collect(),
+            max_dynamic_step_injections: DEFAULT_MAX_DYNAMIC_STEP_INJECTIONS,
+            update_system_message_on_switch: false,
+            max_registered_agents: None,
+            context_rename_on_switch: HashMap::new(),
+            system_message_format: SystemMessageFormat::default(),
+            inject_agent_name_as_message_name: true,
+            context_injectors: Vec::new(),
+            prompt_compression: None,
         }
     }
 }
@@ -1002,95 +2083,1623 @@ impl SwarmConfig {
         &self.runtime_limits
     }
 
-    pub(crate) fn set_runtime_limits(&mut self, limits: RuntimeLimits) {
-        self.runtime_limits = limits;
+    pub fn deduplicate_system_messages(&self) -> bool {
+        self.deduplicate_system_messages
     }
 
-    pub(crate) fn set_api_url(&mut self, api_url: impl Into<String>) -> SwarmResult<()> {
-        self.api_url = ApiUrl::new(api_url, &self.valid_api_url_prefixes)?;
-        Ok(())
+    pub fn handoff_cycle_detection(&self) -> bool {
+        self.handoff_cycle_detection
     }
 
-    pub(crate) fn set_api_version(&mut self, api_version: impl Into<String>) -> SwarmResult<()> {
-        let api_version = api_version.into();
-        if api_version.trim().is_empty() {
-            return Err(SwarmError::ValidationError(
-                "API version cannot be empty".to_string(),
-            ));
-        }
-        self.api_version = api_version;
-        Ok(())
+    pub fn on_circular_handoff(&self) -> CircularHandoffAction {
+        self.on_circular_handoff
     }
 
-    pub(crate) fn set_request_timeout(&mut self, request_timeout: u64) -> SwarmResult<()> {
-        let request_timeout = RequestTimeoutSeconds::new(request_timeout)?;
-        self.request_timeout = request_timeout;
-        self.api_settings
-            .timeout_settings_mut()
-            .set_request_timeout(Duration::from_secs(request_timeout.get()))?;
-        Ok(())
+    pub fn history_window(&self) -> HistoryWindowStrategy {
+        self.history_window
     }
 
-    pub(crate) fn set_connect_timeout(&mut self, connect_timeout: u64) -> SwarmResult<()> {
-        let connect_timeout = ConnectTimeoutSeconds::new(connect_timeout)?;
-        self.connect_timeout = connect_timeout;
-        self.api_settings
-            .timeout_settings_mut()
-            .set_connect_timeout(Duration::from_secs(connect_timeout.get()))?;
-        Ok(())
+    pub fn user_id_provider(&self) -> Option<&Arc<UserIdProvider>> {
+        self.user_id_provider.as_ref()
     }
 
-    pub(crate) fn set_max_retries(&mut self, max_retries: u32) -> SwarmResult<()> {
-        self.max_retries = RetryLimit::new(max_retries)?;
-        self.api_settings
-            .retry_strategy_mut()
-            .set_max_retries(max_retries)?;
-        Ok(())
+    pub fn adaptive_max_tokens(&self) -> bool {
+        self.adaptive_max_tokens
     }
 
-    pub(crate) fn set_max_loop_iterations(&mut self, max_loop_iterations: u32) -> SwarmResult<()> {
-        let max_loop_iterations = LoopIterationLimit::new(max_loop_iterations)?;
-        self.max_loop_iterations = max_loop_iterations;
-        self.loop_control
-            .set_default_max_iterations(max_loop_iterations.get())?;
-        Ok(())
+    pub fn model_context_windows(&self) -> &ModelContextWindow {
+        &self.model_context_windows
     }
 
-    pub(crate) fn set_valid_model_prefixes(
-        &mut self,
-        valid_model_prefixes: Vec<String>,
-    ) -> SwarmResult<()> {
-        if valid_model_prefixes.is_empty() {
-            return Err(SwarmError::ValidationError(
-                "valid_model_prefixes cannot be empty".to_string(),
-            ));
+    pub fn semantic_dedup_threshold(&self) -> Option<f32> {
+        self.semantic_dedup_threshold
+    }
+
+    pub fn safety_instructions(&self) -> Option<&str> {
+        self.safety_instructions.as_deref()
+    }
+
+    pub fn safety_placement(&self) -> SafetyPlacement {
+        self.safety_placement
+    }
+
+    pub fn context_variable_max_size(&self) -> Option<usize> {
+        self.context_variable_max_size
+    }
+
+    pub fn context_variable_max_count(&self) -> Option<usize> {
+        self.context_variable_max_count
+    }
+
+    pub fn context_overflow(&self) -> ContextOverflow {
+        self.context_overflow
+    }
+
+    pub fn default_function_format(&self) -> FunctionCallFormat {
+        self.default_function_format
+    }
+
+    pub fn tool_result_max_tokens(&self) -> Option<u32> {
+        self.tool_result_max_tokens
+    }
+
+    pub fn tool_summarizer_agent(&self) -> Option<&str> {
+        self.tool_summarizer_agent.as_deref()
+    }
+
+    pub fn task_complexity_scorer(&self) -> Option<&Arc<TaskComplexityScorer>> {
+        self.task_complexity_scorer.as_ref()
+    }
+
+    pub fn complexity_model_map(&self) -> &[(f32, String)] {
+        &self.complexity_model_map
+    }
+
+    /// Model for a task of complexity `score`: the one with the highest threshold
+    /// not above `score`, or `None` when the score is below every threshold.
+    pub fn model_for_complexity(&self, score: f32) -> Option<&str> {
+        self.complexity_model_map
+            .iter()
+            .rev()
+            .find(|(threshold, _)| *threshold <= score)
+            .map(|(_, model)| model.as_str())
+    }
+
+    pub fn global_stop_sequences(&self) -> &[String] {
+        &self.global_stop_sequences
+    }
+
+    pub fn context_snapshot_interval(&self) -> ContextSnapshotInterval {
+        self.context_snapshot_interval
+    }
+
+    pub fn max_context_snapshots(&self) -> usize {
+        self.max_context_snapshots
+    }
+
+    pub fn repair_function_arguments(&self) -> bool {
+        self.repair_function_arguments
+    }
+
+    pub fn vision_capable_model_patterns(&self) -> &[String] {
+        &self.vision_capable_model_patterns
+    }
+
+    pub fn message_serialization(&self) -> &MessageSerializationAdapter {
+        &self.message_serialization
+    }
+
+    /// Whether `model` may receive [`ContentPart::ImageUrl`] parts.
+    ///
+    /// Patterns match whole separator-delimited parts of the model name, so
+    /// `gpt-4o` matches `gpt-4o-mini` but `gpt-4` does not match `gpt-4o`.
+    pub fn is_vision_capable_model(&self, model: &str) -> bool {
+        is_vision_capable_model(model, &self.vision_capable_model_patterns)
+    }
+
+    pub fn max_context_tokens_per_step(&self) -> Option<u32> {
+        self.max_context_tokens_per_step
+    }
+
+    pub fn context_overflow_strategy(&self) -> ContextOverflowStrategy {
+        self.context_overflow_strategy
+    }
+
+    pub fn max_content_length_per_role(&self) -> &HashMap<String, usize> {
+        &self.max_content_length_per_role
+    }
+
+    pub fn content_overflow_per_role(&self) -> &HashMap<String, ContentOverflowAction> {
+        &self.content_overflow_per_role
+    }
+
+    pub fn post_function_call_prompt(&self) -> Option<&str> {
+        self.post_function_call_prompt.as_deref()
+    }
+
+    pub fn xml_encoding(&self) -> XmlEncoding {
+        self.xml_encoding
+    }
+
+    pub fn xml_namespace(&self) -> Option<&str> {
+        self.xml_namespace.as_deref()
+    }
+
+    pub fn ephemeral_key_prefix(&self) -> &str {
+        &self.ephemeral_key_prefix
+    }
+
+    pub fn branch_merge_strategy(&self) -> BranchMergeStrategy {
+        self.branch_merge_strategy
+    }
+
+    /// Common words per lowercase language name, used to check replies of agents
+    /// with `enforce_language`.
+    pub fn language_word_lists(&self) -> &HashMap<String, Vec<String>> {
+        &self.language_word_lists
+    }
+
+    /// Total bytes of context variable keys and values above which large values
+    /// are summarized by `context_summarizer_agent`.
+    pub fn context_size_limit(&self) -> Option<usize> {
+        self.context_size_limit
+    }
+
+    /// Values at or below this size are never summarized; unset, any value may be.
+    pub fn context_value_max_bytes(&self) -> Option<usize> {
+        self.context_value_max_bytes
+    }
+
+    pub fn context_summarizer_agent(&self) -> Option<&str> {
+        self.context_summarizer_agent.as_deref()
+    }
+
+    pub fn function_arg_retry_prompt(&self) -> Option<&str> {
+        self.function_arg_retry_prompt.as_deref()
+    }
+
+    pub fn function_arg_max_retries(&self) -> u32 {
+        self.function_arg_max_retries
+    }
+
+    pub fn model_aliases(&self) -> &HashMap<String, String> {
+        &self.model_aliases
+    }
+
+    /// The model a request for `model` is sent to once `model_aliases` is applied.
+    pub fn resolve_model_alias<'a>(&'a self, model: &'a str) -> &'a str {
+        self.model_aliases
+            .get(model)
+            .map(String::as_str)
+            .unwrap_or(model)
+    }
+
+    pub fn max_dynamic_step_injections(&self) -> usize {
+        self.max_dynamic_step_injections
+    }
+
+    pub fn update_system_message_on_switch(&self) -> bool {
+        self.update_system_message_on_switch
+    }
+
+    pub fn max_registered_agents(&self) -> Option<usize> {
+        self.max_registered_agents
+    }
+
+    /// Context key renames (`old_key` to `new_key`) applied when a step switches
+    /// to the agent named by the outer key.
+    pub fn context_rename_on_switch(&self) -> &HashMap<String, HashMap<String, String>> {
+        &self.context_rename_on_switch
+    }
+
+    pub fn system_message_format(&self) -> &SystemMessageFormat {
+        &self.system_message_format
+    }
+
+    pub fn inject_agent_name_as_message_name(&self) -> bool {
+        self.inject_agent_name_as_message_name
+    }
+
+    pub fn context_injectors(&self) -> &[Arc<ContextInjector>] {
+        &self.context_injectors
+    }
+
+    pub fn prompt_compression(&self) -> Option<&PromptCompressionConfig> {
+        self.prompt_compression.as_ref()
+    }
+
+    pub(crate) fn set_runtime_limits(&mut self, limits: RuntimeLimits) {
+        self.runtime_limits = limits;
+    }
+
+    pub(crate) fn set_deduplicate_system_messages(&mut self, enabled: bool) {
+        self.deduplicate_system_messages = enabled;
+    }
+
+    pub(crate) fn set_handoff_cycle_detection(&mut self, enabled: bool) {
+        self.handoff_cycle_detection = enabled;
+    }
+
+    pub(crate) fn set_on_circular_handoff(&mut self, action: CircularHandoffAction) {
+        self.on_circular_handoff = action;
+    }
+
+    pub(crate) fn set_adaptive_max_tokens(&mut self, enabled: bool) {
+        self.adaptive_max_tokens = enabled;
+    }
+
+    pub(crate) fn set_model_context_windows(&mut self, windows: ModelContextWindow) {
+        self.model_context_windows = windows;
+    }
+
+    pub(crate) fn set_semantic_dedup_threshold(
+        &mut self,
+        threshold: Option<f32>,
+    ) -> SwarmResult<()> {
+        if let Some(threshold) = threshold {
+            if !threshold.is_finite() || !(0.0..=1.0).contains(&threshold) {
+                return Err(SwarmError::ValidationError(
+                    "semantic_dedup_threshold must be between 0.0 and 1.0".to_string(),
+                ));
+            }
+        }
+        self.semantic_dedup_threshold = threshold;
+        Ok(())
+    }
+
+    pub(crate) fn set_safety_instructions(
+        &mut self,
+        instructions: Option<String>,
+    ) -> SwarmResult<()> {
+        if instructions
+            .as_ref()
+            .is_some_and(|text| text.trim().is_empty())
+        {
+            return Err(SwarmError::ValidationError(
+                "safety_instructions cannot be empty".to_string(),
+            ));
+        }
+        self.safety_instructions = instructions;
+        Ok(())
+    }
+
+    pub(crate) fn set_safety_placement(&mut self, placement: SafetyPlacement) {
+        self.safety_placement = placement;
+    }
+
+    pub(crate) fn set_context_variable_max_size(
+        &mut self,
+        max_size: Option<usize>,
+    ) -> SwarmResult<()> {
+        if max_size == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "context_variable_max_size must be greater than 0".to_string(),
+            ));
+        }
+        self.context_variable_max_size = max_size;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_variable_max_count(
+        &mut self,
+        max_count: Option<usize>,
+    ) -> SwarmResult<()> {
+        if max_count == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "context_variable_max_count must be greater than 0".to_string(),
+            ));
+        }
+        self.context_variable_max_count = max_count;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_overflow(&mut self, overflow: ContextOverflow) {
+        self.context_overflow = overflow;
+    }
+
+    pub(crate) fn set_default_function_format(&mut self, format: FunctionCallFormat) {
+        self.default_function_format = format;
+    }
+
+    pub(crate) fn set_tool_result_max_tokens(
+        &mut self,
+        max_tokens: Option<u32>,
+    ) -> SwarmResult<()> {
+        if max_tokens == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "tool_result_max_tokens must be greater than 0".to_string(),
+            ));
+        }
+        self.tool_result_max_tokens = max_tokens;
+        Ok(())
+    }
+
+    pub(crate) fn set_tool_summarizer_agent(&mut self, agent: Option<String>) -> SwarmResult<()> {
+        if agent.as_ref().is_some_and(|name| name.trim().is_empty()) {
+            return Err(SwarmError::ValidationError(
+                "tool_summarizer_agent cannot be empty".to_string(),
+            ));
+        }
+        self.tool_summarizer_agent = agent;
+        Ok(())
+    }
+
+    pub(crate) fn set_task_complexity_scorer(&mut self, scorer: Arc<TaskComplexityScorer>) {
+        self.task_complexity_scorer = Some(scorer);
+    }
+
+    pub(crate) fn set_complexity_model_map(
+        &mut self,
+        mut map: Vec<(f32, String)>,
+    ) -> SwarmResult<()> {
+        for (threshold, model) in &map {
+            if !threshold.is_finite() {
+                return Err(SwarmError::ValidationError(format!(
+                    "complexity threshold for '{}' must be a finite number",
+                    model
+                )));
+            }
+            if model.trim().is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "complexity_model_map models cannot be empty".to_string(),
+                ));
+            }
+        }
+        map.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.complexity_model_map = map;
+        Ok(())
+    }
+
+    pub(crate) fn set_global_stop_sequences(&mut self, sequences: Vec<String>) -> SwarmResult<()> {
+        validate_stop_sequences(&sequences)?;
+        self.global_stop_sequences = sequences;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_snapshot_interval(&mut self, interval: ContextSnapshotInterval) {
+        self.context_snapshot_interval = interval;
+    }
+
+    pub(crate) fn set_max_context_snapshots(&mut self, max_snapshots: usize) -> SwarmResult<()> {
+        if max_snapshots == 0 {
+            return Err(SwarmError::ValidationError(
+                "max_context_snapshots must be greater than 0".to_string(),
+            ));
+        }
+        self.max_context_snapshots = max_snapshots;
+        Ok(())
+    }
+
+    pub(crate) fn set_repair_function_arguments(&mut self, enabled: bool) {
+        self.repair_function_arguments = enabled;
+    }
+
+    pub(crate) fn set_message_serialization(&mut self, adapter: MessageSerializationAdapter) {
+        self.message_serialization = adapter;
+    }
+
+    pub(crate) fn set_vision_capable_model_patterns(
+        &mut self,
+        patterns: Vec<String>,
+    ) -> SwarmResult<()> {
+        if patterns.iter().any(|pattern| pattern.trim().is_empty()) {
+            return Err(SwarmError::ValidationError(
+                "vision_capable_model_patterns cannot contain empty patterns".to_string(),
+            ));
+        }
+        self.vision_capable_model_patterns = patterns;
+        Ok(())
+    }
+
+    pub(crate) fn set_max_context_tokens_per_step(
+        &mut self,
+        max_tokens: Option<u32>,
+    ) -> SwarmResult<()> {
+        if max_tokens == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "max_context_tokens_per_step must be greater than 0".to_string(),
+            ));
+        }
+        self.max_context_tokens_per_step = max_tokens;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_overflow_strategy(&mut self, strategy: ContextOverflowStrategy) {
+        self.context_overflow_strategy = strategy;
+    }
+
+    pub(crate) fn set_max_content_length_per_role(
+        &mut self,
+        limits: HashMap<String, usize>,
+    ) -> SwarmResult<()> {
+        for (role, max_length) in &limits {
+            validate_role_name(role)?;
+            if *max_length == 0 {
+                return Err(SwarmError::ValidationError(format!(
+                    "max content length for role '{}' must be greater than 0",
+                    role
+                )));
+            }
+        }
+        self.max_content_length_per_role = limits;
+        Ok(())
+    }
+
+    pub(crate) fn set_content_overflow_per_role(
+        &mut self,
+        actions: HashMap<String, ContentOverflowAction>,
+    ) -> SwarmResult<()> {
+        for (role, action) in &actions {
+            validate_role_name(role)?;
+            if *action == ContentOverflowAction::Truncate(0) {
+                return Err(SwarmError::ValidationError(format!(
+                    "content overflow truncation length for role '{}' must be greater than 0",
+                    role
+                )));
+            }
+        }
+        self.content_overflow_per_role = actions;
+        Ok(())
+    }
+
+    pub(crate) fn set_post_function_call_prompt(
+        &mut self,
+        prompt: Option<String>,
+    ) -> SwarmResult<()> {
+        if prompt.as_ref().is_some_and(|text| text.trim().is_empty()) {
+            return Err(SwarmError::ValidationError(
+                "post_function_call_prompt cannot be empty".to_string(),
+            ));
+        }
+        self.post_function_call_prompt = prompt;
+        Ok(())
+    }
+
+    pub(crate) fn set_xml_encoding(&mut self, encoding: XmlEncoding) {
+        self.xml_encoding = encoding;
+    }
+
+    pub(crate) fn set_xml_namespace(&mut self, namespace: Option<String>) -> SwarmResult<()> {
+        if let Some(namespace) = &namespace {
+            let valid = !namespace.is_empty()
+                && namespace
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'));
+            if !valid {
+                return Err(SwarmError::ValidationError(format!(
+                    "Invalid xml_namespace '{}'",
+                    namespace
+                )));
+            }
+        }
+        self.xml_namespace = namespace;
+        Ok(())
+    }
+
+    pub(crate) fn set_ephemeral_key_prefix(&mut self, prefix: String) -> SwarmResult<()> {
+        if prefix.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "ephemeral_key_prefix cannot be empty".to_string(),
+            ));
+        }
+        self.ephemeral_key_prefix = prefix;
+        Ok(())
+    }
+
+    pub(crate) fn set_branch_merge_strategy(&mut self, strategy: BranchMergeStrategy) {
+        self.branch_merge_strategy = strategy;
+    }
+
+    pub(crate) fn set_language_word_lists(
+        &mut self,
+        lists: HashMap<String, Vec<String>>,
+    ) -> SwarmResult<()> {
+        let mut normalized = HashMap::with_capacity(lists.len());
+        for (language, words) in lists {
+            let language = language.trim().to_lowercase();
+            if language.is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "Language word list names cannot be empty".to_string(),
+                ));
+            }
+            if words.is_empty() || words.iter().any(|word| word.trim().is_empty()) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Word list for language '{}' must hold non-empty words",
+                    language
+                )));
+            }
+            let words = words
+                .iter()
+                .map(|word| word.trim().to_lowercase())
+                .collect();
+            normalized.insert(language, words);
+        }
+        self.language_word_lists = normalized;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_size_limit(&mut self, bytes: Option<usize>) -> SwarmResult<()> {
+        if bytes == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "context_size_limit must be greater than 0".to_string(),
+            ));
+        }
+        self.context_size_limit = bytes;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_value_max_bytes(&mut self, bytes: Option<usize>) -> SwarmResult<()> {
+        if bytes == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "context_value_max_bytes must be greater than 0".to_string(),
+            ));
+        }
+        self.context_value_max_bytes = bytes;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_summarizer_agent(
+        &mut self,
+        agent: Option<String>,
+    ) -> SwarmResult<()> {
+        if agent.as_ref().is_some_and(|name| name.trim().is_empty()) {
+            return Err(SwarmError::ValidationError(
+                "context_summarizer_agent cannot be empty".to_string(),
+            ));
+        }
+        self.context_summarizer_agent = agent;
+        Ok(())
+    }
+
+    pub(crate) fn set_function_arg_retry_prompt(
+        &mut self,
+        prompt: Option<String>,
+    ) -> SwarmResult<()> {
+        if prompt
+            .as_ref()
+            .is_some_and(|prompt| prompt.trim().is_empty())
+        {
+            return Err(SwarmError::ValidationError(
+                "function_arg_retry_prompt cannot be empty".to_string(),
+            ));
+        }
+        self.function_arg_retry_prompt = prompt;
+        Ok(())
+    }
+
+    pub(crate) fn set_function_arg_max_retries(&mut self, retries: u32) {
+        self.function_arg_max_retries = retries;
+    }
+
+    pub(crate) fn set_model_aliases(
+        &mut self,
+        aliases: HashMap<String, String>,
+    ) -> SwarmResult<()> {
+        if aliases
+            .iter()
+            .any(|(from, to)| from.trim().is_empty() || to.trim().is_empty())
+        {
+            return Err(SwarmError::ValidationError(
+                "Model aliases cannot have empty names".to_string(),
+            ));
+        }
+        self.model_aliases = aliases;
+        Ok(())
+    }
+
+    pub(crate) fn set_max_dynamic_step_injections(&mut self, limit: usize) {
+        self.max_dynamic_step_injections = limit;
+    }
+
+    pub(crate) fn set_update_system_message_on_switch(&mut self, enabled: bool) {
+        self.update_system_message_on_switch = enabled;
+    }
+
+    pub(crate) fn set_max_registered_agents(&mut self, limit: Option<usize>) -> SwarmResult<()> {
+        if limit == Some(0) {
+            return Err(SwarmError::ValidationError(
+                "max_registered_agents must be greater than 0".to_string(),
+            ));
+        }
+        self.max_registered_agents = limit;
+        Ok(())
+    }
+
+    pub(crate) fn set_context_rename_on_switch(
+        &mut self,
+        renames: HashMap<String, HashMap<String, String>>,
+    ) -> SwarmResult<()> {
+        for (agent, keys) in &renames {
+            if agent.trim().is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "Context rename agent names cannot be empty".to_string(),
+                ));
+            }
+            if keys
+                .iter()
+                .any(|(old, new)| old.trim().is_empty() || new.trim().is_empty())
+            {
+                return Err(SwarmError::ValidationError(format!(
+                    "Context renames for agent '{}' cannot use empty keys",
+                    agent
+                )));
+            }
+        }
+        self.context_rename_on_switch = renames;
+        Ok(())
+    }
+
+    pub(crate) fn set_system_message_format(&mut self, format: SystemMessageFormat) {
+        self.system_message_format = format;
+    }
+
+    pub(crate) fn set_inject_agent_name_as_message_name(&mut self, enabled: bool) {
+        self.inject_agent_name_as_message_name = enabled;
+    }
+
+    pub(crate) fn add_context_injector(&mut self, injector: Arc<ContextInjector>) {
+        self.context_injectors.push(injector);
+    }
+
+    pub(crate) fn set_prompt_compression(
+        &mut self,
+        compression: Option<PromptCompressionConfig>,
+    ) -> SwarmResult<()> {
+        if let Some(compression) = &compression {
+            compression.validate()?;
+        }
+        self.prompt_compression = compression;
+        Ok(())
+    }
+
+    pub(crate) fn set_user_id_provider(&mut self, provider: Arc<UserIdProvider>) {
+        self.user_id_provider = Some(provider);
+    }
+
+    pub(crate) fn set_history_window(
+        &mut self,
+        strategy: HistoryWindowStrategy,
+    ) -> SwarmResult<()> {
+        strategy.validate()?;
+        self.history_window = strategy;
+        Ok(())
+    }
+
+    pub(crate) fn set_api_url(&mut self, api_url: impl Into<String>) -> SwarmResult<()> {
+        self.api_url = ApiUrl::new(api_url, &self.valid_api_url_prefixes)?;
+        Ok(())
+    }
+
+    pub(crate) fn set_api_version(&mut self, api_version: impl Into<String>) -> SwarmResult<()> {
+        let api_version = api_version.into();
+        if api_version.trim().is_empty() {
+            return Err(SwarmError::ValidationError(
+                "API version cannot be empty".to_string(),
+            ));
+        }
+        self.api_version = api_version;
+        Ok(())
+    }
+
+    pub(crate) fn set_request_timeout(&mut self, request_timeout: u64) -> SwarmResult<()> {
+        let request_timeout = RequestTimeoutSeconds::new(request_timeout)?;
+        self.request_timeout = request_timeout;
+        self.api_settings
+            .timeout_settings_mut()
+            .set_request_timeout(Duration::from_secs(request_timeout.get()))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_connect_timeout(&mut self, connect_timeout: u64) -> SwarmResult<()> {
+        let connect_timeout = ConnectTimeoutSeconds::new(connect_timeout)?;
+        self.connect_timeout = connect_timeout;
+        self.api_settings
+            .timeout_settings_mut()
+            .set_connect_timeout(Duration::from_secs(connect_timeout.get()))?;
+        Ok(())
+    }
+
+    pub(crate) fn set_max_retries(&mut self, max_retries: u32) -> SwarmResult<()> {
+        self.max_retries = RetryLimit::new(max_retries)?;
+        self.api_settings
+            .retry_strategy_mut()
+            .set_max_retries(max_retries)?;
+        Ok(())
+    }
+
+    /// Replaces the retry and timeout settings, keeping `max_retries` and the
+    /// request and connect timeouts (whole seconds) in step with them.
+    pub(crate) fn set_api_settings(&mut self, api_settings: ApiSettings) -> SwarmResult<()> {
+        let timeouts = api_settings.timeout_settings();
+        self.max_retries = RetryLimit::new(api_settings.retry_strategy().max_retries())?;
+        self.request_timeout = RequestTimeoutSeconds::new(timeouts.request_timeout().as_secs())?;
+        self.connect_timeout = ConnectTimeoutSeconds::new(timeouts.connect_timeout().as_secs())?;
+        self.api_settings = api_settings;
+        Ok(())
+    }
+
+    /// Replaces the loop settings, keeping `max_loop_iterations` in step with them.
+    pub(crate) fn set_loop_control(&mut self, loop_control: LoopControl) -> SwarmResult<()> {
+        self.max_loop_iterations = LoopIterationLimit::new(loop_control.default_max_iterations())?;
+        self.loop_control = loop_control;
+        Ok(())
+    }
+
+    pub(crate) fn set_retry_jitter(&mut self, jitter: JitterStrategy) {
+        self.api_settings.retry_strategy_mut().set_jitter(jitter);
+    }
+
+    pub(crate) fn set_max_loop_iterations(&mut self, max_loop_iterations: u32) -> SwarmResult<()> {
+        let max_loop_iterations = LoopIterationLimit::new(max_loop_iterations)?;
+        self.max_loop_iterations = max_loop_iterations;
+        self.loop_control
+            .set_default_max_iterations(max_loop_iterations.get())?;
+        Ok(())
+    }
+
+    pub(crate) fn set_valid_model_prefixes(
+        &mut self,
+        valid_model_prefixes: Vec<String>,
+    ) -> SwarmResult<()> {
+        if valid_model_prefixes.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "valid_model_prefixes cannot be empty".to_string(),
+            ));
+        }
+
+        self.valid_model_prefixes = valid_model_prefixes
+            .into_iter()
+            .map(ModelPrefix::new)
+            .collect::<SwarmResult<Vec<_>>>()?;
+        Ok(())
+    }
+
+    pub(crate) fn set_valid_api_url_prefixes(
+        &mut self,
+        valid_api_url_prefixes: Vec<String>,
+    ) -> SwarmResult<()> {
+        if valid_api_url_prefixes.is_empty() {
+            return Err(SwarmError::ValidationError(
+                "valid_api_url_prefixes cannot be empty".to_string(),
+            ));
+        }
+
+        let valid_api_url_prefixes = valid_api_url_prefixes
+            .into_iter()
+            .map(ApiUrlPrefix::new)
+            .collect::<SwarmResult<Vec<_>>>()?;
+        let current_api_url = self.api_url.as_str().to_string();
+
+        self.valid_api_url_prefixes = valid_api_url_prefixes;
+        self.api_url = ApiUrl::new(current_api_url, &self.valid_api_url_prefixes)?;
+        Ok(())
+    }
+}
+
+/// Field-by-field differences between two [`SwarmConfig`]s, as `(old, new)` pairs.
+///
+/// Covers every setting exposed through `SwarmBuilder` except the hook objects
+/// (context injectors, task complexity scorer and user id provider), which
+/// cannot be compared. `loop_control` and `api_settings` also change with
+/// `max_loop_iterations`, `max_retries`, `retry_jitter` and the timeouts; applying
+/// them sets those fields too, and the more specific fields are applied after them.
+/// Custom serializers and formatters show as `Custom(..)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SwarmConfigDiff {
+    pub api_url: Option<(String, String)>,
+    pub api_version: Option<(String, String)>,
+    pub request_timeout: Option<(u64, u64)>,
+    pub connect_timeout: Option<(u64, u64)>,
+    pub max_retries: Option<(u32, u32)>,
+    pub max_loop_iterations: Option<(u32, u32)>,
+    pub valid_model_prefixes: Option<(Vec<ModelPrefix>, Vec<ModelPrefix>)>,
+    pub valid_api_url_prefixes: Option<(Vec<ApiUrlPrefix>, Vec<ApiUrlPrefix>)>,
+    pub runtime_limits: Option<(RuntimeLimits, RuntimeLimits)>,
+    pub deduplicate_system_messages: Option<(bool, bool)>,
+    pub handoff_cycle_detection: Option<(bool, bool)>,
+    pub on_circular_handoff: Option<(CircularHandoffAction, CircularHandoffAction)>,
+    pub history_window: Option<(HistoryWindowStrategy, HistoryWindowStrategy)>,
+    pub adaptive_max_tokens: Option<(bool, bool)>,
+    pub model_context_windows: Option<(ModelContextWindow, ModelContextWindow)>,
+    pub semantic_dedup_threshold: Option<(Option<f32>, Option<f32>)>,
+    pub safety_instructions: Option<(Option<String>, Option<String>)>,
+    pub safety_placement: Option<(SafetyPlacement, SafetyPlacement)>,
+    pub context_variable_max_size: Option<(Option<usize>, Option<usize>)>,
+    pub context_variable_max_count: Option<(Option<usize>, Option<usize>)>,
+    pub context_overflow: Option<(ContextOverflow, ContextOverflow)>,
+    pub default_function_format: Option<(FunctionCallFormat, FunctionCallFormat)>,
+    pub tool_result_max_tokens: Option<(Option<u32>, Option<u32>)>,
+    pub tool_summarizer_agent: Option<(Option<String>, Option<String>)>,
+    pub complexity_model_map: Option<(ComplexityModelMap, ComplexityModelMap)>,
+    pub global_stop_sequences: Option<(Vec<String>, Vec<String>)>,
+    pub context_snapshot_interval: Option<(ContextSnapshotInterval, ContextSnapshotInterval)>,
+    pub max_context_snapshots: Option<(usize, usize)>,
+    pub repair_function_arguments: Option<(bool, bool)>,
+    pub vision_capable_model_patterns: Option<(Vec<String>, Vec<String>)>,
+    pub max_context_tokens_per_step: Option<(Option<u32>, Option<u32>)>,
+    pub context_overflow_strategy: Option<(ContextOverflowStrategy, ContextOverflowStrategy)>,
+    pub max_content_length_per_role: Option<(HashMap<String, usize>, HashMap<String, usize>)>,
+    pub content_overflow_per_role: Option<(
+        HashMap<String, ContentOverflowAction>,
+        HashMap<String, ContentOverflowAction>,
+    )>,
+    pub post_function_call_prompt: Option<(Option<String>, Option<String>)>,
+    pub xml_encoding: Option<(XmlEncoding, XmlEncoding)>,
+    pub xml_namespace: Option<(Option<String>, Option<String>)>,
+    pub ephemeral_key_prefix: Option<(String, String)>,
+    pub branch_merge_strategy: Option<(BranchMergeStrategy, BranchMergeStrategy)>,
+    pub language_word_lists: Option<(LanguageWordLists, LanguageWordLists)>,
+    pub context_size_limit: Option<(Option<usize>, Option<usize>)>,
+    pub context_value_max_bytes: Option<(Option<usize>, Option<usize>)>,
+    pub context_summarizer_agent: Option<(Option<String>, Option<String>)>,
+    pub function_arg_retry_prompt: Option<(Option<String>, Option<String>)>,
+    pub function_arg_max_retries: Option<(u32, u32)>,
+    pub model_aliases: Option<(HashMap<String, String>, HashMap<String, String>)>,
+    pub max_dynamic_step_injections: Option<(usize, usize)>,
+    pub update_system_message_on_switch: Option<(bool, bool)>,
+    pub max_registered_agents: Option<(Option<usize>, Option<usize>)>,
+    pub context_rename_on_switch: Option<(ContextRenames, ContextRenames)>,
+    pub inject_agent_name_as_message_name: Option<(bool, bool)>,
+    pub prompt_compression: Option<(
+        Option<PromptCompressionConfig>,
+        Option<PromptCompressionConfig>,
+    )>,
+    pub retry_jitter: Option<(JitterStrategy, JitterStrategy)>,
+    pub message_serialization: Option<(MessageSerializationAdapter, MessageSerializationAdapter)>,
+    pub system_message_format: Option<(SystemMessageFormat, SystemMessageFormat)>,
+    pub loop_control: Option<(LoopControl, LoopControl)>,
+    pub api_settings: Option<(ApiSettings, ApiSettings)>,
+}
+
+fn changed<T: PartialEq + Clone>(old: &T, new: &T) -> Option<(T, T)> {
+    (old != new).then(|| (old.clone(), new.clone()))
+}
+
+fn join_prefixes<T: AsRef<str>>(prefixes: &[T]) -> String {
+    prefixes
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl SwarmConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.rows().is_empty()
+    }
+
+    /// `(field, old, new)` for every changed field, in declaration order.
+    fn rows(&self) -> Vec<(&'static str, String, String)> {
+        fn row<T>(
+            rows: &mut Vec<(&'static str, String, String)>,
+            field: &'static str,
+            change: &Option<(T, T)>,
+            show: impl Fn(&T) -> String,
+        ) {
+            if let Some((old, new)) = change {
+                rows.push((field, show(old), show(new)));
+            }
+        }
+
+        let mut rows = Vec::new();
+        row(&mut rows, "api_url", &self.api_url, String::clone);
+        row(&mut rows, "api_version", &self.api_version, String::clone);
+        row(&mut rows, "request_timeout", &self.request_timeout, |v| {
+            format!("{}s", v)
+        });
+        row(&mut rows, "connect_timeout", &self.connect_timeout, |v| {
+            format!("{}s", v)
+        });
+        row(&mut rows, "max_retries", &self.max_retries, u32::to_string);
+        row(
+            &mut rows,
+            "max_loop_iterations",
+            &self.max_loop_iterations,
+            u32::to_string,
+        );
+        row(
+            &mut rows,
+            "valid_model_prefixes",
+            &self.valid_model_prefixes,
+            |v| join_prefixes(v),
+        );
+        row(
+            &mut rows,
+            "valid_api_url_prefixes",
+            &self.valid_api_url_prefixes,
+            |v| join_prefixes(v),
+        );
+        row(&mut rows, "runtime_limits", &self.runtime_limits, |v| {
+            format!("{:?}", v)
+        });
+        row(
+            &mut rows,
+            "deduplicate_system_messages",
+            &self.deduplicate_system_messages,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "handoff_cycle_detection",
+            &self.handoff_cycle_detection,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "on_circular_handoff",
+            &self.on_circular_handoff,
+            |v| format!("{:?}", v),
+        );
+        row(&mut rows, "history_window", &self.history_window, |v| {
+            format!("{:?}", v)
+        });
+        row(
+            &mut rows,
+            "adaptive_max_tokens",
+            &self.adaptive_max_tokens,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "model_context_windows",
+            &self.model_context_windows,
+            |v| format!("{:?}", v.windows),
+        );
+        row(
+            &mut rows,
+            "semantic_dedup_threshold",
+            &self.semantic_dedup_threshold,
+            |v| v.map_or_else(|| "none".to_string(), |t| t.to_string()),
+        );
+        row(
+            &mut rows,
+            "safety_instructions",
+            &self.safety_instructions,
+            |v| v.clone().unwrap_or_else(|| "none".to_string()),
+        );
+        row(&mut rows, "safety_placement", &self.safety_placement, |v| {
+            format!("{:?}", v)
+        });
+        let limit = |v: &Option<usize>| v.map_or_else(|| "none".to_string(), |n| n.to_string());
+        row(
+            &mut rows,
+            "context_variable_max_size",
+            &self.context_variable_max_size,
+            limit,
+        );
+        row(
+            &mut rows,
+            "context_variable_max_count",
+            &self.context_variable_max_count,
+            limit,
+        );
+        row(&mut rows, "context_overflow", &self.context_overflow, |v| {
+            format!("{:?}", v)
+        });
+        row(
+            &mut rows,
+            "default_function_format",
+            &self.default_function_format,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "tool_result_max_tokens",
+            &self.tool_result_max_tokens,
+            |v| v.map_or_else(|| "none".to_string(), |n| n.to_string()),
+        );
+        row(
+            &mut rows,
+            "tool_summarizer_agent",
+            &self.tool_summarizer_agent,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "complexity_model_map",
+            &self.complexity_model_map,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "global_stop_sequences",
+            &self.global_stop_sequences,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "context_snapshot_interval",
+            &self.context_snapshot_interval,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "max_context_snapshots",
+            &self.max_context_snapshots,
+            |v| v.to_string(),
+        );
+        row(
+            &mut rows,
+            "repair_function_arguments",
+            &self.repair_function_arguments,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "vision_capable_model_patterns",
+            &self.vision_capable_model_patterns,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "max_context_tokens_per_step",
+            &self.max_context_tokens_per_step,
+            |v| v.map_or_else(|| "none".to_string(), |n| n.to_string()),
+        );
+        row(
+            &mut rows,
+            "context_overflow_strategy",
+            &self.context_overflow_strategy,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "max_content_length_per_role",
+            &self.max_content_length_per_role,
+            |v| {
+                let mut entries = v.iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                format!("{:?}", entries)
+            },
+        );
+        row(
+            &mut rows,
+            "content_overflow_per_role",
+            &self.content_overflow_per_role,
+            |v| {
+                let mut entries = v.iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                format!("{:?}", entries)
+            },
+        );
+        row(
+            &mut rows,
+            "post_function_call_prompt",
+            &self.post_function_call_prompt,
+            |v| v.clone().unwrap_or_else(|| "none".to_string()),
+        );
+        row(&mut rows, "xml_encoding", &self.xml_encoding, |v| {
+            format!("{:?}", v)
+        });
+        row(&mut rows, "xml_namespace", &self.xml_namespace, |v| {
+            v.clone().unwrap_or_else(|| "none".to_string())
+        });
+        row(
+            &mut rows,
+            "ephemeral_key_prefix",
+            &self.ephemeral_key_prefix,
+            String::clone,
+        );
+        row(
+            &mut rows,
+            "branch_merge_strategy",
+            &self.branch_merge_strategy,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "language_word_lists",
+            &self.language_word_lists,
+            |v| {
+                let mut entries = v.iter().collect::<Vec<_>>();
+                entries.sort_by(|a, b| a.0.cmp(b.0));
+                format!("{:?}", entries)
+            },
+        );
+        row(
+            &mut rows,
+            "context_size_limit",
+            &self.context_size_limit,
+            |v| v.map_or_else(|| "none".to_string(), |n| n.to_string()),
+        );
+        row(
+            &mut rows,
+            "context_value_max_bytes",
+            &self.context_value_max_bytes,
+            |v| v.map_or_else(|| "none".to_string(), |n| n.to_string()),
+        );
+        row(
+            &mut rows,
+            "context_summarizer_agent",
+            &self.context_summarizer_agent,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "function_arg_retry_prompt",
+            &self.function_arg_retry_prompt,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "function_arg_max_retries",
+            &self.function_arg_max_retries,
+            |v| v.to_string(),
+        );
+        row(&mut rows, "model_aliases", &self.model_aliases, |v| {
+            let mut entries = v.iter().collect::<Vec<_>>();
+            entries.sort();
+            format!("{:?}", entries)
+        });
+        row(
+            &mut rows,
+            "max_dynamic_step_injections",
+            &self.max_dynamic_step_injections,
+            |v| v.to_string(),
+        );
+        row(
+            &mut rows,
+            "update_system_message_on_switch",
+            &self.update_system_message_on_switch,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "max_registered_agents",
+            &self.max_registered_agents,
+            |v| v.map_or_else(|| "none".to_string(), |n| n.to_string()),
+        );
+        row(
+            &mut rows,
+            "context_rename_on_switch",
+            &self.context_rename_on_switch,
+            |v| {
+                let mut entries = v
+                    .iter()
+                    .map(|(agent, keys)| {
+                        let mut keys = keys.iter().collect::<Vec<_>>();
+                        keys.sort();
+                        (agent, keys)
+                    })
+                    .collect::<Vec<_>>();
+                entries.sort();
+                format!("{:?}", entries)
+            },
+        );
+        row(
+            &mut rows,
+            "inject_agent_name_as_message_name",
+            &self.inject_agent_name_as_message_name,
+            bool::to_string,
+        );
+        row(
+            &mut rows,
+            "prompt_compression",
+            &self.prompt_compression,
+            |v| {
+                v.as_ref()
+                    .map_or_else(|| "none".to_string(), |c| format!("{:?}", c))
+            },
+        );
+        row(&mut rows, "retry_jitter", &self.retry_jitter, |v| {
+            format!("{:?}", v)
+        });
+        row(
+            &mut rows,
+            "message_serialization",
+            &self.message_serialization,
+            |v| format!("{:?}", v),
+        );
+        row(
+            &mut rows,
+            "system_message_format",
+            &self.system_message_format,
+            |v| format!("{:?}", v),
+        );
+        row(&mut rows, "loop_control", &self.loop_control, |v| {
+            format!("{:?}", v)
+        });
+        row(&mut rows, "api_settings", &self.api_settings, |v| {
+            format!("{:?}", v)
+        });
+        rows
+    }
+}
+
+impl fmt::Display for SwarmConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self.rows();
+        if rows.is_empty() {
+            return write!(f, "no differences");
+        }
+        let field_width = rows
+            .iter()
+            .map(|(field, _, _)| field.len())
+            .max()
+            .unwrap_or(0);
+        let old_width = rows.iter().map(|(_, old, _)| old.len()).max().unwrap_or(0);
+        writeln!(
+            f,
+            "{:<field_width$} | {:<old_width$} | new",
+            "field",
+            "old",
+            field_width = field_width.max("field".len()),
+            old_width = old_width.max("old".len()),
+        )?;
+        for (index, (field, old, new)) in rows.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{:<field_width$} | {:<old_width$} | {}",
+                field,
+                old,
+                new,
+                field_width = field_width.max("field".len()),
+                old_width = old_width.max("old".len()),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SwarmConfig {
+    /// Differences from `self` (old) to `other` (new).
+    pub fn diff(&self, other: &SwarmConfig) -> SwarmConfigDiff {
+        SwarmConfigDiff {
+            api_url: changed(&self.api_url().to_string(), &other.api_url().to_string()),
+            api_version: changed(&self.api_version, &other.api_version),
+            request_timeout: changed(&self.request_timeout(), &other.request_timeout()),
+            connect_timeout: changed(&self.connect_timeout(), &other.connect_timeout()),
+            max_retries: changed(&self.max_retries(), &other.max_retries()),
+            max_loop_iterations: changed(&self.max_loop_iterations(), &other.max_loop_iterations()),
+            valid_model_prefixes: changed(&self.valid_model_prefixes, &other.valid_model_prefixes),
+            valid_api_url_prefixes: changed(
+                &self.valid_api_url_prefixes,
+                &other.valid_api_url_prefixes,
+            ),
+            runtime_limits: changed(&self.runtime_limits, &other.runtime_limits),
+            deduplicate_system_messages: changed(
+                &self.deduplicate_system_messages,
+                &other.deduplicate_system_messages,
+            ),
+            handoff_cycle_detection: changed(
+                &self.handoff_cycle_detection,
+                &other.handoff_cycle_detection,
+            ),
+            on_circular_handoff: changed(&self.on_circular_handoff, &other.on_circular_handoff),
+            history_window: changed(&self.history_window, &other.history_window),
+            adaptive_max_tokens: changed(&self.adaptive_max_tokens, &other.adaptive_max_tokens),
+            model_context_windows: changed(
+                &self.model_context_windows,
+                &other.model_context_windows,
+            ),
+            semantic_dedup_threshold: changed(
+                &self.semantic_dedup_threshold,
+                &other.semantic_dedup_threshold,
+            ),
+            safety_instructions: changed(&self.safety_instructions, &other.safety_instructions),
+            safety_placement: changed(&self.safety_placement, &other.safety_placement),
+            context_variable_max_size: changed(
+                &self.context_variable_max_size,
+                &other.context_variable_max_size,
+            ),
+            context_variable_max_count: changed(
+                &self.context_variable_max_count,
+                &other.context_variable_max_count,
+            ),
+            context_overflow: changed(&self.context_overflow, &other.context_overflow),
+            default_function_format: changed(
+                &self.default_function_format,
+                &other.default_function_format,
+            ),
+            tool_result_max_tokens: changed(
+                &self.tool_result_max_tokens,
+                &other.tool_result_max_tokens,
+            ),
+            tool_summarizer_agent: changed(
+                &self.tool_summarizer_agent,
+                &other.tool_summarizer_agent,
+            ),
+            complexity_model_map: changed(&self.complexity_model_map, &other.complexity_model_map),
+            global_stop_sequences: changed(
+                &self.global_stop_sequences,
+                &other.global_stop_sequences,
+            ),
+            context_snapshot_interval: changed(
+                &self.context_snapshot_interval,
+                &other.context_snapshot_interval,
+            ),
+            max_context_snapshots: changed(
+                &self.max_context_snapshots,
+                &other.max_context_snapshots,
+            ),
+            repair_function_arguments: changed(
+                &self.repair_function_arguments,
+                &other.repair_function_arguments,
+            ),
+            vision_capable_model_patterns: changed(
+                &self.vision_capable_model_patterns,
+                &other.vision_capable_model_patterns,
+            ),
+            max_context_tokens_per_step: changed(
+                &self.max_context_tokens_per_step,
+                &other.max_context_tokens_per_step,
+            ),
+            context_overflow_strategy: changed(
+                &self.context_overflow_strategy,
+                &other.context_overflow_strategy,
+            ),
+            max_content_length_per_role: changed(
+                &self.max_content_length_per_role,
+                &other.max_content_length_per_role,
+            ),
+            content_overflow_per_role: changed(
+                &self.content_overflow_per_role,
+                &other.content_overflow_per_role,
+            ),
+            post_function_call_prompt: changed(
+                &self.post_function_call_prompt,
+                &other.post_function_call_prompt,
+            ),
+            xml_encoding: changed(&self.xml_encoding, &other.xml_encoding),
+            xml_namespace: changed(&self.xml_namespace, &other.xml_namespace),
+            ephemeral_key_prefix: changed(&self.ephemeral_key_prefix, &other.ephemeral_key_prefix),
+            branch_merge_strategy: changed(
+                &self.branch_merge_strategy,
+                &other.branch_merge_strategy,
+            ),
+            language_word_lists: changed(&self.language_word_lists, &other.language_word_lists),
+            context_size_limit: changed(&self.context_size_limit, &other.context_size_limit),
+            context_value_max_bytes: changed(
+                &self.context_value_max_bytes,
+                &other.context_value_max_bytes,
+            ),
+            context_summarizer_agent: changed(
+                &self.context_summarizer_agent,
+                &other.context_summarizer_agent,
+            ),
+            function_arg_retry_prompt: changed(
+                &self.function_arg_retry_prompt,
+                &other.function_arg_retry_prompt,
+            ),
+            function_arg_max_retries: changed(
+                &self.function_arg_max_retries,
+                &other.function_arg_max_retries,
+            ),
+            model_aliases: changed(&self.model_aliases, &other.model_aliases),
+            max_dynamic_step_injections: changed(
+                &self.max_dynamic_step_injections,
+                &other.max_dynamic_step_injections,
+            ),
+            update_system_message_on_switch: changed(
+                &self.update_system_message_on_switch,
+                &other.update_system_message_on_switch,
+            ),
+            max_registered_agents: changed(
+                &self.max_registered_agents,
+                &other.max_registered_agents,
+            ),
+            context_rename_on_switch: changed(
+                &self.context_rename_on_switch,
+                &other.context_rename_on_switch,
+            ),
+            inject_agent_name_as_message_name: changed(
+                &self.inject_agent_name_as_message_name,
+                &other.inject_agent_name_as_message_name,
+            ),
+            prompt_compression: changed(&self.prompt_compression, &other.prompt_compression),
+            retry_jitter: changed(
+                &self.api_settings.retry_strategy().jitter(),
+                &other.api_settings.retry_strategy().jitter(),
+            ),
+            message_serialization: changed(
+                &self.message_serialization,
+                &other.message_serialization,
+            ),
+            system_message_format: changed(
+                &self.system_message_format,
+                &other.system_message_format,
+            ),
+            loop_control: changed(&self.loop_control, &other.loop_control),
+            api_settings: changed(&self.api_settings, &other.api_settings),
+        }
+    }
+
+    /// Applies the `new` side of every changed field in `diff`.
+    ///
+    /// Each value goes through the same validation as the builder; on error
+    /// `self` is left unchanged.
+    pub fn apply_diff(&mut self, diff: &SwarmConfigDiff) -> SwarmResult<()> {
+        let mut updated = self.clone();
+        if let Some((_, prefixes)) = &diff.valid_api_url_prefixes {
+            if prefixes.is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "valid_api_url_prefixes cannot be empty".to_string(),
+                ));
+            }
+            updated.valid_api_url_prefixes = prefixes.clone();
+        }
+        // Validate the URL against the (possibly new) prefixes in one step so a diff
+        // that moves both does not trip over the intermediate state.
+        let api_url = diff
+            .api_url
+            .as_ref()
+            .map(|(_, url)| url.clone())
+            .unwrap_or_else(|| updated.api_url().to_string());
+        updated.api_url = ApiUrl::new(api_url, &updated.valid_api_url_prefixes)?;
+        if let Some((_, version)) = &diff.api_version {
+            updated.set_api_version(version.clone())?;
+        }
+        if let Some((_, loop_control)) = &diff.loop_control {
+            updated.set_loop_control(loop_control.clone())?;
+        }
+        if let Some((_, api_settings)) = &diff.api_settings {
+            updated.set_api_settings(api_settings.clone())?;
+        }
+        if let Some((_, timeout)) = diff.request_timeout {
+            updated.set_request_timeout(timeout)?;
+        }
+        if let Some((_, timeout)) = diff.connect_timeout {
+            updated.set_connect_timeout(timeout)?;
+        }
+        if let Some((_, retries)) = diff.max_retries {
+            updated.set_max_retries(retries)?;
+        }
+        if let Some((_, iterations)) = diff.max_loop_iterations {
+            updated.set_max_loop_iterations(iterations)?;
+        }
+        if let Some((_, prefixes)) = &diff.valid_model_prefixes {
+            if prefixes.is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "valid_model_prefixes cannot be empty".to_string(),
+                ));
+            }
+            updated.valid_model_prefixes = prefixes.clone();
+        }
+        if let Some((_, limits)) = &diff.runtime_limits {
+            updated.set_runtime_limits(limits.clone());
+        }
+        if let Some((_, enabled)) = diff.deduplicate_system_messages {
+            updated.set_deduplicate_system_messages(enabled);
+        }
+        if let Some((_, enabled)) = diff.handoff_cycle_detection {
+            updated.set_handoff_cycle_detection(enabled);
+        }
+        if let Some((_, action)) = diff.on_circular_handoff {
+            updated.set_on_circular_handoff(action);
+        }
+        if let Some((_, strategy)) = diff.history_window {
+            updated.set_history_window(strategy)?;
+        }
+        if let Some((_, enabled)) = diff.adaptive_max_tokens {
+            updated.set_adaptive_max_tokens(enabled);
+        }
+        if let Some((_, windows)) = &diff.model_context_windows {
+            updated.set_model_context_windows(windows.clone());
+        }
+        if let Some((_, threshold)) = diff.semantic_dedup_threshold {
+            updated.set_semantic_dedup_threshold(threshold)?;
+        }
+        if let Some((_, instructions)) = &diff.safety_instructions {
+            updated.set_safety_instructions(instructions.clone())?;
+        }
+        if let Some((_, placement)) = diff.safety_placement {
+            updated.set_safety_placement(placement);
+        }
+        if let Some((_, max_size)) = diff.context_variable_max_size {
+            updated.set_context_variable_max_size(max_size)?;
+        }
+        if let Some((_, max_count)) = diff.context_variable_max_count {
+            updated.set_context_variable_max_count(max_count)?;
+        }
+        if let Some((_, overflow)) = diff.context_overflow {
+            updated.set_context_overflow(overflow);
+        }
+        if let Some((_, format)) = diff.default_function_format {
+            updated.set_default_function_format(format);
+        }
+        if let Some((_, max_tokens)) = diff.tool_result_max_tokens {
+            updated.set_tool_result_max_tokens(max_tokens)?;
         }
-
-        self.valid_model_prefixes = valid_model_prefixes
-            .into_iter()
-            .map(ModelPrefix::new)
-            .collect::<SwarmResult<Vec<_>>>()?;
-        Ok(())
-    }
-
-    pub(crate) fn set_valid_api_url_prefixes(
-        &mut self,
-        valid_api_url_prefixes: Vec<String>,
-    ) -> SwarmResult<()> {
-        if valid_api_url_prefixes.is_empty() {
-            return Err(SwarmError::ValidationError(
-                "valid_api_url_prefixes cannot be empty".to_string(),
-            ));
+        if let Some((_, agent)) = diff.tool_summarizer_agent.clone() {
+            updated.set_tool_summarizer_agent(agent)?;
         }
-
-        let valid_api_url_prefixes = valid_api_url_prefixes
-            .into_iter()
-            .map(ApiUrlPrefix::new)
-            .collect::<SwarmResult<Vec<_>>>()?;
-        let current_api_url = self.api_url.as_str().to_string();
-
-        self.valid_api_url_prefixes = valid_api_url_prefixes;
-        self.api_url = ApiUrl::new(current_api_url, &self.valid_api_url_prefixes)?;
+        if let Some((_, map)) = diff.complexity_model_map.clone() {
+            updated.set_complexity_model_map(map)?;
+        }
+        if let Some((_, sequences)) = diff.global_stop_sequences.clone() {
+            updated.set_global_stop_sequences(sequences)?;
+        }
+        if let Some((_, interval)) = diff.context_snapshot_interval {
+            updated.set_context_snapshot_interval(interval);
+        }
+        if let Some((_, max_snapshots)) = diff.max_context_snapshots {
+            updated.set_max_context_snapshots(max_snapshots)?;
+        }
+        if let Some((_, enabled)) = diff.repair_function_arguments {
+            updated.set_repair_function_arguments(enabled);
+        }
+        if let Some((_, patterns)) = diff.vision_capable_model_patterns.clone() {
+            updated.set_vision_capable_model_patterns(patterns)?;
+        }
+        if let Some((_, max_tokens)) = diff.max_context_tokens_per_step {
+            updated.set_max_context_tokens_per_step(max_tokens)?;
+        }
+        if let Some((_, strategy)) = diff.context_overflow_strategy {
+            updated.set_context_overflow_strategy(strategy);
+        }
+        if let Some((_, limits)) = &diff.max_content_length_per_role {
+            updated.set_max_content_length_per_role(limits.clone())?;
+        }
+        if let Some((_, actions)) = &diff.content_overflow_per_role {
+            updated.set_content_overflow_per_role(actions.clone())?;
+        }
+        if let Some((_, prompt)) = &diff.post_function_call_prompt {
+            updated.set_post_function_call_prompt(prompt.clone())?;
+        }
+        if let Some((_, encoding)) = diff.xml_encoding {
+            updated.set_xml_encoding(encoding);
+        }
+        if let Some((_, namespace)) = &diff.xml_namespace {
+            updated.set_xml_namespace(namespace.clone())?;
+        }
+        if let Some((_, prefix)) = &diff.ephemeral_key_prefix {
+            updated.set_ephemeral_key_prefix(prefix.clone())?;
+        }
+        if let Some((_, strategy)) = diff.branch_merge_strategy {
+            updated.set_branch_merge_strategy(strategy);
+        }
+        if let Some((_, lists)) = &diff.language_word_lists {
+            updated.set_language_word_lists(lists.clone())?;
+        }
+        if let Some((_, bytes)) = diff.context_size_limit {
+            updated.set_context_size_limit(bytes)?;
+        }
+        if let Some((_, bytes)) = diff.context_value_max_bytes {
+            updated.set_context_value_max_bytes(bytes)?;
+        }
+        if let Some((_, agent)) = diff.context_summarizer_agent.clone() {
+            updated.set_context_summarizer_agent(agent)?;
+        }
+        if let Some((_, prompt)) = diff.function_arg_retry_prompt.clone() {
+            updated.set_function_arg_retry_prompt(prompt)?;
+        }
+        if let Some((_, retries)) = diff.function_arg_max_retries {
+            updated.set_function_arg_max_retries(retries);
+        }
+        if let Some((_, aliases)) = &diff.model_aliases {
+            updated.set_model_aliases(aliases.clone())?;
+        }
+        if let Some((_, limit)) = diff.max_dynamic_step_injections {
+            updated.set_max_dynamic_step_injections(limit);
+        }
+        if let Some((_, enabled)) = diff.update_system_message_on_switch {
+            updated.set_update_system_message_on_switch(enabled);
+        }
+        if let Some((_, limit)) = diff.max_registered_agents {
+            updated.set_max_registered_agents(limit)?;
+        }
+        if let Some((_, renames)) = &diff.context_rename_on_switch {
+            updated.set_context_rename_on_switch(renames.clone())?;
+        }
+        if let Some((_, enabled)) = diff.inject_agent_name_as_message_name {
+            updated.set_inject_agent_name_as_message_name(enabled);
+        }
+        if let Some((_, compression)) = diff.prompt_compression.clone() {
+            updated.set_prompt_compression(compression)?;
+        }
+        if let Some((_, jitter)) = diff.retry_jitter {
+            updated.set_retry_jitter(jitter);
+        }
+        if let Some((_, adapter)) = &diff.message_serialization {
+            updated.set_message_serialization(adapter.clone());
+        }
+        if let Some((_, format)) = &diff.system_message_format {
+            updated.set_system_message_format(format.clone());
+        }
+        *self = updated;
         Ok(())
     }
 }
@@ -1117,6 +3726,13 @@ impl MessageRole {
     }
 }
 
+/// Rejects role keys that do not name a [`MessageRole`].
+fn validate_role_name(role: &str) -> SwarmResult<()> {
+    serde_json::from_value::<MessageRole>(json!(role))
+        .map(|_| ())
+        .map_err(|_| SwarmError::ValidationError(format!("Unknown message role '{}'", role)))
+}
+
 impl fmt::Display for MessageRole {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(self.as_str())
@@ -1167,6 +3783,17 @@ impl FunctionCall {
     }
 
     fn validate(&self) -> SwarmResult<()> {
+        self.validate_non_empty()?;
+        serde_json::from_str::<Value>(&self.arguments).map_err(|error| {
+            SwarmError::ValidationError(format!(
+                "Function call arguments must be valid JSON: {}",
+                error
+            ))
+        })?;
+        Ok(())
+    }
+
+    fn validate_non_empty(&self) -> SwarmResult<()> {
         if self.name.trim().is_empty() {
             return Err(SwarmError::ValidationError(
                 "Function call name cannot be empty".to_string(),
@@ -1177,12 +3804,6 @@ impl FunctionCall {
                 "Function call arguments cannot be empty".to_string(),
             ));
         }
-        serde_json::from_str::<Value>(&self.arguments).map_err(|error| {
-            SwarmError::ValidationError(format!(
-                "Function call arguments must be valid JSON: {}",
-                error
-            ))
-        })?;
         Ok(())
     }
 }
@@ -1192,8 +3813,14 @@ impl<'de> Deserialize<'de> for FunctionCall {
     where
         D: Deserializer<'de>,
     {
+        // Model-written arguments are parsed in `Swarm::handle_function_call`,
+        // which can repair malformed JSON when configured to.
         let dto = FunctionCallDto::deserialize(deserializer)?;
-        Self::new(dto.name, dto.arguments).map_err(de::Error::custom)
+        let function_call = Self::from_parts_unchecked(dto.name, dto.arguments);
+        function_call
+            .validate_non_empty()
+            .map_err(de::Error::custom)?;
+        Ok(function_call)
     }
 }
 
@@ -1309,31 +3936,157 @@ impl ToolCallAccumulator {
     }
 }
 
+/// One part of a multi-modal user message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "ContentPartDto", from = "ContentPartDto")]
+pub enum ContentPart {
+    Text(String),
+    /// An image by URL or `data:` URI; `detail` is `low`, `high` or `auto`.
+    ImageUrl {
+        url: String,
+        detail: Option<String>,
+    },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text(text.into())
+    }
+
+    pub fn image_url(url: impl Into<String>) -> Self {
+        Self::ImageUrl {
+            url: url.into(),
+            detail: None,
+        }
+    }
+
+    pub fn is_image(&self) -> bool {
+        matches!(self, Self::ImageUrl { .. })
+    }
+
+    fn validate(&self) -> SwarmResult<()> {
+        match self {
+            Self::Text(text) if text.trim().is_empty() => Err(SwarmError::ValidationError(
+                "Text content parts cannot be empty".to_string(),
+            )),
+            Self::ImageUrl { url, .. } if url.trim().is_empty() => Err(
+                SwarmError::ValidationError("Image content parts require a url".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Chat completions wire format of a [`ContentPart`].
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPartDto {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrlDto },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ImageUrlDto {
+    url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl From<ContentPart> for ContentPartDto {
+    fn from(part: ContentPart) -> Self {
+        match part {
+            ContentPart::Text(text) => Self::Text { text },
+            ContentPart::ImageUrl { url, detail } => Self::ImageUrl {
+                image_url: ImageUrlDto { url, detail },
+            },
+        }
+    }
+}
+
+impl From<ContentPartDto> for ContentPart {
+    fn from(dto: ContentPartDto) -> Self {
+        match dto {
+            ContentPartDto::Text { text } => Self::Text(text),
+            ContentPartDto::ImageUrl { image_url } => Self::ImageUrl {
+                url: image_url.url,
+                detail: image_url.detail,
+            },
+        }
+    }
+}
+
+/// Text parts joined by newlines, or `None` for image-only content.
+fn text_of_parts(parts: &[ContentPart]) -> Option<String> {
+    let text = parts
+        .iter()
+        .filter_map(|part| match part {
+            ContentPart::Text(text) => Some(text.as_str()),
+            ContentPart::ImageUrl { .. } => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!text.is_empty()).then_some(text)
+}
+
 /// Represents a chat message.
-#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Message {
     role: MessageRole,
-    #[serde(skip_serializing_if = "Option::is_none")]
     content: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
+    /// Multi-modal content; serialized as `content` in place of the text.
+    content_parts: Option<Vec<ContentPart>>,
     name: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
     function_call: Option<FunctionCall>,
     /// Multi-call tool invocations (OpenAI tool_calls API). Present on assistant messages.
-    #[serde(skip_serializing_if = "Option::is_none")]
     tool_calls: Option<Vec<ToolCall>>,
     /// Links a tool-role result message back to its originating call id.
-    #[serde(skip_serializing_if = "Option::is_none")]
     tool_call_id: Option<String>,
     /// Transient streaming accumulator — never serialized or deserialized.
-    #[serde(skip)]
     tool_call_accumulators: HashMap<usize, ToolCallAccumulator>,
+    /// Agent that dispatched a tool result. Tool messages cannot carry a `name`,
+    /// so this attribution stays local — never serialized or deserialized.
+    author: Option<String>,
+}
+
+impl Serialize for Message {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_map(None)?;
+        state.serialize_entry("role", &self.role)?;
+        if let Some(parts) = &self.content_parts {
+            state.serialize_entry("content", parts)?;
+        } else if let Some(content) = &self.content {
+            state.serialize_entry("content", content)?;
+        }
+        if let Some(name) = &self.name {
+            state.serialize_entry("name", name)?;
+        }
+        if let Some(function_call) = &self.function_call {
+            state.serialize_entry("function_call", function_call)?;
+        }
+        if let Some(tool_calls) = &self.tool_calls {
+            state.serialize_entry("tool_calls", tool_calls)?;
+        }
+        if let Some(tool_call_id) = &self.tool_call_id {
+            state.serialize_entry("tool_call_id", tool_call_id)?;
+        }
+        state.end()
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MessageContentDto {
+    Text(String),
+    Parts(Vec<ContentPart>),
 }
 
 #[derive(Deserialize)]
 struct MessageDto {
     role: MessageRole,
-    content: Option<String>,
+    content: Option<MessageContentDto>,
     name: Option<String>,
     function_call: Option<FunctionCall>,
     #[serde(default)]
@@ -1356,7 +4109,9 @@ impl Message {
             function_call,
             tool_calls: None,
             tool_call_id: None,
+            content_parts: None,
             tool_call_accumulators: HashMap::new(),
+            author: None,
         };
         message.validate()?;
         Ok(message)
@@ -1376,7 +4131,9 @@ impl Message {
             function_call: None,
             tool_calls: Some(tool_calls),
             tool_call_id: None,
+            content_parts: None,
             tool_call_accumulators: HashMap::new(),
+            author: None,
         };
         message.validate()?;
         Ok(message)
@@ -1394,7 +4151,9 @@ impl Message {
             function_call: None,
             tool_calls: None,
             tool_call_id: Some(tool_call_id.into()),
+            content_parts: None,
             tool_call_accumulators: HashMap::new(),
+            author: None,
         };
         message.validate()?;
         Ok(message)
@@ -1408,6 +4167,23 @@ impl Message {
         Self::new(MessageRole::User, Some(content.into()), None, None)
     }
 
+    /// A user message made of text and image parts, for vision-capable models.
+    pub fn user_with_parts(parts: Vec<ContentPart>) -> SwarmResult<Self> {
+        let message = Self {
+            role: MessageRole::User,
+            content: text_of_parts(&parts),
+            content_parts: Some(parts),
+            name: None,
+            function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+            tool_call_accumulators: HashMap::new(),
+            author: None,
+        };
+        message.validate()?;
+        Ok(message)
+    }
+
     pub fn assistant(content: impl Into<String>) -> SwarmResult<Self> {
         Self::new(MessageRole::Assistant, Some(content.into()), None, None)
     }
@@ -1445,10 +4221,27 @@ impl Message {
         self.content.as_deref()
     }
 
+    pub fn content_parts(&self) -> Option<&[ContentPart]> {
+        self.content_parts.as_deref()
+    }
+
+    /// Whether any content part is an image.
+    pub fn has_images(&self) -> bool {
+        self.content_parts
+            .iter()
+            .flatten()
+            .any(ContentPart::is_image)
+    }
+
     pub fn name(&self) -> Option<&str> {
         self.name.as_deref()
     }
 
+    /// The agent that dispatched this tool result, when agent attribution is enabled.
+    pub fn author(&self) -> Option<&str> {
+        self.author.as_deref()
+    }
+
     pub fn function_call(&self) -> Option<&FunctionCall> {
         self.function_call.as_ref()
     }
@@ -1457,6 +4250,11 @@ impl Message {
         self.tool_calls.as_deref()
     }
 
+    /// Whether this message asks for a function or tool call.
+    pub(crate) fn calls_tools(&self) -> bool {
+        self.function_call.is_some() || self.tool_calls().is_some_and(|calls| !calls.is_empty())
+    }
+
     pub fn tool_call_id(&self) -> Option<&str> {
         self.tool_call_id.as_deref()
     }
@@ -1476,10 +4274,26 @@ impl Message {
                 ));
             }
         }
+        if let Some(parts) = &self.content_parts {
+            if self.role != MessageRole::User {
+                return Err(SwarmError::ValidationError(format!(
+                    "{} messages cannot include content parts",
+                    self.role
+                )));
+            }
+            if parts.is_empty() {
+                return Err(SwarmError::ValidationError(
+                    "Message content parts cannot be empty".to_string(),
+                ));
+            }
+            for part in parts {
+                part.validate()?;
+            }
+        }
 
         match self.role {
             MessageRole::System | MessageRole::User => {
-                if self.content.is_none() {
+                if self.content.is_none() && self.content_parts.is_none() {
                     return Err(SwarmError::ValidationError(format!(
                         "{} messages require content",
                         self.role
@@ -1609,7 +4423,84 @@ impl Message {
             function_call,
             tool_calls: None,
             tool_call_id: None,
+            content_parts: None,
             tool_call_accumulators: HashMap::new(),
+            author: None,
+        }
+    }
+
+    pub(crate) fn set_name(&mut self, name: impl Into<String>) {
+        self.name = Some(name.into());
+    }
+
+    pub(crate) fn set_author(&mut self, author: impl Into<String>) {
+        self.author = Some(author.into());
+    }
+
+    /// Replaces the text content. Multi-modal content keeps its images, with the
+    /// new text in place of the old text parts.
+    pub(crate) fn set_content(&mut self, content: impl Into<String>) {
+        let content = content.into();
+        if let Some(parts) = &mut self.content_parts {
+            let at = parts.iter().position(|part| !part.is_image()).unwrap_or(0);
+            parts.retain(ContentPart::is_image);
+            parts.insert(at, ContentPart::Text(content.clone()));
+        }
+        self.content = Some(content);
+    }
+
+    /// Removes a leading `prefix` from the content, unless nothing would remain.
+    pub(crate) fn strip_content_prefix(&mut self, prefix: &str) {
+        let Some(content) = &self.content else {
+            return;
+        };
+        if let Some(rest) = content.strip_prefix(prefix) {
+            let rest = rest.trim_start();
+            if !rest.is_empty() {
+                self.content = Some(rest.to_string());
+            }
+        }
+    }
+
+    /// Cuts text content longer than `max_len` bytes to fit, ending it with an ellipsis.
+    ///
+    /// Multi-modal content keeps its images; text parts past the limit are dropped.
+    pub(crate) fn truncate_content(&mut self, max_len: usize) {
+        if let Some(parts) = &mut self.content_parts {
+            if self
+                .content
+                .as_ref()
+                .is_some_and(|text| text.len() > max_len)
+            {
+                // Room for the ellipsis is kept back unless the limit cannot hold it.
+                let ellipsis = if max_len < '…'.len_utf8() {
+                    0
+                } else {
+                    '…'.len_utf8()
+                };
+                let mut remaining = max_len - ellipsis;
+                parts.retain_mut(|part| match part {
+                    ContentPart::ImageUrl { .. } => true,
+                    ContentPart::Text(_) if remaining == 0 => false,
+                    ContentPart::Text(text) => {
+                        if text.len() > remaining {
+                            *text = truncate_to_size(text, remaining + ellipsis);
+                            remaining = 0;
+                        } else {
+                            // Parts are joined by a newline.
+                            remaining = remaining.saturating_sub(text.len() + 1);
+                        }
+                        true
+                    }
+                });
+                self.content = text_of_parts(parts);
+            }
+            return;
+        }
+        if let Some(content) = &self.content {
+            if content.len() > max_len {
+                self.content = Some(truncate_to_size(content, max_len));
+            }
         }
     }
 
@@ -1664,14 +4555,21 @@ impl<'de> Deserialize<'de> for Message {
         D: Deserializer<'de>,
     {
         let dto = MessageDto::deserialize(deserializer)?;
+        let (content, content_parts) = match dto.content {
+            Some(MessageContentDto::Text(text)) => (Some(text), None),
+            Some(MessageContentDto::Parts(parts)) => (text_of_parts(&parts), Some(parts)),
+            None => (None, None),
+        };
         let msg = Self {
             role: dto.role,
-            content: dto.content,
+            content,
+            content_parts,
             name: dto.name,
             function_call: dto.function_call,
             tool_calls: dto.tool_calls,
             tool_call_id: dto.tool_call_id,
             tool_call_accumulators: HashMap::new(),
+            author: None,
         };
         msg.validate().map_err(de::Error::custom)?;
         Ok(msg)
@@ -1686,6 +4584,8 @@ pub struct ChatCompletionResponse {
     created: u64,
     choices: Vec<Choice>,
     usage: Option<Usage>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_fingerprint: Option<String>,
 }
 
 impl ChatCompletionResponse {
@@ -1697,6 +4597,7 @@ impl ChatCompletionResponse {
             created: 0,
             choices: Vec::new(),
             usage: None,
+            system_fingerprint: None,
         }
     }
 
@@ -1715,6 +4616,15 @@ impl ChatCompletionResponse {
     pub fn usage(&self) -> Option<&Usage> {
         self.usage.as_ref()
     }
+
+    /// Backend configuration the completion was generated with.
+    pub fn system_fingerprint(&self) -> Option<&str> {
+        self.system_fingerprint.as_deref()
+    }
+
+    pub(crate) fn set_system_fingerprint(&mut self, fingerprint: Option<String>) {
+        self.system_fingerprint = fingerprint;
+    }
 }
 
 /// The reason the model stopped generating tokens.
@@ -1770,6 +4680,9 @@ pub struct Choice {
     pub index: u32,
     pub message: Message,
     pub finish_reason: Option<FinishReason>,
+    /// Token log probabilities, present when the request asked for them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Value>,
 }
 
 impl<'de> Deserialize<'de> for Choice {
@@ -1813,11 +4726,13 @@ impl<'de> Deserialize<'de> for Choice {
         } else {
             return Err(de::Error::missing_field("message (or delta)"));
         };
+        let logprobs = value.get("logprobs").filter(|v| !v.is_null()).cloned();
 
         Ok(Choice {
             index,
             message,
             finish_reason,
+            logprobs,
         })
     }
 }
@@ -1831,16 +4746,181 @@ pub struct Usage {
 }
 
 /// Represents a complete chat response.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct Response {
     pub messages: Vec<Message>,
     pub agent: Option<Agent>,
     pub context_variables: ContextVariables,
     pub termination_reason: Option<TerminationReason>,
     pub tokens_used: u32,
+    /// Agent handoffs and model switches made during the run, in order.
+    pub handoff_history: Vec<HandoffRecord>,
+    /// Timing report when the run had `RunOptions::with_profiling` enabled.
+    pub profile: Option<ProfileReport>,
+    /// One entry per conversation turn, in order.
+    pub turn_metadata: Vec<TurnMetadata>,
+    /// Execution trace when the run had `RunOptions::with_execution_trace` enabled.
+    pub trace: Option<Vec<TraceEntry>>,
+    /// Labelled copies of the context variables, oldest first; see
+    /// `SwarmConfig::context_snapshot_interval`.
+    pub context_snapshots: VecDeque<(String, ContextVariables)>,
+    /// Snapshots saved by `branch` steps, by branch name.
+    pub branches: HashMap<String, BranchPoint>,
+    /// `system_fingerprint` of the latest completion that reported one.
+    pub system_fingerprint: Option<String>,
+    /// HTTP requests the run sent; see [`Response::total_api_calls`].
+    pub api_calls: usize,
+}
+
+/// A function call that failed, as sent to [`SwarmBuilder::with_dead_letter_queue`](crate::SwarmBuilder::with_dead_letter_queue).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetterEntry {
+    pub agent_name: String,
+    pub function_name: String,
+    /// Arguments as sent by the model; empty when they were not a JSON object.
+    pub arguments: ContextVariables,
+    pub error: String,
+    pub timestamp: SystemTime,
+}
+
+/// History and context variables saved by a `branch` step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BranchPoint {
+    pub history: Vec<Message>,
+    pub context_variables: ContextVariables,
+}
+
+/// How a `merge` step combines a branch's context variables with the current ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BranchMergeStrategy {
+    /// Only add keys the current context lacks.
+    #[default]
+    KeepCurrent,
+    /// Overwrite current values with the branch's.
+    PreferBranch,
+}
+
+impl Response {
+    /// The run's history, agent, context and handoffs so far, counting every token
+    /// the run has used. Per-run reports (profile, trace, turn metadata, ...) are
+    /// left empty.
+    pub(crate) fn from_state(
+        state: &RunState,
+        termination_reason: Option<TerminationReason>,
+    ) -> Self {
+        Response {
+            messages: state.history.clone(),
+            agent: Some(state.agent.clone()),
+            context_variables: state.context_variables.clone(),
+            termination_reason,
+            tokens_used: state.total_tokens,
+            handoff_history: state.handoff_history.clone(),
+            ..Response::default()
+        }
+    }
+
+    /// HTTP requests the run sent, counting every retry and side request such as
+    /// summaries and evaluations. Replies served from the response cache are not
+    /// counted.
+    pub fn total_api_calls(&self) -> usize {
+        self.api_calls
+    }
+
+    /// Names of the agents that ran a turn, in order of first use.
+    pub fn agents_used(&self) -> Vec<String> {
+        let mut agents: Vec<String> = Vec::new();
+        for turn in &self.turn_metadata {
+            if !agents.contains(&turn.agent_name) {
+                agents.push(turn.agent_name.clone());
+            }
+        }
+        agents
+    }
+
+    /// Raw `logprobs` payload of each turn, in order; `None` for turns whose
+    /// agent had no [`LogprobsConfig`] or whose provider returned none.
+    pub fn extract_logprobs(&self) -> Vec<Option<&Value>> {
+        self.turn_metadata
+            .iter()
+            .map(|turn| turn.logprobs.as_ref())
+            .collect()
+    }
+
+    /// Assistant messages written, and tool results dispatched, by the agent named
+    /// `agent_name`.
+    pub fn messages_by_agent(&self, agent_name: &str) -> Vec<&Message> {
+        let name = message_name(agent_name);
+        self.messages
+            .iter()
+            .filter(|message| match message.role() {
+                MessageRole::Assistant => message.name() == Some(name.as_str()),
+                MessageRole::Tool => message.author() == Some(agent_name),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Every message in order, paired with its author: the agent name for named
+    /// assistant messages, otherwise the role (`user`, `tool`, ...).
+    pub fn conversation_timeline(&self) -> Vec<(String, &Message)> {
+        self.messages
+            .iter()
+            .map(|message| {
+                let author = match (message.role(), message.name()) {
+                    (MessageRole::Assistant, Some(name)) => name.to_string(),
+                    (role, _) => role.to_string(),
+                };
+                (author, message)
+            })
+            .collect()
+    }
+}
+
+/// What happened in one conversation turn: one assistant reply plus the
+/// functions it called.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TurnMetadata {
+    /// 1-based position of the turn in the run.
+    pub turn: usize,
+    pub agent_name: String,
+    pub model: String,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub duration_ms: u64,
+    /// Functions and tools the assistant called, in order.
+    pub function_calls: Vec<String>,
+    /// The choice's `logprobs` payload as returned by the provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Value>,
+    /// Estimated prompt tokens before and after prompt compression, when the
+    /// turn's requests were compressed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_compression: Option<PromptCompressionStats>,
+}
+
+/// Estimated prompt sizes of a request shrunk by [`PromptCompressionConfig`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PromptCompressionStats {
+    pub original_tokens: u32,
+    pub compressed_tokens: u32,
+}
+
+/// One change of agent or model recorded in [`Response::handoff_history`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "kind")]
+pub enum HandoffRecord {
+    /// A function returned `ResultType::Agent`.
+    Agent { from: String, to: String },
+    /// A `switch_model` step ran.
+    Model {
+        step: usize,
+        from: String,
+        to: String,
+    },
 }
 
-/// Represents a collection of steps parsed from XML.
+/// Represents a collection of steps parsed from a steps definition.
 #[derive(Debug, Deserialize)]
 pub struct Steps {
     #[serde(rename = "step", default)]
@@ -1853,6 +4933,21 @@ pub struct Steps {
 pub enum StepAction {
     RunOnce,
     Loop,
+    /// Changes the model for the rest of the workflow without calling the model.
+    SwitchModel,
+    /// Scores the last assistant message with an evaluator agent, re-running the
+    /// previous step with the feedback while the score is below `min_score`.
+    Evaluate,
+    /// Saves the history and context variables as `branch_name` without calling the model.
+    Branch,
+    /// Merges the context variables saved by the `from_branch` step into the current
+    /// ones, per `SwarmConfig::branch_merge_strategy`.
+    Merge,
+    /// Replaces the conversation with a summary from the step's agent, keeping a
+    /// leading system message and the last `preserve_last` messages.
+    SummarizeHistory,
+    /// Like `loop`, but stops once the last assistant message matches `until_pattern`.
+    RepeatUntil,
 }
 
 impl fmt::Display for StepAction {
@@ -1860,6 +4955,12 @@ impl fmt::Display for StepAction {
         match self {
             Self::RunOnce => write!(f, "run_once"),
             Self::Loop => write!(f, "loop"),
+            Self::SwitchModel => write!(f, "switch_model"),
+            Self::Evaluate => write!(f, "evaluate"),
+            Self::Branch => write!(f, "branch"),
+            Self::Merge => write!(f, "merge"),
+            Self::SummarizeHistory => write!(f, "summarize_history"),
+            Self::RepeatUntil => write!(f, "repeat_until"),
         }
     }
 }
@@ -1867,22 +4968,140 @@ impl fmt::Display for StepAction {
 /// A single step in a steps definition.
 #[derive(Debug, Deserialize)]
 pub struct Step {
-    #[serde(rename = "@number")]
+    #[serde(rename = "@number", alias = "number")]
     pub number: usize,
-    #[serde(rename = "@action")]
+    #[serde(rename = "@action", alias = "action")]
     pub action: StepAction,
-    #[serde(rename = "@agent")]
+    #[serde(rename = "@agent", alias = "agent")]
     pub agent: Option<String>,
+    /// Context variable that receives the step's final assistant text.
+    #[serde(rename = "@output_var", alias = "output_var", default)]
+    pub output_var: Option<String>,
+    /// Upper bound on turns a `loop` step may consume; still capped by the run's remaining turns.
+    #[serde(rename = "@max_turns_per_step", alias = "max_turns_per_step", default)]
+    pub max_turns_per_step: Option<usize>,
+    /// Context variable that must be present and non-empty before the step runs.
+    #[serde(rename = "@precondition", alias = "precondition", default)]
+    pub precondition: Option<String>,
+    /// Context variable that must be present and non-empty after the step runs.
+    #[serde(rename = "@postcondition", alias = "postcondition", default)]
+    pub postcondition: Option<String>,
+    /// Model a `switch_model` step moves the workflow to.
+    #[serde(rename = "@new_model", alias = "new_model", default)]
+    pub new_model: Option<String>,
+    /// Registered agent that scores output for an `evaluate` step.
+    #[serde(rename = "@evaluator_agent", alias = "evaluator_agent", default)]
+    pub evaluator_agent: Option<String>,
+    /// Score below which an `evaluate` step re-runs the previous step.
+    #[serde(rename = "@min_score", alias = "min_score", default)]
+    pub min_score: Option<f32>,
+    /// Re-runs an `evaluate` step may trigger before accepting the output.
+    #[serde(rename = "@max_retry", alias = "max_retry", default)]
+    pub max_retry: Option<usize>,
+    /// Characters below which a `run_once` or `loop` step's reply is re-asked.
+    #[serde(
+        rename = "@min_response_length",
+        alias = "min_response_length",
+        default
+    )]
+    pub min_response_length: Option<usize>,
+    /// Re-asks a too-short reply may trigger; each one uses a turn of the run.
+    #[serde(
+        rename = "@short_response_max_retries",
+        alias = "short_response_max_retries",
+        default
+    )]
+    pub short_response_max_retries: Option<usize>,
+    /// Overrides `SwarmConfig::max_context_tokens_per_step` for this step.
+    #[serde(
+        rename = "@max_context_tokens_per_step",
+        alias = "max_context_tokens_per_step",
+        default
+    )]
+    pub max_context_tokens_per_step: Option<u32>,
+    /// `<assert key=".." value=".."/>`: context values the step must leave behind.
+    #[serde(
+        rename = "assert",
+        default,
+        deserialize_with = "deserialize_step_assertions"
+    )]
+    pub assert_context: HashMap<String, String>,
+    /// `<assert_contains key=".." value=".."/>`: substrings context values must hold
+    /// after the step.
+    #[serde(
+        rename = "assert_contains",
+        default,
+        deserialize_with = "deserialize_step_assertions"
+    )]
+    pub assert_contains: Vec<(String, String)>,
+    /// Name a `branch` step saves its snapshot under.
+    #[serde(rename = "@branch_name", alias = "branch_name", default)]
+    pub branch_name: Option<String>,
+    /// Branch a `merge` step takes context variables from.
+    #[serde(rename = "@from_branch", alias = "from_branch", default)]
+    pub from_branch: Option<String>,
+    /// Fail the step when its prompt has a `{{key}}` placeholder with no context
+    /// variable; otherwise such placeholders are sent as written.
+    #[serde(rename = "@template_strict", alias = "template_strict", default)]
+    pub template_strict: bool,
+    /// Messages a `summarize_history` step keeps verbatim after the summary.
+    #[serde(rename = "@preserve_last", alias = "preserve_last", default)]
+    pub preserve_last: Option<usize>,
+    /// Regex a `repeat_until` step waits for in the last assistant message.
+    #[serde(rename = "@until_pattern", alias = "until_pattern", default)]
+    pub until_pattern: Option<String>,
+    /// Overrides the run's `debug` flag while this step executes.
+    #[serde(rename = "@debug", alias = "debug", default)]
+    pub debug: Option<bool>,
+    /// Required for every action except `switch_model`, `branch`, `merge` and
+    /// `summarize_history`, which replaces its default instruction with it. For `evaluate` it holds
+    /// optional criteria for the evaluator.
+    #[serde(default)]
     pub prompt: String,
 }
 
+/// One `<assert>` or `<assert_contains>` element of a step.
+#[derive(Deserialize)]
+struct StepAssertion {
+    #[serde(rename = "@key", alias = "key")]
+    key: String,
+    #[serde(rename = "@value", alias = "value")]
+    value: String,
+}
+
+fn deserialize_step_assertions<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromIterator<(String, String)>,
+{
+    let assertions = Vec::<StepAssertion>::deserialize(deserializer)?;
+    Ok(assertions
+        .into_iter()
+        .map(|assertion| (assertion.key, assertion.value))
+        .collect())
+}
+
+/// Randomization applied to each computed retry delay, so concurrent callers
+/// that fail together do not retry together.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JitterStrategy {
+    #[default]
+    None,
+    /// Uniform in `[0, delay]`.
+    Full,
+    /// Uniform in `[delay / 2, delay]`.
+    Equal,
+}
+
 /// Strategy used for retrying failed API calls.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct RetryStrategy {
     max_retries: u32,
     initial_delay: Duration,
     max_delay: Duration,
     backoff_factor: f32,
+    jitter: JitterStrategy,
 }
 
 impl RetryStrategy {
@@ -1917,9 +5136,15 @@ impl RetryStrategy {
             initial_delay,
             max_delay,
             backoff_factor,
+            jitter: JitterStrategy::None,
         })
     }
 
+    pub fn with_jitter(mut self, jitter: JitterStrategy) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
     pub fn max_retries(&self) -> u32 {
         self.max_retries
     }
@@ -1932,6 +5157,31 @@ impl RetryStrategy {
     pub fn backoff_factor(&self) -> f32 {
         self.backoff_factor
     }
+    pub fn jitter(&self) -> JitterStrategy {
+        self.jitter
+    }
+
+    /// Delay before retry number `attempt` (0 for the first retry): exponential
+    /// backoff from `initial_delay`, capped at `max_delay`, then jittered.
+    pub fn compute_delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.min(i32::MAX as u32) as i32;
+        let backoff =
+            self.initial_delay.as_secs_f64() * (self.backoff_factor as f64).powi(exponent);
+        let delay = if backoff.is_finite() {
+            Duration::from_secs_f64(backoff.min(self.max_delay.as_secs_f64()))
+        } else {
+            self.max_delay
+        };
+        match self.jitter {
+            JitterStrategy::None => delay,
+            JitterStrategy::Full => delay.mul_f64(random_unit()),
+            JitterStrategy::Equal => delay / 2 + (delay / 2).mul_f64(random_unit()),
+        }
+    }
+
+    pub(crate) fn set_jitter(&mut self, jitter: JitterStrategy) {
+        self.jitter = jitter;
+    }
 
     pub(crate) fn set_max_retries(&mut self, value: u32) -> SwarmResult<()> {
         if value == 0 {
@@ -1944,8 +5194,16 @@ impl RetryStrategy {
     }
 }
 
+/// Uniform sample from `[0, 1)`, drawn from the random bits of a v4 UUID.
+fn random_unit() -> f64 {
+    // The low 64 bits start with the two fixed variant bits; only the 62 below
+    // them are random.
+    let random = uuid::Uuid::new_v4().as_u128() as u64 & ((1u64 << 62) - 1);
+    (random >> 9) as f64 / (1u64 << 53) as f64
+}
+
 /// Timeout settings used for API calls.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct TimeoutSettings {
     request_timeout: Duration,
     connect_timeout: Duration,
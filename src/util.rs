@@ -4,14 +4,18 @@ use crate::error::{SwarmError, SwarmResult};
 ///
 /// This module provides various helper functions for debugging, message handling,
 /// XML processing, and function conversion utilities.
-use crate::types::{AgentFunction, Message, RetryStrategy, Steps};
+use crate::types::{
+    AgentFunction, ContextVariables, Message, MessageRole, RetryStrategy, StepAction, Steps,
+    XmlEncoding,
+};
 use quick_xml::de::from_str as xml_from_str;
 use regex::Regex;
 use serde_json::{json, Value};
+use std::collections::HashSet;
 use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::OnceLock;
-use std::time::Duration;
 
 /// Prints debug messages when debug mode is enabled
 ///
@@ -29,6 +33,119 @@ pub fn debug_print(debug: bool, message: &str) {
     }
 }
 
+/// Rough prompt size for budgeting: ~4 bytes per token, 4 tokens for messages without content.
+///
+/// Actual counts come from the API response's `usage`.
+pub(crate) fn estimate_prompt_tokens<'a>(messages: impl IntoIterator<Item = &'a Message>) -> u32 {
+    messages
+        .into_iter()
+        .map(|m| m.content().map(|c| (c.len() / 4) as u32).unwrap_or(4))
+        .sum()
+}
+
+/// Drops the oldest messages after the first non-system one until `messages`
+/// fit `max_tokens`, keeping the newest message.
+///
+/// Tool and function results go with the message before them, and `droppable`
+/// decides by that message.
+pub(crate) fn truncate_middle(
+    messages: &mut Vec<Message>,
+    max_tokens: u32,
+    droppable: impl Fn(&Message) -> bool,
+) {
+    let is_result = |m: &Message| matches!(m.role(), MessageRole::Function | MessageRole::Tool);
+    let mut start = messages
+        .iter()
+        .take_while(|m| m.role() == MessageRole::System)
+        .count()
+        + 1;
+    while start < messages.len() && is_result(&messages[start]) {
+        start += 1;
+    }
+    while start < messages.len() && estimate_prompt_tokens(messages.iter()) > max_tokens {
+        let mut end = start + 1;
+        while end < messages.len() && is_result(&messages[end]) {
+            end += 1;
+        }
+        if end >= messages.len() {
+            break;
+        }
+        if droppable(&messages[start]) {
+            messages.drain(start..end);
+        } else {
+            start = end;
+        }
+    }
+}
+
+fn placeholder_regex() -> &'static Regex {
+    static PLACEHOLDER_RE: OnceLock<Regex> = OnceLock::new();
+    PLACEHOLDER_RE.get_or_init(|| {
+        Regex::new(r"\{\{\s*([A-Za-z0-9_.\-]+)\s*\}\}")
+            .expect("static placeholder regex must compile")
+    })
+}
+
+/// Replaces each `{{name}}` in `template` with its value from `values`.
+///
+/// Whitespace inside the braces is ignored; placeholders without a value are
+/// left as they are.
+pub(crate) fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    placeholder_regex()
+        .replace_all(template, |captures: &regex::Captures| {
+            values
+                .iter()
+                .find(|(name, _)| *name == &captures[1])
+                .map_or_else(|| captures[0].to_string(), |(_, value)| value.to_string())
+        })
+        .into_owned()
+}
+
+/// Keys of the `{{key}}` placeholders in `template` with no context variable, in order.
+pub fn unresolved_placeholders(
+    template: &str,
+    context_variables: &ContextVariables,
+) -> Vec<String> {
+    let mut missing: Vec<String> = Vec::new();
+    for captures in placeholder_regex().captures_iter(template) {
+        let key = &captures[1];
+        if !context_variables.contains_key(key) && !missing.iter().any(|seen| seen == key) {
+            missing.push(key.to_string());
+        }
+    }
+    missing
+}
+
+/// Value at the JSON Pointer `path` (such as `/usage/total_tokens`) in `value`.
+///
+/// Strings are returned without quotes, other values as JSON text; a missing path
+/// or `null` gives `None`.
+pub fn json_get_path(value: &Value, path: &str) -> Option<String> {
+    match value.pointer(path)? {
+        Value::Null => None,
+        Value::String(text) => Some(text.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+/// Jaccard similarity of the lowercase word sets of `a` and `b`, from 0.0 to 1.0.
+///
+/// Words are runs of alphanumeric characters. Two texts without words are identical (1.0).
+pub fn jaccard_similarity(a: &str, b: &str) -> f32 {
+    fn words(text: &str) -> HashSet<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .collect()
+    }
+    let (a, b) = (words(a), words(b));
+    let union = a.union(&b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(&b).count() as f32 / union as f32
+}
+
 /// Merges a delta message chunk into an existing message
 ///
 /// Used for handling streaming responses where message content arrives in chunks.
@@ -82,6 +199,106 @@ pub fn function_to_json(func: &AgentFunction) -> SwarmResult<Value> {
     }))
 }
 
+/// Repairs common mistakes in model-written JSON: unquoted keys, single-quoted
+/// strings, trailing commas and missing closing brackets or quotes.
+///
+/// # Errors
+///
+/// Returns [`SwarmError::DeserializationError`] when the result still is not valid JSON.
+pub fn repair_json(s: &str) -> SwarmResult<String> {
+    fn drop_trailing_comma(out: &mut String) {
+        let trimmed = out.trim_end();
+        if trimmed.ends_with(',') {
+            out.truncate(trimmed.len() - 1);
+        }
+    }
+
+    let chars: Vec<char> = s.chars().collect();
+    let mut out = String::with_capacity(s.len() + 8);
+    let mut closers: Vec<char> = Vec::new();
+    // Quote character of the string being copied, if any.
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if let Some(q) = quote {
+            match c {
+                '\\' if i + 1 < chars.len() => {
+                    // `\'` is not a valid JSON escape; an apostrophe needs none.
+                    if chars[i + 1] != '\'' {
+                        out.push('\\');
+                    }
+                    out.push(chars[i + 1]);
+                    i += 1;
+                }
+                '"' if q == '\'' => out.push_str("\\\""),
+                c if c == q => {
+                    out.push('"');
+                    quote = None;
+                }
+                c => out.push(c),
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            '"' | '\'' => {
+                quote = Some(c);
+                out.push('"');
+            }
+            '{' => {
+                closers.push('}');
+                out.push(c);
+            }
+            '[' => {
+                closers.push(']');
+                out.push(c);
+            }
+            '}' | ']' => {
+                drop_trailing_comma(&mut out);
+                if closers.last() == Some(&c) {
+                    closers.pop();
+                }
+                out.push(c);
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let start = i;
+                while i + 1 < chars.len()
+                    && (chars[i + 1].is_alphanumeric() || matches!(chars[i + 1], '_' | '$' | '-'))
+                {
+                    i += 1;
+                }
+                let word: String = chars[start..=i].iter().collect();
+                let is_key = chars[i + 1..]
+                    .iter()
+                    .find(|c| !c.is_whitespace())
+                    .is_some_and(|c| *c == ':');
+                if is_key {
+                    out.push('"');
+                    out.push_str(&word);
+                    out.push('"');
+                } else {
+                    out.push_str(&word);
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    if quote.is_some() {
+        out.push('"');
+    }
+    drop_trailing_comma(&mut out);
+    while let Some(closer) = closers.pop() {
+        drop_trailing_comma(&mut out);
+        out.push(closer);
+    }
+
+    serde_json::from_str::<Value>(&out)
+        .map_err(|e| SwarmError::DeserializationError(format!("Could not repair JSON: {}", e)))?;
+    Ok(out)
+}
+
 /// Parses XML content into a Steps structure
 ///
 /// Converts XML-formatted step definitions into a structured Steps object
@@ -107,15 +324,294 @@ pub fn function_to_json(func: &AgentFunction) -> SwarmResult<Value> {
 pub fn parse_steps_from_xml(xml_content: &str) -> SwarmResult<Steps> {
     let steps: Steps = xml_from_str(xml_content)
         .map_err(|e| SwarmError::XmlError(format!("Failed to parse XML steps: {}", e)))?;
-    for step in &steps.steps {
-        if step.prompt.trim().is_empty() {
+    validate_steps(&steps)?;
+    Ok(steps)
+}
+
+/// Replaces every `<include file="relative/path.xml"/>` in a `<steps>` block with
+/// the `<step>` elements of the referenced file.
+///
+/// Paths are relative to `base_dir`; paths inside an included file are relative to
+/// that file's directory. An included file may hold a full `<steps>` document or
+/// bare `<step>` elements, and may itself include further files. Including the
+/// same file twice is fine; a file that (indirectly) includes itself is rejected.
+/// Includes may not leave `base_dir`: absolute paths are rejected, as are relative
+/// paths that resolve (through `..` or symlinks) outside it.
+///
+/// # Errors
+///
+/// Returns [`SwarmError::XmlError`] when `base_dir` or an included file cannot be
+/// read, an include is circular, or an include points outside `base_dir`.
+pub fn resolve_xml_includes(content: &str, base_dir: &Path) -> SwarmResult<String> {
+    resolve_xml_includes_with_encoding(content, base_dir, XmlEncoding::Utf8)
+}
+
+/// [`resolve_xml_includes`] for included files stored in `encoding`.
+pub fn resolve_xml_includes_with_encoding(
+    content: &str,
+    base_dir: &Path,
+    encoding: XmlEncoding,
+) -> SwarmResult<String> {
+    let root = base_dir.canonicalize().map_err(|e| {
+        SwarmError::XmlError(format!(
+            "Failed to resolve steps base directory '{}': {}",
+            base_dir.display(),
+            e
+        ))
+    })?;
+    resolve_includes_from(content, &root, &root, encoding, &mut Vec::new())
+}
+
+/// Inlines the includes of `content`, resolving them against `base_dir` and keeping
+/// them inside `root`, the canonical directory the top-level document came from.
+fn resolve_includes_from(
+    content: &str,
+    base_dir: &Path,
+    root: &Path,
+    encoding: XmlEncoding,
+    stack: &mut Vec<PathBuf>,
+) -> SwarmResult<String> {
+    static INCLUDE_RE: OnceLock<Regex> = OnceLock::new();
+    static STEPS_BODY_RE: OnceLock<Regex> = OnceLock::new();
+    let include_re = INCLUDE_RE.get_or_init(|| {
+        Regex::new(r#"<include\s+file\s*=\s*"([^"]+)"\s*(?:/>|>\s*</include>)"#)
+            .expect("static include regex must compile")
+    });
+    let steps_body_re = STEPS_BODY_RE.get_or_init(|| {
+        Regex::new(r"(?s)<steps\b[^>]*>(.*)</steps>").expect("static steps regex must compile")
+    });
+
+    let mut resolved = String::with_capacity(content.len());
+    let mut last = 0;
+    for captures in include_re.captures_iter(content) {
+        let tag = captures.get(0).expect("match has a whole-match group");
+        let file = &captures[1];
+        if Path::new(file).is_absolute() {
+            return Err(SwarmError::XmlError(format!(
+                "Included steps '{}' must be a relative path",
+                file
+            )));
+        }
+        let path = base_dir.join(file).canonicalize().map_err(|e| {
+            SwarmError::XmlError(format!(
+                "Failed to resolve included steps '{}': {}",
+                file, e
+            ))
+        })?;
+        if !path.starts_with(root) {
+            return Err(SwarmError::XmlError(format!(
+                "Included steps '{}' are outside the steps base directory",
+                file
+            )));
+        }
+        if stack.contains(&path) {
+            return Err(SwarmError::XmlError(format!(
+                "Circular steps include: {}",
+                path.display()
+            )));
+        }
+        let included = std::fs::read(&path).map_err(|e| {
+            SwarmError::XmlError(format!("Failed to read included steps '{}': {}", file, e))
+        })?;
+        let included = decode_xml(&included, encoding)?;
+        let body = steps_body_re
+            .captures(&included)
+            .map_or(included.as_str(), |inner| {
+                inner.get(1).map_or("", |body| body.as_str())
+            });
+
+        let include_dir = path.parent().unwrap_or(base_dir).to_path_buf();
+        stack.push(path);
+        let body = resolve_includes_from(body, &include_dir, root, encoding, stack)?;
+        stack.pop();
+
+        resolved.push_str(&content[last..tag.start()]);
+        resolved.push_str(&body);
+        last = tag.end();
+    }
+    resolved.push_str(&content[last..]);
+    Ok(resolved)
+}
+
+/// Decodes XML read from a file, e.g. instructions holding a `<steps>` block.
+///
+/// # Errors
+///
+/// Returns [`SwarmError::XmlError`] when `bytes` are not valid UTF-8 under
+/// [`XmlEncoding::Utf8`].
+pub fn decode_xml(bytes: &[u8], encoding: XmlEncoding) -> SwarmResult<String> {
+    match encoding {
+        XmlEncoding::Utf8 => String::from_utf8(bytes.to_vec())
+            .map_err(|e| SwarmError::XmlError(format!("XML is not valid UTF-8: {}", e))),
+        // WHATWG decodes ISO-8859-1 as its superset windows-1252.
+        XmlEncoding::Latin1 => {
+            let (text, _) = encoding_rs::WINDOWS_1252.decode_without_bom_handling(bytes);
+            Ok(text.into_owned())
+        }
+    }
+}
+
+/// Removes `prefix` from namespaced tags, so `<my:steps>` reads as `<steps>`.
+pub fn strip_xml_namespace(content: &str, prefix: &str) -> String {
+    content
+        .replace(&format!("</{}:", prefix), "</")
+        .replace(&format!("<{}:", prefix), "<")
+}
+
+/// Checks parsed steps for empty prompts, blank `output_var`s, blank conditions,
+/// zero turn limits and `evaluate` steps without an evaluator or a step to re-run.
+///
+/// Also warns when a postcondition names a variable that no step up to and
+/// including its own declares as `output_var`; such a postcondition can only be
+/// satisfied by a function writing the variable.
+///
+/// Every [`StepsParser`](crate::steps_parser::StepsParser) should run this on its output.
+pub fn validate_steps(steps: &Steps) -> SwarmResult<()> {
+    for (index, step) in steps.steps.iter().enumerate() {
+        for (kind, condition) in [
+            ("precondition", &step.precondition),
+            ("postcondition", &step.postcondition),
+        ] {
+            if condition.as_ref().is_some_and(|key| key.trim().is_empty()) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} has an empty {}",
+                    step.number, kind
+                )));
+            }
+        }
+        if let Some(postcondition) = &step.postcondition {
+            let produced = steps.steps[..=index]
+                .iter()
+                .any(|earlier| earlier.output_var.as_deref() == Some(postcondition.as_str()));
+            if !produced {
+                tracing::warn!(
+                    step = step.number,
+                    postcondition = %postcondition,
+                    "Step postcondition is not set by any step's output_var"
+                );
+            }
+        }
+        let assertion_keys = step
+            .assert_context
+            .keys()
+            .chain(step.assert_contains.iter().map(|(key, _)| key));
+        for key in assertion_keys {
+            if key.trim().is_empty() {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} has an assertion without a key",
+                    step.number
+                )));
+            }
+        }
+        if step.max_context_tokens_per_step == Some(0) {
+            return Err(SwarmError::ValidationError(format!(
+                "Step {} has a max_context_tokens_per_step of 0",
+                step.number
+            )));
+        }
+        if step.action == StepAction::SwitchModel {
+            let has_model = step
+                .new_model
+                .as_ref()
+                .is_some_and(|model| !model.trim().is_empty());
+            if !has_model {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} uses switch_model without a new_model",
+                    step.number
+                )));
+            }
+        } else if step.action == StepAction::Evaluate {
+            let has_evaluator = step
+                .evaluator_agent
+                .as_ref()
+                .is_some_and(|agent| !agent.trim().is_empty());
+            if !has_evaluator {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} uses evaluate without an evaluator_agent",
+                    step.number
+                )));
+            }
+            if step.min_score.is_some_and(|score| !score.is_finite()) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} has a non-finite min_score",
+                    step.number
+                )));
+            }
+            let previous = index.checked_sub(1).map(|previous| &steps.steps[previous]);
+            let can_rerun =
+                previous.is_some_and(|previous| previous.action != StepAction::Evaluate);
+            if step.min_score.is_some() && !can_rerun {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} must follow a step it can re-run",
+                    step.number
+                )));
+            }
+        } else if step.action == StepAction::Branch {
+            if step
+                .branch_name
+                .as_ref()
+                .is_none_or(|name| name.trim().is_empty())
+            {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} uses branch without a branch_name",
+                    step.number
+                )));
+            }
+        } else if step.action == StepAction::Merge {
+            let from_branch = step.from_branch.as_deref().unwrap_or_default();
+            let branched = steps.steps[..index].iter().any(|earlier| {
+                earlier.action == StepAction::Branch
+                    && earlier.branch_name.as_deref() == Some(from_branch)
+            });
+            if !branched {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} merges '{}', which no earlier branch step saves",
+                    step.number, from_branch
+                )));
+            }
+        } else if step.prompt.trim().is_empty() && step.action != StepAction::SummarizeHistory {
             return Err(SwarmError::ValidationError(format!(
                 "Step {} has an empty prompt",
                 step.number
             )));
         }
+        if let Some(output_var) = &step.output_var {
+            if output_var.trim().is_empty() {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} has an empty output_var",
+                    step.number
+                )));
+            }
+        }
+        if step.max_turns_per_step == Some(0) {
+            return Err(SwarmError::ValidationError(format!(
+                "Step {} has max_turns_per_step of 0",
+                step.number
+            )));
+        }
+        if step.min_response_length == Some(0) {
+            return Err(SwarmError::ValidationError(format!(
+                "Step {} has min_response_length of 0",
+                step.number
+            )));
+        }
+        if step.action == StepAction::RepeatUntil {
+            let pattern = step.until_pattern.as_deref().unwrap_or_default();
+            if pattern.is_empty() {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} uses repeat_until without an until_pattern",
+                    step.number
+                )));
+            }
+            if let Err(err) = Regex::new(pattern) {
+                return Err(SwarmError::ValidationError(format!(
+                    "Step {} has an invalid until_pattern: {}",
+                    step.number, err
+                )));
+            }
+        }
     }
-    Ok(steps)
+    Ok(())
 }
 
 /// Extracts XML step definitions from instructions text
@@ -158,6 +654,49 @@ pub fn extract_xml_steps(instructions: &str) -> SwarmResult<(String, Option<Stri
     Ok((instructions_without_xml.trim().to_string(), xml_steps))
 }
 
+/// [`extract_xml_steps`] that also finds a `<{namespace}:steps>` block. The block
+/// keeps its prefixes, so includes can be resolved before [`strip_xml_namespace`].
+pub(crate) fn extract_namespaced_xml_steps(
+    instructions: &str,
+    namespace: Option<&str>,
+) -> SwarmResult<(String, Option<String>)> {
+    let Some(namespace) = namespace else {
+        return extract_xml_steps(instructions);
+    };
+    let re = Regex::new(&format!(
+        r"(?s)<{0}:steps\b[^>]*>.*?</{0}:steps>",
+        regex::escape(namespace)
+    ))
+    .map_err(|e| SwarmError::XmlError(format!("Invalid xml_namespace '{}': {}", namespace, e)))?;
+    match re.find(instructions) {
+        Some(mat) => {
+            let mut instructions_without_xml = instructions.to_string();
+            instructions_without_xml.replace_range(mat.range(), "");
+            Ok((
+                instructions_without_xml.trim().to_string(),
+                Some(mat.as_str().to_string()),
+            ))
+        }
+        None => extract_xml_steps(instructions),
+    }
+}
+
+/// `agent_name` as a message `name`, which OpenAI limits to 64 characters from
+/// `[a-zA-Z0-9_-]`: other characters become `_` and the rest is cut off.
+pub(crate) fn message_name(agent_name: &str) -> String {
+    agent_name
+        .chars()
+        .take(64)
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
 /// Truncates a string to at most `max_len` **bytes**, appending "…" if truncated.
 ///
 /// The actual cut point may be ≤ `max_len` bytes when the byte at `max_len` falls
@@ -177,10 +716,28 @@ pub fn safe_truncate(s: &str, max_len: usize) -> String {
     }
 }
 
+/// Truncates a string so the result, ellipsis included, fits in `max_size` bytes.
+///
+/// When `max_size` is too small to hold the ellipsis the string is cut without one.
+pub(crate) fn truncate_to_size(s: &str, max_size: usize) -> String {
+    let ellipsis = '…'.len_utf8();
+    if s.len() <= max_size {
+        return s.to_string();
+    }
+    if max_size >= ellipsis {
+        return safe_truncate(s, max_size - ellipsis);
+    }
+    let truncate_at = (0..=max_size)
+        .rev()
+        .find(|&i| s.is_char_boundary(i))
+        .unwrap_or(0);
+    s[..truncate_at].to_string()
+}
+
 /// Retries an async operation according to the given [`RetryStrategy`].
 ///
-/// Only retries when [`SwarmError::is_retriable`] returns `true`. Uses
-/// exponential backoff capped at `strategy.max_delay`.
+/// Only retries when [`SwarmError::is_retriable`] returns `true`. Waits
+/// [`RetryStrategy::compute_delay`] between attempts.
 ///
 /// # Example
 /// ```rust,ignore
@@ -192,11 +749,11 @@ pub async fn with_retry<F, T>(strategy: &RetryStrategy, mut f: F) -> SwarmResult
 where
     F: FnMut() -> Pin<Box<dyn Future<Output = SwarmResult<T>> + 'static>>,
 {
-    let mut delay = strategy.initial_delay();
     for attempt in 0..=strategy.max_retries() {
         match f().await {
             Ok(value) => return Ok(value),
             Err(err) if attempt < strategy.max_retries() && err.is_retriable() => {
+                let delay = strategy.compute_delay(attempt);
                 tracing::warn!(
                     "Retryable error on attempt {}/{}, retrying in {}ms: {}",
                     attempt + 1,
@@ -205,8 +762,6 @@ where
                     err
                 );
                 tokio::time::sleep(delay).await;
-                let next_ms = (delay.as_millis() as f64 * strategy.backoff_factor() as f64) as u64;
-                delay = Duration::from_millis(next_ms.min(strategy.max_delay().as_millis() as u64));
             }
             Err(err) => return Err(err),
         }
@@ -1,7 +1,7 @@
 //  ./src/validation.rs
 /// Validation module for Swarm API requests and configurations.
 use crate::error::{SwarmError, SwarmResult};
-use crate::types::{Agent, Instructions, Message, RuntimeLimits, SwarmConfig};
+use crate::types::{Agent, Instructions, Message, MessageRole, RuntimeLimits, SwarmConfig};
 use serde_json::Value;
 use std::time::Instant;
 use url::Url;
@@ -31,6 +31,7 @@ use url::Url;
 /// * Agent name is empty
 /// * Agent instructions are empty
 /// * Message roles or content are empty
+/// * A message contains image parts and the model matches no default vision pattern
 /// * max_turns is 0 or exceeds config.max_loop_iterations
 ///
 ///
@@ -39,6 +40,29 @@ pub fn validate_api_request(
     messages: &[Message],
     model: &Option<String>,
     max_turns: usize,
+) -> SwarmResult<()> {
+    validate_api_request_with_config(
+        agent,
+        messages,
+        &[],
+        model,
+        max_turns,
+        &SwarmConfig::default(),
+    )
+}
+
+/// Validates an API request against the settings of `config`.
+///
+/// Performs the same checks as [`validate_api_request`], and also checks the
+/// run's `priming_messages`. Image inputs are checked against
+/// `config.vision_capable_model_patterns()` instead of the default patterns.
+pub fn validate_api_request_with_config(
+    agent: &Agent,
+    messages: &[Message],
+    priming_messages: &[Message],
+    model: &Option<String>,
+    max_turns: usize,
+    config: &SwarmConfig,
 ) -> SwarmResult<()> {
     // Validate max_turns
     if max_turns == 0 {
@@ -83,7 +107,51 @@ pub fn validate_api_request(
     for message in messages {
         message.validate()?;
     }
+    validate_priming_messages(priming_messages)?;
+
+    // Validate image inputs against the model that will receive them
+    let model_name = model.as_deref().unwrap_or(agent.model());
+    if messages
+        .iter()
+        .chain(priming_messages)
+        .any(Message::has_images)
+        && !config.is_vision_capable_model(model_name)
+    {
+        return Err(SwarmError::ValidationError(format!(
+            "Model '{}' does not accept image inputs",
+            model_name
+        )));
+    }
+
+    Ok(())
+}
 
+/// Validates run-level priming messages.
+///
+/// Priming messages are resent on every request but never stored in history,
+/// so each one must be a self-contained system, user or assistant text message.
+///
+/// # Errors
+///
+/// Will return `SwarmError::ValidationError` if a priming message:
+/// * Is structurally invalid
+/// * Uses the function or tool role
+/// * Has no content
+fn validate_priming_messages(messages: &[Message]) -> SwarmResult<()> {
+    for message in messages {
+        message.validate()?;
+        if matches!(message.role(), MessageRole::Function | MessageRole::Tool) {
+            return Err(SwarmError::ValidationError(format!(
+                "Priming messages cannot use the {} role",
+                message.role()
+            )));
+        }
+        if message.content().is_none() {
+            return Err(SwarmError::ValidationError(
+                "Priming messages require content".to_string(),
+            ));
+        }
+    }
     Ok(())
 }
 
@@ -365,4 +433,19 @@ mod tests {
             Err(BudgetExhausted::MaxDepth { depth: 2, limit: 1 })
         ));
     }
+
+    #[test]
+    fn test_priming_messages_reject_tool_roles() {
+        assert!(validate_priming_messages(&[
+            Message::system("Reference document").expect("system"),
+            Message::user("Example question").expect("user"),
+            Message::assistant("Example answer").expect("assistant"),
+        ])
+        .is_ok());
+
+        let error =
+            validate_priming_messages(&[Message::function("lookup", "result").expect("fn")])
+                .expect_err("function role is not allowed");
+        assert!(error.to_string().contains("function role"));
+    }
 }